@@ -376,3 +376,23 @@ instance Show a => Show (Maybe a) where
     show x = case x of
         Just y -> "Just (" ++ show y ++ ")"
         Nothing -> "Nothing"
+
+showArgument :: [Char] -> [Char]
+showArgument s = case s of
+    : c cs -> case c of
+        '(' -> s
+        '[' -> s
+        '"' -> s
+        '\'' -> s
+        '-' -> "(" ++ s ++ ")"
+        x -> case hasSpace cs of
+            True -> "(" ++ s ++ ")"
+            False -> s
+    [] -> s
+
+hasSpace :: [Char] -> Bool
+hasSpace s = case s of
+    : c cs -> case c of
+        ' ' -> True
+        x -> hasSpace cs
+    [] -> False
@@ -0,0 +1,539 @@
+//Test support for property based tests.
+//Generates random (but reproducible) modules and expressions together with a printer
+//which turns them back into source code which the parser accepts.
+use std::hashmap::HashMap;
+use module::*;
+use typecheck::function_type;
+
+///Small deterministic random number generator so that a failing case can be reproduced from its seed
+pub struct Gen {
+    priv state: u64
+}
+
+impl Gen {
+    pub fn new(seed: u64) -> Gen {
+        Gen { state: seed * 6364136223846793005 + 1442695040888963407 }
+    }
+
+    pub fn next(&mut self) -> u64 {
+        //xorshift64*
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state * 2685821657736338717
+    }
+
+    ///Returns a number in the range [0, n)
+    pub fn below(&mut self, n: uint) -> uint {
+        (self.next() % (n as u64)) as uint
+    }
+
+    pub fn chance(&mut self, percent: uint) -> bool {
+        self.below(100) < percent
+    }
+
+    pub fn choose<'a, T>(&mut self, values: &'a [T]) -> &'a T {
+        &values[self.below(values.len())]
+    }
+}
+
+static OPERATORS: &'static [&'static str] = &["+", "-", "*", "==", "<", "++", "&&"];
+static GLOBALS: &'static [&'static str] = &["map", "foldl", "id", "not", "primIntAdd", "fst"];
+
+fn variable_name(gen: &mut Gen) -> ~str {
+    format!("v{}", gen.below(20))
+}
+
+fn alphanumeric(gen: &mut Gen) -> char {
+    let chars = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+    chars.char_at(gen.below(chars.len()))
+}
+
+fn leaf(gen: &mut Gen, scope: &[~str]) -> TypedExpr {
+    match gen.below(6) {
+        0 if scope.len() > 0 => TypedExpr::new(Identifier(intern(*gen.choose(scope)))),
+        1 => TypedExpr::new(Identifier(intern(*gen.choose(GLOBALS)))),
+        2 => TypedExpr::new(Rational(gen.below(100) as f64 + 0.25 * (1 + gen.below(3)) as f64)),
+        3 => {
+            let mut s = ~"";
+            for _ in range(0, gen.below(5)) {
+                s.push_char(alphanumeric(gen));
+            }
+            TypedExpr::new(String(s))
+        }
+        4 => TypedExpr::new(Char(alphanumeric(gen))),
+        _ => TypedExpr::new(Number(gen.below(1000) as int))
+    }
+}
+
+fn apply2(func: ~str, lhs: TypedExpr, rhs: TypedExpr) -> TypedExpr {
+    let f = TypedExpr::new(Identifier(intern(func)));
+    TypedExpr::new(Apply(~(TypedExpr::new(Apply(~(f, lhs))), rhs)))
+}
+
+///Generates an arbitrary pattern which can appear directly after 'case ... of'
+fn arbitrary_pattern(gen: &mut Gen, bound: &mut ~[~str]) -> Pattern {
+    match gen.below(5) {
+        0 => NumberPattern(gen.below(100) as int),
+        1 => ConstructorPattern(~"[]", ~[]),
+        2 => {
+            let args = ~[arbitrary_sub_pattern(gen, bound), arbitrary_sub_pattern(gen, bound)];
+            ConstructorPattern(~":", args)
+        }
+        3 => {
+            let args = ~[arbitrary_sub_pattern(gen, bound), arbitrary_sub_pattern(gen, bound)];
+            ConstructorPattern(~"(,)", args)
+        }
+        _ => {
+            let name = variable_name(gen);
+            bound.push(name.clone());
+            IdentifierPattern(intern(name))
+        }
+    }
+}
+
+///Generates a pattern which can appear as an argument to a constructor pattern
+fn arbitrary_sub_pattern(gen: &mut Gen, bound: &mut ~[~str]) -> Pattern {
+    match gen.below(4) {
+        0 => NumberPattern(gen.below(100) as int),
+        1 => ConstructorPattern(~"[]", ~[]),
+        _ => {
+            let name = variable_name(gen);
+            bound.push(name.clone());
+            IdentifierPattern(intern(name))
+        }
+    }
+}
+
+fn arbitrary_binding(gen: &mut Gen, name: ~str, scope: &[~str], depth: uint) -> Binding {
+    let arity = gen.below(3);
+    let mut inner_scope = scope.to_owned();
+    let mut arguments = ~[];
+    for _ in range(0, arity) {
+        let arg = variable_name(gen);
+        inner_scope.push(arg.clone());
+        arguments.push(arg);
+    }
+    let mut expression = arbitrary_expr(gen, inner_scope, depth);
+    while arguments.len() > 0 {
+        expression = TypedExpr::new(Lambda(intern(arguments.pop()), ~expression));
+    }
+    Binding { name: name, expression: expression, typeDecl: Default::default(), arity: arity }
+}
+
+///Generates an arbitrary expression which the parser can produce, nested at most 'depth' levels.
+///The expression is not necessarily well typed.
+pub fn arbitrary_expr(gen: &mut Gen, scope: &[~str], depth: uint) -> TypedExpr {
+    if depth == 0 {
+        return leaf(gen, scope);
+    }
+    match gen.below(8) {
+        0 => {
+            let f = leaf(gen, scope);
+            let arg = arbitrary_expr(gen, scope, depth - 1);
+            TypedExpr::new(Apply(~(f, arg)))
+        }
+        1 => {
+            let op = gen.choose(OPERATORS).to_owned();
+            let lhs = arbitrary_expr(gen, scope, depth - 1);
+            let rhs = arbitrary_expr(gen, scope, depth - 1);
+            apply2(op, lhs, rhs)
+        }
+        2 => {
+            let lhs = arbitrary_expr(gen, scope, depth - 1);
+            let rhs = arbitrary_expr(gen, scope, depth - 1);
+            apply2(~"(,)", lhs, rhs)
+        }
+        3 => {
+            let mut list = TypedExpr::new(Identifier(intern("[]")));
+            for _ in range(0, gen.below(4)) {
+                let element = arbitrary_expr(gen, scope, depth - 1);
+                list = apply2(~":", element, list);
+            }
+            list
+        }
+        4 => {
+            let mut bindings = ~[];
+            let mut inner_scope = scope.to_owned();
+            for i in range(0, 1 + gen.below(3)) {
+                inner_scope.push(format!("l{}", i));
+            }
+            for i in range(0, inner_scope.len() - scope.len()) {
+                bindings.push(arbitrary_binding(gen, format!("l{}", i), inner_scope, depth - 1));
+            }
+            let body = arbitrary_expr(gen, inner_scope, depth - 1);
+            TypedExpr::new(Let(bindings, ~body))
+        }
+        5 => {
+            let scrutinee = arbitrary_expr(gen, scope, depth - 1);
+            let mut alts = ~[];
+            for _ in range(0, 1 + gen.below(3)) {
+                let mut inner_scope = scope.to_owned();
+                let pattern = arbitrary_pattern(gen, &mut inner_scope);
+                let expression = arbitrary_expr(gen, inner_scope, depth - 1);
+                alts.push(Alternative { pattern: Located { location: Location::eof(), node: pattern }, expression: expression });
+            }
+            TypedExpr::new(Case(~scrutinee, alts))
+        }
+        _ => leaf(gen, scope)
+    }
+}
+
+fn arbitrary_data_definition(gen: &mut Gen, index: uint) -> DataDefinition {
+    let name = format!("T{}", index);
+    let has_parameter = gen.chance(50);
+    let mut parameters = HashMap::new();
+    let typ = if has_parameter {
+        parameters.insert(~"a", -1);
+        Type::new_op(name.clone(), ~[Type::new_var(-1)])
+    }
+    else {
+        Type::new_op(name.clone(), ~[])
+    };
+    let mut constructors = ~[];
+    for tag in range(0, 1 + gen.below(3)) {
+        let arity = gen.below(3);
+        let mut ctor_type = typ.clone();
+        for _ in range(0, arity) {
+            let arg = match gen.below(3) {
+                0 if has_parameter => Type::new_var(-1),
+                1 => typ.clone(),
+                _ => Type::new_op(~"Int", ~[])
+            };
+            ctor_type = function_type(&arg, &ctor_type);
+        }
+        constructors.push(Constructor { name: format!("C{}{}", index, tag), typ: ctor_type, tag: tag as int, arity: arity as int, fields: ~[] });
+    }
+    DataDefinition { constructors: constructors, typ: typ, parameters: parameters, context: ~[], deriving: ~[], location: Location::eof() }
+}
+
+///Generates an arbitrary module containing data definitions and top level bindings
+pub fn arbitrary_module(gen: &mut Gen) -> Module {
+    let mut dataDefinitions = ~[];
+    for i in range(0, gen.below(3)) {
+        dataDefinitions.push(arbitrary_data_definition(gen, i));
+    }
+    let names : ~[~str] = range(0, 1 + gen.below(5)).map(|i| format!("f{}", i)).collect();
+    let mut bindings = ~[];
+    for name in names.iter() {
+        let depth = 1 + gen.below(4);
+        bindings.push(arbitrary_binding(gen, name.clone(), names, depth));
+    }
+    let mut module = Module {
+        name: ~"Main",
+        bindings: bindings,
+        typeDeclarations: ~[],
+        classes: ~[],
+        instances: ~[],
+        dataDefinitions: dataDefinitions,
+        fixityDeclarations: ~[],
+        typeSynonyms: ~[],
+        imports: ~[],
+        exports: None,
+        documentation: HashMap::new(),
+        index: ModuleIndex::new()
+    };
+    module.build_index();
+    module
+}
+
+fn int_type() -> Type { Type::new_op(~"Int", ~[]) }
+fn double_type() -> Type { Type::new_op(~"Double", ~[]) }
+fn char_type() -> Type { Type::new_op(~"Char", ~[]) }
+fn list_type(typ: Type) -> Type { Type::new_op(~"[]", ~[typ]) }
+
+///Generates a type which 'arbitrary_typed_expr' knows how to create expressions for
+pub fn arbitrary_type(gen: &mut Gen, depth: uint) -> Type {
+    let choice = if depth == 0 { gen.below(3) } else { gen.below(5) };
+    match choice {
+        0 => int_type(),
+        1 => double_type(),
+        2 => char_type(),
+        3 => list_type(arbitrary_type(gen, depth - 1)),
+        _ => {
+            let lhs = arbitrary_type(gen, depth - 1);
+            let rhs = arbitrary_type(gen, depth - 1);
+            Type::new_op(~"(,)", ~[lhs, rhs])
+        }
+    }
+}
+
+///Generates an expression which has the type 'typ' when typechecked in an environment
+///containing only the primitives of TypeEnvironment::new
+pub fn arbitrary_typed_expr(gen: &mut Gen, typ: &Type, scope: &[(~str, Type)], depth: uint) -> TypedExpr {
+    //Use a variable in scope with the same type sometimes
+    let candidates : ~[&(~str, Type)] = scope.iter().filter(|&&(_, ref t)| t == typ).collect();
+    if candidates.len() > 0 && gen.chance(30) {
+        let &&(ref name, _) = gen.choose(candidates);
+        return TypedExpr::new(Identifier(intern(*name)));
+    }
+    if depth > 0 {
+        match gen.below(4) {
+            0 => {
+                //let v = <expr> in <expr>
+                let bound_type = arbitrary_type(gen, 1);
+                let name = format!("l{}", scope.len());
+                let bound = arbitrary_typed_expr(gen, &bound_type, scope, depth - 1);
+                let mut inner_scope = scope.to_owned();
+                inner_scope.push((name.clone(), bound_type));
+                let body = arbitrary_typed_expr(gen, typ, inner_scope, depth - 1);
+                let bind = Binding { name: name, expression: bound, typeDecl: Default::default(), arity: 0 };
+                return TypedExpr::new(Let(~[bind], ~body));
+            }
+            1 => {
+                //case <[Int]> of { : y ys -> <expr> ; [] -> <expr> }
+                let scrutinee = arbitrary_typed_expr(gen, &list_type(int_type()), scope, depth - 1);
+                let head = format!("h{}", scope.len());
+                let tail = format!("t{}", scope.len());
+                let mut inner_scope = scope.to_owned();
+                inner_scope.push((head.clone(), int_type()));
+                inner_scope.push((tail.clone(), list_type(int_type())));
+                let cons = Alternative {
+                    pattern: Located { location: Location::eof(), node: ConstructorPattern(~":", ~[IdentifierPattern(intern(head)), IdentifierPattern(intern(tail))]) },
+                    expression: arbitrary_typed_expr(gen, typ, inner_scope, depth - 1)
+                };
+                let nil = Alternative {
+                    pattern: Located { location: Location::eof(), node: ConstructorPattern(~"[]", ~[]) },
+                    expression: arbitrary_typed_expr(gen, typ, scope, depth - 1)
+                };
+                return TypedExpr::new(Case(~scrutinee, ~[cons, nil]));
+            }
+            _ => ()
+        }
+    }
+    let next = if depth == 0 { 0 } else { depth - 1 };
+    match (&typ.typ, typ.types().len()) {
+        (&TypeOperator(ref op), 0) if "Int" == op.name => {
+            if depth > 0 && gen.chance(50) {
+                let prim = gen.choose(&["primIntAdd", "primIntSubtract", "primIntMultiply"]).to_owned();
+                let lhs = arbitrary_typed_expr(gen, typ, scope, next);
+                let rhs = arbitrary_typed_expr(gen, typ, scope, next);
+                apply2(prim, lhs, rhs)
+            }
+            else if depth > 0 && gen.chance(50) {
+                let arg = arbitrary_typed_expr(gen, &double_type(), scope, next);
+                TypedExpr::new(Apply(~(TypedExpr::new(Identifier(intern("primDoubleToInt"))), arg)))
+            }
+            else {
+                let lhs = TypedExpr::new(Number(gen.below(100) as int));
+                apply2(~"primIntAdd", lhs, TypedExpr::new(Number(gen.below(100) as int)))
+            }
+        }
+        (&TypeOperator(ref op), 0) if "Double" == op.name => {
+            if depth > 0 && gen.chance(50) {
+                let prim = gen.choose(&["primDoubleAdd", "primDoubleSubtract", "primDoubleMultiply"]).to_owned();
+                let lhs = arbitrary_typed_expr(gen, typ, scope, next);
+                let rhs = arbitrary_typed_expr(gen, typ, scope, next);
+                apply2(prim, lhs, rhs)
+            }
+            else if depth > 0 && gen.chance(50) {
+                let arg = arbitrary_typed_expr(gen, &int_type(), scope, next);
+                TypedExpr::new(Apply(~(TypedExpr::new(Identifier(intern("primIntToDouble"))), arg)))
+            }
+            else {
+                let lhs = TypedExpr::new(Rational(gen.below(100) as f64 + 0.5));
+                apply2(~"primDoubleAdd", lhs, TypedExpr::new(Rational(0.25)))
+            }
+        }
+        (&TypeOperator(ref op), 0) if "Char" == op.name => TypedExpr::new(Char(alphanumeric(gen))),
+        (&TypeOperator(ref op), 1) if "[]" == op.name => {
+            if typ.types()[0] == char_type() && gen.chance(50) {
+                let mut s = ~"";
+                for _ in range(0, 1 + gen.below(4)) {
+                    s.push_char(alphanumeric(gen));
+                }
+                return TypedExpr::new(String(s));
+            }
+            //A non-empty list so that the element type is known
+            let mut list = TypedExpr::new(Identifier(intern("[]")));
+            for _ in range(0, 1 + gen.below(3)) {
+                let element = arbitrary_typed_expr(gen, &typ.types()[0], scope, next);
+                list = apply2(~":", element, list);
+            }
+            list
+        }
+        (&TypeOperator(ref op), 2) if "(,)" == op.name => {
+            let lhs = arbitrary_typed_expr(gen, &typ.types()[0], scope, next);
+            let rhs = arbitrary_typed_expr(gen, &typ.types()[1], scope, next);
+            apply2(~"(,)", lhs, rhs)
+        }
+        _ => fail!("arbitrary_typed_expr: Can't generate an expression of type {}", *typ)
+    }
+}
+
+///Turns a module back into source code which parses to the same module
+pub fn show_module(module: &Module) -> ~str {
+    let mut result = ~"";
+    for data in module.dataDefinitions.iter() {
+        result.push_str(show_data_definition(data));
+        result.push_char('\n');
+    }
+    for bind in module.bindings.iter() {
+        result.push_str(show_binding(bind));
+        result.push_char('\n');
+    }
+    result
+}
+
+fn show_data_definition(data: &DataDefinition) -> ~str {
+    let mut result = format!("data {}", data.typ.op().name);
+    for name in data.parameters.keys() {
+        result.push_str(" " + *name);
+    }
+    result.push_str(" =");
+    for (i, ctor) in data.constructors.iter().enumerate() {
+        if i != 0 {
+            result.push_str(" |");
+        }
+        result.push_str(" " + ctor.name);
+        let mut typ = &ctor.typ;
+        for _ in range(0, ctor.arity) {
+            result.push_str(" " + show_constructor_argument(data, &typ.types()[0]));
+            typ = &typ.types()[1];
+        }
+    }
+    result
+}
+
+fn show_constructor_argument(data: &DataDefinition, typ: &Type) -> ~str {
+    match &typ.typ {
+        &TypeVariable(_) => {
+            //All parameters share the same variable so any name will do
+            data.parameters.keys().next().expect("Type variable in data definition without parameters").clone()
+        }
+        &TypeOperator(ref op) => {
+            if typ.types().len() == 0 {
+                op.name.clone()
+            }
+            else {
+                let mut result = "(" + op.name;
+                for arg in typ.types().iter() {
+                    result.push_str(" " + show_constructor_argument(data, arg));
+                }
+                result + ")"
+            }
+        }
+    }
+}
+
+fn show_binding(bind: &Binding) -> ~str {
+    let mut result = bind.name.clone();
+    let mut expr = &bind.expression;
+    for _ in range(0, bind.arity) {
+        match &expr.expr {
+            &Lambda(ref arg, ref body) => {
+                result.push_str(" " + arg.as_slice());
+                expr = &**body;
+            }
+            _ => fail!("Binding {} has fewer lambdas than its arity", bind.name)
+        }
+    }
+    result.push_str(" = ");
+    result.push_str(show_expr(expr));
+    result
+}
+
+///Prints an expression, adding parentheses around every compound expression
+pub fn show_expr(expr: &TypedExpr) -> ~str {
+    match &expr.expr {
+        &Identifier(ref name) => name.to_owned(),
+        &Number(n) => n.to_str(),
+        &Rational(r) => r.to_str(),
+        &String(ref s) => format!("\"{}\"", *s),
+        &Char(c) => format!("'{}'", c),
+        &Apply(~(ref func, ref arg)) => {
+            match &func.expr {
+                &Apply(~(ref op, ref lhs)) => {
+                    match &op.expr {
+                        &Identifier(ref name) if "(,)" == name.as_slice() => {
+                            return format!("({}, {})", show_expr(lhs), show_expr(arg));
+                        }
+                        &Identifier(ref name) if !name.as_slice().char_at(0).is_alphanumeric() && name.as_slice().char_at(0) != '(' => {
+                            return format!("({} {} {})", show_expr(lhs), *name, show_expr(arg));
+                        }
+                        _ => ()
+                    }
+                }
+                _ => ()
+            }
+            format!("({} {})", show_expr(func), show_expr(arg))
+        }
+        &Lambda(_, _) => fail!("Lambda expressions have no syntax"),
+        &Let(ref bindings, ref body) => {
+            let binds : ~[~str] = bindings.iter().map(show_binding).collect();
+            format!("(let \\{ {} \\} in {})", binds.connect(" ; "), show_expr(*body))
+        }
+        &Case(ref scrutinee, ref alts) => {
+            let alternatives : ~[~str] = alts.iter()
+                .map(|alt| format!("{} -> {}", show_pattern(&alt.pattern.node), show_expr(&alt.expression)))
+                .collect();
+            format!("(case {} of \\{ {} \\})", show_expr(*scrutinee), alternatives.connect(" ; "))
+        }
+    }
+}
+
+fn show_pattern(pattern: &Pattern) -> ~str {
+    match pattern {
+        &NumberPattern(n) => n.to_str(),
+        &CharPattern(c) => format!("'{}'", c),
+        &IdentifierPattern(ref name) => name.to_owned(),
+        &ConstructorPattern(ref name, ref patterns) => {
+            if name.char_at(0) == '(' {
+                let args : ~[~str] = patterns.iter().map(show_pattern).collect();
+                format!("({})", args.connect(", "))
+            }
+            else {
+                let mut result = name.clone();
+                for p in patterns.iter() {
+                    result.push_str(" " + show_pattern(p));
+                }
+                result
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+use std::task;
+use arbitrary::*;
+use parser::parse_module_safe;
+use typecheck::TypeEnvironment;
+
+#[test]
+fn parse_print_roundtrip() {
+    for seed in range(0u64, 200) {
+        let mut gen = Gen::new(seed);
+        let module = arbitrary_module(&mut gen);
+        let source = show_module(&module);
+        match parse_module_safe(source) {
+            Ok(parsed) => {
+                assert!(parsed.bindings == module.bindings, "Seed {} did not roundtrip:\n{}", seed, source);
+                assert!(parsed.dataDefinitions == module.dataDefinitions, "Seed {} did not roundtrip:\n{}", seed, source);
+            }
+            Err(errors) => fail!("Seed {} failed to parse: {:?}\n{}", seed, errors, source)
+        }
+    }
+}
+
+#[test]
+fn typecheck_arbitrary_expressions() {
+    for seed in range(0u64, 200) {
+        let result = task::try(proc() {
+            let mut gen = Gen::new(seed);
+            let typ = arbitrary_type(&mut gen, 2);
+            let mut expr = arbitrary_typed_expr(&mut gen, &typ, [], 3);
+            let mut env = TypeEnvironment::new();
+            env.typecheck(&mut expr).unwrap();
+            expr.typ == typ
+        });
+        match result {
+            Ok(true) => (),
+            Ok(false) => fail!("Seed {} did not typecheck to the expected type", seed),
+            Err(_) => fail!("Typechecking seed {} failed", seed)
+        }
+    }
+}
+
+}
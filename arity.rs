@@ -0,0 +1,225 @@
+//Arity analysis and eta expansion of the top level functions of a module.
+//A binding such as 'inc = add 1' has arity 0 so the call 'inc 2' first evaluates 'inc' to a partial application
+//which the Unwind loop then has to apply to '2'. When the arguments of the partial application are cheap to
+//compute again the binding is expanded to 'inc x = add 1 x' which has the true arity 1, so calls of it are
+//saturated and the strictness analysis and the inliner can see through it.
+//Expressions which do work, such as 'add (f 2)', are never expanded as the work would be repeated on every call.
+use std::hashmap::HashMap;
+use std::util::replace;
+use module::{Module, Binding, TypedExpr, Type, TypeOperator, Identifier, Apply, Number, Rational, Char, Lambda};
+use interner::{InternedStr, intern};
+use typecheck::function_type;
+use strictness::primitive_arity;
+use variables::free_variables;
+
+///Expands the bindings of 'module' which evaluate to a partial application so their arity is their true arity
+pub fn eta_expand_module(module: &mut Module) {
+    let mut true_arities = HashMap::new();
+    {
+        let mut arities = HashMap::new();
+        for data in module.dataDefinitions.iter() {
+            for ctor in data.constructors.iter() {
+                arities.insert(intern(ctor.name), ctor.arity as uint);
+            }
+        }
+        //A function with a context is also given an instance dictionary so it is left as it is
+        let mut bindings = HashMap::new();
+        for bind in module.bindings.iter().filter(|bind| bind.typeDecl.context.len() == 0) {
+            arities.insert(intern(bind.name), bind.arity);
+            bindings.insert(intern(bind.name), bind);
+        }
+        for bind in module.bindings.iter() {
+            true_arity(intern(bind.name), &bindings, &arities, &mut true_arities, &mut ~[]);
+        }
+    }
+    for bind in module.bindings.mut_iter() {
+        let arity = match true_arities.find(&intern(bind.name)) {
+            Some(arity) => *arity,
+            None => continue
+        };
+        if arity > bind.arity {
+            let added = eta_expand(&mut bind.expression, bind.arity, arity - bind.arity);
+            bind.arity += added;
+        }
+    }
+}
+
+///Returns the arity which the global 'name' has after eta expansion or None if it is not a known function.
+///'visiting' holds the bindings whose arity is being computed, a binding which is reached again keeps its arity.
+fn true_arity(name: InternedStr, bindings: &HashMap<InternedStr, &Binding>, arities: &HashMap<InternedStr, uint>,
+        true_arities: &mut HashMap<InternedStr, uint>, visiting: &mut ~[InternedStr]) -> Option<uint> {
+    match true_arities.find(&name) {
+        Some(arity) => return Some(*arity),
+        None => ()
+    }
+    let arity = match arities.find(&name) {
+        Some(arity) => *arity,
+        None => return primitive_arity(name.as_slice())
+    };
+    let bind = match bindings.find(&name) {
+        Some(bind) => *bind,
+        None => return Some(arity)
+    };
+    if visiting.contains(&name) {
+        return Some(arity);
+    }
+    visiting.push(name);
+    let result = match partial_application(bind) {
+        Some((head, argument_count)) => {
+            match true_arity(head, bindings, arities, true_arities, visiting) {
+                Some(head_arity) if head_arity > argument_count => arity + head_arity - argument_count,
+                _ => arity
+            }
+        }
+        None => arity
+    };
+    visiting.pop();
+    true_arities.insert(name, result);
+    Some(result)
+}
+
+///Returns the function and the number of arguments it is applied to if the body of 'bind' is an application
+///of a global to arguments which are cheap to compute
+fn partial_application(bind: &Binding) -> Option<(InternedStr, uint)> {
+    let mut arguments = ~[];
+    let mut body = &bind.expression;
+    loop {
+        match &body.expr {
+            &Lambda(ref arg, ref inner) if arguments.len() < bind.arity => {
+                arguments.push(*arg);
+                body = &**inner;
+            }
+            _ => break
+        }
+    }
+    let mut count = 0;
+    loop {
+        match &body.expr {
+            &Apply(~(ref func, ref arg)) => {
+                if !is_cheap(arg) {
+                    return None;
+                }
+                count += 1;
+                body = func;
+            }
+            _ => break
+        }
+    }
+    match &body.expr {
+        //An argument of the binding may shadow a global
+        &Identifier(ref name) if !arguments.contains(name) => Some((*name, count)),
+        _ => None
+    }
+}
+
+fn is_cheap(expr: &TypedExpr) -> bool {
+    match &expr.expr {
+        &Identifier(_) | &Number(_) | &Rational(_) | &Char(_) => true,
+        _ => false
+    }
+}
+
+///Splits a function type into the type of its argument and the type of its result
+fn split_function(typ: &Type) -> Option<(Type, Type)> {
+    match &typ.typ {
+        &TypeOperator(ref op) if "->" == op.name => Some((typ.types()[0].clone(), typ.types()[1].clone())),
+        _ => None
+    }
+}
+
+///Adds up to 'count' arguments to the body below the first 'depth' lambdas of 'expr' and applies the body to them,
+///fewer are added if the type of the body does not have that many arguments. Returns the number of arguments added.
+fn eta_expand(expr: &mut TypedExpr, depth: uint, count: uint) -> uint {
+    if depth > 0 {
+        return match expr.expr {
+            Lambda(_, ref mut body) => eta_expand(&mut **body, depth - 1, count),
+            _ => 0
+        };
+    }
+    let location = expr.location;
+    let mut body = replace(expr, TypedExpr::new(Number(0)));
+    //The new arguments must not capture a variable which the body refers to
+    let mut free = ~[];
+    free_variables(&body, &mut ~[], &mut free);
+    let mut index = 0;
+    let mut arguments = ~[];
+    while arguments.len() < count {
+        let (argument_type, result_type) = match split_function(&body.typ) {
+            Some(types) => types,
+            None => break
+        };
+        let name = fresh_name(free.as_slice(), &mut index);
+        let mut argument = TypedExpr::with_location(Identifier(name), location);
+        argument.typ = argument_type.clone();
+        let mut apply = TypedExpr::with_location(Apply(~(body, argument)), location);
+        apply.typ = result_type;
+        body = apply;
+        arguments.push((name, argument_type));
+    }
+    let added = arguments.len();
+    for (name, argument_type) in arguments.move_rev_iter() {
+        let typ = function_type(&argument_type, &body.typ);
+        let mut lambda = TypedExpr::with_location(Lambda(name, ~body), location);
+        lambda.typ = typ;
+        body = lambda;
+    }
+    *expr = body;
+    added
+}
+
+///Returns the first name '#eta<n>', starting from 'index', which is not one of the variables in 'free'
+fn fresh_name(free: &[(InternedStr, Type)], index: &mut uint) -> InternedStr {
+    loop {
+        let name = intern("#eta" + index.to_str());
+        *index += 1;
+        if !free.iter().any(|&(ref var, _)| *var == name) {
+            return name;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+use arity::eta_expand_module;
+use parser::Parser;
+use typecheck::TypeEnvironment;
+use vm::{execute_main, IntResult};
+
+#[test]
+fn expand_partial_applications() {
+    let mut parser = Parser::new(
+r"data Pair a b = Pair a b
+add x y = primIntAdd x y
+inc = add 1
+increment = inc
+pair = Pair 1
+double = primIntMultiply 2
+expensive = add (primIntMultiply 2 3)
+apply f = f".chars());
+    let mut module = parser.module();
+    let mut type_env = TypeEnvironment::new();
+    type_env.typecheck_module(&mut module).unwrap();
+    eta_expand_module(&mut module);
+    let arity = |name: &str| module.bindings.iter().find(|bind| name == bind.name.as_slice()).unwrap().arity;
+    assert_eq!(arity("add"), 2);
+    assert_eq!(arity("inc"), 1);
+    assert_eq!(arity("increment"), 1);
+    assert_eq!(arity("pair"), 1);
+    assert_eq!(arity("double"), 1);
+    assert_eq!(arity("expensive"), 0);
+    assert_eq!(arity("apply"), 1);
+}
+
+#[test]
+fn run_expanded_bindings() {
+    let module =
+r"data Box a = Box a
+add x y = primIntAdd x y
+inc = add 1
+box = Box
+unbox b = case b of
+    Box x -> x
+main = unbox (box (inc 2))";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(3)));
+}
+}
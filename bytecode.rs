@@ -0,0 +1,370 @@
+//Compact encoding of the instructions which the VM executes.
+//Each instruction is stored as an 8 byte Op holding an opcode and a 32 bit operand, Pack and PushDictionaryRange store
+//their two operands as two 16 bit halves of the operand. Operands which do not fit in 32 bits
+//(integers, doubles and strings) are stored in constant tables next to the ops and the operand is their index.
+//Jump addresses stay valid since every instruction is encoded as exactly one op.
+use std::cast;
+use compiler::*;
+use hbc::{Encoder, Decoder};
+
+#[deriving(Clone, Eq)]
+pub struct Op {
+    opcode: u8,
+    operand: u32
+}
+
+///Encoded instructions together with the constants they refer to
+#[deriving(Clone, Eq)]
+pub struct Code {
+    priv ops: ~[Op],
+    priv ints: ~[int],
+    priv doubles: ~[f64],
+    priv strings: ~[~str]
+}
+
+///A borrowed view of Code which the VM executes
+#[deriving(Clone)]
+pub struct CodeSlice<'a> {
+    priv ops: &'a [Op],
+    priv ints: &'a [int],
+    priv doubles: &'a [f64],
+    priv strings: &'a [~str]
+}
+
+pub static ADD: u8 = 0;
+pub static SUB: u8 = 1;
+pub static MULTIPLY: u8 = 2;
+pub static DIVIDE: u8 = 3;
+pub static REMAINDER: u8 = 4;
+pub static INT_EQ: u8 = 5;
+pub static INT_LT: u8 = 6;
+pub static INT_LE: u8 = 7;
+pub static INT_GT: u8 = 8;
+pub static INT_GE: u8 = 9;
+pub static DOUBLE_ADD: u8 = 10;
+pub static DOUBLE_SUB: u8 = 11;
+pub static DOUBLE_MULTIPLY: u8 = 12;
+pub static DOUBLE_DIVIDE: u8 = 13;
+pub static DOUBLE_REMAINDER: u8 = 14;
+pub static DOUBLE_EQ: u8 = 15;
+pub static DOUBLE_LT: u8 = 16;
+pub static DOUBLE_LE: u8 = 17;
+pub static DOUBLE_GT: u8 = 18;
+pub static DOUBLE_GE: u8 = 19;
+pub static INT_TO_DOUBLE: u8 = 20;
+pub static DOUBLE_TO_INT: u8 = 21;
+pub static PUSH: u8 = 22;
+pub static PUSH_GLOBAL: u8 = 23;
+pub static PUSH_INT: u8 = 24;
+pub static PUSH_FLOAT: u8 = 25;
+pub static PUSH_CHAR: u8 = 26;
+pub static MKAP: u8 = 27;
+pub static EVAL: u8 = 28;
+pub static UNWIND: u8 = 29;
+pub static UPDATE: u8 = 30;
+pub static POP: u8 = 31;
+pub static SLIDE: u8 = 32;
+pub static SPLIT: u8 = 33;
+pub static PACK: u8 = 34;
+pub static CASE_JUMP: u8 = 35;
+pub static JUMP: u8 = 36;
+pub static JUMP_FALSE: u8 = 37;
+pub static PUSH_DICTIONARY: u8 = 38;
+pub static PUSH_DICTIONARY_MEMBER: u8 = 39;
+pub static CHAR_EQ: u8 = 40;
+pub static PUSH_STRING: u8 = 41;
+pub static ALLOC: u8 = 42;
+pub static FILL: u8 = 43;
+pub static PUSH_DICTIONARY_RANGE: u8 = 44;
+pub static CASE_TABLE: u8 = 45;
+pub static FAIL: u8 = 46;
+
+static EVAL_OPS: &'static [Op] = &[Op { opcode: EVAL, operand: 0 }];
+static UNWIND_OPS: &'static [Op] = &[Op { opcode: UNWIND, operand: 0 }];
+
+fn operand(value: uint) -> u32 {
+    assert!(value <= ::std::u32::max_value as uint, "Operand {} does not fit in an instruction", value);
+    value as u32
+}
+
+fn half(value: uint) -> u32 {
+    assert!(value <= ::std::u16::max_value as uint, "Operand {} does not fit in half of an instruction", value);
+    value as u32
+}
+
+impl Code {
+    ///Encodes 'instructions'
+    pub fn encode(instructions: &[Instruction]) -> Code {
+        let mut code = Code { ops: ::std::vec::with_capacity(instructions.len()), ints: ~[], doubles: ~[], strings: ~[] };
+        for instruction in instructions.iter() {
+            code.push(instruction);
+        }
+        code
+    }
+
+    fn push(&mut self, instruction: &Instruction) {
+        let (opcode, value) = match *instruction {
+            Add => (ADD, 0),
+            Sub => (SUB, 0),
+            Multiply => (MULTIPLY, 0),
+            Divide => (DIVIDE, 0),
+            Remainder => (REMAINDER, 0),
+            IntEQ => (INT_EQ, 0),
+            IntLT => (INT_LT, 0),
+            IntLE => (INT_LE, 0),
+            IntGT => (INT_GT, 0),
+            IntGE => (INT_GE, 0),
+            DoubleAdd => (DOUBLE_ADD, 0),
+            DoubleSub => (DOUBLE_SUB, 0),
+            DoubleMultiply => (DOUBLE_MULTIPLY, 0),
+            DoubleDivide => (DOUBLE_DIVIDE, 0),
+            DoubleRemainder => (DOUBLE_REMAINDER, 0),
+            DoubleEQ => (DOUBLE_EQ, 0),
+            DoubleLT => (DOUBLE_LT, 0),
+            DoubleLE => (DOUBLE_LE, 0),
+            DoubleGT => (DOUBLE_GT, 0),
+            DoubleGE => (DOUBLE_GE, 0),
+            IntToDouble => (INT_TO_DOUBLE, 0),
+            DoubleToInt => (DOUBLE_TO_INT, 0),
+            CharEQ => (CHAR_EQ, 0),
+            Push(index) => (PUSH, operand(index)),
+            PushGlobal(index) => (PUSH_GLOBAL, operand(index)),
+            PushInt(value) => {
+                self.ints.push(value);
+                (PUSH_INT, operand(self.ints.len() - 1))
+            }
+            PushFloat(value) => {
+                self.doubles.push(value);
+                (PUSH_FLOAT, operand(self.doubles.len() - 1))
+            }
+            PushChar(c) => (PUSH_CHAR, c as u32),
+            PushString(ref s) => {
+                //Each distinct string is stored once no matter how many instructions push it
+                let index = match self.strings.iter().position(|string| string == s) {
+                    Some(index) => index,
+                    None => {
+                        self.strings.push(s.clone());
+                        self.strings.len() - 1
+                    }
+                };
+                (PUSH_STRING, operand(index))
+            }
+            Mkap => (MKAP, 0),
+            Eval => (EVAL, 0),
+            Unwind => (UNWIND, 0),
+            Update(index) => (UPDATE, operand(index)),
+            Alloc(size) => (ALLOC, operand(size)),
+            Fill(index) => (FILL, operand(index)),
+            Pop(size) => (POP, operand(size)),
+            Slide(size) => (SLIDE, operand(size)),
+            Split(size) => (SPLIT, operand(size)),
+            Pack(tag, arity) => (PACK, (tag as u32 << 16) | arity as u32),
+            CaseJump(tag) => (CASE_JUMP, operand(tag)),
+            CaseTable(size) => (CASE_TABLE, operand(size)),
+            Jump(address) => (JUMP, operand(address)),
+            JumpFalse(address) => (JUMP_FALSE, operand(address)),
+            PushDictionary(index) => (PUSH_DICTIONARY, operand(index)),
+            PushDictionaryMember(index) => (PUSH_DICTIONARY_MEMBER, operand(index)),
+            PushDictionaryRange(offset, size) => (PUSH_DICTIONARY_RANGE, (half(offset) << 16) | half(size)),
+            Fail => (FAIL, 0)
+        };
+        self.ops.push(Op { opcode: opcode, operand: value });
+    }
+
+    pub fn as_slice<'a>(&'a self) -> CodeSlice<'a> {
+        CodeSlice { ops: self.ops, ints: self.ints, doubles: self.doubles, strings: self.strings }
+    }
+
+    pub fn len(&self) -> uint {
+        self.ops.len()
+    }
+
+    ///Decodes every instruction
+    pub fn instructions(&self) -> ~[Instruction] {
+        self.as_slice().instructions()
+    }
+
+    ///Writes the ops and constant tables to 'e' (see hbc.rs)
+    pub fn serialize(&self, e: &mut Encoder) {
+        e.seq(self.ops.as_slice(), |e, op| { e.u8(op.opcode); e.u32(op.operand); });
+        e.seq(self.ints.as_slice(), |e, i| e.int(*i));
+        e.seq(self.doubles.as_slice(), |e, f| e.f64(*f));
+        e.seq(self.strings.as_slice(), |e, s| e.str(s.as_slice()));
+    }
+
+    ///Reads code written by 'serialize'. The ops are not checked, an invalid op fails when it is decoded.
+    pub fn deserialize(d: &mut Decoder) -> Result<Code, ~str> {
+        let ops = try!(d.seq(|d| {
+            let opcode = try!(d.u8());
+            Ok(Op { opcode: opcode, operand: try!(d.u32()) })
+        }));
+        let ints = try!(d.seq(|d| d.int()));
+        let doubles = try!(d.seq(|d| d.f64()));
+        let strings = try!(d.seq(|d| d.str()));
+        Ok(Code { ops: ops, ints: ints, doubles: doubles, strings: strings })
+    }
+}
+
+impl Op {
+    pub fn value(&self) -> uint {
+        self.operand as uint
+    }
+    ///The operand stored in the upper half of the op (the tag of Pack and the offset of PushDictionaryRange)
+    pub fn high(&self) -> uint {
+        (self.operand >> 16) as uint
+    }
+    ///The operand stored in the lower half of the op (the arity of Pack and the size of PushDictionaryRange)
+    pub fn low(&self) -> uint {
+        (self.operand & 0xFFFF) as uint
+    }
+    pub fn char(&self) -> char {
+        unsafe { cast::transmute(self.operand) }
+    }
+}
+
+impl <'a> CodeSlice<'a> {
+    pub fn len(&self) -> uint {
+        self.ops.len()
+    }
+
+    ///Returns the op at 'index' without decoding it, the VM dispatches on its opcode directly
+    pub fn op(&self, index: uint) -> Op {
+        self.ops[index]
+    }
+    pub fn int(&self, index: uint) -> int {
+        self.ints[index]
+    }
+    pub fn double(&self, index: uint) -> f64 {
+        self.doubles[index]
+    }
+    pub fn string(&self, index: uint) -> &'a str {
+        self.strings[index].as_slice()
+    }
+
+    ///Decodes the instruction at 'index'
+    pub fn get(&self, index: uint) -> Instruction {
+        let op = self.ops[index];
+        let value = op.value();
+        match op.opcode {
+            ADD => Add,
+            SUB => Sub,
+            MULTIPLY => Multiply,
+            DIVIDE => Divide,
+            REMAINDER => Remainder,
+            INT_EQ => IntEQ,
+            INT_LT => IntLT,
+            INT_LE => IntLE,
+            INT_GT => IntGT,
+            INT_GE => IntGE,
+            DOUBLE_ADD => DoubleAdd,
+            DOUBLE_SUB => DoubleSub,
+            DOUBLE_MULTIPLY => DoubleMultiply,
+            DOUBLE_DIVIDE => DoubleDivide,
+            DOUBLE_REMAINDER => DoubleRemainder,
+            DOUBLE_EQ => DoubleEQ,
+            DOUBLE_LT => DoubleLT,
+            DOUBLE_LE => DoubleLE,
+            DOUBLE_GT => DoubleGT,
+            DOUBLE_GE => DoubleGE,
+            INT_TO_DOUBLE => IntToDouble,
+            DOUBLE_TO_INT => DoubleToInt,
+            CHAR_EQ => CharEQ,
+            PUSH => Push(value),
+            PUSH_GLOBAL => PushGlobal(value),
+            PUSH_INT => PushInt(self.ints[value]),
+            PUSH_FLOAT => PushFloat(self.doubles[value]),
+            PUSH_CHAR => PushChar(op.char()),
+            PUSH_STRING => PushString(self.strings[value].clone()),
+            MKAP => Mkap,
+            EVAL => Eval,
+            UNWIND => Unwind,
+            UPDATE => Update(value),
+            ALLOC => Alloc(value),
+            FILL => Fill(value),
+            POP => Pop(value),
+            SLIDE => Slide(value),
+            SPLIT => Split(value),
+            PACK => Pack(op.high() as u16, op.low() as u16),
+            CASE_JUMP => CaseJump(value),
+            CASE_TABLE => CaseTable(value),
+            JUMP => Jump(value),
+            JUMP_FALSE => JumpFalse(value),
+            PUSH_DICTIONARY => PushDictionary(value),
+            PUSH_DICTIONARY_MEMBER => PushDictionaryMember(value),
+            PUSH_DICTIONARY_RANGE => PushDictionaryRange(op.high(), op.low()),
+            FAIL => Fail,
+            opcode => fail!("Invalid opcode {}", opcode)
+        }
+    }
+
+    pub fn instructions(&self) -> ~[Instruction] {
+        ::std::vec::from_fn(self.len(), |i| self.get(i))
+    }
+
+    ///Checks that every op can be decoded, that is it has a valid opcode and its constant exists
+    pub fn check(&self) -> Result<(), ~str> {
+        for (i, op) in self.ops.iter().enumerate() {
+            let value = op.operand as uint;
+            let valid = match op.opcode {
+                PUSH_INT => value < self.ints.len(),
+                PUSH_FLOAT => value < self.doubles.len(),
+                PUSH_STRING => value < self.strings.len(),
+                PUSH_CHAR => ::std::char::from_u32(op.operand).is_some(),
+                opcode => opcode <= FAIL
+            };
+            if !valid {
+                return Err(format!("Invalid op {} with operand {} at {}", op.opcode, op.operand, i));
+            }
+        }
+        Ok(())
+    }
+
+    ///Returns a pointer to the first op, two slices of the same code have the same address
+    pub fn as_ptr(&self) -> *Op {
+        self.ops.as_ptr()
+    }
+}
+
+///Code which evaluates the node on the top of the stack
+pub fn eval_code() -> CodeSlice<'static> {
+    CodeSlice { ops: EVAL_OPS, ints: &[], doubles: &[], strings: &[] }
+}
+
+///Code which unwinds the node on the top of the stack
+pub fn unwind_code() -> CodeSlice<'static> {
+    CodeSlice { ops: UNWIND_OPS, ints: &[], doubles: &[], strings: &[] }
+}
+
+#[cfg(test)]
+mod tests {
+use bytecode::{Code, Op, PUSH_CHAR, PUSH_INT, PUSH_STRING};
+use compiler::*;
+
+#[test]
+fn encode_and_decode() {
+    let instructions = ~[PushInt(-12345678901), PushFloat(3.5), PushChar('λ'), PushString(~"abc"), Pack(3, 65535), Push(7),
+        JumpFalse(2), Add, Alloc(2), Fill(1), PushDictionaryRange(2, 3), CaseTable(2), Jump(0), Jump(1), Fail, Update(0), Unwind];
+    let code = Code::encode(instructions);
+    assert_eq!(code.len(), instructions.len());
+    assert_eq!(code.instructions(), instructions);
+}
+
+#[test]
+fn intern_strings() {
+    let instructions = ~[PushString(~"abc"), PushString(~"def"), PushString(~"abc")];
+    let code = Code::encode(instructions);
+    assert_eq!(code.as_slice().op(0), code.as_slice().op(2));
+    assert_eq!(code.instructions(), instructions);
+}
+
+#[test]
+fn check_operands() {
+    let code = |op: Op| Code { ops: ~[op], ints: ~[1], doubles: ~[], strings: ~[] };
+    assert!(code(Op { opcode: PUSH_CHAR, operand: 'a' as u32 }).as_slice().check().is_ok());
+    assert!(code(Op { opcode: PUSH_CHAR, operand: 0xD800 }).as_slice().check().is_err());
+    assert!(code(Op { opcode: PUSH_CHAR, operand: 0x110000 }).as_slice().check().is_err());
+    assert!(code(Op { opcode: PUSH_INT, operand: 1 }).as_slice().check().is_err());
+    assert!(code(Op { opcode: PUSH_STRING, operand: 0 }).as_slice().check().is_err());
+}
+
+}
@@ -0,0 +1,271 @@
+//C interface which lets programs written in other languages embed the VM (see hvm.h).
+//Build the crate as a library (rustc --staticlib main.rs) to link against these functions.
+//No failure is allowed to unwind into the C caller so compilation and evaluation run in their
+//own task, errors are reported through the return value and hvm_last_error instead.
+use std::cast;
+use std::ptr;
+use std::task;
+use std::c_str::{CString, ToCStr};
+use std::libc::{c_char, c_int, c_long, c_double};
+use compiler::{Assembly, Instruction, PushInt, PushFloat, PushGlobal, Mkap, CompilerOptions};
+use loader;
+use bytecode::Code;
+use parser::parse_module_safe;
+use diagnostic::failure_message;
+use vm::{VM, VMResult, IntResult, DoubleResult, ConstructorResult, extract_result};
+
+pub static HVM_INT: c_int = 0;
+pub static HVM_DOUBLE: c_int = 1;
+pub static HVM_CONSTRUCTOR: c_int = 2;
+
+///A value passed to or returned from the VM.
+///'tag' decides which of the other fields are valid.
+pub struct HvmValue {
+    tag: c_int,
+    int_value: c_long,
+    double_value: c_double,
+    constructor_tag: c_int,
+    field_count: c_int,
+    ///Points to 'field_count' values which are owned by the HvmVm and valid until the next call
+    fields: *HvmValue
+}
+
+impl HvmValue {
+    fn empty() -> HvmValue {
+        HvmValue { tag: HVM_INT, int_value: 0, double_value: 0.0, constructor_tag: 0, field_count: 0, fields: ptr::null() }
+    }
+}
+
+pub struct HvmVm {
+    priv vm: VM<'static>,
+    priv error: Option<CString>,
+    ///Storage for the fields of the last returned constructor
+    priv fields: ~[~[HvmValue]]
+}
+
+impl HvmVm {
+    fn set_error(&mut self, message: ~str) -> c_int {
+        self.error = Some(message.to_c_str());
+        -1
+    }
+
+    fn to_value(&mut self, result: &VMResult) -> HvmValue {
+        let mut value = HvmValue::empty();
+        match result {
+            &IntResult(i) => value.int_value = i as c_long,
+            &DoubleResult(d) => {
+                value.tag = HVM_DOUBLE;
+                value.double_value = d as c_double;
+            }
+            &ConstructorResult(tag, ref args) => {
+                let fields : ~[HvmValue] = args.iter().map(|arg| self.to_value(arg)).collect();
+                value.tag = HVM_CONSTRUCTOR;
+                value.constructor_tag = tag as c_int;
+                value.field_count = fields.len() as c_int;
+                value.fields = fields.as_ptr();
+                self.fields.push(fields);
+            }
+        }
+        value
+    }
+}
+
+fn compile_with_dependencies(dependencies: &[Assembly], source: &str) -> Result<Assembly, ~str> {
+    let mut module = match parse_module_safe(source) {
+        Ok(module) => module,
+        Err(errors) => {
+            let messages : ~[~str] = errors.iter().map(|e| format!("{}", *e)).collect();
+            return Err(messages.connect("\n"))
+        }
+    };
+    //The C interface has no way of reporting warnings so only errors are returned
+    let mut warnings = ~[];
+    let options : CompilerOptions = Default::default();
+    loader::compile_module(dependencies, &mut warnings, &mut module, source, &options)
+}
+
+///Creates a new VM without any code loaded. Destroy it with hvm_destroy.
+#[no_mangle]
+pub extern "C" fn hvm_create() -> *mut HvmVm {
+    unsafe { cast::transmute(~HvmVm { vm: VM::new(), error: None, fields: ~[] }) }
+}
+
+#[no_mangle]
+pub extern "C" fn hvm_destroy(vm: *mut HvmVm) {
+    if !vm.is_null() {
+        let _vm: ~HvmVm = unsafe { cast::transmute(vm) };
+    }
+}
+
+///Returns the message of the last error or NULL if no error has occured.
+///The string is owned by the VM.
+#[no_mangle]
+pub extern "C" fn hvm_last_error(vm: *HvmVm) -> *c_char {
+    if vm.is_null() {
+        return ptr::null();
+    }
+    let hvm = unsafe { &*vm };
+    match hvm.error {
+        Some(ref msg) => msg.with_ref(|p| p),
+        None => ptr::null()
+    }
+}
+
+///Compiles the module in 'source' and loads it into the VM.
+///The module can use every function of the modules loaded before it.
+#[no_mangle]
+pub extern "C" fn hvm_load_source(vm: *mut HvmVm, source: *c_char) -> c_int {
+    if vm.is_null() {
+        return -1;
+    }
+    let hvm = unsafe { &mut *vm };
+    if source.is_null() {
+        return hvm.set_error(~"Source is NULL");
+    }
+    let source = match unsafe { CString::new(source, false) }.as_str() {
+        Some(source) => source.to_owned(),
+        None => return hvm.set_error(~"Source is not valid UTF-8")
+    };
+    //The task gets its own copy so that nothing borrowed from the caller escapes into it
+    let dependencies = hvm.vm.assembly.clone();
+    let result = task::try(proc() {
+        compile_with_dependencies(dependencies.as_slice(), source)
+    });
+    match result {
+        Ok(Ok(assembly)) => {
+            hvm.vm.add_assembly(assembly);
+            hvm.error = None;
+            0
+        }
+        Ok(Err(msg)) => hvm.set_error(msg),
+        Err(cause) => hvm.set_error(failure_message(&cause))
+    }
+}
+
+///Calls the function 'name' with 'argc' arguments and evaluates the result into 'result'.
+///Only Int and Double arguments are supported.
+#[no_mangle]
+pub extern "C" fn hvm_call(vm: *mut HvmVm, name: *c_char, args: *HvmValue, argc: c_int, result: *mut HvmValue) -> c_int {
+    if vm.is_null() {
+        return -1;
+    }
+    let hvm = unsafe { &mut *vm };
+    if name.is_null() || result.is_null() || (argc > 0 && args.is_null()) {
+        return hvm.set_error(~"The function name, the arguments or the result is NULL");
+    }
+    let name = match unsafe { CString::new(name, false) }.as_str() {
+        Some(name) => name.to_owned(),
+        None => return hvm.set_error(~"Function name is not valid UTF-8")
+    };
+    let mut found = None;
+    for (global_index, &(assembly_index, index)) in hvm.vm.globals.iter().enumerate() {
+        let sc = &hvm.vm.assembly[assembly_index].superCombinators[index];
+        if name.as_slice() == sc.name.as_slice() {
+            found = Some((global_index, sc.assembly_id, sc.arity, sc.constraints.len()));
+        }
+    }
+    let (global_index, assembly_id) = match found {
+        Some((_, _, _, constraints)) if constraints > 0 => {
+            return hvm.set_error(format!("{} has class constraints and can't be called directly", name))
+        }
+        Some((_, _, arity, _)) if arity != argc as uint => {
+            return hvm.set_error(format!("{} expects {} arguments but was called with {}", name, arity, argc))
+        }
+        Some((global_index, assembly_id, _, _)) => (global_index, assembly_id),
+        None => return hvm.set_error(format!("Could not find function {}", name))
+    };
+
+    let mut instructions : ~[Instruction] = ~[];
+    for i in range(0, argc as uint).invert() {
+        let arg = unsafe { &*ptr::offset(args, i as int) };
+        if arg.tag == HVM_INT {
+            instructions.push(PushInt(arg.int_value as int));
+        }
+        else if arg.tag == HVM_DOUBLE {
+            instructions.push(PushFloat(arg.double_value as f64));
+        }
+        else {
+            return hvm.set_error(format!("Argument {} must be an Int or a Double", i));
+        }
+    }
+    instructions.push(PushGlobal(global_index));
+    for _ in range(0, argc) {
+        instructions.push(Mkap);
+    }
+
+    let vm_ptr: *VM<'static> = &hvm.vm;
+    //The caller is blocked until the task finishes so the VM outlives it
+    let evaluated = task::try(proc() {
+        let vm: &'static VM<'static> = unsafe { cast::transmute(vm_ptr) };
+        let code = Code::encode(instructions);
+        extract_result(vm.evaluate(code.as_slice(), assembly_id))
+    });
+    match evaluated {
+        Ok(Some(value)) => {
+            hvm.fields = ~[];
+            hvm.error = None;
+            let value = hvm.to_value(&value);
+            unsafe { *result = value; }
+            0
+        }
+        Ok(None) => hvm.set_error(format!("The result of {} can't be converted to a value", name)),
+        Err(cause) => hvm.set_error(failure_message(&cause))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+use std::ptr;
+use std::c_str::ToCStr;
+use capi::*;
+
+#[test]
+fn call_function() {
+    let vm = hvm_create();
+    let source = "add x y = primIntAdd x y\npair = (1, 2.5)";
+    assert_eq!(source.with_c_str(|s| hvm_load_source(vm, s)), 0);
+
+    let mut result = HvmValue { tag: -1, int_value: 0, double_value: 0.0, constructor_tag: 0, field_count: 0, fields: ptr::null() };
+    let args = [HvmValue { tag: HVM_INT, int_value: 2, double_value: 0.0, constructor_tag: 0, field_count: 0, fields: ptr::null() },
+                HvmValue { tag: HVM_INT, int_value: 3, double_value: 0.0, constructor_tag: 0, field_count: 0, fields: ptr::null() }];
+    assert_eq!("add".with_c_str(|name| hvm_call(vm, name, args.as_ptr(), 2, &mut result)), 0);
+    assert_eq!(result.tag, HVM_INT);
+    assert_eq!(result.int_value, 5);
+
+    assert_eq!("pair".with_c_str(|name| hvm_call(vm, name, ptr::null(), 0, &mut result)), 0);
+    assert_eq!(result.tag, HVM_CONSTRUCTOR);
+    assert_eq!(result.field_count, 2);
+    let second = unsafe { &*ptr::offset(result.fields, 1) };
+    assert_eq!(second.tag, HVM_DOUBLE);
+    assert_eq!(second.double_value, 2.5);
+    hvm_destroy(vm);
+}
+
+#[test]
+fn errors_are_reported() {
+    let vm = hvm_create();
+    assert!(hvm_last_error(vm as *HvmVm).is_null());
+    assert_eq!("main = ".with_c_str(|s| hvm_load_source(vm, s)), -1);
+    assert!(!hvm_last_error(vm as *HvmVm).is_null());
+
+    let mut result = HvmValue { tag: -1, int_value: 0, double_value: 0.0, constructor_tag: 0, field_count: 0, fields: ptr::null() };
+    assert_eq!("missing".with_c_str(|name| hvm_call(vm, name, ptr::null(), 0, &mut result)), -1);
+    assert_eq!(result.tag, -1);
+    hvm_destroy(vm);
+}
+
+#[test]
+fn null_vm_is_an_error() {
+    let mut result = HvmValue { tag: -1, int_value: 0, double_value: 0.0, constructor_tag: 0, field_count: 0, fields: ptr::null() };
+    assert!(hvm_last_error(ptr::null()).is_null());
+    assert_eq!("main = 1".with_c_str(|s| hvm_load_source(ptr::mut_null(), s)), -1);
+    assert_eq!("main".with_c_str(|name| hvm_call(ptr::mut_null(), name, ptr::null(), 0, &mut result)), -1);
+    assert_eq!(result.tag, -1);
+
+    let vm = hvm_create();
+    assert_eq!(hvm_load_source(vm, ptr::null()), -1);
+    assert!(!hvm_last_error(vm as *HvmVm).is_null());
+    hvm_destroy(vm);
+}
+
+}
@@ -1,13 +1,40 @@
+use std::hashmap::{HashMap, HashSet};
 use module::*;
 use Scope;
-use typecheck::{Types, TypeEnvironment, function_type};
+use typecheck::{Types, TypeEnvironment, function_type, match_instance_type};
 use std::iter::range_step;
+use metrics::Metrics;
+use extra::arc::Arc;
+use bytecode::{Code, CodeSlice};
+use strictness::analyze_module;
+use peephole::optimize_with_positions;
+use interner::{EMPTY, DICT, FROM_INTEGER, FROM_RATIONAL};
 
 condition! {
-    compile_error: () -> (int, int);
+    compile_error: () -> Location;
 }
 
-#[deriving(Eq)]
+///Version of the instruction set, must be increased whenever an instruction is added, removed or changed
+pub static INSTRUCTION_SET_VERSION: uint = 7;
+
+///The fewest constructors a data type must have for a case expression on it to be compiled with a CaseTable
+///instead of testing the alternatives one by one
+static CASE_TABLE_MIN_CONSTRUCTORS: uint = 4;
+
+///Options which control how modules are compiled, usually read from hvm.toml (see config.rs)
+#[deriving(Clone, Eq, Default)]
+pub struct CompilerOptions {
+    ///0 disables all optimizations, higher levels enable more of them
+    optimization_level: uint,
+    ///Directories which are searched for imported modules
+    include_paths: ~[~str],
+    ///Print the types of all top level declarations after typechecking
+    dump_types: bool,
+    ///Print the instructions of each compiled function
+    dump_instructions: bool
+}
+
+#[deriving(Clone, Eq)]
 pub enum Instruction {
     Add,
     Sub,
@@ -31,24 +58,39 @@ pub enum Instruction {
     DoubleGE,
     IntToDouble,
     DoubleToInt,
+    CharEQ,
     Push(uint),
     PushGlobal(uint),
     PushInt(int),
     PushFloat(f64),
     PushChar(char),
+    ///Pushes the list of characters of a string literal
+    PushString(~str),
     Mkap,
     Eval,
     Unwind,
     Update(uint),
+    ///Pushes the given number of holes for the bindings of a recursive let
+    Alloc(uint),
+    ///Fills the hole at the index with the node on the top of the stack and pops that node
+    Fill(uint),
     Pop(uint),
     Slide(uint),
     Split(uint),
     Pack(u16, u16),
     CaseJump(uint),
+    ///Jumps to one of the given number of Jump instructions which follow it, chosen by the tag
+    ///of the constructor on the top of the stack. The constructor is left on the stack.
+    CaseTable(uint),
     Jump(uint),
     JumpFalse(uint),
     PushDictionary(uint),
     PushDictionaryMember(uint),
+    ///Pushes the part of the dictionary at the bottom of the stack which starts at the first index and
+    ///has the second number of functions, which is the dictionary of a superclass
+    PushDictionaryRange(uint, uint),
+    ///Fails since no alternative of a case expression matched the value on the top of the stack
+    Fail,
 }
 
 #[deriving(Clone)]
@@ -56,30 +98,133 @@ enum Var<'a> {
     StackVariable(uint),
     GlobalVariable(uint),
     ConstructorVariable(u16, u16),
-    ClassVariable(&'a Type, &'a TypeVariable),
+    ///A method of a class, its declared type and the variable of the class
+    ClassVariable(&'a str, &'a Type, &'a TypeVariable),
     ConstraintVariable(uint, &'a Type, &'a[Constraint])
 }
 
+#[deriving(Clone)]
 pub struct SuperCombinator {
     arity : uint,
-    name: ~str,
+    name: InternedStr,
     assembly_id: uint,
-    instructions : ~[Instruction],
+    ///Immutable and shared between every copy of the assembly so cloning an assembly never copies code
+    instructions : Arc<Code>,
     type_declaration: TypeDeclaration,
-    constraints: ~[Constraint]
+    constraints: ~[Constraint],
+    ///The locations in the source which the instructions were compiled from, shared like the instructions
+    locations: Arc<LocationTable>
 }
 impl SuperCombinator {
     fn new() -> SuperCombinator {
-        SuperCombinator { arity : 0, name: ~"", instructions : ~[], type_declaration: Default::default(), constraints: ~[], assembly_id: 0 }
+        SuperCombinator { arity : 0, name: EMPTY, instructions : Arc::new(Code::encode([])), type_declaration: Default::default(),
+            constraints: ~[], assembly_id: 0, locations: Arc::new(LocationTable::new()) }
     }
+
+    pub fn code<'a>(&'a self) -> CodeSlice<'a> {
+        self.instructions.get().as_slice()
+    }
+
+    ///Returns the location in the source of the expression which the instruction at 'index' was compiled from
+    pub fn location(&self, index: uint) -> Option<Location> {
+        self.locations.get().find(index)
+    }
+}
+
+///Maps the instructions of a function to the locations in the source which they were compiled from
+#[deriving(Clone, Eq)]
+pub struct LocationTable {
+    ///Pairs of an instruction index and a location sorted by the index.
+    ///A location applies to the instructions from its index up to the index of the next pair.
+    priv entries: ~[(uint, Location)]
 }
 
+impl LocationTable {
+    pub fn new() -> LocationTable {
+        LocationTable { entries: ~[] }
+    }
+
+    ///Creates a table from pairs which are sorted by their instruction index, see 'entries'
+    pub fn from_entries(entries: ~[(uint, Location)]) -> LocationTable {
+        LocationTable { entries: entries }
+    }
+
+    pub fn entries<'a>(&'a self) -> &'a [(uint, Location)] {
+        self.entries.as_slice()
+    }
+
+    ///Returns the location of the instruction at 'index' or None if it was not compiled from the source
+    pub fn find(&self, index: uint) -> Option<Location> {
+        let mut found = None;
+        for &(start, ref location) in self.entries.iter() {
+            if start > index {
+                break;
+            }
+            found = Some(location);
+        }
+        match found {
+            Some(location) if location.row >= 0 => Some(location.clone()),
+            _ => None
+        }
+    }
+
+    ///Returns the location which the next instruction would be given
+    fn current(&self) -> Location {
+        match self.entries.last_opt() {
+            Some(&(_, ref location)) => location.clone(),
+            None => Location::eof()
+        }
+    }
+
+    ///Sets the location of the instructions starting at 'index'
+    fn add(&mut self, index: uint, location: Location) {
+        //An earlier location at the same index has no instructions so it is replaced
+        loop {
+            match self.entries.last_opt() {
+                Some(&(last, _)) if last == index => (),
+                _ => break
+            }
+            self.entries.pop();
+        }
+        if self.current() != location {
+            self.entries.push((index, location));
+        }
+    }
+
+    ///Moves each index to its position in 'positions', used after instructions have been removed or merged
+    fn remap(&self, positions: &[uint]) -> LocationTable {
+        let mut table = LocationTable::new();
+        for &(index, ref location) in self.entries.iter() {
+            table.add(positions[index], location.clone());
+        }
+        table
+    }
+}
+
+///A global of another assembly which the code of an assembly refers to by 'index'.
+///When the assembly is linked the index is replaced by the position of the global named 'name' in 'module'.
+#[deriving(Clone, Eq)]
+pub struct Relocation {
+    index: uint,
+    module: ~str,
+    name: InternedStr
+}
+
+#[deriving(Clone)]
 pub struct Assembly {
+    ///The name of the module the assembly was compiled from
+    name: ~str,
     superCombinators: ~[SuperCombinator],
     instance_dictionaries: ~[~[uint]],
     classes: ~[Class],
     instances: ~[(~[Constraint], Type)],
     data_definitions: ~[DataDefinition],
+    type_synonyms: ~[TypeSynonym],
+    ///The names exported by the module the assembly was compiled from, None if it exports everything
+    exported: Option<HashSet<~str>>,
+    ///The globals of other assemblies which the assembly uses, sorted by index
+    relocations: ~[Relocation],
+    ///The index of the first supercombinator of the assembly among every global
     offset: uint
 }
 
@@ -90,9 +235,12 @@ trait Globals {
 
 impl Globals for Assembly {
     fn find_global<'a>(&'a self, name: &str) -> Option<Var<'a>> {
+        if !is_exported(&self.exported, name) {
+            return None;
+        }
         let mut index = 0;
         for sc in self.superCombinators.iter() {
-            if name == sc.name {
+            if name == sc.name.as_slice() {
                 if sc.constraints.len() > 0 {
                     return Some(ConstraintVariable(self.offset + index, &sc.type_declaration.typ, sc.constraints));
                 }
@@ -105,7 +253,7 @@ impl Globals for Assembly {
         for class in self.classes.iter() {
             for decl in class.declarations.iter() {
                 if decl.name.equiv(&name) {
-                    return Some(ClassVariable(&decl.typ, &class.variable));
+                    return Some(ClassVariable(class.name.as_slice(), &decl.typ, &class.variable));
                 }
             }
         }
@@ -121,7 +269,7 @@ impl Globals for Assembly {
     }
 }
 
-fn find_global<'a>(module: &'a Module, offset: uint, name: &str) -> Option<Var<'a>> {
+fn find_global<'a>(module: &'a Module, offset: uint, builtin_wrappers: &[InternedStr], name: &str) -> Option<Var<'a>> {
     
     for dataDef in module.dataDefinitions.iter() {
         for ctor in dataDef.constructors.iter() {
@@ -134,7 +282,7 @@ fn find_global<'a>(module: &'a Module, offset: uint, name: &str) -> Option<Var<'
     for class in module.classes.iter() {
         for decl in class.declarations.iter() {
             if decl.name.equiv(&name) {
-                return Some(ClassVariable(&decl.typ, &class.variable));
+                return Some(ClassVariable(class.name.as_slice(), &decl.typ, &class.variable));
             }
         }
     }
@@ -161,14 +309,73 @@ fn find_global<'a>(module: &'a Module, offset: uint, name: &str) -> Option<Var<'
         }
         global_index += 1;
     }
+    //The wrappers of the constructors are placed after the bindings (see 'compileModule')
+    for dataDef in module.dataDefinitions.iter() {
+        for ctor in dataDef.constructors.iter().filter(|ctor| ctor.arity > 0) {
+            if constructor_wrapper_name(ctor.name.as_slice()).equiv(&name) {
+                return Some(GlobalVariable(offset + global_index));
+            }
+            global_index += 1;
+        }
+    }
+    for ctor in builtin_wrappers.iter() {
+        if constructor_wrapper_name(ctor.as_slice()).equiv(&name) {
+            return Some(GlobalVariable(offset + global_index));
+        }
+        global_index += 1;
+    }
     None
 }
 
+///Returns the tag and arity of 'name' if it is one of the constructors which are built into the compiler,
+///the constructors of lists and tuples
+fn builtin_constructor(name: &str) -> Option<(u16, u16)> {
+    if name.len() >= 3 && name.char_at(0) == '(' && name.char_at(name.len() - 1) == ')'
+    && name.chars().skip(1).take(name.len() - 2).all(|c| c == ',') {
+        return Some((0, (name.len() - 1) as u16));
+    }
+    match name {
+        "[]" => Some((0, 0)),
+        ":" => Some((1, 2)),
+        _ => None
+    }
+}
+
+///Returns the built-in constructors which take arguments and are referenced by the bindings of 'module'
+fn builtin_wrappers(module: &Module) -> ~[InternedStr] {
+    let mut names = ~[];
+    for instance in module.instances.iter() {
+        for bind in instance.bindings.iter() {
+            referenced_names(&bind.expression, &mut names);
+        }
+    }
+    for bind in module.bindings.iter() {
+        referenced_names(&bind.expression, &mut names);
+    }
+    let mut wrappers = ~[];
+    for name in names.move_iter() {
+        match builtin_constructor(name.as_slice()) {
+            Some((_, arity)) if arity > 0 && !wrappers.contains(&name) => wrappers.push(name),
+            _ => ()
+        }
+    }
+    wrappers
+}
+
+///Returns the name of the supercombinator which is called when the constructor 'name' is not applied to all of its arguments
+pub fn constructor_wrapper_name(name: &str) -> ~str {
+    "#" + name
+}
+
+///Only the names exported by the module the assembly was compiled from are visible
 impl Types for Assembly {
     ///Lookup a type
     fn find_type<'a>(&'a self, name: &str) -> Option<&'a Type> {
+        if !is_exported(&self.exported, name) {
+            return None;
+        }
         for sc in self.superCombinators.iter() {
-            if sc.name.equiv(&name) {
+            if name == sc.name.as_slice() {
                 return Some(&sc.type_declaration.typ);
             }
         }
@@ -192,28 +399,55 @@ impl Types for Assembly {
     }
 
     fn find_class<'a>(&'a self, name: &str) -> Option<&'a Class> {
+        if !is_exported(&self.exported, name) {
+            return None;
+        }
         self.classes.iter().find(|class| name == class.name)
     }
     fn find_instance<'a>(&'a self, classname: &str, typ: &Type) -> Option<(&'a [Constraint], &'a Type)> {
         for &(ref constraints, ref op) in self.instances.iter() {
-            if classname == op.op().name && op.types[0].op().name == typ.op().name {
+            //The instance type may be a function or tuple type as well as a data type so the whole type is matched
+            if classname == op.op().name && match_instance_type(&mut HashMap::new(), &op.types()[0], typ) {
                 let c : &[Constraint] = *constraints;
                 return Some((c, op));
             }
         }
         None
     }
+    fn find_data_definition<'a>(&'a self, name: &str) -> Option<&'a DataDefinition> {
+        if !is_exported(&self.exported, name) {
+            return None;
+        }
+        self.data_definitions.iter().find(|data| name == data.typ.op().name)
+    }
+    fn find_type_synonym<'a>(&'a self, name: &str) -> Option<&'a TypeSynonym> {
+        if !is_exported(&self.exported, name) {
+            return None;
+        }
+        self.type_synonyms.iter().find(|synonym| name == synonym.name)
+    }
     fn each_typedeclaration(&self, func: |&TypeDeclaration|) {
-        for sc in self.superCombinators.iter() {
+        for sc in self.superCombinators.iter().filter(|sc| is_exported(&self.exported, sc.name.as_slice())) {
             func(&sc.type_declaration);
         }
         
         for class in self.classes.iter() {
-            for decl in class.declarations.iter() {
+            for decl in class.declarations.iter().filter(|decl| is_exported(&self.exported, decl.name)) {
                 func(decl);
             }
         }
     }
+    fn each_data_definition(&self, func: |&DataDefinition|) {
+        for data in self.data_definitions.iter().filter(|data| is_exported(&self.exported, data.typ.op().name)) {
+            func(data);
+        }
+    }
+    fn each_instance(&self, func: |&str, &[Constraint], &Type|) {
+        for &(ref constraints, ref op) in self.instances.iter() {
+            //Instances are stored as the class applied to the instance type
+            func(op.op().name, *constraints, &op.types()[0]);
+        }
+    }
 }
 
 pub struct Compiler<'a> {
@@ -223,18 +457,33 @@ pub struct Compiler<'a> {
     stackSize : uint,
     ///Array of all the assemblies which can be used to lookup functions in
     assemblies: ~[&'a Assembly],
+    ///The strictness analysis and the peephole optimizations are only done if the optimization level is above 0
+    options: CompilerOptions,
+    ///The arguments which each function of the module being compiled is strict in
+    priv strictness: HashMap<InternedStr, ~[bool]>,
+    ///The locations of the instructions of the binding being compiled
+    priv locations: LocationTable,
+    priv instructions_emitted: uint,
+    ///The built-in constructors which the module being compiled uses, their wrappers are placed after the
+    ///wrappers of the constructors of the module (see 'builtin_wrappers')
+    priv builtin_wrappers: ~[InternedStr]
 }
 
 
 impl <'a> Compiler<'a> {
     pub fn new(type_env: &'a TypeEnvironment) -> Compiler<'a> {
-        Compiler { type_env: type_env, instance_dictionaries: ~[],
-            stackSize : 0, assemblies: ~[] }
+        Compiler { type_env: type_env, instance_dictionaries: ~[], stackSize : 0, assemblies: ~[], options: Default::default(),
+            strictness: HashMap::new(), locations: LocationTable::new(), instructions_emitted: 0, builtin_wrappers: ~[] }
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        Metrics { instructions_emitted: self.instructions_emitted, .. Metrics::new() }
     }
     
     pub fn compileModule(&mut self, module : &Module) -> Assembly {
 
         let mut assembly = Assembly {
+            name: module.name.clone(),
             superCombinators: ~[],
             instance_dictionaries: ~[],
             offset: self.assemblies.iter().flat_map(|assembly| assembly.superCombinators.iter()).len(),
@@ -242,8 +491,13 @@ impl <'a> Compiler<'a> {
             instances: module.instances.iter().map(
                 |inst| (inst.constraints.clone(), Type::new_op(inst.classname.clone(), ~[inst.typ.clone()] ))
                 ).collect(),
-            data_definitions: ~[]
+            data_definitions: ~[],
+            type_synonyms: module.typeSynonyms.clone(),
+            exported: module.index.exported.clone(),
+            relocations: ~[]
         };
+        self.strictness = self.analyze_strictness(module);
+        self.builtin_wrappers = builtin_wrappers(module);
         
         for def in module.dataDefinitions.iter() {
             let mut constructors = ~[];
@@ -264,7 +518,7 @@ impl <'a> Compiler<'a> {
         for instance in module.instances.iter() {
             for bind in instance.bindings.iter() {
                 let mut sc = self.compileBinding(bind, Some(module));
-                sc.name = bind.name.clone();
+                sc.name = intern(bind.name);
                 assembly.superCombinators.push(sc);
             }
         }
@@ -272,45 +526,153 @@ impl <'a> Compiler<'a> {
             let mut sc = self.compileBinding(bind, Some(module));
             let constraints = self.type_env.find_constraints(&bind.expression.typ);
             sc.constraints = constraints;
-            sc.name = bind.name.clone();
+            sc.name = intern(bind.name);
+            assembly.superCombinators.push(sc);
+        }
+        //Constructors which are not applied to all of their arguments are compiled as calls to these
+        for def in module.dataDefinitions.iter() {
+            for ctor in def.constructors.iter().filter(|ctor| ctor.arity > 0) {
+                let sc = self.compile_constructor_wrapper(ctor);
+                assembly.superCombinators.push(sc);
+            }
+        }
+        //Lists and tuples are built into the compiler so the module gets its own wrappers for the ones it uses
+        for name in self.builtin_wrappers.clone().iter() {
+            let (tag, arity) = builtin_constructor(name.as_slice()).unwrap();
+            let typ = self.type_env.find(name.as_slice())
+                .expect(format!("The built-in constructor {} does not exist in the type environment", *name))
+                .clone();
+            let ctor = Constructor { name: name.as_slice().to_owned(), typ: typ, tag: tag as int, arity: arity as int, fields: ~[] };
+            let sc = self.compile_constructor_wrapper(&ctor);
             assembly.superCombinators.push(sc);
         }
 
         for &(_, ref dict) in self.instance_dictionaries.iter() {
             assembly.instance_dictionaries.push(dict.clone());
         }
+        assembly.relocations = self.relocations(&assembly);
         assembly
     }
+
+    ///Returns the arguments which each function of 'module' is strict in, none of them unless optimizations are enabled
+    fn analyze_strictness(&self, module: &Module) -> HashMap<InternedStr, ~[bool]> {
+        if self.options.optimization_level > 0 {
+            analyze_module(module)
+        }
+        else {
+            HashMap::new()
+        }
+    }
+
+    ///Returns a relocation for each global of the other assemblies which 'assembly' uses
+    fn relocations(&self, assembly: &Assembly) -> ~[Relocation] {
+        let mut used = HashSet::new();
+        for sc in assembly.superCombinators.iter() {
+            for instruction in sc.code().instructions().iter() {
+                match *instruction {
+                    PushGlobal(index) if index < assembly.offset => { used.insert(index); }
+                    _ => ()
+                }
+            }
+        }
+        for dict in assembly.instance_dictionaries.iter() {
+            for index in dict.iter().filter(|index| **index < assembly.offset) {
+                used.insert(*index);
+            }
+        }
+        let mut indexes : ~[uint] = used.move_iter().collect();
+        indexes.sort_by(|l, r| l.cmp(r));
+        indexes.move_iter().map(|index| {
+            let other = self.assemblies.iter()
+                .find(|other| other.offset <= index && index < other.offset + other.superCombinators.len())
+                .unwrap();
+            Relocation { index: index, module: other.name.clone(), name: other.superCombinators[index - other.offset].name }
+        }).collect()
+    }
     fn compileBinding(&mut self, bind : &Binding, module: Option<&Module>) -> SuperCombinator {
-        debug!("Compiling binding {} {:?} {}", bind.name, bind.typeDecl.context, bind.typeDecl.typ);
+        log_phase!(Compiling, Debug, "Compiling binding {} {:?} {}", bind.name, bind.typeDecl.context, bind.typeDecl.typ);
         let mut comb = SuperCombinator::new();
         comb.assembly_id = self.assemblies.len();
         comb.type_declaration = bind.typeDecl.clone();
         let dict_arg = if self.type_env.find_constraints(&comb.type_declaration.typ).len() > 0 { 1 } else { 0 };
         comb.arity = bind.arity + dict_arg;
+        self.locations = LocationTable::new();
         let mut stack = CompilerNode { compiler: self, stack: Scope::new(), constraints: bind.typeDecl.context, module: module };
         if dict_arg == 1 {
-            stack.newStackVar(~"$dict");
+            stack.newStackVar(DICT);
         }
+        let mut instructions = ~[];
         match &bind.expression.expr {
             &Lambda(_, _) => {
-                stack.compile(&bind.expression, &mut comb.instructions, true);
-                comb.instructions.push(Update(0));
-                comb.instructions.push(Pop(comb.arity));
-                comb.instructions.push(Unwind);
+                stack.compile(&bind.expression, &mut instructions, true);
+                instructions.push(Update(0));
+                instructions.push(Pop(comb.arity));
+                instructions.push(Unwind);
             }
             _ => {
-                stack.compile(&bind.expression, &mut comb.instructions, true);
-                comb.instructions.push(Update(0));
-                comb.instructions.push(Unwind);
+                stack.compile(&bind.expression, &mut instructions, true);
+                instructions.push(Update(0));
+                instructions.push(Unwind);
             }
        }
+       let (instructions, positions) = if stack.compiler.options.optimization_level > 0 {
+           optimize_with_positions(instructions)
+       }
+       else {
+           let positions = range(0, instructions.len() + 1).collect();
+           (instructions, positions)
+       };
+       stack.compiler.instructions_emitted += instructions.len();
+       comb.instructions = Arc::new(Code::encode(instructions));
+       comb.locations = Arc::new(stack.compiler.locations.remap(positions));
        comb
     }
+    ///Compiles a supercombinator which takes the arguments of 'ctor' and packs them into a value
+    fn compile_constructor_wrapper(&mut self, ctor: &Constructor) -> SuperCombinator {
+        let arity = ctor.arity as uint;
+        let mut instructions = ~[];
+        //The first argument is at the bottom of the stack but Pack takes it from the top
+        for i in range(0, arity).invert() {
+            instructions.push(Push(i));
+        }
+        instructions.push(Pack(ctor.tag as u16, ctor.arity as u16));
+        instructions.push(Update(0));
+        instructions.push(Pop(arity));
+        instructions.push(Unwind);
+        self.instructions_emitted += instructions.len();
+        let name = constructor_wrapper_name(ctor.name.as_slice());
+        let mut comb = SuperCombinator::new();
+        comb.arity = arity;
+        comb.assembly_id = self.assemblies.len();
+        comb.type_declaration = TypeDeclaration { context: ~[], typ: ctor.typ.clone(), name: name.clone(), location: Location::eof() };
+        comb.name = intern(name);
+        comb.instructions = Arc::new(Code::encode(instructions));
+        comb
+    }
+
+    ///Compiles 'expr' together with the functions which were lifted out of it into 'lifted'.
+    ///The lifted functions are returned as supercombinators which must be placed after the supercombinators of the last assembly.
+    pub fn compile_lifted_expression(&mut self, lifted: &Module, expr: &TypedExpr) -> (~[SuperCombinator], ~[Instruction]) {
+        self.strictness = self.analyze_strictness(lifted);
+        let mut combinators = ~[];
+        for bind in lifted.bindings.iter() {
+            let mut sc = self.compileBinding(bind, Some(lifted));
+            sc.constraints = self.type_env.find_constraints(&bind.expression.typ);
+            sc.name = intern(bind.name);
+            combinators.push(sc);
+        }
+        let mut stack = CompilerNode { compiler: self, stack: Scope::new(), constraints: [], module: Some(lifted) };
+        let mut instructions = ~[];
+        stack.compile(expr, &mut instructions, false);
+        stack.compiler.instructions_emitted += instructions.len();
+        (combinators, instructions)
+    }
+
     pub fn compileExpression(&mut self, expr: &TypedExpr) -> ~[Instruction] {
         let mut stack = CompilerNode { compiler: self, stack: Scope::new(), constraints: [], module: None };
         let mut instructions = ~[];
         stack.compile(expr, &mut instructions, false);
+        stack.compiler.instructions_emitted += instructions.len();
         instructions
     }
 
@@ -335,8 +697,9 @@ impl <'a, 'b, 'c> Drop for CompilerNode<'a, 'b, 'c> {
 impl <'a, 'b, 'c> CompilerNode<'a, 'b, 'c> {
     
     ///Find a variable by walking through the stack followed by all globals
-    fn find(&'a self, identifier : &str) -> Option<Var<'a>> {
-        self.stack.find(identifier).map(|x| x.clone())
+    fn find(&'a self, name : InternedStr) -> Option<Var<'a>> {
+        let identifier = name.as_slice();
+        self.stack.find(&name).map(|x| x.clone())
         .or_else(|| {
             match self.module {
                 Some(ref module) => {
@@ -348,7 +711,7 @@ impl <'a, 'b, 'c> CompilerNode<'a, 'b, 'c> {
                     else {
                         0
                     };
-                    find_global(*module, offset, identifier)
+                    find_global(*module, offset, self.compiler.builtin_wrappers, identifier)
                 }
                 None => None
             }
@@ -362,16 +725,7 @@ impl <'a, 'b, 'c> CompilerNode<'a, 'b, 'c> {
             }
             None
         }).or_else(|| {
-            if identifier.len() >= 3 && identifier.char_at(0) == '('
-            && identifier.char_at(identifier.len() - 1) == ')'
-            && identifier.chars().skip(1).take(identifier.len() - 2).all(|c| c == ',') {
-                return Some(ConstructorVariable(0, (identifier.len() - 1) as u16));
-            }
-            match identifier {
-                &"[]" => Some(ConstructorVariable(0, 0)),
-                &":" => Some(ConstructorVariable(1, 2)),
-                _ => None
-            }
+            builtin_constructor(identifier).map(|(tag, arity)| ConstructorVariable(tag, arity))
         })
     }
 
@@ -388,11 +742,11 @@ impl <'a, 'b, 'c> CompilerNode<'a, 'b, 'c> {
         })
     }
 
-    fn newStackVar(&mut self, identifier : ~str) {
+    fn newStackVar(&mut self, identifier : InternedStr) {
         self.stack.insert(identifier, StackVariable(self.compiler.stackSize));
         self.compiler.stackSize += 1;
     }
-    fn removeStackVar(&mut self, identifier : &~str) {
+    fn removeStackVar(&mut self, identifier : &InternedStr) {
         self.stack.variables.remove(identifier);
         self.compiler.stackSize -= 1;
     }
@@ -404,26 +758,42 @@ impl <'a, 'b, 'c> CompilerNode<'a, 'b, 'c> {
 
     ///Compile an expression by appending instructions to the instructions array
     fn compile(&mut self, expr : &TypedExpr, instructions : &mut ~[Instruction], strict: bool) {
-        compile_error::cond.trap(|_| (expr.location.row, expr.location.column)).inside(|| {
+        let outer_location = self.compiler.locations.current();
+        self.compiler.locations.add(instructions.len(), expr.location.clone());
+        compile_error::cond.trap(|_| expr.location).inside(|| {
             self.compile_(expr, instructions, strict)
-        })
+        });
+        //The instructions after this expression belong to the expression containing it
+        self.compiler.locations.add(instructions.len(), outer_location);
     }
     fn compile_(&mut self, expr : &TypedExpr, instructions : &mut ~[Instruction], strict: bool) {
-        debug!("Compiling {}", expr.expr);
+        log_phase!(Compiling, Debug, "Compiling {}", expr.expr);
         match &expr.expr {
             &Identifier(ref name) => {
                 //When compiling a variable which has constraints a new instance dictionary
                 //might be created which is returned here and added to the assembly
-                let maybe_new_dict = match self.find(*name) {
-                    None => fail!("Undefined variable " + *name),
+                let maybe_new_dict = match self.find(name.clone()) {
+                    None => {
+                        let location = compile_error::cond.raise(());
+                        fail!("{} Error: Undefined variable {}", location, *name)
+                    }
                     Some(var) => {
                         match var {
                             StackVariable(index) => { instructions.push(Push(index)); None }
                             GlobalVariable(index) => { instructions.push(PushGlobal(index)); None }
-                            ConstructorVariable(tag, arity) => { instructions.push(Pack(tag, arity)); None }
-                            ClassVariable(typ, var) => self.compile_instance_variable(&expr.typ, instructions, *name, typ, var),
+                            ConstructorVariable(tag, 0) => { instructions.push(Pack(tag, 0)); None }
+                            ConstructorVariable(tag, arity) => {
+                                //Saturated constructors are packed directly when compiling Apply so this is a partial application
+                                match self.find(intern(constructor_wrapper_name(name.as_slice()))) {
+                                    Some(GlobalVariable(index)) => instructions.push(PushGlobal(index)),
+                                    //Only an expression compiled without a module can lack the wrapper
+                                    _ => instructions.push(Pack(tag, arity))
+                                }
+                                None
+                            }
+                            ClassVariable(class, typ, var) => self.compile_instance_variable(&expr.typ, instructions, class, name.as_slice(), typ, var),
                             ConstraintVariable(index, _, constraints) => {
-                                let x = self.compile_with_constraints(*name, &expr.typ, constraints, instructions);
+                                let x = self.compile_with_constraints(name.as_slice(), &expr.typ, constraints, instructions);
                                 instructions.push(PushGlobal(index));
                                 instructions.push(Mkap);
                                 x
@@ -447,11 +817,11 @@ impl <'a, 'b, 'c> CompilerNode<'a, 'b, 'c> {
                     instructions.push(PushFloat(num as f64));
                 }
                 else {
-                    let mut fromInteger = TypedExpr::new(Identifier(~"fromInteger"));
+                    let mut fromInteger = TypedExpr::new(Identifier(FROM_INTEGER));
                     fromInteger.typ = function_type(&Type::new_op(~"Int", ~[]), &expr.typ);
                     let mut number = TypedExpr::new(Number(num));
                     number.typ = Type::new_op(~"Int", ~[]);
-                    let mut apply = TypedExpr::new(Apply(~fromInteger, ~number));
+                    let mut apply = TypedExpr::new(Apply(~(fromInteger, number)));
                     apply.typ = expr.typ.clone();
                     self.compile(&apply, instructions, strict);
                 }
@@ -462,32 +832,34 @@ impl <'a, 'b, 'c> CompilerNode<'a, 'b, 'c> {
                     instructions.push(PushFloat(num));
                 }
                 else {
-                    let mut fromRational = TypedExpr::new(Identifier(~"fromRational"));
+                    let mut fromRational = TypedExpr::new(Identifier(FROM_RATIONAL));
                     fromRational.typ = function_type(&Type::new_op(~"Double", ~[]), &expr.typ);
                     let mut number = TypedExpr::new(Rational(num));
                     number.typ = Type::new_op(~"Double", ~[]);
-                    let mut apply = TypedExpr::new(Apply(~fromRational, ~number));
+                    let mut apply = TypedExpr::new(Apply(~(fromRational, number)));
                     apply.typ = expr.typ.clone();
                     self.compile(&apply, instructions, strict);
                 }
             }
             &String(ref s) => {
-                instructions.push(Pack(0, 0));
-                for c in s.chars_rev() {
-                    instructions.push(PushChar(c));
-                    instructions.push(Pack(1, 2));
-                }
+                instructions.push(PushString(s.clone()));
             }
             &Char(c) => {
                 instructions.push(PushChar(c));
             }
-            &Apply(ref func, ref arg) => {
-                if !self.primitive(*func, *arg, instructions) {
-                    self.compile(*arg, instructions, false);
-                    self.compile(*func, instructions, false);
-                    match &instructions[instructions.len() - 1] {
-                        &Pack(_, _) => (),//The application was a constructor so dont do Mkap and the Pack instruction is strict already
-                        _ => {
+            &Apply(~(ref func, ref arg)) => {
+                match self.known_constructor(expr) {
+                    //A constructor applied to all of its arguments is packed directly, the result is already evaluated
+                    Some((tag, arguments)) => {
+                        for argument in arguments.iter().invert() {
+                            self.compile(*argument, instructions, false);
+                        }
+                        instructions.push(Pack(tag, arguments.len() as u16));
+                    }
+                    None => {
+                        if !self.primitive(func, arg, instructions) && !(strict && self.compile_strict_call(expr, instructions)) {
+                            self.compile(arg, instructions, false);
+                            self.compile(func, instructions, false);
                             instructions.push(Mkap);
                             if strict {
                                 instructions.push(Eval);
@@ -501,46 +873,66 @@ impl <'a, 'b, 'c> CompilerNode<'a, 'b, 'c> {
                 self.compile(*body, instructions, false);
             }
             &Let(ref bindings, ref body) => {
-                for bind in bindings.iter() {
-                    self.newStackVar(bind.name.clone());
-                    self.compile(&bind.expression, instructions, false);
+                if is_recursive(*bindings) {
+                    //Every binding is allocated as a hole first so that bindings can refer to themselves
+                    //and to the bindings after them
+                    let first = self.compiler.stackSize;
+                    instructions.push(Alloc(bindings.len()));
+                    for bind in bindings.iter() {
+                        self.newStackVar(intern(bind.name));
+                    }
+                    for (i, bind) in bindings.iter().enumerate() {
+                        self.compile(&bind.expression, instructions, false);
+                        instructions.push(Fill(first + i));
+                    }
+                }
+                else {
+                    for bind in bindings.iter() {
+                        self.newStackVar(intern(bind.name));
+                        self.compile(&bind.expression, instructions, false);
+                    }
                 }
                 self.compile(*body, instructions, strict);
                 instructions.push(Slide(bindings.len()));
             }
             &Case(ref body, ref alternatives) => {
+                //When the alternative can be chosen at compile time only its expression is compiled
+                //with the variables of its pattern bound to the arguments of the constructor
+                let selected = if self.compiler.options.optimization_level > 0 {
+                    self.select_alternative(*body, *alternatives)
+                }
+                else {
+                    None
+                };
+                match selected {
+                    Some((alt, bindings)) => {
+                        let mut childScope = self.child();
+                        //Every value is compiled before the pattern variables are in scope
+                        //since the pattern may shadow a variable which a later value refers to
+                        let first = childScope.compiler.stackSize;
+                        for &(_, value) in bindings.iter() {
+                            childScope.compile(value, instructions, false);
+                            childScope.compiler.stackSize += 1;
+                        }
+                        childScope.compiler.stackSize = first;
+                        for &(name, _) in bindings.iter() {
+                            childScope.newStackVar(name);
+                        }
+                        childScope.compile(&alt.expression, instructions, strict);
+                        instructions.push(Slide(bindings.len()));
+                        return;
+                    }
+                    None => ()
+                }
                 self.compile(*body, instructions, true);
-                self.newStackVar(~"");//Dummy variable for the case expression
+                self.newStackVar(EMPTY);//Dummy variable for the case expression
                 //Storage for all the jumps that should go to the end of the case expression
                 let mut end_branches = ~[];
-                for i in range(0, alternatives.len()) {
-                    let alt = &alternatives[i];
-                    let mut childScope = self.child();
-                    let pattern_start = instructions.len() as int;
-                    let mut branches = ~[];
-                    let stack_increase = childScope.compile_pattern(&alt.pattern.node, &mut branches, instructions, self.compiler.stackSize - 1, 0);
-                    let pattern_end = instructions.len() as int;
-
-                    childScope.compile(&alt.expression, instructions, strict);
-                    instructions.push(Slide(stack_increase));
-                    instructions.push(Jump(0));//Should jump to the end
-                    end_branches.push(instructions.len() - 1);
-
-                    //Here the current branch ends and the next one starts
-                    //We need to set all the jump instructions to their actual location
-                    //and append Slide instructions to bring the stack back to normal if the match fails
-                    for j in range_step(pattern_end, pattern_start, -1) {
-                        match instructions[j] {
-                            Jump(_) => {
-                                instructions[j] = Jump(instructions.len());
-                            }
-                            JumpFalse(_) => instructions[j] = JumpFalse(instructions.len()),
-                            Split(size) => instructions.push(Pop(size)),
-                            _ => ()
-                        }
-                    }
+                match self.case_table_size(*alternatives) {
+                    Some(size) => self.compile_case_table(size, *alternatives, &mut end_branches, instructions, strict),
+                    None => self.compile_case_chain(*alternatives, &mut end_branches, instructions, strict)
                 }
-                self.removeStackVar(&~"");
+                self.removeStackVar(&EMPTY);
                 for branch in end_branches.iter() {
                     instructions[*branch] = Jump(instructions.len());
                 }
@@ -553,13 +945,182 @@ impl <'a, 'b, 'c> CompilerNode<'a, 'b, 'c> {
         }
     }
 
+    ///Compiles the alternatives of a case expression by testing the tag of each alternative in order.
+    ///An alternative which is only a variable matches anything so the alternatives after it are never compiled.
+    fn compile_case_chain(&mut self, alternatives: &[Alternative], end_branches: &mut ~[uint], instructions: &mut ~[Instruction], strict: bool) {
+        let scrutinee = self.compiler.stackSize - 1;
+        for alt in alternatives.iter() {
+            let mut childScope = self.child();
+            let pattern_start = instructions.len() as int;
+            let mut branches = ~[];
+            let (stack_increase, is_default) = match &alt.pattern.node {
+                &IdentifierPattern(ref ident) => {
+                    instructions.push(Push(scrutinee));
+                    childScope.newStackVar(ident.clone());
+                    (1, true)
+                }
+                pattern => (childScope.compile_pattern(pattern, &mut branches, instructions, scrutinee, false), false)
+            };
+            let pattern_end = instructions.len() as int;
+
+            childScope.compile(&alt.expression, instructions, strict);
+            instructions.push(Slide(stack_increase));
+            instructions.push(Jump(0));//Should jump to the end
+            end_branches.push(instructions.len() - 1);
+
+            //Here the current branch ends and the next one starts
+            //We need to set all the jump instructions to their actual location
+            //and append Slide instructions to bring the stack back to normal if the match fails
+            for j in range_step(pattern_end, pattern_start, -1) {
+                match instructions[j] {
+                    Jump(_) => {
+                        instructions[j] = Jump(instructions.len());
+                    }
+                    JumpFalse(_) => instructions[j] = JumpFalse(instructions.len()),
+                    Split(size) => instructions.push(Pop(size)),
+                    _ => ()
+                }
+            }
+            if is_default {
+                return;
+            }
+        }
+        //A value which no alternative matches ends up here
+        if !self.is_exhaustive(alternatives) {
+            instructions.push(Fail);
+        }
+    }
+
+    ///Returns true if the alternatives match every constructor of the type of the scrutinee with patterns which
+    ///only bind variables, so a value always matches one of them
+    fn is_exhaustive(&self, alternatives: &[Alternative]) -> bool {
+        let mut tags = HashSet::new();
+        let mut size = None;
+        for alt in alternatives.iter() {
+            match &alt.pattern.node {
+                &ConstructorPattern(ref name, ref patterns) => {
+                    if !patterns.iter().all(|pattern| match *pattern { IdentifierPattern(_) => true, _ => false }) {
+                        continue;
+                    }
+                    match self.find(intern(*name)) {
+                        Some(ConstructorVariable(tag, _)) => { tags.insert(tag); }
+                        _ => return false
+                    }
+                    size = self.constructor_count(*name);
+                }
+                _ => ()
+            }
+        }
+        size == Some(tags.len())
+    }
+
+    ///Compiles the alternatives of a case expression with a CaseTable which jumps straight to the alternative
+    ///for the tag of the scrutinee, 'size' is the number of constructors of its type (see 'case_table_size')
+    fn compile_case_table(&mut self, size: uint, alternatives: &[Alternative], end_branches: &mut ~[uint], instructions: &mut ~[Instruction], strict: bool) {
+        let scrutinee = self.compiler.stackSize - 1;
+        instructions.push(Push(scrutinee));
+        instructions.push(CaseTable(size));
+        let table = instructions.len();
+        for _ in range(0, size) {
+            instructions.push(Jump(0));//Set to the alternative of each tag below
+        }
+        let mut targets : ~[Option<uint>] = ::std::vec::from_elem(size, None);
+        let mut default = None;
+        for alt in alternatives.iter() {
+            let start = instructions.len();
+            let mut childScope = self.child();
+            let stack_increase = match &alt.pattern.node {
+                &ConstructorPattern(ref name, ref patterns) => {
+                    let tag = match childScope.find(intern(*name)) {
+                        Some(ConstructorVariable(tag, _)) => tag as uint,
+                        _ => {
+                            let location = compile_error::cond.raise(());
+                            fail!("{} Error: Undefined constructor {}", location, *name)
+                        }
+                    };
+                    if targets[tag].is_some() {
+                        //An earlier alternative already matches every value with this tag
+                        continue;
+                    }
+                    targets[tag] = Some(start);
+                    instructions.push(Split(patterns.len()));
+                    for pattern in patterns.iter() {
+                        match pattern {
+                            &IdentifierPattern(ref ident) => childScope.newStackVar(ident.clone()),
+                            _ => fail!("Only variables can be nested in a pattern which is compiled with a CaseTable")
+                        }
+                    }
+                    patterns.len()
+                }
+                //The copy of the scrutinee which the table left on the stack is the value of the variable
+                &IdentifierPattern(ref ident) => {
+                    default = Some(start);
+                    childScope.newStackVar(ident.clone());
+                    1
+                }
+                _ => fail!("Only constructor and variable patterns can be compiled with a CaseTable")
+            };
+            childScope.compile(&alt.expression, instructions, strict);
+            instructions.push(Slide(stack_increase));
+            instructions.push(Jump(0));//Should jump to the end
+            end_branches.push(instructions.len() - 1);
+            if default.is_some() {
+                break;
+            }
+        }
+        for (i, target) in targets.iter().enumerate() {
+            instructions[table + i] = Jump(target.or(default).unwrap());
+        }
+    }
+
+    ///Returns the number of constructors of the type of the scrutinee if 'alternatives' should be compiled with a CaseTable.
+    ///A table is used when the type has many constructors, the patterns of the constructors only bind variables
+    ///and every constructor is matched by some alternative. Otherwise the alternatives are tested one by one.
+    fn case_table_size(&self, alternatives: &[Alternative]) -> Option<uint> {
+        let mut size = None;
+        let mut tags = HashSet::new();
+        for alt in alternatives.iter() {
+            match &alt.pattern.node {
+                &ConstructorPattern(ref name, ref patterns) => {
+                    if !patterns.iter().all(|pattern| match *pattern { IdentifierPattern(_) => true, _ => false }) {
+                        return None;
+                    }
+                    match self.find(intern(*name)) {
+                        Some(ConstructorVariable(tag, _)) => { tags.insert(tag); }
+                        _ => return None
+                    }
+                    if size.is_none() {
+                        size = self.constructor_count(*name);
+                    }
+                }
+                //A variable matches the constructors which the earlier alternatives did not
+                &IdentifierPattern(_) => return match size {
+                    Some(size) if size >= CASE_TABLE_MIN_CONSTRUCTORS => Some(size),
+                    _ => None
+                },
+                _ => return None
+            }
+        }
+        match size {
+            Some(size) if size >= CASE_TABLE_MIN_CONSTRUCTORS && tags.len() == size => Some(size),
+            _ => None
+        }
+    }
+
+    ///Returns the number of constructors of the data type which has the constructor 'name'
+    fn constructor_count(&self, name: &str) -> Option<uint> {
+        self.module.and_then(|module| constructor_count(module.dataDefinitions.as_slice(), name))
+            .or_else(|| self.compiler.assemblies.iter().filter_map(|assembly| constructor_count(assembly.data_definitions.as_slice(), name)).next())
+            .or_else(|| if name == "[]" || name == ":" { Some(2) } else { None })
+    }
+
     ///Compile a function which is defined in a class
-    fn compile_instance_variable(&self, actual_type: &Type, instructions: &mut ~[Instruction], name: &str, typ: &Type, var: &TypeVariable) -> Option<(~[(~str, Type)], ~[uint])> {
+    fn compile_instance_variable(&self, actual_type: &Type, instructions: &mut ~[Instruction], class: &str, name: &str, typ: &Type, var: &TypeVariable) -> Option<(~[(~str, Type)], ~[uint])> {
         match try_find_instance_type(var, typ, actual_type) {
-            Some(typename) => {
+            Some(instance_type) => {
                 //We should be able to retrieve the instance directly
-                let instance_fn_name = "#" + typename + name;
-                match self.find(instance_fn_name) {
+                let instance_fn_name = self.instance_function_name(class, instance_type, name);
+                match self.find(intern(instance_fn_name)) {
                     Some(GlobalVariable(index)) => {
                         let function_type = self.compiler.type_env.find(instance_fn_name)
                             .expect(format!("Error {} does not exist in the type environment", instance_fn_name));
@@ -587,19 +1148,52 @@ impl <'a, 'b, 'c> CompilerNode<'a, 'b, 'c> {
 
     ///Compile the loading of a variable which has constraints and will thus need to load a dictionary with functions as well
     fn compile_with_constraints(&self, name: &str, typ: &Type, constraints: &[Constraint], instructions: &mut ~[Instruction]) -> Option<(~[(~str, Type)], ~[uint])> {
-        match self.find("$dict") {
+        //When every instance is known at the call site the function's own dictionary is not needed,
+        //class methods are called directly and other functions are given the dictionary of the known instances
+        let dictionary_key = self.specialized_instances(name, typ);
+        if dictionary_key.len() == constraints.len() && dictionary_key.iter().all(|&(_, ref typ)| is_closed(typ)) {
+            if dictionary_key.len() == 1 && self.push_dictionary_member(constraints, name).is_some() {
+                let &(ref class, ref instance_type) = &dictionary_key[0];
+                let instance_fn_name = self.instance_function_name(*class, instance_type, name);
+                //A method of an instance with constraints, such as 'Show a => Show (Maybe a)', needs the dictionary
+                let instance_constraints = match self.compiler.type_env.find(instance_fn_name) {
+                    Some(function_type) => self.compiler.type_env.find_constraints(function_type).len(),
+                    None => 0
+                };
+                if instance_constraints == 0 {
+                    match self.find(intern(instance_fn_name)) {
+                        Some(GlobalVariable(index)) => {
+                            instructions.push(PushGlobal(index));
+                            return None;
+                        }
+                        _ => ()
+                    }
+                }
+            }
+            let (index, dict) = self.find_dictionary_index(dictionary_key);
+            instructions.push(PushDictionary(index));
+            return dict;
+        }
+        match self.find(DICT) {
             Some(StackVariable(_)) => {
                 //Push dictionary or member of dictionary
                 match self.push_dictionary_member(constraints, name) {
                     Some(index) => instructions.push(PushDictionaryMember(index)),
-                    None => instructions.push(Push(0))
+                    None => {
+                        //A function whose constraint is a superclass of the current constraint is given the part
+                        //of the current dictionary which holds the superclass
+                        match self.superclass_dictionary(constraints) {
+                            Some((offset, size)) => instructions.push(PushDictionaryRange(offset, size)),
+                            None => instructions.push(Push(0))
+                        }
+                    }
                 }
                 None
             }
             _ => {
                 //get dictionary index
                 //push dictionary
-                let dictionary_key = self.compiler.type_env.find_specialized_instances(name, typ);
+                let dictionary_key = self.specialized_instances(name, typ);
                 let (index, dict) = self.find_dictionary_index(dictionary_key);
                 instructions.push(PushDictionary(index));
                 dict
@@ -607,26 +1201,126 @@ impl <'a, 'b, 'c> CompilerNode<'a, 'b, 'c> {
         }
     }
 
+    ///Returns the class and type of each instance which 'name' needs when it is used at the type 'typ'
+    fn specialized_instances(&self, name: &str, typ: &Type) -> ~[(~str, Type)] {
+        let location = compile_error::cond.raise(());
+        match self.compiler.type_env.find_specialized_instances(name, typ, location) {
+            Ok(instances) => instances,
+            Err(error) => fail!("{}", error)
+        }
+    }
+
     ///Lookup which index in the instance dictionary that holds the function called 'name'
     fn push_dictionary_member(&self, constraints: &[Constraint], name: &str) -> Option<uint> {
         if constraints.len() == 0 {
             fail!("Attempted to push dictionary member '{}' with no constraints", name)
         }
+        let mut offset = 0;
         for c in constraints.iter() {
-            match self.find_class(c.class) {
-                Some(class) => {
-                    for ii in range(0, class.declarations.len()) {
-                        if class.declarations[ii].name.equiv(&name) {
-                            return Some(ii)
-                        }
+            match self.find_class_member(c.class, name) {
+                Some(index) => return Some(offset + index),
+                None => offset += self.dictionary_size(c.class)
+            }
+        }
+        None
+    }
+
+    ///Returns the number of functions in the dictionary of the class 'class_name'.
+    ///A dictionary holds the dictionaries of the superclasses, in order, followed by the methods of the class
+    fn dictionary_size(&self, class_name: &str) -> uint {
+        match self.find_class(class_name) {
+            Some(class) => class.constraints.iter().fold(class.declarations.len(), |size, c| size + self.dictionary_size(c.class)),
+            None => fail!("Could not find class '{}'", class_name)
+        }
+    }
+
+    ///Returns the index of the method 'name' in the dictionary of the class 'class_name'
+    fn find_class_member(&self, class_name: &str, name: &str) -> Option<uint> {
+        match self.find_class(class_name) {
+            Some(class) => {
+                let mut offset = 0;
+                for superclass in class.constraints.iter() {
+                    match self.find_class_member(superclass.class, name) {
+                        Some(index) => return Some(offset + index),
+                        None => offset += self.dictionary_size(superclass.class)
                     }
                 }
-                None => fail!("Undefined instance {:?}", c)
+                class.declarations.iter().position(|decl| decl.name.equiv(&name)).map(|index| offset + index)
+            }
+            None => fail!("Could not find class '{}'", class_name)
+        }
+    }
+
+    ///Returns the offset and size of the dictionary of 'target' within the dictionary of the class 'class_name'
+    fn find_superclass(&self, class_name: &str, target: &str) -> Option<(uint, uint)> {
+        if class_name == target {
+            return Some((0, self.dictionary_size(class_name)));
+        }
+        match self.find_class(class_name) {
+            Some(class) => {
+                let mut offset = 0;
+                for superclass in class.constraints.iter() {
+                    match self.find_superclass(superclass.class, target) {
+                        Some((index, size)) => return Some((offset + index, size)),
+                        None => offset += self.dictionary_size(superclass.class)
+                    }
+                }
+                None
+            }
+            None => fail!("Could not find class '{}'", class_name)
+        }
+    }
+
+    ///Returns the part of the dictionary of the current function which is the dictionary for the single constraint in 'constraints',
+    ///if it is a proper part of that dictionary
+    fn superclass_dictionary(&self, constraints: &[Constraint]) -> Option<(uint, uint)> {
+        if constraints.len() != 1 {
+            return None;
+        }
+        let mut offset = 0;
+        for c in self.constraints.iter() {
+            match self.find_superclass(c.class, constraints[0].class) {
+                Some((index, size)) => {
+                    let total = self.constraints.iter().fold(0, |total, c| total + self.dictionary_size(c.class));
+                    return if size == total { None } else { Some((offset + index, size)) };
+                }
+                None => offset += self.dictionary_size(c.class)
             }
         }
         None
     }
 
+    ///Returns the name of the function which implements the method 'name' in the instance of 'class' for 'typ'.
+    ///The function is named after the type of the instance which matches 'typ' (see 'instance_name').
+    fn instance_function_name(&self, class: &str, typ: &Type, name: &str) -> ~str {
+        let instance = match self.compiler.type_env.find_matching_instance(class, typ) {
+            Some((_, instance_type, _)) => instance_name(&instance_type),
+            None => instance_name(typ)
+        };
+        "#" + instance + name
+    }
+
+    ///Adds the instance functions of the dictionary of the class 'class_name' for 'typ' to 'function_indexes'
+    fn add_dictionary_functions(&self, class_name: &str, typ: &Type, function_indexes: &mut ~[uint]) {
+        match self.find_class(class_name) {
+            Some(class) => {
+                for superclass in class.constraints.iter() {
+                    self.add_dictionary_functions(superclass.class, typ, function_indexes);
+                }
+                for decl in class.declarations.iter() {
+                    let f = self.instance_function_name(class_name, typ, decl.name);
+                    match self.find(intern(f)) {
+                        Some(GlobalVariable(index)) => {
+                            function_indexes.push(index as uint);
+                        }
+                        _ => fail!("Did not find function {}", f)
+                    }
+                }
+            }
+            None => fail!("Could not find class '{}'", class_name)
+        }
+    }
+
     ///Find the index of the instance dictionary for the constraints and types in 'constraints'
     ///Returns the index and possibly a new dictionary which needs to be added to the assemblies dictionaries
     fn find_dictionary_index(&self, constraints: &[(~str, Type)]) -> (uint, Option<(~[(~str, Type)], ~[uint])>) {
@@ -638,26 +1332,12 @@ impl <'a, 'b, 'c> CompilerNode<'a, 'b, 'c> {
         }
 
         if constraints.len() == 0 {
-            let (row, column) = compile_error::cond.raise(());
-            fail!("Error: Attempted to compile dictionary with no constraints at {}:{}", row, column);
+            let location = compile_error::cond.raise(());
+            fail!("{} Error: Attempted to compile dictionary with no constraints", location);
         }
         let mut function_indexes = ~[];
         for &(ref class_name, ref typ) in constraints.iter() {
-            match self.find_class(*class_name) {
-                Some(class) => {
-                    assert!(class.declarations.len() > 0);
-                    for decl in class.declarations.iter() {
-                        let f = "#" + typ.op().name + decl.name;
-                        match self.find(f) {
-                            Some(GlobalVariable(index)) => {
-                                function_indexes.push(index as uint);
-                            }
-                            _ => fail!("Did not find function {}", f)
-                        }
-                    }
-                }
-                None => fail!("Could not find class '{}'", *class_name)
-            }
+            self.add_dictionary_functions(*class_name, typ, &mut function_indexes);
         }
         (dict_len, Some((constraints.to_owned(), function_indexes)))
     }
@@ -665,37 +1345,37 @@ impl <'a, 'b, 'c> CompilerNode<'a, 'b, 'c> {
     ///Attempt to compile a binary primitive, returning true if it succeded
     fn primitive(&mut self, func: &TypedExpr, arg: &TypedExpr, instructions: &mut ~[Instruction]) -> bool {
         match &func.expr {
-            &Apply(ref prim_func, ref arg2) => {
+            &Apply(~(ref prim_func, ref arg2)) => {
                 match &prim_func.expr {
                     &Identifier(ref name) => {
                         //Binary functions
-                        let maybeOP = match *name {
-                            ~"primIntAdd" => Some(Add),
-                            ~"primIntSubtract" => Some(Sub),
-                            ~"primIntMultiply" => Some(Multiply),
-                            ~"primIntDivide" => Some(Divide),
-                            ~"primIntRemainder" => Some(Remainder),
-                            ~"primIntEQ" => Some(IntEQ),
-                            ~"primIntLT" => Some(IntLT),
-                            ~"primIntLE" => Some(IntLE),
-                            ~"primIntGT" => Some(IntGT),
-                            ~"primIntGE" => Some(IntGE),
-                            ~"primDoubleAdd" => Some(DoubleAdd),
-                            ~"primDoubleSubtract" => Some(DoubleSub),
-                            ~"primDoubleMultiply" => Some(DoubleMultiply),
-                            ~"primDoubleDivide" => Some(DoubleDivide),
-                            ~"primDoubleRemainder" => Some(DoubleRemainder),
-                            ~"primDoubleEQ" => Some(DoubleEQ),
-                            ~"primDoubleLT" => Some(DoubleLT),
-                            ~"primDoubleLE" => Some(DoubleLE),
-                            ~"primDoubleGT" => Some(DoubleGT),
-                            ~"primDoubleGE" => Some(DoubleGE),
+                        let maybeOP = match name.as_slice() {
+                            "primIntAdd" => Some(Add),
+                            "primIntSubtract" => Some(Sub),
+                            "primIntMultiply" => Some(Multiply),
+                            "primIntDivide" => Some(Divide),
+                            "primIntRemainder" => Some(Remainder),
+                            "primIntEQ" => Some(IntEQ),
+                            "primIntLT" => Some(IntLT),
+                            "primIntLE" => Some(IntLE),
+                            "primIntGT" => Some(IntGT),
+                            "primIntGE" => Some(IntGE),
+                            "primDoubleAdd" => Some(DoubleAdd),
+                            "primDoubleSubtract" => Some(DoubleSub),
+                            "primDoubleMultiply" => Some(DoubleMultiply),
+                            "primDoubleDivide" => Some(DoubleDivide),
+                            "primDoubleRemainder" => Some(DoubleRemainder),
+                            "primDoubleEQ" => Some(DoubleEQ),
+                            "primDoubleLT" => Some(DoubleLT),
+                            "primDoubleLE" => Some(DoubleLE),
+                            "primDoubleGT" => Some(DoubleGT),
+                            "primDoubleGE" => Some(DoubleGE),
                             _ => None
                         };
                         match maybeOP {
                             Some(op) => {
                                 self.compile(arg, instructions, true);
-                                self.compile(*arg2, instructions, true);
+                                self.compile(arg2, instructions, true);
                                 instructions.push(op);
                                 true
                             }
@@ -706,7 +1386,7 @@ impl <'a, 'b, 'c> CompilerNode<'a, 'b, 'c> {
                 }
             }
             &Identifier(ref name) => {
-                let n: &str = *name;
+                let n: &str = name.as_slice();
                 let maybeOP = match n {
                     "primIntToDouble" => Some(IntToDouble),
                     "primDoubleToInt" => Some(DoubleToInt),
@@ -725,61 +1405,229 @@ impl <'a, 'b, 'c> CompilerNode<'a, 'b, 'c> {
         }
     }
 
-    fn compile_pattern(&mut self, pattern: &Pattern, branches: &mut ~[uint], instructions: &mut ~[Instruction], stack_index: uint, pattern_index: uint) -> uint {
-        //TODO this is unlikely to work with nested patterns currently
+    ///Compiles a call, which is evaluated immediately, of a function which is strict in some of its arguments.
+    ///The strict arguments are evaluated before the call instead of being passed as thunks.
+    ///Returns false without emitting any instructions if 'expr' is not such a call
+    fn compile_strict_call(&mut self, expr: &TypedExpr, instructions: &mut ~[Instruction]) -> bool {
+        //The arguments of the call from the last to the first
+        let mut arguments = ~[];
+        let mut head = expr;
+        loop {
+            match &head.expr {
+                &Apply(~(ref func, ref arg)) => {
+                    arguments.push(arg);
+                    head = func;
+                }
+                _ => break
+            }
+        }
+        let name = match &head.expr {
+            &Identifier(ref name) => *name,
+            _ => return false
+        };
+        let strict_arguments = match self.compiler.strictness.find(&name) {
+            Some(strict_arguments) => strict_arguments.clone(),
+            None => return false
+        };
+        if arguments.len() < strict_arguments.len() || !strict_arguments.iter().any(|is_strict| *is_strict) {
+            return false;
+        }
+        //A local variable may shadow the function
+        match self.find(name) {
+            Some(GlobalVariable(_)) => (),
+            _ => return false
+        }
+        let count = arguments.len();
+        for (i, arg) in arguments.iter().enumerate() {
+            let index = count - 1 - i;
+            self.compile(*arg, instructions, index < strict_arguments.len() && strict_arguments[index]);
+        }
+        self.compile(head, instructions, false);
+        for _ in range(0, count) {
+            instructions.push(Mkap);
+        }
+        instructions.push(Eval);
+        true
+    }
+
+    ///Returns the alternative which a case expression on 'scrutinee' selects and the expressions which the
+    ///variables of its pattern are bound to, if the constructor of 'scrutinee' is known at compile time
+    fn select_alternative<'e>(&self, scrutinee: &'e TypedExpr, alternatives: &'e [Alternative]) -> Option<(&'e Alternative, ~[(InternedStr, &'e TypedExpr)])> {
+        for alt in alternatives.iter() {
+            let mut bindings = ~[];
+            match self.match_known(&alt.pattern.node, scrutinee, &mut bindings) {
+                Some(true) => return Some((alt, bindings)),
+                Some(false) => (),
+                None => return None
+            }
+        }
+        None
+    }
+
+    ///Returns Some(true) if 'pattern' matches 'expr', Some(false) if it can't match and None if it can't be known without evaluating 'expr'
+    fn match_known<'e>(&self, pattern: &Pattern, expr: &'e TypedExpr, bindings: &mut ~[(InternedStr, &'e TypedExpr)]) -> Option<bool> {
         match pattern {
+            &IdentifierPattern(ref ident) => {
+                bindings.push((*ident, expr));
+                Some(true)
+            }
             &ConstructorPattern(ref name, ref patterns) => {
-                instructions.push(Push(stack_index - pattern_index));
+                let (tag, arguments) = match self.known_constructor(expr) {
+                    Some(x) => x,
+                    None => return None
+                };
+                match self.find(intern(*name)) {
+                    Some(ConstructorVariable(pattern_tag, _)) if pattern_tag == tag => (),
+                    Some(ConstructorVariable(_, _)) => return Some(false),
+                    _ => return None
+                }
+                for (p, arg) in patterns.iter().zip(arguments.move_iter()) {
+                    match self.match_known(p, arg, bindings) {
+                        Some(true) => (),
+                        result => return result
+                    }
+                }
+                Some(true)
+            }
+            &NumberPattern(_) | &CharPattern(_) => None
+        }
+    }
+
+    ///Returns the tag and the arguments of 'expr' if it is a constructor applied to all of its arguments
+    fn known_constructor<'e>(&self, expr: &'e TypedExpr) -> Option<(u16, ~[&'e TypedExpr])> {
+        let mut arguments = ~[];
+        let mut head = expr;
+        loop {
+            match &head.expr {
+                &Apply(~(ref func, ref arg)) => {
+                    arguments.unshift(arg);
+                    head = func;
+                }
+                _ => break
+            }
+        }
+        match &head.expr {
+            &Identifier(ref name) => {
                 match self.find(*name) {
+                    Some(ConstructorVariable(tag, arity)) if arity as uint == arguments.len() => Some((tag, arguments)),
+                    _ => None
+                }
+            }
+            _ => None
+        }
+    }
+
+    ///Compiles a test of 'pattern' against the value at 'index' on the stack and binds the variables of the pattern.
+    ///'evaluate' is true for the fields of a constructor since those may still be unevaluated.
+    ///Returns the number of values which the pattern leaves on the stack.
+    fn compile_pattern(&mut self, pattern: &Pattern, branches: &mut ~[uint], instructions: &mut ~[Instruction], index: uint, evaluate: bool) -> uint {
+        match pattern {
+            &ConstructorPattern(ref name, ref patterns) => {
+                instructions.push(Push(index));
+                if evaluate {
+                    instructions.push(Eval);
+                }
+                match self.find(intern(*name)) {
                     Some(ConstructorVariable(tag, _)) => {
                         instructions.push(CaseJump(tag as uint));
                         branches.push(instructions.len());
                         instructions.push(Jump(0));
                     }
-                    _ => fail!("Undefined constructor {}", *name)
+                    _ => {
+                        let location = compile_error::cond.raise(());
+                        fail!("{} Error: Undefined constructor {}", location, *name)
+                    }
                 }
                 instructions.push(Split(patterns.len()));
-                let mut size = 0;
+                //Every field is given a variable before any nested pattern splits its field,
+                //that way the variables refer to the same place on the stack as the fields.
+                //Fields which are not bound to a name, such as '_', are given a unique name so each takes up a place.
+                let first = self.compiler.stackSize;
+                for (i, p) in patterns.iter().enumerate() {
+                    let name = match p {
+                        &IdentifierPattern(ref ident) if "_" != ident.as_slice() => ident.clone(),
+                        _ => intern("#" + (first + i).to_str())
+                    };
+                    self.newStackVar(name);
+                }
+                let mut size = patterns.len();
                 for (i, p) in patterns.iter().enumerate() {
-                    let stack_size = stack_index + patterns.len();
-                    size += self.compile_pattern(p, branches, instructions, stack_size, patterns.len() - i - 1);
+                    size += self.compile_pattern(p, branches, instructions, first + i, true);
                 }
-                size + patterns.len()
+                size
             }
             &NumberPattern(number) => {
-                self.newStackVar(pattern_index.to_str());
-                instructions.push(Push(stack_index - pattern_index));
+                instructions.push(Push(index));
                 instructions.push(Eval);
                 instructions.push(PushInt(number));
                 instructions.push(IntEQ);
                 instructions.push(JumpFalse(0));
                 0
             }
-            &IdentifierPattern(ref ident) => {
-                self.newStackVar(ident.clone());
+            &CharPattern(c) => {
+                instructions.push(Push(index));
+                instructions.push(Eval);
+                instructions.push(PushChar(c));
+                instructions.push(CharEQ);
+                instructions.push(JumpFalse(0));
                 0
             }
+            //The variable was bound when the field was split
+            &IdentifierPattern(_) => 0
         }
     }
 }
 
+///Returns the number of constructors of the data type in 'data_definitions' which has the constructor 'name'
+fn constructor_count(data_definitions: &[DataDefinition], name: &str) -> Option<uint> {
+    data_definitions.iter()
+        .find(|data| data.constructors.iter().any(|ctor| name == ctor.name))
+        .map(|data| data.constructors.len())
+}
+
+///Returns true if some binding refers to itself or to a binding after it
+fn is_recursive(bindings: &[Binding]) -> bool {
+    bindings.iter().enumerate().any(|(i, bind)| {
+        bindings.slice_from(i).iter().any(|other| mentions(&bind.expression, other.name))
+    })
+}
+
+///Returns true if the variable 'name' occurs in 'expr'
+fn mentions(expr: &TypedExpr, name: &str) -> bool {
+    match &expr.expr {
+        &Identifier(ref ident) => ident.as_slice() == name,
+        &Apply(~(ref func, ref arg)) => mentions(func, name) || mentions(arg, name),
+        &Lambda(_, ref body) => mentions(&**body, name),
+        &Let(ref bindings, ref body) => mentions(&**body, name) || bindings.iter().any(|bind| mentions(&bind.expression, name)),
+        &Case(ref scrutinee, ref alternatives) => mentions(&**scrutinee, name) || alternatives.iter().any(|alt| mentions(&alt.expression, name)),
+        &Number(_) | &Rational(_) | &String(_) | &Char(_) => false
+    }
+}
+
+///Returns true if 'typ' contains no type variables
+fn is_closed(typ: &Type) -> bool {
+    match &typ.typ {
+        &TypeVariable(_) => false,
+        &TypeOperator(_) => typ.types().iter().all(|t| is_closed(t))
+    }
+}
+
 ///Attempts to find the actual type of the for the variable which has a constraint
-fn try_find_instance_type<'a>(class_var: &TypeVariable, class_type: &Type, actual_type: &'a Type) -> Option<&'a str> {
+fn try_find_instance_type<'a>(class_var: &TypeVariable, class_type: &Type, actual_type: &'a Type) -> Option<&'a Type> {
     match (&class_type.typ, &actual_type.typ) {
-        (&TypeVariable(ref var), &TypeOperator(ref actual_op)) => {
+        (&TypeVariable(ref var), &TypeOperator(_)) => {
             if var == class_var {
-                //Found the class variable so return the name of the type
-                let x : &str = actual_op.name;
-                return Some(x)
+                //Found the class variable so return the type
+                return Some(actual_type)
             }
             None
         }
         (&TypeOperator(ref class_op), &TypeOperator(ref actual_op)) => {
             assert_eq!(class_op.name, actual_op.name);
-            assert_eq!(class_type.types.len(), actual_type.types.len());
-            for ii in range(0, class_type.types.len()) {
-                let result = try_find_instance_type(class_var, &class_type.types[ii], &actual_type.types[ii]);
-                if result != None {
+            assert_eq!(class_type.types().len(), actual_type.types().len());
+            for ii in range(0, class_type.types().len()) {
+                let result = try_find_instance_type(class_var, &class_type.types()[ii], &actual_type.types()[ii]);
+                if result.is_some() {
                     return result;
                 }
             }
@@ -793,6 +1641,7 @@ fn try_find_instance_type<'a>(class_var: &TypeVariable, class_type: &Type, actua
 mod tests {
 
 use compiler::*;
+use interner::intern;
 use typecheck::TypeEnvironment;
 use parser::Parser;
 use typecheck::{identifier, apply, number};
@@ -803,7 +1652,7 @@ use std::str::from_utf8;
 fn add() {
     let mut e = apply(apply(identifier(~"primIntAdd"), number(1)), number(2));
     let mut type_env = TypeEnvironment::new();
-    type_env.typecheck(&mut e);
+    type_env.typecheck(&mut e).unwrap();
     let mut comp = Compiler::new(&type_env);
     let instr = comp.compileExpression(&e);
 
@@ -818,13 +1667,28 @@ main = add 2. 3.";
     let mut parser = Parser::new(file.chars());
     let mut module = parser.module();
     let mut type_env = TypeEnvironment::new();
-    type_env.typecheck_module(&mut module);
+    type_env.typecheck_module(&mut module).unwrap();
+    let mut comp = Compiler::new(&type_env);
+    let assembly = comp.compileModule(&module);
+
+    assert_eq!(assembly.superCombinators[0].code().instructions(), ~[Push(1), Eval, Push(0), Eval, DoubleAdd, Update(0), Pop(2), Unwind]);
+    assert_eq!(assembly.superCombinators[1].code().instructions(), ~[PushFloat(3.), PushFloat(2.), PushGlobal(0), Mkap, Mkap, Eval, Update(0), Unwind]);
+}
+#[test]
+fn string_literal() {
+    let file = r#"main = "ab""#;
+    let mut parser = Parser::new(file.chars());
+    let mut module = parser.module();
+    let mut type_env = TypeEnvironment::new();
+    type_env.typecheck_module(&mut module).unwrap();
     let mut comp = Compiler::new(&type_env);
     let assembly = comp.compileModule(&module);
 
-    assert_eq!(assembly.superCombinators[0].instructions, ~[Push(1), Eval, Push(0), Eval, DoubleAdd, Update(0), Pop(2), Unwind]);
-    assert_eq!(assembly.superCombinators[1].instructions, ~[PushFloat(3.), PushFloat(2.), PushGlobal(0), Mkap, Mkap, Eval, Update(0), Unwind]);
+    let instructions = assembly.superCombinators[0].code().instructions();
+    assert!(instructions.contains(&PushString(~"ab")));
+    assert!(!instructions.iter().any(|instruction| match *instruction { Pack(_, _) | PushChar(_) => true, _ => false }));
 }
+
 #[test]
 fn push_num_double() {
     let file =
@@ -832,11 +1696,11 @@ r"main = primDoubleAdd 2 3";
     let mut parser = Parser::new(file.chars());
     let mut module = parser.module();
     let mut type_env = TypeEnvironment::new();
-    type_env.typecheck_module(&mut module);
+    type_env.typecheck_module(&mut module).unwrap();
     let mut comp = Compiler::new(&type_env);
     let assembly = comp.compileModule(&module);
 
-    assert_eq!(assembly.superCombinators[0].instructions, ~[PushFloat(3.), PushFloat(2.), DoubleAdd, Update(0), Unwind]);
+    assert_eq!(assembly.superCombinators[0].code().instructions(), ~[PushFloat(3.), PushFloat(2.), DoubleAdd, Update(0), Unwind]);
 }
 
 #[test]
@@ -847,11 +1711,28 @@ main = add 2 3";
     let mut parser = Parser::new(file.chars());
     let mut module = parser.module();
     let mut type_env = TypeEnvironment::new();
-    type_env.typecheck_module(&mut module);
+    type_env.typecheck_module(&mut module).unwrap();
     let mut comp = Compiler::new(&type_env);
     let assembly = comp.compileModule(&module);
 
-    assert_eq!(assembly.superCombinators[1].instructions, ~[PushInt(3), PushInt(2), PushGlobal(0), Mkap, Mkap, Eval, Update(0), Unwind]);
+    assert_eq!(assembly.superCombinators[1].code().instructions(), ~[PushInt(3), PushInt(2), PushGlobal(0), Mkap, Mkap, Eval, Update(0), Unwind]);
+}
+
+#[test]
+fn strict_arguments_are_evaluated_before_the_call() {
+    let file =
+r"add x y = primIntAdd x y
+main = add (add 1 2) 3";
+    let mut parser = Parser::new(file.chars());
+    let mut module = parser.module();
+    let mut type_env = TypeEnvironment::new();
+    type_env.typecheck_module(&mut module).unwrap();
+    let mut comp = Compiler::new(&type_env);
+    comp.options.optimization_level = 1;
+    let assembly = comp.compileModule(&module);
+
+    assert_eq!(assembly.superCombinators[1].code().instructions(), ~[PushInt(3), PushInt(2), PushInt(1), PushGlobal(0), Mkap, Mkap, Eval,
+        PushGlobal(0), Mkap, Mkap, Eval, Update(0), Unwind]);
 }
 
 #[test]
@@ -861,7 +1742,7 @@ r"primIntAdd 1 0 : []";
     let mut parser = Parser::new(file.chars());
     let mut expr = parser.expression_();
     let mut type_env = TypeEnvironment::new();
-    type_env.typecheck(&mut expr);
+    type_env.typecheck(&mut expr).unwrap();
     let mut comp = Compiler::new(&type_env);
     let instructions = comp.compileExpression(&expr);
 
@@ -875,47 +1756,82 @@ r"test x y = (primIntAdd 0 1, x, y)";
     let mut parser = Parser::new(file.chars());
     let mut module = parser.module();
     let mut type_env = TypeEnvironment::new();
-    type_env.typecheck_module(&mut module);
+    type_env.typecheck_module(&mut module).unwrap();
     let mut comp = Compiler::new(&type_env);
     let assembly = comp.compileModule(&module);
 
-    assert_eq!(assembly.superCombinators[0].instructions, ~[Push(1), Push(0), PushInt(1), PushInt(0), Add, Pack(0, 3), Update(0), Pop(2), Unwind]);
+    assert_eq!(assembly.superCombinators[0].code().instructions(), ~[Push(1), Push(0), PushInt(1), PushInt(0), Add, Pack(0, 3), Update(0), Pop(2), Unwind]);
 }
 
 #[test]
 fn compile_case() {
     let file =
-r"case [primIntAdd 1 0] of
+r"\xs -> case xs of
     : x xs -> x
     [] -> 2";
     let mut parser = Parser::new(file.chars());
     let mut expr = parser.expression_();
     let mut type_env = TypeEnvironment::new();
-    type_env.typecheck(&mut expr);
+    type_env.typecheck(&mut expr).unwrap();
     let mut comp = Compiler::new(&type_env);
     let instructions = comp.compileExpression(&expr);
 
-    assert_eq!(instructions, ~[Pack(0, 0), PushInt(0), PushInt(1), Add, Pack(1, 2),
-        Push(0), CaseJump(1), Jump(13), Split(2), Push(1), Slide(2), Jump(21), Pop(2),
-        Push(0), CaseJump(0), Jump(21), Split(0), PushInt(2), Slide(0), Jump(21), Pop(0), Slide(1)]);
+    assert_eq!(instructions, ~[Push(0), Eval,
+        Push(1), CaseJump(1), Jump(10), Split(2), Push(2), Slide(2), Jump(18), Pop(2),
+        Push(1), CaseJump(0), Jump(18), Split(0), PushInt(2), Slide(0), Jump(18), Pop(0), Slide(1)]);
 }
 
 #[test]
 fn compile_nested_case() {
     let file =
-r"case [primIntAdd 1 0] of
+r"\xs -> case xs of
     : 1 xs -> primIntAdd 1 1
     [] -> 2";
     let mut parser = Parser::new(file.chars());
     let mut expr = parser.expression_();
     let mut type_env = TypeEnvironment::new();
-    type_env.typecheck(&mut expr);
+    type_env.typecheck(&mut expr).unwrap();
     let mut comp = Compiler::new(&type_env);
     let instructions = comp.compileExpression(&expr);
 
-    assert_eq!(instructions, ~[Pack(0, 0), PushInt(0), PushInt(1), Add, Pack(1, 2),
-        Push(0), CaseJump(1), Jump(20), Split(2), Push(1), Eval, PushInt(1), IntEQ, JumpFalse(19), PushInt(1), PushInt(1), Add, Slide(2), Jump(28), Pop(2),
-        Push(0), CaseJump(0), Jump(28), Split(0), PushInt(2), Slide(0), Jump(28), Pop(0), Slide(1)]);
+    assert_eq!(instructions, ~[Push(0), Eval,
+        Push(1), CaseJump(1), Jump(17), Split(2), Push(2), Eval, PushInt(1), IntEQ, JumpFalse(16), PushInt(1), PushInt(1), Add, Slide(2), Jump(26), Pop(2),
+        Push(1), CaseJump(0), Jump(25), Split(0), PushInt(2), Slide(0), Jump(26), Pop(0), Fail, Slide(1)]);
+}
+
+#[test]
+fn compile_case_of_known_constructor() {
+    let file =
+r"case [primIntAdd 1 0] of
+    : x xs -> x
+    [] -> 2";
+    let mut parser = Parser::new(file.chars());
+    let mut expr = parser.expression_();
+    let mut type_env = TypeEnvironment::new();
+    type_env.typecheck(&mut expr).unwrap();
+    let mut comp = Compiler::new(&type_env);
+    comp.options.optimization_level = 1;
+    let instructions = comp.compileExpression(&expr);
+
+    assert_eq!(instructions, ~[PushInt(0), PushInt(1), Add, Pack(0, 0), Push(0), Slide(2)]);
+}
+
+#[test]
+fn compile_recursive_let() {
+    let file =
+r"let
+    xs = primIntAdd 1 0 : ys
+    ys = 2 : xs
+in xs";
+    let mut parser = Parser::new(file.chars());
+    let mut expr = parser.expression_();
+    let mut type_env = TypeEnvironment::new();
+    type_env.typecheck(&mut expr).unwrap();
+    let mut comp = Compiler::new(&type_env);
+    let instructions = comp.compileExpression(&expr);
+
+    assert_eq!(instructions, ~[Alloc(2), Push(1), PushInt(0), PushInt(1), Add, Pack(1, 2), Fill(0),
+        Push(0), PushInt(2), Pack(1, 2), Fill(1), Push(0), Slide(2)]);
 }
 
 #[test]
@@ -931,13 +1847,13 @@ main = test (primIntAdd 6 0)";
     let mut parser = Parser::new(file.chars());
     let mut module = parser.module();
     let mut type_env = TypeEnvironment::new();
-    type_env.typecheck_module(&mut module);
+    type_env.typecheck_module(&mut module).unwrap();
     let mut comp = Compiler::new(&type_env);
     let assembly = comp.compileModule(&module);
 
     let main = &assembly.superCombinators[1];
-    assert_eq!(main.name, ~"main");
-    assert_eq!(main.instructions, ~[PushInt(0), PushInt(6), Add, PushGlobal(0), Mkap, Eval, Update(0), Unwind]);
+    assert_eq!(main.name, intern("main"));
+    assert_eq!(main.code().instructions(), ~[PushInt(0), PushInt(6), Add, PushGlobal(0), Mkap, Eval, Update(0), Unwind]);
 }
 
 #[test]
@@ -953,13 +1869,38 @@ main x = primIntAdd (test x) 6";
     let mut parser = Parser::new(file.chars());
     let mut module = parser.module();
     let mut type_env = TypeEnvironment::new();
-    type_env.typecheck_module(&mut module);
+    type_env.typecheck_module(&mut module).unwrap();
     let mut comp = Compiler::new(&type_env);
     let assembly = comp.compileModule(&module);
 
     let main = assembly.superCombinators[1];
-    assert_eq!(main.name, ~"main");
-    assert_eq!(main.instructions, ~[PushInt(6), Push(1), PushDictionaryMember(0), Mkap, Eval, Add, Update(0), Pop(2), Unwind]);
+    assert_eq!(main.name, intern("main"));
+    assert_eq!(main.code().instructions(), ~[PushInt(6), Push(1), PushDictionaryMember(0), Mkap, Eval, Add, Update(0), Pop(2), Unwind]);
+}
+
+#[test]
+fn compile_class_constraints_known_in_constrained_function() {
+    let file =
+r"class Test a where
+    test :: a -> Int
+
+instance Test Int where
+    test x = x
+
+wrap x = test x
+
+main x = primIntAdd (test x) (wrap (primIntAdd 5 0))";
+    let mut parser = Parser::new(file.chars());
+    let mut module = parser.module();
+    let mut type_env = TypeEnvironment::new();
+    type_env.typecheck_module(&mut module).unwrap();
+    let mut comp = Compiler::new(&type_env);
+    let assembly = comp.compileModule(&module);
+
+    let main = assembly.superCombinators[2];
+    assert_eq!(main.name, intern("main"));
+    assert_eq!(main.code().instructions(), ~[PushInt(0), PushInt(5), Add, PushDictionary(0), PushGlobal(1), Mkap, Mkap, Eval,
+        Push(1), PushDictionaryMember(0), Mkap, Eval, Add, Update(0), Pop(2), Unwind]);
 }
 
 #[test]
@@ -971,7 +1912,7 @@ fn compile_prelude() {
         let contents : &str = from_utf8(s);
         let mut parser = Parser::new(contents.chars()); 
         let mut module = parser.module();
-        type_env.typecheck_module(&mut module);
+        type_env.typecheck_module(&mut module).unwrap();
         let mut compiler = Compiler::new(&type_env);
         compiler.compileModule(&mut module)
     };
@@ -980,14 +1921,103 @@ fn compile_prelude() {
 r"main = id (primIntAdd 2 0)";
     let mut parser = Parser::new(file.chars());
     let mut module = parser.module();
-    type_env.typecheck_module(&mut module);
+    type_env.typecheck_module(&mut module).unwrap();
     let mut compiler = Compiler::new(&type_env);
     compiler.assemblies.push(&prelude);
     let assembly = compiler.compileModule(&module);
 
     let sc = &assembly.superCombinators[0];
-    let id_index = prelude.superCombinators.iter().position(|sc| sc.name.equiv(& &"id")).unwrap();
-    assert_eq!(sc.instructions, ~[PushInt(0), PushInt(2), Add, PushGlobal(id_index), Mkap, Eval, Update(0), Unwind]);
+    let id_index = prelude.superCombinators.iter().position(|sc| sc.name == intern("id")).unwrap();
+    assert_eq!(sc.code().instructions(), ~[PushInt(0), PushInt(2), Add, PushGlobal(id_index), Mkap, Eval, Update(0), Unwind]);
+}
+
+#[test]
+fn cloned_assemblies_share_code() {
+    let file = r"main = primIntAdd 2 3";
+    let mut parser = Parser::new(file.chars());
+    let mut module = parser.module();
+    let mut type_env = TypeEnvironment::new();
+    type_env.typecheck_module(&mut module).unwrap();
+    let mut compiler = Compiler::new(&type_env);
+    let assembly = compiler.compileModule(&module);
+
+    let copy = assembly.clone();
+    assert_eq!(assembly.superCombinators[0].code().as_ptr(), copy.superCombinators[0].code().as_ptr());
+}
+
+#[test]
+fn instructions_have_source_locations() {
+    let file = "main = primIntAdd 2\n    3";
+    let mut parser = Parser::new(file.chars());
+    let mut module = parser.module();
+    let mut type_env = TypeEnvironment::new();
+    type_env.typecheck_module(&mut module).unwrap();
+    let mut compiler = Compiler::new(&type_env);
+    let assembly = compiler.compileModule(&module);
+
+    let sc = &assembly.superCombinators[0];
+    assert_eq!(sc.code().instructions(), ~[PushInt(3), PushInt(2), Add, Update(0), Unwind]);
+    assert_eq!(sc.location(0).map(|location| location.row), Some(2));
+    assert_eq!(sc.location(1).map(|location| location.row), Some(1));
+    assert_eq!(sc.location(2).map(|location| location.row), Some(1));
+}
+
+#[test]
+fn compile_case_with_many_constructors_to_table() {
+    let file =
+r"data Op = Plus | Minus | Times | Negate Int
+data Maybe a = Just a | Nothing
+
+value op = case op of
+    Plus -> 1
+    Negate n -> n
+    other -> 2
+
+fromMaybe d m = case m of
+    Just x -> x
+    Nothing -> d";
+    let mut parser = Parser::new(file.chars());
+    let mut module = parser.module();
+    let mut type_env = TypeEnvironment::new();
+    type_env.typecheck_module(&mut module).unwrap();
+    let mut compiler = Compiler::new(&type_env);
+    let assembly = compiler.compileModule(&module);
+
+    let value = assembly.superCombinators.iter().find(|sc| sc.name == intern("value")).unwrap().code().instructions();
+    let table = value.iter().position(|instruction| *instruction == CaseTable(4)).unwrap();
+    //Minus and Times have no alternative of their own so they jump to the default alternative
+    assert!(value[table + 2] == value[table + 3]);
+    assert!(value[table + 1] != value[table + 2]);
+    let from_maybe = assembly.superCombinators.iter().find(|sc| sc.name == intern("fromMaybe")).unwrap().code().instructions();
+    assert!(from_maybe.iter().all(|instruction| match *instruction { CaseTable(_) => false, _ => true }));
+}
+
+#[test]
+fn partially_applied_constructors_call_wrappers() {
+    let file =
+r"data Maybe a = Just a | Nothing
+wrap = Just
+just x = Just x
+cons = (:)";
+    let mut parser = Parser::new(file.chars());
+    let mut module = parser.module();
+    let mut type_env = TypeEnvironment::new();
+    type_env.typecheck_module(&mut module).unwrap();
+    let mut comp = Compiler::new(&type_env);
+    let assembly = comp.compileModule(&module);
+
+    let wrapper = assembly.superCombinators.iter().position(|sc| sc.name == intern("#Just")).unwrap();
+    assert_eq!(assembly.superCombinators[wrapper].code().instructions(), ~[Push(0), Pack(0, 1), Update(0), Pop(1), Unwind]);
+    let wrap = assembly.superCombinators.iter().find(|sc| sc.name == intern("wrap")).unwrap();
+    assert_eq!(wrap.code().instructions(), ~[PushGlobal(wrapper), Eval, Update(0), Unwind]);
+    let just = assembly.superCombinators.iter().find(|sc| sc.name == intern("just")).unwrap();
+    assert_eq!(just.code().instructions(), ~[Push(0), Pack(0, 1), Update(0), Pop(1), Unwind]);
+    assert!(!assembly.superCombinators.iter().any(|sc| sc.name == intern("#Nothing")));
+    //Lists and tuples are built in so the module has its own wrapper for the ones it uses
+    let list_wrapper = assembly.superCombinators.iter().position(|sc| sc.name == intern("#:")).unwrap();
+    let cons = assembly.superCombinators.iter().find(|sc| sc.name == intern("cons")).unwrap();
+    assert_eq!(cons.code().instructions(), ~[PushGlobal(list_wrapper), Eval, Update(0), Unwind]);
+    assert!(!assembly.superCombinators.iter().any(|sc| sc.name == intern("#(,)")));
 }
 
 }
@@ -0,0 +1,218 @@
+//Reading of the optional project file hvm.toml which holds the options that would otherwise
+//have to be passed on the command line. Only the subset of TOML needed for the options is
+//understood: [sections], comments and keys with string, integer, boolean or string array values.
+//
+//    prelude = "Prelude.hs"
+//    source_dirs = ["src", "."]
+//    include_paths = ["lib"]
+//    optimization_level = 1
+//
+//    [dump]
+//    types = true
+//    instructions = false
+use host::FileSystem;
+use compiler::CompilerOptions;
+
+pub static CONFIG_FILE: &'static str = "hvm.toml";
+
+#[deriving(Clone, Eq, ToStr)]
+pub struct Config {
+    ///The file which is loaded before every program
+    prelude: ~str,
+    ///Directories which are searched for the source files given on the command line
+    source_dirs: ~[~str],
+    include_paths: ~[~str],
+    optimization_level: uint,
+    dump_types: bool,
+    dump_instructions: bool
+}
+
+#[deriving(Clone, Eq, ToStr)]
+enum Value {
+    StringValue(~str),
+    IntValue(int),
+    BoolValue(bool),
+    ArrayValue(~[~str])
+}
+
+fn parse_string(s: &str) -> Option<~str> {
+    if s.len() >= 2 && s.starts_with("\"") && s.ends_with("\"") {
+        Some(s.slice(1, s.len() - 1).to_owned())
+    }
+    else {
+        None
+    }
+}
+
+fn parse_value(s: &str) -> Option<Value> {
+    if "true" == s {
+        Some(BoolValue(true))
+    }
+    else if "false" == s {
+        Some(BoolValue(false))
+    }
+    else if s.starts_with("[") && s.ends_with("]") {
+        let inner = s.slice(1, s.len() - 1).trim();
+        let mut elements = ~[];
+        if inner.len() != 0 {
+            for element in inner.split(',') {
+                let element = element.trim();
+                //Allow a trailing comma
+                if element.len() == 0 {
+                    continue;
+                }
+                match parse_string(element) {
+                    Some(e) => elements.push(e),
+                    None => return None
+                }
+            }
+        }
+        Some(ArrayValue(elements))
+    }
+    else {
+        parse_string(s).map(|s| StringValue(s))
+            .or_else(|| from_str::<int>(s).map(|i| IntValue(i)))
+    }
+}
+
+///Removes a comment from the line, '#' inside strings does not start a comment
+fn strip_comment<'a>(line: &'a str) -> &'a str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        if c == '"' {
+            in_string = !in_string;
+        }
+        else if c == '#' && !in_string {
+            return line.slice_to(i);
+        }
+    }
+    line
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            prelude: ~"Prelude.hs",
+            source_dirs: ~[~"."],
+            include_paths: ~[],
+            optimization_level: 0,
+            dump_types: false,
+            dump_instructions: false
+        }
+    }
+
+    ///Parses the contents of a configuration file, options which are not set keep their default value
+    pub fn parse(contents: &str) -> Result<Config, ~str> {
+        let mut config = Config::new();
+        let mut section = ~"";
+        for (index, line) in contents.lines().enumerate() {
+            let row = index + 1;
+            let line = strip_comment(line).trim();
+            if line.len() == 0 {
+                continue;
+            }
+            if line.starts_with("[") && line.ends_with("]") {
+                section = line.slice(1, line.len() - 1).trim().to_owned();
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(i) => (line.slice_to(i).trim(), line.slice_from(i + 1).trim()),
+                None => return Err(format!("{}:{}: Expected 'key = value'", CONFIG_FILE, row))
+            };
+            let value = match parse_value(value) {
+                Some(value) => value,
+                None => return Err(format!("{}:{}: Invalid value '{}'", CONFIG_FILE, row, value))
+            };
+            let name = if section.len() == 0 { key.to_owned() } else { format!("{}.{}", section, key) };
+            match (name.as_slice(), value) {
+                ("prelude", StringValue(s)) => config.prelude = s,
+                ("source_dirs", ArrayValue(dirs)) => config.source_dirs = dirs,
+                ("include_paths", ArrayValue(paths)) => config.include_paths = paths,
+                ("optimization_level", IntValue(level)) if level >= 0 => config.optimization_level = level as uint,
+                ("dump.types", BoolValue(b)) => config.dump_types = b,
+                ("dump.instructions", BoolValue(b)) => config.dump_instructions = b,
+                ("prelude", _) | ("source_dirs", _) | ("include_paths", _) | ("optimization_level", _)
+                    | ("dump.types", _) | ("dump.instructions", _) => {
+                    return Err(format!("{}:{}: Invalid value for {}", CONFIG_FILE, row, name))
+                }
+                _ => return Err(format!("{}:{}: Unknown option {}", CONFIG_FILE, row, name))
+            }
+        }
+        Ok(config)
+    }
+
+    ///Reads hvm.toml from 'fs', returning the default configuration if the file does not exist
+    pub fn load(fs: &FileSystem) -> Result<Config, ~str> {
+        match fs.read_file(CONFIG_FILE) {
+            Ok(contents) => Config::parse(contents),
+            Err(_) => Ok(Config::new())
+        }
+    }
+
+    ///Returns the path to the source file 'filename' by looking in each of the source directories,
+    ///falling back to 'filename' itself if it is not found in any of them
+    pub fn find_source(&self, fs: &FileSystem, filename: &str) -> ~str {
+        for dir in self.source_dirs.iter() {
+            let path = if "." == *dir { filename.to_owned() } else { format!("{}/{}", *dir, filename) };
+            if fs.exists(path) {
+                return path;
+            }
+        }
+        filename.to_owned()
+    }
+
+    pub fn compiler_options(&self) -> CompilerOptions {
+        CompilerOptions {
+            optimization_level: self.optimization_level,
+            include_paths: self.include_paths.clone(),
+            dump_types: self.dump_types,
+            dump_instructions: self.dump_instructions
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+use config::Config;
+use host::MemoryFileSystem;
+use host::FileSystem;
+
+#[test]
+fn parse_config() {
+    let config = Config::parse(
+r#"# Project settings
+prelude = "MyPrelude.hs"
+source_dirs = ["src", "."] # searched in order
+optimization_level = 2
+
+[dump]
+instructions = true
+"#).unwrap();
+    assert_eq!(config.prelude, ~"MyPrelude.hs");
+    assert_eq!(config.source_dirs, ~[~"src", ~"."]);
+    assert_eq!(config.optimization_level, 2);
+    assert!(config.dump_instructions);
+    assert!(!config.dump_types);
+    assert_eq!(config.include_paths, ~[]);
+}
+
+#[test]
+fn invalid_config() {
+    assert!(Config::parse("unknown = 1").is_err());
+    assert!(Config::parse("prelude = 1").is_err());
+    assert!(Config::parse("prelude").is_err());
+    assert!(Config::parse("source_dirs = [src]").is_err());
+}
+
+#[test]
+fn load_and_find_source() {
+    let mut fs = MemoryFileSystem::new();
+    assert_eq!(Config::load(&fs as &FileSystem), Ok(Config::new()));
+    fs.add_file(~"hvm.toml", ~"source_dirs = [\"src\"]");
+    fs.add_file(~"src/Main.hs", ~"main = 1");
+    let config = Config::load(&fs as &FileSystem).unwrap();
+    assert_eq!(config.find_source(&fs as &FileSystem, "Main.hs"), ~"src/Main.hs");
+    assert_eq!(config.find_source(&fs as &FileSystem, "Other.hs"), ~"Other.hs");
+}
+
+}
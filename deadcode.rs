@@ -0,0 +1,150 @@
+//Removes the supercombinators of linked assemblies which can't be reached from the entry point of a program.
+//Globals are numbered across every assembly in the order the assemblies are linked so the supercombinators
+//which are kept are renumbered, and every PushGlobal instruction and instance dictionary is updated to match.
+//Dictionaries are only kept if some reachable code pushes them.
+use std::vec::from_elem;
+use extra::arc::Arc;
+use compiler::*;
+use bytecode::Code;
+use interner::intern;
+
+///Removes every supercombinator which is not reachable from the first supercombinator named 'root'.
+///Does nothing if no supercombinator is named 'root'.
+pub fn remove_dead_code(assemblies: &mut ~[Assembly], root: &str) {
+    //The assembly and the index in it of each global
+    let mut globals = ~[];
+    for (a, assembly) in assemblies.iter().enumerate() {
+        for i in range(0, assembly.superCombinators.len()) {
+            globals.push((a, i));
+        }
+    }
+    let root = intern(root);
+    let first = match globals.iter().position(|&(a, i)| assemblies[a].superCombinators[i].name == root) {
+        Some(index) => index,
+        None => return
+    };
+
+    let mut reachable = from_elem(globals.len(), false);
+    let mut dictionaries : ~[~[bool]] = assemblies.iter()
+        .map(|assembly| from_elem(assembly.instance_dictionaries.len(), false))
+        .collect();
+    let mut work = ~[first];
+    reachable[first] = true;
+    while work.len() > 0 {
+        let (a, i) = globals[work.pop()];
+        let sc = &assemblies[a].superCombinators[i];
+        for instruction in sc.code().instructions().iter() {
+            match *instruction {
+                PushGlobal(index) => mark(&mut reachable, &mut work, index),
+                PushDictionary(index) if !dictionaries[sc.assembly_id][index] => {
+                    dictionaries[sc.assembly_id][index] = true;
+                    for member in assemblies[sc.assembly_id].instance_dictionaries[index].iter() {
+                        mark(&mut reachable, &mut work, *member);
+                    }
+                }
+                _ => ()
+            }
+        }
+    }
+
+    let global_indexes = new_indexes(reachable.as_slice());
+    let dictionary_indexes : ~[~[Option<uint>]] = dictionaries.iter().map(|used| new_indexes(used.as_slice())).collect();
+    let mut old_offset = 0;
+    let mut new_offset = 0;
+    for (a, assembly) in assemblies.mut_iter().enumerate() {
+        let superCombinators = ::std::util::replace(&mut assembly.superCombinators, ~[]);
+        let count = superCombinators.len();
+        for (i, sc) in superCombinators.move_iter().enumerate() {
+            if !reachable[old_offset + i] {
+                continue;
+            }
+            let mut sc = sc;
+            let assembly_id = sc.assembly_id;
+            let instructions : ~[Instruction] = sc.code().instructions().move_iter().map(|instruction| {
+                match instruction {
+                    PushGlobal(index) => PushGlobal(global_indexes[index].unwrap()),
+                    PushDictionary(index) => PushDictionary(dictionary_indexes[assembly_id][index].unwrap()),
+                    instruction => instruction
+                }
+            }).collect();
+            sc.instructions = Arc::new(Code::encode(instructions));
+            assembly.superCombinators.push(sc);
+        }
+        let instance_dictionaries = ::std::util::replace(&mut assembly.instance_dictionaries, ~[]);
+        for (d, dictionary) in instance_dictionaries.move_iter().enumerate() {
+            if dictionaries[a][d] {
+                assembly.instance_dictionaries.push(dictionary.map(|member| global_indexes[*member].unwrap()));
+            }
+        }
+        assembly.relocations = assembly.relocations.iter()
+            .filter_map(|relocation| global_indexes[relocation.index].map(|index| {
+                Relocation { index: index, module: relocation.module.clone(), name: relocation.name }
+            }))
+            .collect();
+        assembly.offset = new_offset;
+        old_offset += count;
+        new_offset += assembly.superCombinators.len();
+    }
+}
+
+fn mark(reachable: &mut ~[bool], work: &mut ~[uint], index: uint) {
+    if !reachable[index] {
+        reachable[index] = true;
+        work.push(index);
+    }
+}
+
+///Returns the new index of each used entry when the unused entries are removed
+fn new_indexes(used: &[bool]) -> ~[Option<uint>] {
+    let mut count = 0;
+    used.iter().map(|is_used| {
+        if *is_used {
+            count += 1;
+            Some(count - 1)
+        }
+        else {
+            None
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+use deadcode::remove_dead_code;
+use vm::{compile_iter, run_main_with, IntResult};
+use interner::intern;
+
+#[test]
+fn remove_unreachable_supercombinators() {
+    let assembly = compile_iter(
+r"unused x = x
+identity x = x
+main = identity 3".chars());
+    let mut assemblies = ~[assembly];
+    remove_dead_code(&mut assemblies, "main");
+    let names : ~[~str] = assemblies[0].superCombinators.iter().map(|sc| sc.name.as_slice().to_owned()).collect();
+    assert_eq!(names, ~[~"identity", ~"main"]);
+    assert!(!assemblies[0].superCombinators.iter().any(|sc| sc.name == intern("unused")));
+    assert_eq!(run_main_with(assemblies), Some(IntResult(3)));
+}
+
+#[test]
+fn keep_instance_dictionaries() {
+    let assembly = compile_iter(
+r"class Test a where
+    test :: a -> Int
+
+instance Test Int where
+    test x = primIntAdd x 1
+
+unused x = x
+
+wrap x = test x
+
+main = wrap (primIntAdd 2 0)".chars());
+    let mut assemblies = ~[assembly];
+    remove_dead_code(&mut assemblies, "main");
+    assert!(!assemblies[0].superCombinators.iter().any(|sc| sc.name == intern("unused")));
+    assert_eq!(run_main_with(assemblies), Some(IntResult(3)));
+}
+}
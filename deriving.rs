@@ -0,0 +1,233 @@
+//Generates the instances requested by the deriving clause of a data definition, 'data T = A | B deriving (Eq, Ord, Show)'.
+//The instances are created before typechecking as ordinary instance declarations so they are checked and compiled
+//exactly like the ones written by hand.
+use module::{DataDefinition, Constructor, Instance, Binding, TypeDeclaration, Constraint, Type, TypeVariable,
+    TypedExpr, Alternative, Located, Location, Pattern, ConstructorPattern, IdentifierPattern,
+    Identifier, Apply, Number, String, Lambda, Case, instance_name};
+use interner::intern;
+
+///Returns true if instances of 'class' can be derived
+pub fn can_derive(class: &str) -> bool {
+    match class {
+        "Eq" | "Ord" | "Show" => true,
+        _ => false
+    }
+}
+
+///Creates an instance for each class in the deriving clause of 'data'
+pub fn derived_instances(data: &DataDefinition) -> ~[Instance] {
+    data.deriving.iter().map(|class| {
+        let mut bindings = match class.as_slice() {
+            "Eq" => ~[
+                binding(data, "==", ~["#x", "#y"], equal(data)),
+                binding(data, "/=", ~["#x", "#y"], apply("not", ~[apply("==", ~[var("#x"), var("#y")])]))
+            ],
+            "Ord" => ~[
+                binding(data, "compare", ~["#x", "#y"], compare(data)),
+                binding(data, "<", ~["#x", "#y"], ordering(["True", "False", "False"])),
+                binding(data, ">", ~["#x", "#y"], ordering(["False", "False", "True"])),
+                binding(data, "<=", ~["#x", "#y"], ordering(["True", "True", "False"])),
+                binding(data, ">=", ~["#x", "#y"], ordering(["False", "True", "True"])),
+                binding(data, "min", ~["#x", "#y"], choose("<=")),
+                binding(data, "max", ~["#x", "#y"], choose(">="))
+            ],
+            "Show" => ~[binding(data, "show", ~["#x"], show(data))],
+            _ => fail!("Cannot derive instances of the class '{}'", *class)
+        };
+        for bind in bindings.mut_iter() {
+            locate(&mut bind.expression, data.location);
+        }
+        //Each parameter of the type must be an instance of the class as well, 'instance Eq a => Eq (Maybe a)'
+        let constraints = data.typ.types().iter()
+            .map(|param| Constraint { class: class.clone(), variables: ~[TypeVariable { id: param.var().id }] })
+            .collect();
+        Instance {
+            bindings: bindings,
+            constraints: constraints,
+            typ: data.typ.clone(),
+            classname: class.clone(),
+            location: data.location
+        }
+    }).collect()
+}
+
+///'(==) x y', true if both values have the same constructor and all of their fields are equal
+fn equal(data: &DataDefinition) -> TypedExpr {
+    each_constructor(data, var("False"), |ctor| {
+        let mut fields = range(0, ctor.arity as uint).invert();
+        match fields.next() {
+            Some(last) => fields.fold(field_equal(last), |rest, i| apply("&&", ~[field_equal(i), rest])),
+            None => var("True")
+        }
+    })
+}
+
+fn field_equal(index: uint) -> TypedExpr {
+    apply("==", ~[var(field("#a", index)), var(field("#b", index))])
+}
+
+///'compare x y', constructors are ordered by the order they are declared in and
+///values with the same constructor compare their fields from left to right
+fn compare(data: &DataDefinition) -> TypedExpr {
+    //Only the tags are compared if the constructors differ
+    let different = case(apply("primIntLT", ~[tag(data, "#x"), tag(data, "#y")]), ~[
+        (ConstructorPattern(~"True", ~[]), var("LT")),
+        (ConstructorPattern(~"False", ~[]), var("GT"))
+    ]);
+    each_constructor(data, different, |ctor| {
+        range(0, ctor.arity as uint).invert().fold(var("EQ"), |rest, i| {
+            let ordering = apply("compare", ~[var(field("#a", i)), var(field("#b", i))]);
+            case(ordering, ~[
+                (ConstructorPattern(~"LT", ~[]), var("LT")),
+                (ConstructorPattern(~"EQ", ~[]), rest),
+                (ConstructorPattern(~"GT", ~[]), var("GT"))
+            ])
+        })
+    })
+}
+
+///The tag of the constructor of the variable 'name', 'case x of { A _ -> 0; B -> 1 }'
+fn tag(data: &DataDefinition, name: &str) -> TypedExpr {
+    let alternatives = data.constructors.iter()
+        .map(|ctor| (constructor_pattern(ctor, "#t"), TypedExpr::new(Number(ctor.tag))))
+        .collect();
+    case(var(name), alternatives)
+}
+
+///Defines a comparison operator by the result it gives for each of LT, EQ and GT
+fn ordering(results: [&str, ..3]) -> TypedExpr {
+    let ordering = apply("compare", ~[var("#x"), var("#y")]);
+    case(ordering, ~[
+        (ConstructorPattern(~"LT", ~[]), var(results[0])),
+        (ConstructorPattern(~"EQ", ~[]), var(results[1])),
+        (ConstructorPattern(~"GT", ~[]), var(results[2]))
+    ])
+}
+
+///'min' and 'max', returns 'x' if 'x op y' holds, otherwise 'y'
+fn choose(op: &str) -> TypedExpr {
+    case(apply(op, ~[var("#x"), var("#y")]), ~[
+        (ConstructorPattern(~"True", ~[]), var("#x")),
+        (ConstructorPattern(~"False", ~[]), var("#y"))
+    ])
+}
+
+///'show x', the name of the constructor followed by each of its fields. A field is only put within parentheses
+///if it is not shown as a single value, 'Just (B True)' but 'B True' (see 'showArgument' in the Prelude)
+fn show(data: &DataDefinition) -> TypedExpr {
+    let alternatives = data.constructors.iter().map(|ctor| {
+        let shown = range(0, ctor.arity as uint).invert().fold(None, |rest: Option<TypedExpr>, i| {
+            let shown_field = append(string(" "), apply("showArgument", ~[apply("show", ~[var(field("#a", i))])]));
+            Some(match rest {
+                Some(rest) => append(shown_field, rest),
+                None => shown_field
+            })
+        });
+        let expr = match shown {
+            Some(fields) => append(string(ctor.name), fields),
+            None => string(ctor.name)
+        };
+        (constructor_pattern(ctor, "#a"), expr)
+    }).collect();
+    case(var("#x"), alternatives)
+}
+
+///Matches 'x' and 'y' against each constructor and uses 'f' to create the expression for when both have that constructor,
+///'different' is the result when they do not have the same constructor.
+///The fields of 'x' are bound to '#a0', '#a1', ... and those of 'y' to '#b0', '#b1', ...
+fn each_constructor(data: &DataDefinition, different: TypedExpr, f: |&Constructor| -> TypedExpr) -> TypedExpr {
+    let alternatives = data.constructors.iter().map(|ctor| {
+        let mut inner = ~[(constructor_pattern(ctor, "#b"), f(ctor))];
+        if data.constructors.len() > 1 {
+            inner.push((IdentifierPattern(intern("_")), different.clone()));
+        }
+        (constructor_pattern(ctor, "#a"), case(var("#y"), inner))
+    }).collect();
+    case(var("#x"), alternatives)
+}
+
+fn binding(data: &DataDefinition, method: &str, arguments: ~[&str], body: TypedExpr) -> Binding {
+    let arity = arguments.len();
+    let expression = arguments.iter().invert().fold(body, |body, arg| TypedExpr::new(Lambda(intern(*arg), ~body)));
+    Binding {
+        name: "#" + instance_name(&data.typ) + method,
+        expression: expression,
+        typeDecl: TypeDeclaration { context: ~[], typ: Type::new_var(-1), name: ~"", location: Location::eof() },
+        arity: arity
+    }
+}
+
+fn constructor_pattern(ctor: &Constructor, prefix: &str) -> Pattern {
+    let fields = range(0, ctor.arity as uint).map(|i| IdentifierPattern(intern(field(prefix, i)))).collect();
+    ConstructorPattern(ctor.name.clone(), fields)
+}
+
+fn field(prefix: &str, index: uint) -> ~str {
+    prefix + index.to_str()
+}
+
+fn case(scrutinee: TypedExpr, alternatives: ~[(Pattern, TypedExpr)]) -> TypedExpr {
+    let alternatives = alternatives.move_iter()
+        .map(|(pattern, expression)| Alternative {
+            pattern: Located { location: Location::eof(), node: pattern },
+            expression: expression
+        })
+        .collect();
+    TypedExpr::new(Case(~scrutinee, alternatives))
+}
+
+///Gives 'expr' and every expression and pattern in it the location 'location'
+fn locate(expr: &mut TypedExpr, location: Location) {
+    expr.location = location;
+    match expr.expr {
+        Apply(~(ref mut func, ref mut arg)) => {
+            locate(func, location);
+            locate(arg, location);
+        }
+        Lambda(_, ref mut body) => locate(&mut **body, location),
+        Case(ref mut scrutinee, ref mut alternatives) => {
+            locate(&mut **scrutinee, location);
+            for alt in alternatives.mut_iter() {
+                alt.pattern.location = location;
+                locate(&mut alt.expression, location);
+            }
+        }
+        _ => ()
+    }
+}
+
+fn append(left: TypedExpr, right: TypedExpr) -> TypedExpr {
+    apply("++", ~[left, right])
+}
+
+fn apply(function: &str, arguments: ~[TypedExpr]) -> TypedExpr {
+    arguments.move_iter().fold(var(function), |func, arg| TypedExpr::new(Apply(~(func, arg))))
+}
+
+fn var(name: &str) -> TypedExpr {
+    TypedExpr::new(Identifier(intern(name)))
+}
+
+fn string(s: &str) -> TypedExpr {
+    TypedExpr::new(String(s.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+use deriving::derived_instances;
+use parser::Parser;
+
+#[test]
+fn derived_instance_bindings() {
+    let mut parser = Parser::new(r"data Tree a = Leaf | Node (Tree a) a (Tree a) deriving (Eq, Show)".chars());
+    let module = parser.module();
+    let instances = derived_instances(&module.dataDefinitions[0]);
+    assert_eq!(instances.len(), 2);
+    assert_eq!(instances[0].classname, ~"Eq");
+    assert_eq!(instances[0].constraints.len(), 1);
+    let names : ~[&str] = instances[0].bindings.iter().map(|bind| bind.name.as_slice()).collect();
+    assert_eq!(names, ~["#Tree==", "#Tree/="]);
+    assert_eq!(instances[1].bindings[0].name, ~"#Treeshow");
+    assert_eq!(instances[1].bindings[0].arity, 1);
+}
+}
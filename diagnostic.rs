@@ -0,0 +1,96 @@
+use std::fmt;
+use std::any::AnyRefExt;
+use lexer::{Location, is_operator};
+
+#[deriving(Clone, Eq, ToStr)]
+pub enum Severity {
+    Error,
+    ///A problem which does not stop the program from being compiled
+    Warning
+}
+
+///A problem found in the source code together with the location where it occured
+#[deriving(Clone, Eq, ToStr)]
+pub struct Diagnostic {
+    location: Location,
+    message: ~str,
+    severity: Severity
+}
+
+impl Diagnostic {
+    pub fn new(location: Location, message: ~str) -> Diagnostic {
+        Diagnostic { location: location, message: message, severity: Error }
+    }
+    pub fn warning(location: Location, message: ~str) -> Diagnostic {
+        Diagnostic { location: location, message: message, severity: Warning }
+    }
+}
+
+impl fmt::Default for Diagnostic {
+    fn fmt(diagnostic: &Diagnostic, f: &mut fmt::Formatter) {
+        write!(f.buf, "{} {}: {}", diagnostic.location, diagnostic.severity.to_str(), diagnostic.message)
+    }
+}
+
+///Returns the message of a failed task
+pub fn failure_message(cause: &~Any) -> ~str {
+    match cause.as_ref::<~str>() {
+        Some(msg) => msg.clone(),
+        None => match cause.as_ref::<&'static str>() {
+            Some(msg) => msg.to_owned(),
+            None => ~"Unknown error"
+        }
+    }
+}
+
+///Returns the line of 'source' which 'location' points into followed by a line which
+///underlines the token starting at 'location', or None if the location is not in 'source'
+pub fn source_excerpt(source: &str, location: &Location) -> Option<~str> {
+    if location.row <= 0 || location.column <= 0 {
+        return None;
+    }
+    let line = match source.lines().nth((location.row - 1) as uint) {
+        Some(line) => line,
+        None => return None
+    };
+    let start = (location.column - 1) as uint;
+    let chars : ~[char] = line.chars().collect();
+    if start > chars.len() {
+        return None;
+    }
+    let rest = chars.slice_from(start);
+    let length = match rest.head_opt() {
+        Some(c) if c.is_alphanumeric() || *c == '_' => {
+            rest.iter().take_while(|c| c.is_alphanumeric() || **c == '_' || **c == '\'').count()
+        }
+        Some(c) if is_operator(*c) => rest.iter().take_while(|c| is_operator(**c)).count(),
+        _ => 1
+    };
+    let gutter = location.row.to_str();
+    let mut excerpt = format!("{} | {}\n{} | ", gutter, line, " ".repeat(gutter.len()));
+    //Tabs are kept so that the underline lines up with the source line
+    for c in chars.slice_to(start).iter() {
+        excerpt.push_char(if *c == '\t' { '\t' } else { ' ' });
+    }
+    excerpt.push_str("^".repeat(length));
+    Some(excerpt)
+}
+
+#[cfg(test)]
+mod tests {
+use lexer::Location;
+use diagnostic::source_excerpt;
+
+#[test]
+fn excerpt_underlines_token() {
+    let source = "main = 1\ntest = primIntAdd 1 'a'";
+    let location = Location { column: 8, row: 2, absolute: 16 };
+    assert_eq!(source_excerpt(source, &location), Some(~"2 | test = primIntAdd 1 'a'\n  |        ^^^^^^^^^^"));
+}
+
+#[test]
+fn excerpt_outside_source() {
+    assert_eq!(source_excerpt("main = 1", &Location::eof()), None);
+    assert_eq!(source_excerpt("main = 1", &Location { column: 1, row: 3, absolute: 20 }), None);
+}
+}
@@ -0,0 +1,117 @@
+//Extraction of the documentation comments ('-- |' and '{-| -}') of a module into a model
+//which can be used to generate reference documentation.
+use module::*;
+
+#[deriving(Clone, Eq, ToStr)]
+pub enum DocKind {
+    FunctionDoc,
+    ClassDoc,
+    DataDoc
+}
+
+///The documentation of a single top level declaration
+#[deriving(Clone, Eq, ToStr)]
+pub struct DocItem {
+    name: ~str,
+    kind: DocKind,
+    ///The declaration as it would be written in the source, None for functions without a type signature
+    signature: Option<~str>,
+    doc: ~str
+}
+
+pub fn class_signature(class: &Class) -> ~str {
+    let mut signature = format!("class {} where", class.name);
+    for decl in class.declarations.iter() {
+        signature.push_str(format!("\n    {} :: {}", decl.name, decl.typ));
+    }
+    signature
+}
+
+pub fn data_signature(data: &DataDefinition) -> ~str {
+    let mut signature = format!("data {}", data.typ);
+    for (i, ctor) in data.constructors.iter().enumerate() {
+        signature.push_str(if i == 0 { " = " } else { " | " });
+        signature.push_str(ctor.name.as_slice());
+        let mut typ = &ctor.typ;
+        for _ in range(0, ctor.arity) {
+            signature.push_str(format!(" {}", typ.types()[0]));
+            typ = &typ.types()[1];
+        }
+    }
+    if data.deriving.len() != 0 {
+        signature.push_str(format!(" deriving ({})", data.deriving.connect(", ")));
+    }
+    signature
+}
+
+///Returns the documented declarations of 'module' in the order bindings, classes and data definitions
+pub fn extract_docs(module: &Module) -> ~[DocItem] {
+    let mut items = ~[];
+    for bind in module.bindings.iter() {
+        match module.documentation.find(&bind.name) {
+            Some(doc) => {
+                let signature = module.typeDeclarations.iter()
+                    .find(|decl| decl.name == bind.name)
+                    .map(|decl| format!("{}", *decl));
+                items.push(DocItem { name: bind.name.clone(), kind: FunctionDoc, signature: signature, doc: doc.clone() });
+            }
+            None => ()
+        }
+    }
+    for class in module.classes.iter() {
+        match module.documentation.find(&class.name) {
+            Some(doc) => items.push(DocItem { name: class.name.clone(), kind: ClassDoc, signature: Some(class_signature(class)), doc: doc.clone() }),
+            None => ()
+        }
+    }
+    for data in module.dataDefinitions.iter() {
+        let name = &data.typ.op().name;
+        match module.documentation.find(name) {
+            Some(doc) => items.push(DocItem { name: name.clone(), kind: DataDoc, signature: Some(data_signature(data)), doc: doc.clone() }),
+            None => ()
+        }
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+use docs::*;
+use parser::Parser;
+
+#[test]
+fn extract_documentation() {
+    let mut parser = Parser::new(
+r"-- | Adds one
+-- to a number
+succ :: Int -> Int
+succ x = primIntAdd x 1
+
+-- A normal comment
+undocumented = 1
+
+{-| Values which may be missing -}
+data Maybe a = Just a | Nothing
+
+-- | Types with equality
+class Eq a where
+    (==) :: a -> a -> Bool
+
+-- | Doubles a number
+double x = primIntMultiply x 2".chars());
+    let module = parser.module();
+    let docs = extract_docs(&module);
+    assert_eq!(docs.len(), 4);
+    assert_eq!(docs[0].name, ~"succ");
+    assert_eq!(docs[0].kind, FunctionDoc);
+    assert_eq!(docs[0].doc, ~"Adds one\nto a number");
+    assert!(docs[0].signature.is_some());
+    assert_eq!(docs[1].name, ~"double");
+    assert_eq!(docs[1].signature, None);
+    assert_eq!(docs[2].name, ~"Eq");
+    assert_eq!(docs[2].kind, ClassDoc);
+    assert_eq!(docs[3].name, ~"Maybe");
+    assert_eq!(docs[3].doc, ~"Values which may be missing");
+}
+
+}
@@ -0,0 +1,27 @@
+//Values which are created the first time they are used and then shared by every task in the process,
+//such as the interner and the cache of compiled preludes. Each value is guarded by a lock which is
+//released even if the task fails while holding it, so one failed task does not block every other task.
+use std::cast;
+use std::unstable::mutex::Mutex;
+
+///Unlocks the lock it points to when it is dropped
+struct Unlock {
+    lock: *mut Mutex
+}
+
+impl Drop for Unlock {
+    fn drop(&mut self) {
+        unsafe { (*self.lock).unlock(); }
+    }
+}
+
+///Calls 'f' with the value in 'global' while holding 'lock', the value is created with 'init' the first time.
+///'lock' and 'global' must be the static variables which guard and hold the value, the value is never freed.
+pub unsafe fn with_global<T, U>(lock: &mut Mutex, global: &mut *mut T, init: || -> T, f: |&mut T| -> U) -> U {
+    lock.lock();
+    let _unlock = Unlock { lock: lock as *mut Mutex };
+    if global.is_null() {
+        *global = cast::transmute(~init());
+    }
+    f(&mut **global)
+}
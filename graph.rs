@@ -56,11 +56,15 @@ impl <T> Graph<T> {
     }
 }
 
+///Returns the strongly connected components of 'graph' in reverse topological order,
+///so each component only depends on itself and the components before it.
+///Uses an explicit stack instead of recursion so large graphs do not overflow the task's stack.
 pub fn strongly_connected_components<T>(graph: &Graph<T>) -> ~[~[VertexIndex]] {
     
     let mut tarjan = TarjanComponents { graph: graph, index: 1, stack: ~[], connections: ~[],
-        valid: vec::from_fn(graph.len(), |_| 0),
-        lowlink: vec::from_fn(graph.len(), |_| 0)};
+        valid: vec::from_elem(graph.len(), 0u),
+        lowlink: vec::from_elem(graph.len(), 0u),
+        on_stack: vec::from_elem(graph.len(), false)};
     
 
     for vert in range(0, graph.len()) {
@@ -77,39 +81,62 @@ struct TarjanComponents<'a, T>{
     graph: &'a Graph<T>,
     valid: ~[uint],
     lowlink: ~[uint],
+    ///Whether each vertex is currently on 'stack'
+    on_stack: ~[bool],
     stack: ~[VertexIndex],
     connections: ~[~[VertexIndex]]
 }
 
 impl <'a, T> TarjanComponents<'a, T> {
-    fn strong_connect(&mut self, v: VertexIndex) {
+    fn visit(&mut self, v: VertexIndex) {
         self.valid[v.get()] = self.index;
         self.lowlink[v.get()] = self.index;
         self.index += 1;
         self.stack.push(v);
+        self.on_stack[v.get()] = true;
+    }
 
-        for edge_index in self.graph.get_vertex(v).edges.iter() {
-            let edge = self.graph.get_edge(*edge_index);
-            if self.valid[edge.to.get()] == 0 {
-                self.strong_connect(edge.to);
-                self.lowlink[v.get()] = min(self.lowlink[v.get()], self.lowlink[edge.to.get()]); 
+    fn strong_connect(&mut self, root: VertexIndex) {
+        //Each entry is a vertex being visited and the position of the next edge to look at
+        let mut calls = ~[(root, 0u)];
+        self.visit(root);
+        while calls.len() > 0 {
+            let (v, edge_position) = calls[calls.len() - 1];
+            let graph = self.graph;
+            let edges = &graph.get_vertex(v).edges;
+            if edge_position < edges.len() {
+                calls[calls.len() - 1] = (v, edge_position + 1);
+                let to = graph.get_edge(edges[edge_position]).to;
+                if self.valid[to.get()] == 0 {
+                    self.visit(to);
+                    calls.push((to, 0));
+                }
+                else if self.on_stack[to.get()] {
+                    self.lowlink[v.get()] = min(self.lowlink[v.get()], self.valid[to.get()]);
+                }
+                continue;
             }
-            else if (self.stack.iter().any(|x| *x == edge.to)) {
-                self.lowlink[v.get()] = min(self.lowlink[v.get()], self.valid[edge.to.get()]);
+
+            //All edges of 'v' are done, return to the vertex which visited it
+            calls.pop();
+            if calls.len() > 0 {
+                let (parent, _) = calls[calls.len() - 1];
+                self.lowlink[parent.get()] = min(self.lowlink[parent.get()], self.lowlink[v.get()]);
             }
-        }
 
-        if self.lowlink[v.get()] == self.valid[v.get()] {
-            let mut connected = ~[];
-            loop {
-                
-                let w = self.stack.pop();
-                connected.push(w);
-                if w == v {
-                    break
+            if self.lowlink[v.get()] == self.valid[v.get()] {
+                let mut connected = ~[];
+                loop {
+                    
+                    let w = self.stack.pop();
+                    self.on_stack[w.get()] = false;
+                    connected.push(w);
+                    if w == v {
+                        break
+                    }
                 }
+                self.connections.push(connected);
             }
-            self.connections.push(connected);
         }
     }
 }
@@ -170,3 +197,32 @@ fn test_tarjan3() {
     assert_eq!(connections[1], ~[v4, v3]);
     assert_eq!(connections[2], ~[v2, v1]);
 }
+
+#[cfg(test)]
+fn chain(length: uint) -> Graph<()> {
+    let mut graph = Graph::new();
+    let mut previous = graph.new_vertex(());
+    for _ in range(1, length) {
+        let next = graph.new_vertex(());
+        graph.connect(previous, next);
+        previous = next;
+    }
+    graph
+}
+
+#[test]
+fn test_tarjan_deep_graph() {
+    //Deep enough to overflow the stack if each vertex was visited recursively
+    let mut graph = chain(200000);
+    let last = VertexIndex(graph.len() - 1);
+    graph.connect(last, VertexIndex(0));
+    let connections = strongly_connected_components(&graph);
+    assert_eq!(connections.len(), 1);
+    assert_eq!(connections[0].len(), 200000);
+}
+
+#[bench]
+fn bench_tarjan_chain(b: &mut ::extra::test::BenchHarness) {
+    let graph = chain(10000);
+    b.iter(|| { strongly_connected_components(&graph); });
+}
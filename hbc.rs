@@ -0,0 +1,512 @@
+//The .hbc file format which stores a compiled assembly so that it can be loaded without its source.
+//A file starts with a header of the magic bytes 'HBC\0', the version of the format and the version of the instruction set,
+//all numbers are little endian. After the header follow the name of the module, the relocations (see link.rs), the supercombinators,
+//the instance dictionaries and the classes, instances, data definitions, type synonyms and exported names which the typechecker
+//needs to use the assembly.
+//Sequences are stored as their length followed by their elements and strings as their length in bytes followed by UTF-8.
+use std::cast;
+use std::str::from_utf8_opt;
+use std::hashmap::{HashMap, HashSet};
+use extra::arc::Arc;
+#[cfg(not(target_arch = "wasm32"))]
+use std::io::{File, io_error};
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::Path;
+use module::{Class, Constraint, Constructor, DataDefinition, TypeDeclaration, TypeSynonym,
+    Type, TypeVariable, TypeOperator, Location, intern};
+use compiler::{Assembly, SuperCombinator, Relocation, LocationTable, INSTRUCTION_SET_VERSION};
+use bytecode::Code;
+
+static MAGIC: &'static [u8] = &['H' as u8, 'B' as u8, 'C' as u8, 0];
+
+///Version of the file format, must be increased whenever the layout of the file changes
+pub static HBC_VERSION: uint = 4;
+static MAX_TYPE_NESTING: uint = 256;
+
+///Writes values to a byte buffer
+pub struct Encoder {
+    priv bytes: ~[u8]
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder { bytes: ~[] }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        for i in range(0, 4) {
+            self.bytes.push((value >> (8 * i)) as u8);
+        }
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        for i in range(0, 8) {
+            self.bytes.push((value >> (8 * i)) as u8);
+        }
+    }
+
+    pub fn uint(&mut self, value: uint) {
+        assert!(value <= ::std::u32::max_value as uint, "{} is too large to be stored in an assembly file", value);
+        self.u32(value as u32);
+    }
+
+    pub fn int(&mut self, value: int) {
+        self.u64(value as i64 as u64);
+    }
+
+    pub fn f64(&mut self, value: f64) {
+        self.u64(unsafe { cast::transmute(value) });
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn str(&mut self, value: &str) {
+        self.uint(value.len());
+        self.bytes.push_all(value.as_bytes());
+    }
+
+    ///Writes the length of 'values' followed by each value written by 'f'
+    pub fn seq<T>(&mut self, values: &[T], f: |&mut Encoder, &T|) {
+        self.uint(values.len());
+        for value in values.iter() {
+            f(self, value);
+        }
+    }
+
+    pub fn unwrap(self) -> ~[u8] {
+        self.bytes
+    }
+}
+
+///Reads the values written by an Encoder, every read fails with an error message if the input is too short
+pub struct Decoder<'a> {
+    priv bytes: &'a [u8],
+    priv position: uint
+}
+
+impl <'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Decoder<'a> {
+        Decoder { bytes: bytes, position: 0 }
+    }
+
+    fn take(&mut self, count: uint) -> Result<&'a [u8], ~str> {
+        if self.bytes.len() - self.position < count {
+            return Err(format!("Unexpected end of the assembly at byte {}", self.position));
+        }
+        let bytes = self.bytes.slice(self.position, self.position + count);
+        self.position += count;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, ~str> {
+        Ok(try!(self.take(1))[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, ~str> {
+        let bytes = try!(self.take(4));
+        Ok(bytes.iter().invert().fold(0u32, |value, b| (value << 8) | *b as u32))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, ~str> {
+        let bytes = try!(self.take(8));
+        Ok(bytes.iter().invert().fold(0u64, |value, b| (value << 8) | *b as u64))
+    }
+
+    pub fn uint(&mut self) -> Result<uint, ~str> {
+        self.u32().map(|value| value as uint)
+    }
+
+    pub fn int(&mut self) -> Result<int, ~str> {
+        self.u64().map(|value| value as i64 as int)
+    }
+
+    pub fn f64(&mut self) -> Result<f64, ~str> {
+        self.u64().map(|value| unsafe { cast::transmute(value) })
+    }
+
+    pub fn bool(&mut self) -> Result<bool, ~str> {
+        match try!(self.u8()) {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(format!("Expected a boolean in the assembly, got {}", b))
+        }
+    }
+
+    pub fn str(&mut self) -> Result<~str, ~str> {
+        let len = try!(self.uint());
+        let position = self.position;
+        match from_utf8_opt(try!(self.take(len))) {
+            Some(s) => Ok(s.to_owned()),
+            None => Err(format!("Invalid UTF-8 in the string at byte {}", position))
+        }
+    }
+
+    ///Reads a sequence written by 'Encoder::seq', reading each value with 'f'
+    pub fn seq<T>(&mut self, f: |&mut Decoder<'a>| -> Result<T, ~str>) -> Result<~[T], ~str> {
+        let len = try!(self.uint());
+        //The length is not trusted to preallocate since each element takes at least one byte
+        if len > self.bytes.len() - self.position {
+            return Err(format!("Sequence of {} elements at byte {} is longer than the assembly", len, self.position));
+        }
+        let mut values = ~[];
+        for _ in range(0, len) {
+            values.push(try!(f(self)));
+        }
+        Ok(values)
+    }
+
+    ///Returns true if every byte has been read
+    pub fn is_empty(&self) -> bool {
+        self.position == self.bytes.len()
+    }
+}
+
+///Encodes 'assembly' in the .hbc format
+pub fn encode_assembly(assembly: &Assembly) -> ~[u8] {
+    let mut e = Encoder::new();
+    for b in MAGIC.iter() {
+        e.u8(*b);
+    }
+    e.uint(HBC_VERSION);
+    e.uint(INSTRUCTION_SET_VERSION);
+    e.str(assembly.name.as_slice());
+    e.uint(assembly.offset);
+    e.seq(assembly.relocations.as_slice(), |e, relocation| {
+        e.uint(relocation.index);
+        e.str(relocation.module.as_slice());
+        e.str(relocation.name.as_slice());
+    });
+    e.seq(assembly.superCombinators.as_slice(), |e, sc| {
+        e.str(sc.name.as_slice());
+        e.uint(sc.arity);
+        e.uint(sc.assembly_id);
+        write_declaration(e, &sc.type_declaration);
+        e.seq(sc.constraints.as_slice(), |e, c| write_constraint(e, c));
+        sc.instructions.get().serialize(e);
+        e.seq(sc.locations.get().entries(), |e, &(index, ref location)| {
+            e.uint(index);
+            e.int(location.row);
+            e.int(location.column);
+            e.int(location.absolute);
+        });
+    });
+    e.seq(assembly.instance_dictionaries.as_slice(), |e, dict| e.seq(dict.as_slice(), |e, index| e.uint(*index)));
+    e.seq(assembly.classes.as_slice(), |e, class| {
+        e.str(class.name.as_slice());
+        e.int(class.variable.id);
+        e.seq(class.constraints.as_slice(), |e, c| write_constraint(e, c));
+        e.seq(class.declarations.as_slice(), |e, decl| write_declaration(e, decl));
+    });
+    e.seq(assembly.instances.as_slice(), |e, &(ref constraints, ref typ)| {
+        e.seq(constraints.as_slice(), |e, c| write_constraint(e, c));
+        write_type(e, typ);
+    });
+    e.seq(assembly.data_definitions.as_slice(), |e, data| {
+        write_type(e, &data.typ);
+        e.seq(data.constructors.as_slice(), |e, ctor| {
+            e.str(ctor.name.as_slice());
+            write_type(e, &ctor.typ);
+            e.int(ctor.tag);
+            e.int(ctor.arity);
+            e.seq(ctor.fields.as_slice(), |e, field| e.str(field.as_slice()));
+        });
+        let mut parameters : ~[(&~str, &int)] = data.parameters.iter().collect();
+        parameters.sort_by(|&(l, _), &(r, _)| l.as_slice().cmp(&r.as_slice()));
+        e.seq(parameters.as_slice(), |e, &(name, id)| { e.str(name.as_slice()); e.int(*id); });
+        e.seq(data.context.as_slice(), |e, c| write_constraint(e, c));
+        e.seq(data.deriving.as_slice(), |e, class| e.str(class.as_slice()));
+        e.int(data.location.row);
+        e.int(data.location.column);
+        e.int(data.location.absolute);
+    });
+    e.seq(assembly.type_synonyms.as_slice(), |e, synonym| {
+        e.str(synonym.name.as_slice());
+        e.seq(synonym.parameters.as_slice(), |e, var| e.int(var.id));
+        write_type(e, &synonym.typ);
+    });
+    match assembly.exported {
+        Some(ref names) => {
+            e.bool(true);
+            let mut names : ~[&~str] = names.iter().collect();
+            names.sort_by(|l, r| l.as_slice().cmp(&r.as_slice()));
+            e.seq(names.as_slice(), |e, name| e.str(name.as_slice()));
+        }
+        None => e.bool(false)
+    }
+    e.unwrap()
+}
+
+///Decodes an assembly written by 'encode_assembly'
+pub fn decode_assembly(bytes: &[u8]) -> Result<Assembly, ~str> {
+    let mut d = Decoder::new(bytes);
+    for b in MAGIC.iter() {
+        if try!(d.u8()) != *b {
+            return Err(~"The file is not an assembly");
+        }
+    }
+    let version = try!(d.uint());
+    if version != HBC_VERSION {
+        return Err(format!("The assembly has version {} of the file format but version {} is required", version, HBC_VERSION));
+    }
+    let instruction_set = try!(d.uint());
+    if instruction_set != INSTRUCTION_SET_VERSION {
+        return Err(format!("The assembly was compiled for version {} of the instruction set but the VM uses version {}",
+            instruction_set, INSTRUCTION_SET_VERSION));
+    }
+    let name = try!(d.str());
+    let offset = try!(d.uint());
+    let relocations = try!(d.seq(|d| {
+        let index = try!(d.uint());
+        let module = try!(d.str());
+        Ok(Relocation { index: index, module: module, name: intern(try!(d.str())) })
+    }));
+    let superCombinators = try!(d.seq(|d| {
+        let name = intern(try!(d.str()));
+        let arity = try!(d.uint());
+        let assembly_id = try!(d.uint());
+        let type_declaration = try!(read_declaration(d));
+        let constraints = try!(d.seq(|d| read_constraint(d)));
+        let code = try!(Code::deserialize(d));
+        let locations = try!(d.seq(|d| {
+            let index = try!(d.uint());
+            let row = try!(d.int());
+            let column = try!(d.int());
+            Ok((index, Location { row: row, column: column, absolute: try!(d.int()) }))
+        }));
+        Ok(SuperCombinator { arity: arity, name: name, assembly_id: assembly_id, instructions: Arc::new(code),
+            type_declaration: type_declaration, constraints: constraints, locations: Arc::new(LocationTable::from_entries(locations)) })
+    }));
+    let instance_dictionaries = try!(d.seq(|d| d.seq(|d| d.uint())));
+    let classes = try!(d.seq(|d| {
+        let name = try!(d.str());
+        let variable = TypeVariable { id: try!(d.int()) };
+        let constraints = try!(d.seq(|d| read_constraint(d)));
+        let declarations = try!(d.seq(|d| read_declaration(d)));
+        Ok(Class { constraints: constraints, name: name, variable: variable, declarations: declarations })
+    }));
+    let instances = try!(d.seq(|d| {
+        let constraints = try!(d.seq(|d| read_constraint(d)));
+        Ok((constraints, try!(read_type(d))))
+    }));
+    let data_definitions = try!(d.seq(|d| {
+        let typ = try!(read_type(d));
+        let constructors = try!(d.seq(|d| {
+            let name = try!(d.str());
+            let typ = try!(read_type(d));
+            let tag = try!(d.int());
+            let arity = try!(d.int());
+            let fields = try!(d.seq(|d| d.str()));
+            Ok(Constructor { name: name, typ: typ, tag: tag, arity: arity, fields: fields })
+        }));
+        let mut parameters = HashMap::new();
+        for (name, id) in try!(d.seq(|d| { let name = try!(d.str()); Ok((name, try!(d.int()))) })).move_iter() {
+            parameters.insert(name, id);
+        }
+        let context = try!(d.seq(|d| read_constraint(d)));
+        let deriving = try!(d.seq(|d| d.str()));
+        let row = try!(d.int());
+        let column = try!(d.int());
+        let location = Location { row: row, column: column, absolute: try!(d.int()) };
+        Ok(DataDefinition { constructors: constructors, typ: typ, parameters: parameters, context: context, deriving: deriving, location: location })
+    }));
+    let type_synonyms = try!(d.seq(|d| {
+        let name = try!(d.str());
+        let parameters = try!(d.seq(|d| d.int().map(|id| TypeVariable { id: id })));
+        let typ = try!(read_type(d));
+        Ok(TypeSynonym { name: name, parameters: parameters, typ: typ })
+    }));
+    let exported = if try!(d.bool()) {
+        let mut names = HashSet::new();
+        for name in try!(d.seq(|d| d.str())).move_iter() {
+            names.insert(name);
+        }
+        Some(names)
+    }
+    else {
+        None
+    };
+    if !d.is_empty() {
+        return Err(~"Unexpected data after the end of the assembly");
+    }
+    Ok(Assembly {
+        name: name,
+        superCombinators: superCombinators,
+        instance_dictionaries: instance_dictionaries,
+        classes: classes,
+        instances: instances,
+        data_definitions: data_definitions,
+        type_synonyms: type_synonyms,
+        exported: exported,
+        relocations: relocations,
+        offset: offset
+    })
+}
+
+///Writes 'assembly' to the file at 'path'
+#[cfg(not(target_arch = "wasm32"))]
+pub fn write_assembly_file(path: &str, assembly: &Assembly) -> Result<(), ~str> {
+    let bytes = encode_assembly(assembly);
+    let mut error = None;
+    io_error::cond.trap(|e| error = Some(e.desc)).inside(|| {
+        File::create(&Path::new(path)).write(bytes)
+    });
+    match error {
+        Some(desc) => Err(format!("Could not write {}: {}", path, desc)),
+        None => Ok(())
+    }
+}
+
+///Reads the assembly in the file at 'path'
+#[cfg(not(target_arch = "wasm32"))]
+pub fn read_assembly_file(path: &str) -> Result<Assembly, ~str> {
+    let mut error = None;
+    let bytes = io_error::cond.trap(|e| error = Some(e.desc)).inside(|| {
+        File::open(&Path::new(path)).read_to_end()
+    });
+    match error {
+        Some(desc) => Err(format!("Could not read {}: {}", path, desc)),
+        None => decode_assembly(bytes).map_err(|msg| format!("{}: {}", path, msg))
+    }
+}
+
+fn write_type(e: &mut Encoder, typ: &Type) {
+    match typ.typ {
+        TypeVariable(ref var) => {
+            e.u8(0);
+            e.int(var.id);
+        }
+        TypeOperator(ref op) => {
+            e.u8(1);
+            e.str(op.name.as_slice());
+        }
+    }
+    e.seq(typ.types(), |e, t| write_type(e, t));
+}
+
+fn read_type(d: &mut Decoder) -> Result<Type, ~str> {
+    read_nested_type(d, 0)
+}
+
+///Reads a type which is nested inside 'depth' other types, types nested too deeply are rejected
+///instead of overflowing the stack
+fn read_nested_type(d: &mut Decoder, depth: uint) -> Result<Type, ~str> {
+    if depth >= MAX_TYPE_NESTING {
+        return Err(format!("A type in the assembly is nested deeper than {} levels", MAX_TYPE_NESTING));
+    }
+    let typ = match try!(d.u8()) {
+        0 => TypeVariable(TypeVariable { id: try!(d.int()) }),
+        1 => TypeOperator(TypeOperator { name: try!(d.str()) }),
+        tag => return Err(format!("Invalid type tag {} in the assembly", tag))
+    };
+    let types = try!(d.seq(|d| read_nested_type(d, depth + 1)));
+    Ok(Type::new(typ, types))
+}
+
+fn write_constraint(e: &mut Encoder, constraint: &Constraint) {
+    e.str(constraint.class.as_slice());
+    e.seq(constraint.variables.as_slice(), |e, var| e.int(var.id));
+}
+
+fn read_constraint(d: &mut Decoder) -> Result<Constraint, ~str> {
+    let class = try!(d.str());
+    let variables = try!(d.seq(|d| d.int().map(|id| TypeVariable { id: id })));
+    Ok(Constraint { class: class, variables: variables })
+}
+
+fn write_declaration(e: &mut Encoder, decl: &TypeDeclaration) {
+    e.str(decl.name.as_slice());
+    e.seq(decl.context.as_slice(), |e, c| write_constraint(e, c));
+    write_type(e, &decl.typ);
+}
+
+fn read_declaration(d: &mut Decoder) -> Result<TypeDeclaration, ~str> {
+    let name = try!(d.str());
+    let context = try!(d.seq(|d| read_constraint(d)));
+    let typ = try!(read_type(d));
+    Ok(TypeDeclaration { context: context, typ: typ, name: name, location: Location::eof() })
+}
+
+#[cfg(test)]
+mod tests {
+use hbc::{encode_assembly, decode_assembly, read_type, Encoder, Decoder};
+use vm::{compile_file, run_main_with};
+use vm::IntResult;
+use compiler::Compiler;
+use typecheck::TypeEnvironment;
+use parser::Parser;
+
+#[test]
+fn encode_and_decode_prelude() {
+    let prelude = compile_file("Prelude.hs");
+    let bytes = encode_assembly(&prelude);
+    let decoded = decode_assembly(bytes).unwrap();
+    assert_eq!(decoded.superCombinators.len(), prelude.superCombinators.len());
+    for (sc, original) in decoded.superCombinators.iter().zip(prelude.superCombinators.iter()) {
+        assert_eq!(sc.name, original.name);
+        assert_eq!(sc.arity, original.arity);
+        assert_eq!(sc.type_declaration, original.type_declaration);
+        assert_eq!(sc.instructions.get(), original.instructions.get());
+        assert_eq!(sc.locations.get(), original.locations.get());
+    }
+    assert_eq!(decoded.instance_dictionaries, prelude.instance_dictionaries);
+    assert_eq!(decoded.classes, prelude.classes);
+    assert_eq!(decoded.instances, prelude.instances);
+    assert_eq!(decoded.data_definitions, prelude.data_definitions);
+    assert_eq!(decoded.exported, prelude.exported);
+    assert_eq!(decoded.name, prelude.name);
+    assert_eq!(decoded.relocations, prelude.relocations);
+    //Encoding is deterministic so the decoded assembly encodes to the same bytes
+    assert_eq!(encode_assembly(&decoded), bytes);
+}
+
+#[test]
+fn run_decoded_assembly() {
+    let prelude = decode_assembly(encode_assembly(&compile_file("Prelude.hs"))).unwrap();
+    let assembly = {
+        let file =
+r"add x y = primIntAdd x y
+main = foldl add 0 [1,2,3,4]";
+        let mut parser = Parser::new(file.chars());
+        let mut module = parser.module();
+        let mut type_env = TypeEnvironment::new();
+        type_env.add_types(&prelude);
+        type_env.typecheck_module(&mut module).unwrap();
+        let mut compiler = Compiler::new(&type_env);
+        compiler.assemblies.push(&prelude);
+        compiler.compileModule(&module)
+    };
+    assert_eq!(run_main_with(~[prelude, assembly]), Some(IntResult(10)));
+}
+
+#[test]
+fn reject_invalid_files() {
+    let bytes = encode_assembly(&compile_file("Prelude.hs"));
+    assert!(decode_assembly(bytes.slice_to(bytes.len() - 1)).is_err());
+    assert!(decode_assembly([0u8, 1, 2, 3]).is_err());
+    let mut wrong_version = bytes.clone();
+    wrong_version[4] += 1;
+    assert!(decode_assembly(wrong_version).is_err());
+}
+
+#[test]
+fn reject_deeply_nested_types() {
+    //[[[...[a]...]]] nested far deeper than any type which is compiled
+    let mut e = Encoder::new();
+    for _ in range(0, 100000) {
+        e.u8(1);
+        e.str("[]");
+        e.uint(1);
+    }
+    e.u8(0);
+    e.int(0);
+    e.uint(0);
+    let bytes = e.unwrap();
+    assert!(read_type(&mut Decoder::new(bytes)).is_err());
+}
+}
@@ -0,0 +1,158 @@
+//Everything the compiler and VM need from the environment they run in.
+//The rest of the crate only accesses files and the console through these traits so that it
+//can be built for targets which lack them, such as wasm32-unknown-unknown.
+use std::hashmap::HashMap;
+#[cfg(not(target_arch = "wasm32"))]
+use std::io::{File, io_error, stdin};
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::Path;
+#[cfg(not(target_arch = "wasm32"))]
+use std::str::from_utf8_opt;
+
+///Provides the contents of source files
+pub trait FileSystem {
+    ///Reads the entire file at 'path', returning an error message if it could not be read
+    fn read_file(&self, path: &str) -> Result<~str, ~str>;
+    ///Returns true if there is a file at 'path', without reading it
+    fn exists(&self, path: &str) -> bool;
+}
+
+///Receives the text which would otherwise be printed to stdout and provides the text read from stdin
+pub trait Console {
+    fn write_line(&mut self, line: &str);
+    ///Reads a line without the line terminator, returns None when there is no more input
+    fn read_line(&mut self) -> Option<~str>;
+}
+
+///Reads files from the disk
+#[cfg(not(target_arch = "wasm32"))]
+pub struct NativeFileSystem;
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FileSystem for NativeFileSystem {
+    fn read_file(&self, path: &str) -> Result<~str, ~str> {
+        let mut error = None;
+        let bytes = io_error::cond.trap(|e| error = Some(e.desc)).inside(|| {
+            File::open(&Path::new(path)).read_to_end()
+        });
+        match error {
+            Some(desc) => Err(format!("Could not read {}: {}", path, desc)),
+            None => match from_utf8_opt(bytes) {
+                Some(contents) => Ok(contents.to_owned()),
+                None => Err(format!("{} is not valid UTF-8", path))
+            }
+        }
+    }
+    fn exists(&self, path: &str) -> bool {
+        Path::new(path).is_file()
+    }
+}
+
+///Prints to stdout and reads from stdin
+#[cfg(not(target_arch = "wasm32"))]
+pub struct StdConsole;
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Console for StdConsole {
+    fn write_line(&mut self, line: &str) {
+        println!("{}", line);
+    }
+    fn read_line(&mut self) -> Option<~str> {
+        //Read a byte at a time so that no input is left buffered between calls
+        let mut bytes = ~[];
+        let mut reader = stdin();
+        loop {
+            match io_error::cond.trap(|_| ()).inside(|| reader.read_byte()) {
+                Some(b) if b == '\n' as u8 => break,
+                Some(b) => bytes.push(b),
+                None if bytes.len() == 0 => return None,
+                None => break
+            }
+        }
+        if bytes.len() > 0 && bytes[bytes.len() - 1] == '\r' as u8 {
+            bytes.pop();
+        }
+        from_utf8_opt(bytes).map(|line| line.to_owned())
+    }
+}
+
+///A filesystem which only contains the files which were added to it.
+///Used on targets without a real filesystem, eg when running in a browser.
+pub struct MemoryFileSystem {
+    priv files: HashMap<~str, ~str>
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> MemoryFileSystem {
+        MemoryFileSystem { files: HashMap::new() }
+    }
+
+    pub fn add_file(&mut self, path: ~str, contents: ~str) {
+        self.files.insert(path, contents);
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn read_file(&self, path: &str) -> Result<~str, ~str> {
+        match self.files.find_equiv(&path) {
+            Some(contents) => Ok(contents.clone()),
+            None => Err(format!("Could not read {}: No such file", path))
+        }
+    }
+    fn exists(&self, path: &str) -> bool {
+        self.files.find_equiv(&path).is_some()
+    }
+}
+
+///A console which stores every line written to it and reads from a fixed list of lines
+pub struct BufferedConsole {
+    lines: ~[~str],
+    ///The lines returned by read_line, in order
+    input: ~[~str]
+}
+
+impl BufferedConsole {
+    pub fn new() -> BufferedConsole {
+        BufferedConsole { lines: ~[], input: ~[] }
+    }
+}
+
+impl Console for BufferedConsole {
+    fn write_line(&mut self, line: &str) {
+        self.lines.push(line.to_owned());
+    }
+    fn read_line(&mut self) -> Option<~str> {
+        if self.input.len() == 0 {
+            None
+        }
+        else {
+            Some(self.input.shift())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+use host::*;
+
+#[test]
+fn memory_file_system() {
+    let mut fs = MemoryFileSystem::new();
+    fs.add_file(~"Main.hs", ~"main = 1");
+    assert_eq!(fs.read_file("Main.hs"), Ok(~"main = 1"));
+    assert!(fs.read_file("Prelude.hs").is_err());
+    assert!(fs.exists("Main.hs"));
+    assert!(!fs.exists("Prelude.hs"));
+}
+
+#[test]
+fn native_file_system() {
+    let fs = NativeFileSystem;
+    assert!(fs.read_file("Prelude.hs").unwrap().len() > 0);
+    assert!(fs.read_file("DoesNotExist.hs").is_err());
+    assert!(fs.exists("Prelude.hs"));
+    assert!(!fs.exists("DoesNotExist.hs"));
+}
+
+}
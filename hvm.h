@@ -0,0 +1,33 @@
+/* C interface to the Haskell VM, implemented in capi.rs */
+#ifndef HVM_H
+#define HVM_H
+
+#define HVM_INT 0
+#define HVM_DOUBLE 1
+#define HVM_CONSTRUCTOR 2
+
+typedef struct HvmVm HvmVm;
+
+typedef struct HvmValue {
+    int tag;
+    long int_value;
+    double double_value;
+    int constructor_tag;
+    int field_count;
+    /* Owned by the VM, valid until the next call to hvm_call */
+    const struct HvmValue* fields;
+} HvmValue;
+
+/* A NULL vm is accepted everywhere: hvm_destroy ignores it, hvm_last_error returns NULL
+   and the other functions return -1 without setting an error */
+
+HvmVm* hvm_create(void);
+void hvm_destroy(HvmVm* vm);
+/* Returns NULL if no error has occured */
+const char* hvm_last_error(const HvmVm* vm);
+/* Returns 0 on success and -1 on failure */
+int hvm_load_source(HvmVm* vm, const char* source);
+/* Returns 0 on success and -1 on failure */
+int hvm_call(HvmVm* vm, const char* name, const HvmValue* args, int argc, HvmValue* result);
+
+#endif
@@ -0,0 +1,271 @@
+//Replaces saturated calls of small top level functions, such as 'id x' or 'const x y', with the
+//body of the function before the module is compiled, saving the application and the call.
+//Only functions without a context are inlined since the compiler chooses instance dictionaries from
+//the types of the expressions and in the body of a function with a context those are type variables.
+//Bodies which contain lambdas or lets are not inlined either as those are only compiled at the top of a binding.
+use std::hashmap::HashMap;
+use module::{Module, Binding, TypedExpr, Identifier, Apply, Number, Rational, String, Char, Lambda, Let, Case};
+use variables::{free_variables, bound_variables, pattern_variables, replace_variables};
+use interner::{InternedStr, intern};
+
+///Functions whose bodies have more expressions than this are not inlined
+static MAX_INLINE_SIZE: uint = 12;
+
+///The arguments and body of a function which can be inlined
+struct Inlinable {
+    arguments: ~[InternedStr],
+    body: TypedExpr
+}
+
+///Inlines the small functions of 'module' in every binding of the module
+pub fn inline_module(module: &mut Module) {
+    let mut functions = HashMap::new();
+    for bind in module.bindings.iter() {
+        match inlinable(bind) {
+            Some(function) => { functions.insert(intern(bind.name), function); }
+            None => ()
+        }
+    }
+    if functions.len() == 0 {
+        return;
+    }
+    let mut scope = ~[];
+    for bind in module.bindings.mut_iter() {
+        inline_expr(&functions, &mut scope, &mut bind.expression);
+    }
+    for instance in module.instances.mut_iter() {
+        for bind in instance.bindings.mut_iter() {
+            inline_expr(&functions, &mut scope, &mut bind.expression);
+        }
+    }
+}
+
+fn inlinable(bind: &Binding) -> Option<Inlinable> {
+    if bind.arity == 0 || bind.typeDecl.context.len() != 0 {
+        return None;
+    }
+    let mut arguments = ~[];
+    let mut body = &bind.expression;
+    loop {
+        match &body.expr {
+            &Lambda(ref arg, ref inner) if arguments.len() < bind.arity => {
+                arguments.push(*arg);
+                body = &**inner;
+            }
+            _ => break
+        }
+    }
+    if arguments.len() != bind.arity || !is_simple(body) || size(body) > MAX_INLINE_SIZE {
+        return None;
+    }
+    //A recursive function would be inlined into itself
+    let mut free = ~[];
+    free_variables(body, &mut arguments.clone(), &mut free);
+    let name = intern(bind.name);
+    if free.iter().any(|&(ref var, _)| *var == name) {
+        return None;
+    }
+    Some(Inlinable { arguments: arguments, body: body.clone() })
+}
+
+///Inlines the calls in 'expr', 'scope' holds the local variables which are in scope
+fn inline_expr(functions: &HashMap<InternedStr, Inlinable>, scope: &mut ~[InternedStr], expr: &mut TypedExpr) {
+    match expr.expr {
+        Apply(~(ref mut func, ref mut arg)) => {
+            inline_expr(functions, scope, func);
+            inline_expr(functions, scope, arg);
+        }
+        Lambda(ref arg, ref mut body) => {
+            scope.push(*arg);
+            inline_expr(functions, scope, &mut **body);
+            scope.pop();
+        }
+        Let(ref mut bindings, ref mut body) => {
+            for bind in bindings.iter() {
+                scope.push(intern(bind.name));
+            }
+            for bind in bindings.mut_iter() {
+                inline_expr(functions, scope, &mut bind.expression);
+            }
+            inline_expr(functions, scope, &mut **body);
+            for _ in bindings.iter() {
+                scope.pop();
+            }
+        }
+        Case(ref mut scrutinee, ref mut alternatives) => {
+            inline_expr(functions, scope, &mut **scrutinee);
+            for alt in alternatives.mut_iter() {
+                let before = scope.len();
+                pattern_variables(&alt.pattern.node, scope);
+                inline_expr(functions, scope, &mut alt.expression);
+                scope.truncate(before);
+            }
+        }
+        Identifier(_) | Number(_) | Rational(_) | String(_) | Char(_) => ()
+    }
+    match try_inline(functions, scope.as_slice(), expr) {
+        Some(inlined) => *expr = inlined,
+        None => ()
+    }
+}
+
+///Returns the body of the function called in 'expr' with its arguments substituted, if the call can be inlined
+fn try_inline(functions: &HashMap<InternedStr, Inlinable>, scope: &[InternedStr], expr: &TypedExpr) -> Option<TypedExpr> {
+    //The applications of the call from the innermost to the outermost
+    let mut applications = ~[];
+    let mut head = expr;
+    loop {
+        match &head.expr {
+            &Apply(~(ref func, _)) => {
+                applications.unshift(head);
+                head = func;
+            }
+            _ => break
+        }
+    }
+    let name = match &head.expr {
+        &Identifier(ref name) if !scope.contains(name) => *name,
+        _ => return None
+    };
+    let function = match functions.find(&name) {
+        Some(function) => function,
+        None => return None
+    };
+    let arity = function.arguments.len();
+    if applications.len() < arity {
+        return None;
+    }
+    let arguments : ~[&TypedExpr] = applications.slice_to(arity).iter().map(|&application| {
+        match &application.expr {
+            &Apply(~(_, ref arg)) => arg,
+            _ => fail!()
+        }
+    }).collect();
+
+    //The globals which the body refers to must not be shadowed by local variables at the call
+    let mut free = ~[];
+    free_variables(&function.body, &mut function.arguments.clone(), &mut free);
+    if free.iter().any(|&(ref name, _)| scope.contains(name)) {
+        return None;
+    }
+    //The variables bound in the body must not capture the variables of the arguments
+    let mut bound = ~[];
+    bound_variables(&function.body, &mut bound);
+    for arg in arguments.iter() {
+        let mut arg_free = ~[];
+        free_variables(*arg, &mut ~[], &mut arg_free);
+        if arg_free.iter().any(|&(ref name, _)| bound.contains(name)) {
+            return None;
+        }
+    }
+    //Arguments which are used more than once would be evaluated more than once unless they are trivial
+    for (name, arg) in function.arguments.iter().zip(arguments.iter()) {
+        if occurrences(&function.body, *name) > 1 && !is_trivial(*arg) {
+            return None;
+        }
+    }
+
+    let substitutions : ~[(InternedStr, TypedExpr)] = function.arguments.iter().map(|name| *name)
+        .zip(arguments.move_iter().map(|arg| arg.clone()))
+        .collect();
+    let mut result = function.body.clone();
+    replace_variables(&mut result, substitutions.as_slice());
+    result.typ = applications[arity - 1].typ.clone();
+    result.location = applications[arity - 1].location;
+    //Arguments beyond the arity of the function are applied to the inlined body
+    for application in applications.slice_from(arity).iter() {
+        let arg = match &application.expr {
+            &Apply(~(_, ref arg)) => arg.clone(),
+            _ => fail!()
+        };
+        result = TypedExpr { expr: Apply(~(result, arg)), typ: application.typ.clone(), location: application.location };
+    }
+    Some(result)
+}
+
+///Returns true if 'expr' only contains expressions which can be compiled anywhere in a function
+fn is_simple(expr: &TypedExpr) -> bool {
+    match &expr.expr {
+        &Apply(~(ref func, ref arg)) => is_simple(func) && is_simple(arg),
+        &Case(ref scrutinee, ref alternatives) => is_simple(&**scrutinee) && alternatives.iter().all(|alt| is_simple(&alt.expression)),
+        &Lambda(..) | &Let(..) => false,
+        &Identifier(_) | &Number(_) | &Rational(_) | &String(_) | &Char(_) => true
+    }
+}
+
+///Variables and literals can be copied without duplicating any work
+fn is_trivial(expr: &TypedExpr) -> bool {
+    match &expr.expr {
+        &Identifier(_) | &Number(_) | &Rational(_) | &Char(_) => true,
+        _ => false
+    }
+}
+
+fn size(expr: &TypedExpr) -> uint {
+    match &expr.expr {
+        &Apply(~(ref func, ref arg)) => 1 + size(func) + size(arg),
+        &Lambda(_, ref body) => 1 + size(&**body),
+        &Let(ref bindings, ref body) => bindings.iter().fold(1 + size(&**body), |total, bind| total + size(&bind.expression)),
+        &Case(ref scrutinee, ref alternatives) => alternatives.iter().fold(1 + size(&**scrutinee), |total, alt| total + size(&alt.expression)),
+        &Identifier(_) | &Number(_) | &Rational(_) | &String(_) | &Char(_) => 1
+    }
+}
+
+///Returns the number of times 'name' is referenced in 'expr' without being shadowed
+fn occurrences(expr: &TypedExpr, name: InternedStr) -> uint {
+    match &expr.expr {
+        &Identifier(ref ident) => if *ident == name { 1 } else { 0 },
+        &Apply(~(ref func, ref arg)) => occurrences(func, name) + occurrences(arg, name),
+        &Lambda(ref arg, ref body) => if *arg == name { 0 } else { occurrences(&**body, name) },
+        &Let(ref bindings, ref body) => {
+            if bindings.iter().any(|bind| name.as_slice() == bind.name) {
+                0
+            }
+            else {
+                bindings.iter().fold(occurrences(&**body, name), |total, bind| total + occurrences(&bind.expression, name))
+            }
+        }
+        &Case(ref scrutinee, ref alternatives) => {
+            alternatives.iter().fold(occurrences(&**scrutinee, name), |total, alt| {
+                let mut variables = ~[];
+                pattern_variables(&alt.pattern.node, &mut variables);
+                if variables.contains(&name) { total } else { total + occurrences(&alt.expression, name) }
+            })
+        }
+        &Number(_) | &Rational(_) | &String(_) | &Char(_) => 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+use inline::inline_module;
+use module::Number;
+use parser::Parser;
+use typecheck::TypeEnvironment;
+use vm::{execute_main, IntResult};
+
+#[test]
+fn inline_small_functions() {
+    let mut parser = Parser::new(
+r"identity x = x
+const x y = x
+main = const (identity 3) 4".chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    env.typecheck_module(&mut module).unwrap();
+    inline_module(&mut module);
+    let main = module.bindings.iter().find(|bind| bind.name == ~"main").unwrap();
+    assert_eq!(main.expression.expr, Number(3));
+}
+
+#[test]
+fn inline_keeps_shadowed_globals() {
+    let module =
+r"add x y = primIntAdd x y
+twice x = add x x
+main = let
+        add = primIntSubtract
+    in twice (add 3 1)";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(4)));
+}
+}
@@ -0,0 +1,160 @@
+//Interning of identifiers so that the names passed between the lexer, parser, typechecker,
+//compiler and VM are small copyable handles which are compared as integers.
+//The interner is shared by every task since assemblies are regularly compiled in one task
+//and executed in another. Interned strings are never freed.
+//Each task also keeps the strings it has already seen so that most lookups do not need to take the lock
+//of the shared interner.
+use std::cast;
+use std::fmt;
+use std::local_data;
+use std::hashmap::HashMap;
+use std::unstable::mutex::{Mutex, MUTEX_INIT};
+use global::with_global;
+
+///A handle to a string stored in the interner
+#[deriving(Clone, Eq, TotalEq, IterBytes)]
+pub struct InternedStr(uint);
+
+///Names which are looked up while compiling and running every program, they are interned first so their handles are constants
+static KNOWN: &'static [&'static str] = &["", "main", "$dict", "show", "fromInteger", "fromRational"];
+pub static EMPTY: InternedStr = InternedStr(0);
+pub static MAIN: InternedStr = InternedStr(1);
+pub static DICT: InternedStr = InternedStr(2);
+pub static SHOW: InternedStr = InternedStr(3);
+pub static FROM_INTEGER: InternedStr = InternedStr(4);
+pub static FROM_RATIONAL: InternedStr = InternedStr(5);
+
+struct Interner {
+    indexes: HashMap<~str, uint>,
+    strings: ~[~str]
+}
+
+impl Interner {
+    fn new() -> Interner {
+        let mut interner = Interner { indexes: HashMap::new(), strings: ~[] };
+        for s in KNOWN.iter() {
+            interner.intern(*s);
+        }
+        interner
+    }
+
+    fn intern(&mut self, s: &str) -> InternedStr {
+        match self.indexes.find_equiv(&s) {
+            Some(index) => return InternedStr(*index),
+            None => ()
+        }
+        let index = self.strings.len();
+        self.indexes.insert(s.to_owned(), index);
+        self.strings.push(s.to_owned());
+        InternedStr(index)
+    }
+}
+
+///The strings of the shared interner which the current task has looked up
+struct LocalInterner {
+    indexes: HashMap<~str, uint>,
+    ///The strings of the shared interner up to the last one the task has looked up
+    strings: ~[&'static str]
+}
+
+local_data_key!(local_interner: LocalInterner)
+
+static mut LOCK: Mutex = MUTEX_INIT;
+static mut INTERNER: *mut Interner = 0 as *mut Interner;
+
+fn with_interner<T>(f: |&mut Interner| -> T) -> T {
+    unsafe { with_global(&mut LOCK, &mut INTERNER, || Interner::new(), f) }
+}
+
+fn with_local<T>(f: |&mut LocalInterner| -> T) -> T {
+    if local_data::get(local_interner, |local| local.is_none()) {
+        local_data::set(local_interner, LocalInterner { indexes: HashMap::new(), strings: ~[] });
+    }
+    local_data::get_mut(local_interner, |local| f(local.unwrap()))
+}
+
+///Returns the handle for 's', the same string always gives the same handle
+pub fn intern(s: &str) -> InternedStr {
+    with_local(|local| {
+        match local.indexes.find_equiv(&s) {
+            Some(index) => return InternedStr(*index),
+            None => ()
+        }
+        let handle = with_interner(|interner| interner.intern(s));
+        let InternedStr(index) = handle;
+        local.indexes.insert(s.to_owned(), index);
+        handle
+    })
+}
+
+impl InternedStr {
+    ///Returns the string which was interned.
+    ///The strings are never removed from the interner and their contents never move so the slice stays valid.
+    pub fn as_slice(&self) -> &'static str {
+        let InternedStr(index) = *self;
+        with_local(|local| {
+            if index >= local.strings.len() {
+                let start = local.strings.len();
+                with_interner(|interner| {
+                    for s in interner.strings.slice_from(start).iter() {
+                        let s: &str = *s;
+                        local.strings.push(unsafe { cast::transmute(s) });
+                    }
+                });
+            }
+            local.strings[index]
+        })
+    }
+
+    pub fn to_owned(&self) -> ~str {
+        self.as_slice().to_owned()
+    }
+}
+
+impl Default for InternedStr {
+    fn default() -> InternedStr {
+        EMPTY
+    }
+}
+
+impl fmt::Default for InternedStr {
+    fn fmt(s: &InternedStr, f: &mut fmt::Formatter) {
+        write!(f.buf, "{}", s.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+use std::task;
+use interner::*;
+use interner::with_interner;
+
+#[test]
+fn intern_strings() {
+    let a = intern("abc");
+    assert_eq!(a, intern("abc"));
+    assert!(a != intern("abd"));
+    assert_eq!(a.as_slice(), "abc");
+    //Handles stay the same in other tasks
+    let b = task::try(proc() intern("abc")).unwrap();
+    assert_eq!(a, b);
+    assert_eq!(task::try(proc() intern("abc").as_slice()).unwrap(), "abc");
+}
+
+#[test]
+fn known_names() {
+    assert_eq!(intern(""), EMPTY);
+    assert_eq!(intern("main"), MAIN);
+    assert_eq!(intern("$dict"), DICT);
+    assert_eq!(intern("show"), SHOW);
+    assert_eq!(intern("fromInteger"), FROM_INTEGER);
+    assert_eq!(intern("fromRational"), FROM_RATIONAL);
+}
+
+#[test]
+fn failure_does_not_keep_the_lock() {
+    assert!(task::try(proc() with_interner(|_| -> () fail!("Failure while holding the lock"))).is_err());
+    assert_eq!(intern("after failure").as_slice(), "after failure");
+}
+
+}
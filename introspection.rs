@@ -0,0 +1,111 @@
+//Queries about what the VM supports and what is loaded into it, for tools which need to
+//check compatibility before handing code to the VM.
+use module::{Type, TypeOperator};
+use typecheck::TypeEnvironment;
+use compiler::INSTRUCTION_SET_VERSION;
+use vm::VM;
+
+///A primitive function which is built into the compiler
+#[deriving(Clone, Eq)]
+pub struct PrimitiveInfo {
+    name: ~str,
+    arity: uint,
+    typ: Type
+}
+
+///A global function which can be called in a loaded assembly
+#[deriving(Clone, Eq)]
+pub struct GlobalInfo {
+    name: ~str,
+    ///The index used by PushGlobal to refer to this global
+    global_index: uint,
+    arity: uint,
+    typ: Type
+}
+
+#[deriving(Clone, Eq)]
+pub struct AssemblyInfo {
+    ///The index of the assembly in the VM
+    index: uint,
+    globals: ~[GlobalInfo]
+}
+
+///Returns the version of the instruction set which the compiler emits and the VM executes
+pub fn instruction_set_version() -> uint {
+    INSTRUCTION_SET_VERSION
+}
+
+///Returns true if bytecode using the instruction set 'version' can be run by this VM
+pub fn is_compatible(version: uint) -> bool {
+    version == INSTRUCTION_SET_VERSION
+}
+
+fn arity(typ: &Type) -> uint {
+    let mut arity = 0;
+    let mut typ = typ;
+    loop {
+        match &typ.typ {
+            &TypeOperator(ref op) if "->" == op.name => {
+                arity += 1;
+                typ = &typ.types()[1];
+            }
+            _ => return arity
+        }
+    }
+}
+
+///Returns all the primitive functions sorted by name
+pub fn primitives() -> ~[PrimitiveInfo] {
+    let env = TypeEnvironment::new();
+    let mut result : ~[PrimitiveInfo] = env.namedTypes.iter()
+        .filter(|&(name, _)| name.as_slice().starts_with("prim"))
+        .map(|(name, typ)| PrimitiveInfo { name: name.to_owned(), arity: arity(typ), typ: typ.clone() })
+        .collect();
+    result.sort_by(|l, r| l.name.cmp(&r.name));
+    result
+}
+
+///Returns the assemblies loaded into 'vm' and the globals each of them exports
+pub fn loaded_assemblies(vm: &VM) -> ~[AssemblyInfo] {
+    let mut result : ~[AssemblyInfo] = range(0, vm.assembly.len())
+        .map(|index| AssemblyInfo { index: index, globals: ~[] })
+        .collect();
+    for (global_index, &(assembly_index, index)) in vm.globals.iter().enumerate() {
+        let sc = &vm.assembly[assembly_index].superCombinators[index];
+        result[assembly_index].globals.push(GlobalInfo {
+            name: sc.name.to_owned(),
+            global_index: global_index,
+            arity: sc.arity,
+            typ: sc.type_declaration.typ.clone()
+        });
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+use introspection::*;
+use vm::{VM, compile_iter};
+
+#[test]
+fn list_primitives() {
+    let prims = primitives();
+    let add = prims.iter().find(|p| "primIntAdd" == p.name).unwrap();
+    assert_eq!(add.arity, 2);
+    let to_double = prims.iter().find(|p| "primIntToDouble" == p.name).unwrap();
+    assert_eq!(to_double.arity, 1);
+    assert!(prims.iter().all(|p| p.name.starts_with("prim")));
+}
+
+#[test]
+fn list_loaded_assemblies() {
+    let mut vm = VM::new();
+    vm.add_assembly(compile_iter("main = add 1 2\nadd x y = primIntAdd x y".chars()));
+    let assemblies = loaded_assemblies(&vm);
+    assert_eq!(assemblies.len(), 1);
+    let add = assemblies[0].globals.iter().find(|g| "add" == g.name).unwrap();
+    assert_eq!(add.arity, 2);
+    assert!(is_compatible(instruction_set_version()));
+}
+
+}
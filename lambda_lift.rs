@@ -0,0 +1,422 @@
+//Lambda lifting, which turns the functions defined in a let and the lambdas inside expressions into
+//top level functions before the module is compiled, since the compiler can only compile a lambda at the top of a binding.
+//The local variables which a lifted function refers to are passed as extra arguments before its own arguments,
+//so each local function is replaced by its lifted function partially applied to those variables.
+//A function which needs instance dictionaries gets the context of the binding it is lifted from,
+//so it is called with the same dictionary as that binding, unless it has constraints of its own.
+//A local function with constraints of its own may be used at several types so each use of it is replaced
+//by the lifted function instead of binding it once in the let.
+use module::{Module, Binding, TypeDeclaration, TypedExpr, Type, Constraint, Location,
+    Identifier, Apply, Number, Rational, String, Char, Lambda, Let, Case};
+use typecheck::{TypeEnvironment, function_type};
+use variables::{free_variables, bound_variables, pattern_variables, replace_variables_with};
+use interner::{InternedStr, intern};
+
+struct LambdaLifter<'a> {
+    type_env: &'a TypeEnvironment<'a>,
+    ///The name of the top level binding which is being lifted from
+    outer: ~str,
+    ///The context of the top level binding which is being lifted from
+    context: ~[Constraint],
+    ///The lifted functions
+    bindings: ~[Binding],
+    count: uint
+}
+
+///A function which has been lifted to a top level binding
+struct Lifted {
+    ///The lifted function applied to the local variables it refers to
+    replacement: TypedExpr,
+    ///The name of the lifted function
+    name: ~str,
+    ///The local variables which are passed to the lifted function
+    free: ~[(InternedStr, Type)],
+    ///True if the function has constraints which the binding it is lifted from does not have
+    own_context: bool
+}
+
+///Lifts every local function and lambda in the bindings and instances of 'module' to new top level bindings
+pub fn lift_module(module: &mut Module, type_env: &mut TypeEnvironment) {
+    let bindings = {
+        let mut lifter = LambdaLifter::new(&*type_env);
+        for instance in module.instances.mut_iter() {
+            for bind in instance.bindings.mut_iter() {
+                lifter.lift_binding(bind);
+            }
+        }
+        for bind in module.bindings.mut_iter() {
+            lifter.lift_binding(bind);
+        }
+        lifter.bindings
+    };
+    add_types(type_env, bindings.as_slice());
+    module.bindings.push_all_move(bindings);
+    module.build_index();
+}
+
+///Lifts every local function and lambda in 'expr', which is compiled on its own rather than as part of a module.
+///Returns the lifted functions which must be compiled together with 'expr'.
+pub fn lift_expression(expr: &mut TypedExpr, type_env: &mut TypeEnvironment) -> ~[Binding] {
+    let bindings = {
+        let mut lifter = LambdaLifter::new(&*type_env);
+        lifter.outer = ~"expr";
+        lifter.lift(&mut ~[], expr);
+        lifter.bindings
+    };
+    add_types(type_env, bindings.as_slice());
+    bindings
+}
+
+///Adds the types of the lifted functions so the compiler can find the instances of those which have a context
+fn add_types(type_env: &mut TypeEnvironment, bindings: &[Binding]) {
+    for bind in bindings.iter() {
+        type_env.insert_global(intern(bind.name), bind.typeDecl.typ.clone());
+    }
+}
+
+impl <'a> LambdaLifter<'a> {
+    fn new(type_env: &'a TypeEnvironment<'a>) -> LambdaLifter<'a> {
+        LambdaLifter { type_env: type_env, outer: ~"", context: ~[], bindings: ~[], count: 0 }
+    }
+
+    fn lift_binding(&mut self, bind: &mut Binding) {
+        self.outer = bind.name.clone();
+        self.context = bind.typeDecl.context.clone();
+        self.lift_function_body(&mut ~[], &mut bind.expression);
+    }
+
+    ///Lifts the functions inside the body of the lambdas at the top of 'expr' without lifting those lambdas
+    fn lift_function_body(&mut self, scope: &mut ~[InternedStr], expr: &mut TypedExpr) {
+        match expr.expr {
+            Lambda(ref arg, ref mut body) => {
+                scope.push(*arg);
+                self.lift_function_body(scope, &mut **body);
+                scope.pop();
+            }
+            _ => self.lift(scope, expr)
+        }
+    }
+
+    ///Lifts the functions in 'expr', 'scope' holds the local variables which are in scope
+    fn lift(&mut self, scope: &mut ~[InternedStr], expr: &mut TypedExpr) {
+        if is_lambda(expr) {
+            self.lift_function_body(scope, expr);
+            let name = self.new_name("lambda");
+            let lifted = self.lift_group(scope.as_slice(), ~[(None, name, expr.clone())]);
+            *expr = lifted[0].replacement.clone();
+            return;
+        }
+        match expr.expr {
+            Apply(~(ref mut func, ref mut arg)) => {
+                self.lift(scope, func);
+                self.lift(scope, arg);
+            }
+            Let(ref mut bindings, ref mut body) => {
+                let before = scope.len();
+                for bind in bindings.iter() {
+                    scope.push(intern(bind.name));
+                }
+                let mut functions = ~[];
+                for bind in bindings.mut_iter() {
+                    if is_lambda(&bind.expression) {
+                        self.lift_function_body(scope, &mut bind.expression);
+                        let name = self.new_name(bind.name.as_slice());
+                        functions.push((Some(intern(bind.name)), name, bind.expression.clone()));
+                    }
+                    else {
+                        self.lift(scope, &mut bind.expression);
+                    }
+                }
+                if functions.len() > 0 {
+                    let mut lifted = self.lift_group(scope.as_slice(), functions).move_iter();
+                    let mut substituted = ~[];
+                    for bind in bindings.mut_iter().filter(|bind| is_lambda(&bind.expression)) {
+                        let function = lifted.next().unwrap();
+                        bind.expression = function.replacement.clone();
+                        bind.arity = 0;
+                        if function.own_context {
+                            substituted.push((intern(bind.name), function));
+                        }
+                    }
+                    if substituted.len() > 0 {
+                        bindings.retain(|bind| !substituted.iter().any(|&(ref name, _)| name.as_slice() == bind.name));
+                        substitute(substituted.as_slice(), bindings.as_mut_slice(), &mut **body);
+                    }
+                }
+                self.lift(scope, &mut **body);
+                scope.truncate(before);
+            }
+            Case(ref mut scrutinee, ref mut alternatives) => {
+                self.lift(scope, &mut **scrutinee);
+                for alt in alternatives.mut_iter() {
+                    let before = scope.len();
+                    pattern_variables(&alt.pattern.node, scope);
+                    self.lift(scope, &mut alt.expression);
+                    scope.truncate(before);
+                }
+            }
+            Lambda(..) | Identifier(_) | Number(_) | Rational(_) | String(_) | Char(_) => ()
+        }
+    }
+
+    ///Lifts a group of functions which are defined together and may refer to each other.
+    ///Each function is given the local variables which any of them refers to as extra arguments.
+    fn lift_group(&mut self, scope: &[InternedStr], functions: ~[(Option<InternedStr>, ~str, TypedExpr)]) -> ~[Lifted] {
+        let locals : ~[(InternedStr, ~str)] = functions.iter()
+            .filter_map(|&(local, ref name, _)| local.map(|local| (local, name.clone())))
+            .collect();
+        let mut free = ~[];
+        for &(_, _, ref function) in functions.iter() {
+            free_variables(function, &mut ~[], &mut free);
+        }
+        free.retain(|&(ref name, _)| scope.contains(name) && !locals.iter().any(|&(ref local, _)| local == name));
+        //Inside the lifted functions the local variables are renamed so that a variable bound inside
+        //one of the functions can't shadow those passed on where the functions refer to each other
+        let parameters : ~[(InternedStr, Type)] = free.iter()
+            .map(|&(ref var, ref typ)| (intern("#" + var.as_slice()), typ.clone()))
+            .collect();
+        let mut names : ~[InternedStr] = free.iter().map(|&(ref var, _)| *var).collect();
+        for &(ref local, _) in locals.iter() {
+            names.push(*local);
+        }
+
+        let mut lifted = ~[];
+        for (_, name, function) in functions.move_iter() {
+            let location = function.location;
+            let typ = function.typ.clone();
+            let types = partial_types(&typ, free.as_slice());
+            //The dictionary of the outer binding is passed on if it holds every instance the function needs,
+            //otherwise the function is given a dictionary of its own
+            let constraints = self.type_env.find_constraints(&types[0]);
+            let own_context = !constraints.iter().all(|constraint| self.context.contains(constraint));
+            let context = if own_context { constraints }
+                else if constraints.len() > 0 { self.context.clone() }
+                else { ~[] };
+
+            let mut expression = function;
+            replace_variables_with(&mut expression, names.as_slice(), |variable| {
+                let var = match variable.expr {
+                    Identifier(ref var) => *var,
+                    _ => fail!("Expected a variable")
+                };
+                match locals.iter().find(|&&(ref local, _)| *local == var) {
+                    Some(&(_, ref lifted_name)) => apply_free(lifted_name.as_slice(), &variable.typ, parameters.as_slice(), variable.location),
+                    None => {
+                        let index = free.iter().position(|&(ref name, _)| *name == var).unwrap();
+                        let (parameter, _) = parameters[index].clone();
+                        TypedExpr { expr: Identifier(parameter), typ: variable.typ.clone(), location: variable.location }
+                    }
+                }
+            });
+            let mut arity = lambda_count(&expression);
+            for i in range(0, parameters.len()).invert() {
+                let (parameter, _) = parameters[i].clone();
+                expression = TypedExpr { expr: Lambda(parameter, ~expression), typ: types[i].clone(), location: location };
+                arity += 1;
+            }
+            self.bindings.push(Binding {
+                name: name.clone(),
+                typeDecl: TypeDeclaration { context: context, typ: types[0].clone(), name: name.clone(), location: Location::eof() },
+                expression: expression,
+                arity: arity
+            });
+            lifted.push(Lifted {
+                replacement: apply_free(name.as_slice(), &typ, free.as_slice(), location),
+                name: name,
+                free: free.clone(),
+                own_context: own_context
+            });
+        }
+        lifted
+    }
+
+    fn new_name(&mut self, local: &str) -> ~str {
+        self.count += 1;
+        "#" + self.outer + "#" + local + (self.count - 1).to_str()
+    }
+}
+
+///Replaces each use of the local functions in 'substituted', which have constraints of their own,
+///in the bindings and the body of a let by their lifted function applied to the local variables it refers to
+fn substitute(substituted: &[(InternedStr, Lifted)], bindings: &mut [Binding], body: &mut TypedExpr) {
+    let mut bound = ~[];
+    for bind in bindings.iter() {
+        bound_variables(&bind.expression, &mut bound);
+    }
+    bound_variables(body, &mut bound);
+    for &(ref local, ref function) in substituted.iter() {
+        match function.free.iter().find(|&&(ref var, _)| bound.contains(var)) {
+            Some(&(ref var, _)) => fail!("{} Error: The local function '{}' has constraints of its own and refers to '{}' which is shadowed where '{}' is used",
+                function.replacement.location, *local, *var, *local),
+            None => ()
+        }
+    }
+    let names : ~[InternedStr] = substituted.iter().map(|&(ref local, _)| *local).collect();
+    let replace = |variable: &TypedExpr| {
+        let function = substituted.iter()
+            .find(|&&(ref local, _)| match variable.expr { Identifier(ref var) => var == local, _ => false })
+            .map(|&(_, ref function)| function)
+            .unwrap();
+        apply_free(function.name.as_slice(), &variable.typ, function.free.as_slice(), variable.location)
+    };
+    for bind in bindings.mut_iter() {
+        replace_variables_with(&mut bind.expression, names.as_slice(), |variable| replace(variable));
+    }
+    replace_variables_with(body, names.as_slice(), |variable| replace(variable));
+}
+
+///Returns the type of a function of type 'typ' after the variables in 'free' have been added as its first arguments,
+///followed by its type after each of those arguments has been applied
+fn partial_types(typ: &Type, free: &[(InternedStr, Type)]) -> ~[Type] {
+    let mut types = ~[typ.clone()];
+    for &(_, ref arg) in free.rev_iter() {
+        let last = function_type(arg, &types[types.len() - 1]);
+        types.push(last);
+    }
+    types.reverse();
+    types
+}
+
+///Creates the application of the lifted function 'name' to the variables in 'free' which has the type 'typ'
+fn apply_free(name: &str, typ: &Type, free: &[(InternedStr, Type)], location: Location) -> TypedExpr {
+    let types = partial_types(typ, free);
+    let mut expr = TypedExpr { expr: Identifier(intern(name)), typ: types[0].clone(), location: location };
+    for (i, &(ref var, ref typ)) in free.iter().enumerate() {
+        let arg = TypedExpr { expr: Identifier(*var), typ: typ.clone(), location: location };
+        expr = TypedExpr { expr: Apply(~(expr, arg)), typ: types[i + 1].clone(), location: location };
+    }
+    expr
+}
+
+fn is_lambda(expr: &TypedExpr) -> bool {
+    match &expr.expr {
+        &Lambda(..) => true,
+        _ => false
+    }
+}
+
+fn lambda_count(expr: &TypedExpr) -> uint {
+    match &expr.expr {
+        &Lambda(_, ref body) => 1 + lambda_count(&**body),
+        _ => 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+use lambda_lift::lift_module;
+use parser::Parser;
+use typecheck::TypeEnvironment;
+use vm::{execute_main, IntResult};
+
+#[test]
+fn lift_local_function() {
+    let mut parser = Parser::new(
+r"test n = let
+        add x = primIntAdd x n
+    in add 2".chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    env.typecheck_module(&mut module).unwrap();
+    lift_module(&mut module, &mut env);
+    assert_eq!(module.bindings.len(), 2);
+    assert!(module.find_declaration("#test#add0").is_some());
+    let lifted = &module.bindings[1];
+    assert_eq!(lifted.name, ~"#test#add0");
+    assert_eq!(lifted.arity, 2);
+}
+
+#[test]
+fn recursive_local_function() {
+    let module =
+r"sumTo n = let
+        go acc i = case i of
+            0 -> acc
+            x -> go (primIntAdd acc n) (primIntSubtract i 1)
+    in go 0 n
+main = sumTo 4";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(16)));
+}
+
+#[test]
+fn lambda_argument() {
+    let module =
+r"apply f x = f x
+test y = apply (\x -> primIntAdd x y) 1
+main = test 2";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(3)));
+}
+
+#[test]
+fn lambda_with_patterns() {
+    let module =
+r"apply f x = f x
+
+main = apply (\x -> primIntAdd x 1) ((\a (b, c) -> primIntMultiply a c) 2 (0, 3))";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(7)));
+}
+
+#[test]
+fn lift_from_binding_with_context() {
+    let module =
+r"class Test a where
+    test :: a -> Int
+
+instance Test Int where
+    test x = primIntAdd x 1
+
+twice :: Test a => a -> Int
+twice x = let
+        f y = primIntAdd (test x) y
+    in f (test x)
+main = twice (primIntAdd 2 0)";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(6)));
+}
+
+#[test]
+fn mutually_recursive_functions_shadowing_free_variables() {
+    let module =
+r"test n = let
+        even x = case x of
+            0 -> n
+            k -> odd (primIntSubtract k 1)
+        odd n = case n of
+            0 -> 0
+            k -> even (primIntSubtract k 1)
+    in even 4
+main = test 7";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(7)));
+}
+
+#[test]
+fn local_function_with_own_context() {
+    let module =
+r"class Test a where
+    test :: a -> Int
+
+instance Test Int where
+    test x = primIntAdd x 1
+
+instance Test Char where
+    test c = 10
+
+main = let
+        f x = test x
+    in primIntAdd (f (primIntAdd 1 0)) (f 'a')";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(12)));
+}
+
+#[test]
+fn lift_from_instance_method() {
+    let module =
+r"class Test a where
+    test :: a -> Int
+
+instance Test Int where
+    test x = let
+            add y = primIntAdd x y
+        in add 1
+main = test (primIntAdd 2 0)";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(3)));
+}
+}
@@ -3,6 +3,11 @@ use std::fmt;
 use extra::container::Deque;
 use extra::ringbuf::RingBuf;
 use std::iter::Peekable;
+use std::hashmap::HashMap;
+use std::io::{Reader, Buffer, BufferedReader};
+use metrics::Metrics;
+use interner::{InternedStr, intern};
+use diagnostic::Diagnostic;
 #[deriving(Clone, Eq, ToStr)]
 pub enum TokenEnum {
 	EOF,
@@ -33,9 +38,24 @@ pub enum TokenEnum {
 	OF,
 	ARROW,
 	TYPEDECL,
-	DATA
+	DATA,
+	IF,
+	THEN,
+	ELSE,
+	LAMBDA,
+	INFIXL,
+	INFIXR,
+	INFIX,
+	TYPE,
+	IMPORT,
+	DERIVING,
+	///Input which could not be lexed, the problem is reported in the lexer's errors
+	ERROR
 }
 
+///A position in the source code.
+///'row' and 'column' count from 1 while 'absolute' is the offset in bytes from the start of the input.
+///All fields are -1 for locations which do not point into the source (such as the end of the input).
 #[deriving(Clone, Eq, ToStr)]
 pub struct Location {
     column : int,
@@ -49,6 +69,13 @@ impl Location {
     }
 }
 
+impl Default for Location {
+    fn default() -> Location {
+        Location::eof()
+    }
+}
+
+#[deriving(Clone)]
 pub struct Located<T> {
     location: Location,
     node: T
@@ -70,19 +97,25 @@ impl fmt::Default for Location {
 #[deriving(Clone)]
 pub struct Token {
     token : TokenEnum,
-    value : ~str,
-    location : Location
+    value : InternedStr,
+    location : Location,
+    ///The location directly after the last character of the token
+    end : Location,
+    ///The start of the whitespace and comments which precede the token, the source between
+    ///'trivia' and 'location' is the trivia and the source between 'location' and 'end' is the token.
+    ///Tokens inserted by the layout algorithm are empty and have no trivia.
+    trivia : Location
 }
 impl Token {
     fn eof() -> Token {
-        Token { token : EOF, value : ~"", location : Location { column : -1, row : -1, absolute : -1} }
+        Token::new(EOF, "", Location::eof())
     }
 
-    fn new(token : TokenEnum, value : ~str, loc : Location) -> Token {
-        Token { token : token, value : value, location : loc }
+    fn new(token : TokenEnum, value : &str, loc : Location) -> Token {
+        Token { token : token, value : intern(value), location : loc, end : loc, trivia : loc }
     }
     #[cfg(test)]
-    fn new_(token : TokenEnum, value : ~str) -> Token {
+    fn new_(token : TokenEnum, value : &str) -> Token {
         Token::new(token, value, Location { column : -1, row : -1, absolute : -1 })
     }
 }
@@ -105,11 +138,23 @@ fn name_or_keyword(tok : &str) -> TokenEnum {
         "of" => OF,
         "->" => ARROW,
         "data" => DATA,
+        "if" => IF,
+        "then" => THEN,
+        "else" => ELSE,
+        "infixl" => INFIXL,
+        "infixr" => INFIXR,
+        "infix" => INFIX,
+        "type" => TYPE,
+        "import" => IMPORT,
+        "deriving" => DERIVING,
         _ => NAME
     }
 }
 
-fn is_operator(first_char : char) -> bool {
+///Stands in for characters in literals which could not be decoded
+static REPLACEMENT_CHAR : char = '\uFFFD';
+
+pub fn is_operator(first_char : char) -> bool {
     match first_char {
         '+' | '-' | '*' | '/' | '.' | '$' |
         ':' | '=' | '<' | '>' | '|' | '&' | '!' => true,
@@ -124,14 +169,43 @@ pub struct Lexer<Stream> {
     priv unprocessedTokens : ~[Token],
     priv tokens : extra::ringbuf::RingBuf<Token>,
     priv indentLevels : ~[int],
-    priv offset : uint
+    priv offset : uint,
+    ///Byte offset of the next character in the input
+    priv byte_offset : int,
+    priv tokens_lexed : uint,
+    ///Documentation comment which has been read but not yet attached to a token
+    priv pending_doc : Option<~str>,
+    ///Documentation comments keyed by the absolute location of the token they precede
+    priv docs : HashMap<int, ~str>,
+    ///Problems found in the input, each of them has produced an ERROR token or been recovered from in place
+    priv errors : ~[Diagnostic],
+    ///Holds the text of the token being scanned, reused for every token so that scanning
+    ///only allocates when the interner sees a string for the first time
+    priv buffer : ~str
 }
 
+///Iterator over the characters of a Reader, the input is read in chunks as the characters are needed
+pub struct ReaderChars<R> {
+    priv reader : BufferedReader<R>
+}
+
+impl <R : Reader> Iterator<char> for ReaderChars<R> {
+    fn next(&mut self) -> Option<char> {
+        self.reader.read_char()
+    }
+}
+
+impl <R : Reader> Lexer<ReaderChars<R>> {
+    ///Creates a lexer which reads its input from 'reader' as it lexes instead of needing all of it up front
+    pub fn from_reader(reader : R) -> Lexer<ReaderChars<R>> {
+        Lexer::new(ReaderChars { reader : BufferedReader::new(reader) })
+    }
+}
 
 impl <Stream : Iterator<char>> Lexer<Stream> {
     
     pub fn new(input : Stream) -> Lexer<Stream> {
-        let start = Location { column : 0, row : 0, absolute : 0};
+        let start = Location { column : 0, row : 1, absolute : 0};
         Lexer { 
             input : input.peekable(),
             location : start,
@@ -139,7 +213,17 @@ impl <Stream : Iterator<char>> Lexer<Stream> {
             unprocessedTokens : ~[],
             tokens : extra::ringbuf::RingBuf::with_capacity(20),
             indentLevels : ~[],
-            offset : 0}
+            offset : 0,
+            byte_offset : 0,
+            tokens_lexed : 0,
+            pending_doc : None,
+            docs : HashMap::new(),
+            errors : ~[],
+            buffer : ~""}
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        Metrics { tokens_lexed: self.tokens_lexed, .. Metrics::new() }
     }
     pub fn module_next<'a>(&'a mut self) -> &'a Token {
         let mut newline = false;
@@ -149,10 +233,10 @@ impl <Stream : Iterator<char>> Lexer<Stream> {
         let loc = self.unprocessedTokens[self.unprocessedTokens.len() - 1].location;
 
         if newTok != LBRACE && newTok != MODULE {
-            self.unprocessedTokens.push(Token::new(INDENTSTART, ~"{n}", loc));
+            self.unprocessedTokens.push(Token::new(INDENTSTART, "{n}", loc));
         }
         if newline {
-            self.unprocessedTokens.push(Token::new(INDENTLEVEL, ~"<n>", loc));
+            self.unprocessedTokens.push(Token::new(INDENTLEVEL, "<n>", loc));
         }
         
         self.layout_independent_token(|_| false);
@@ -172,7 +256,7 @@ impl <Stream : Iterator<char>> Lexer<Stream> {
         }
         else if self.unprocessedTokens.len() > 0 {
             self.layout_independent_token(parseError);
-            self.tokens.back().unwrap()
+            self.current()
         }
         else {
             self.new_token(parseError)
@@ -190,6 +274,32 @@ impl <Stream : Iterator<char>> Lexer<Stream> {
         self.offset += 1;
     }
 
+    ///The parse-error(t) rule of the layout algorithm, called by the parser when the current token
+    ///can not continue the innermost block, such as the ')' in '(case x of y -> y)'.
+    ///If that block was opened by layout it is closed by inserting a '}' before the current token.
+    ///Returns false if the block was opened by an explicit '{' or tokens after the current one have already been read.
+    pub fn close_implicit_block(&mut self) -> bool {
+        if self.offset != 0 || self.indentLevels.len() == 0 || self.indentLevels[self.indentLevels.len() - 1] == 0 {
+            return false;
+        }
+        let token = self.tokens.pop_back().unwrap();
+        let location = token.location;
+        self.unprocessedTokens.push(token);
+        self.indentLevels.pop();
+        self.tokens.push_back(Token::new(RBRACE, "}", location));
+        true
+    }
+
+    ///Returns the documentation comment ('-- |' or '{-| -}') which directly precedes the token at 'location'
+    pub fn take_doc_comment(&mut self, location: &Location) -> Option<~str> {
+        self.docs.pop(&location.absolute)
+    }
+
+    ///Returns the errors which have been found in the input so far
+    pub fn take_errors(&mut self) -> ~[Diagnostic] {
+        ::std::util::replace(&mut self.errors, ~[])
+    }
+
     pub fn valid(&self) -> bool {
         self.offset > 0 || match self.tokens.back() { None => true, Some(x) => x.token != EOF }
     }
@@ -205,7 +315,8 @@ impl <Stream : Iterator<char>> Lexer<Stream> {
         match self.input.next() {
             Some(c) => {
                 self.previousLocation = self.location;
-                self.location.absolute += 1;
+                self.location.absolute = self.byte_offset;
+                self.byte_offset += c.len_utf8() as int;
                 self.location.column += 1;
                 if (c == '\n' || c == '\r')
                 {
@@ -215,6 +326,7 @@ impl <Stream : Iterator<char>> Lexer<Stream> {
                     let x = '\n';
                     if c == '\r' && self.input.peek() == Some(&x) {
                         self.input.next();
+                        self.byte_offset += 1;
                     }
                 }
                 Some(c)
@@ -223,56 +335,108 @@ impl <Stream : Iterator<char>> Lexer<Stream> {
         }
     }
 
-    fn scan_digits(&mut self) -> ~str {
-        let mut result = ~"";
+    ///Returns the location directly after the last character which has been read
+    fn end_location(&self) -> Location {
+        Location { column : self.location.column + 1, row : self.location.row, absolute : self.byte_offset }
+    }
+
+    ///Starts a new token in the buffer which begins with 'c'
+    fn start_buffer(&mut self, c: char) {
+        self.buffer.truncate(0);
+        self.buffer.push_char(c);
+    }
+
+    ///Appends characters to the buffer as long as 'predicate' holds for them
+    fn scan_while(&mut self, predicate: |char| -> bool) {
         loop {
             match self.peek() {
-                Some(x) => {
-                    if !x.is_digit() {
-                        return result;
-                    }
+                Some(ch) if predicate(ch) => {
                     self.read_char();
-                    result.push_char(x)
+                    self.buffer.push_char(ch);
                 }
-                None => break
+                _ => break
             }
         }
-        result
     }
 
     fn scan_number(&mut self, c : char, location : Location) -> Token {
-        let mut number = c.to_str() + self.scan_digits();
+        self.start_buffer(c);
+        self.scan_while(|c| c.is_digit());
         let mut token = NUMBER;
         match self.peek() {
             Some('.') => {
-                self.input.next();
+                self.read_char();
                 token = FLOAT;
-                number.push_char('.');
-                number = number.append(self.scan_digits());
+                self.buffer.push_char('.');
+                self.scan_while(|c| c.is_digit());
             }
             _ => ()
         }
-        Token { token : token, value : number, location : location }
+        Token::new(token, self.buffer, location)
     }
 
-    fn scan_identifier(&mut self, c: char, startLocation: Location) -> Token {
-        let mut result = c.to_str();
+    ///Reads an escape sequence in a character or string literal after its '\\'
+    fn scan_escape(&mut self, location : Location) -> char {
+        match self.read_char() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('a') => '\x07',
+            Some('b') => '\x08',
+            Some('f') => '\x0c',
+            Some('v') => '\x0b',
+            Some('\\') => '\\',
+            Some('\'') => '\'',
+            Some('"') => '"',
+            Some('x') => self.scan_escape_code(16, 0, location),
+            Some('o') => self.scan_escape_code(8, 0, location),
+            Some(c) if c.is_digit() => self.scan_escape_code(10, c.to_digit(10).unwrap() as u32, location),
+            Some(c) => {
+                self.error(location, format!("Invalid escape sequence '\\{}'", c));
+                REPLACEMENT_CHAR
+            }
+            None => {
+                self.error(location, ~"Unexpected end of input in escape sequence");
+                REPLACEMENT_CHAR
+            }
+        }
+    }
+
+    ///Reads the digits of a numeric escape such as '\\x41' and returns the character with that code
+    fn scan_escape_code(&mut self, radix : uint, initial : u32, location : Location) -> char {
+        let mut code = initial;
+        let mut digits = if initial == 0 && radix != 10 { 0 } else { 1 };
         loop {
-            match self.peek() {
-                Some(ch) => {
-                    if !ch.is_alphanumeric() && ch != '_' {
-                        break;
-                    }
+            match self.peek().and_then(|c| c.to_digit(radix)) {
+                Some(digit) => {
                     self.read_char();
-                    result.push_char(ch);
+                    code = code * radix as u32 + digit as u32;
+                    digits += 1;
+                    if code > 0x10FFFF {
+                        //Keep reading the digits so they do not end up in the next token
+                        code = 0x110000;
+                    }
                 }
                 None => break
             }
         }
-        return Token {
-            token : name_or_keyword(result),
-            location : startLocation,
-            value : result};
+        if digits == 0 {
+            self.error(location, ~"Expected digits in escape sequence");
+            return REPLACEMENT_CHAR;
+        }
+        match ::std::char::from_u32(code) {
+            Some(c) => c,
+            None => {
+                self.error(location, ~"Invalid character code in escape sequence");
+                REPLACEMENT_CHAR
+            }
+        }
+    }
+
+    fn scan_identifier(&mut self, c: char, startLocation: Location) -> Token {
+        self.start_buffer(c);
+        self.scan_while(|ch| ch.is_alphanumeric() || ch == '_');
+        Token::new(name_or_keyword(self.buffer), self.buffer, startLocation)
     }
  
     fn new_token<'a>(&'a mut self, parseError : |&Token| -> bool) -> &'a Token {
@@ -284,9 +448,9 @@ impl <Stream : Iterator<char>> Lexer<Stream> {
         if newTok != LBRACE {
             match self.tokens.back() {
                 Some(tok) => {
-                    if tok.token == LET || tok.token == WHERE || tok.token == OF {
+                    if tok.token == LET || tok.token == WHERE || tok.token == OF || self.is_lambda_case() {
                         let loc = self.unprocessedTokens[self.unprocessedTokens.len() - 1].location;
-                        let indentstart = Token { token : INDENTSTART, value : ~"{n}", location : loc };
+                        let indentstart = Token::new(INDENTSTART, "{n}", loc);
                         self.unprocessedTokens.push(indentstart);
                     }
                 }
@@ -295,10 +459,16 @@ impl <Stream : Iterator<char>> Lexer<Stream> {
         }
         if newline {
             let loc = self.unprocessedTokens[self.unprocessedTokens.len() - 1].location;
-            self.unprocessedTokens.push(Token::new(INDENTLEVEL, ~"<n>", loc));
+            self.unprocessedTokens.push(Token::new(INDENTLEVEL, "<n>", loc));
         }
         self.layout_independent_token(parseError);
-        self.tokens.back().unwrap()
+        self.current()
+    }
+
+    ///Returns true if the last two tokens are '\case' which starts a layout block just as 'of' does
+    fn is_lambda_case(&self) -> bool {
+        let len = self.tokens.len();
+        len >= 2 && self.tokens.get(len - 1).token == CASE && self.tokens.get(len - 2).token == LAMBDA
     }
 
     fn layout_independent_token(&mut self, parseError : |&Token| -> bool) {
@@ -311,17 +481,17 @@ impl <Stream : Iterator<char>> Lexer<Stream> {
                         let m = self.indentLevels[self.indentLevels.len() - 1];
                         //m == n
                         if (m == tok.location.column) {
-                            debug!("Indents are same, inserted semicolon");
-                            self.tokens.push_back(Token::new(SEMICOLON, ~";", tok.location));
+                            log_phase!(Lexing, Debug, "Indents are same, inserted semicolon");
+                            self.tokens.push_back(Token::new(SEMICOLON, ";", tok.location));
                             self.unprocessedTokens.pop();
                             return;
                         }
                         else if (tok.location.column < m)// n < m
                         {
                             //TODO
-                            debug!("n < m, insert \\}");
+                            log_phase!(Lexing, Debug, "n < m, insert \\}");
                             self.indentLevels.pop();
-                            self.tokens.push_back(Token::new(RBRACE, ~"}", tok.location));
+                            self.tokens.push_back(Token::new(RBRACE, "}", tok.location));
                             return;
                         }
                     }
@@ -341,24 +511,26 @@ impl <Stream : Iterator<char>> Lexer<Stream> {
                         //m:ms
                         let m = self.indentLevels[self.indentLevels.len() - 1];
                         if (n > m) {
-                            debug!("n > m + INDENTSTART, insert \\{");
+                            log_phase!(Lexing, Debug, "n > m + INDENTSTART, insert \\{");
                             self.unprocessedTokens.pop();
-                            self.tokens.push_back(Token::new(LBRACE, ~"{", tok.location));
+                            self.tokens.push_back(Token::new(LBRACE, "{", tok.location));
                             self.indentLevels.push(n);
                             return;
                         }
                     }
-                    if (n > 0)
+                    else if (n > 0)
                     {
-                        self.tokens.push_back(Token::new(LBRACE, ~"{", tok.location));
+                        self.tokens.push_back(Token::new(LBRACE, "{", tok.location));
                         self.unprocessedTokens.pop();
                         self.indentLevels.push(n);
                         return;
                     }
-                    self.tokens.push_back(Token::new(LBRACE, ~"{", tok.location));
-                    self.tokens.push_back(Token::new(RBRACE, ~"}", tok.location));
+                    //A block which is not indented further than the enclosing one is empty
+                    //L ({n} : ts) ms = { : } : (L (<n> : ts) ms)
+                    self.tokens.push_back(Token::new(LBRACE, "{", tok.location));
+                    self.tokens.push_back(Token::new(RBRACE, "}", tok.location));
                     self.unprocessedTokens.pop();
-                    self.unprocessedTokens.push(Token::new(INDENTLEVEL, ~"<n>", tok.location));
+                    self.unprocessedTokens.push(Token::new(INDENTLEVEL, "<n>", tok.location));
                     self.offset += 1;
                     return;
                 }
@@ -385,8 +557,8 @@ impl <Stream : Iterator<char>> Lexer<Stream> {
                 let m = self.indentLevels[self.indentLevels.len() - 1];
                 if (m != 0 && parseError(&tok))
                 {
-                    debug!("ParseError on token {:?}, inserting \\}", tok.token);
-                    self.tokens.push_back(Token::new(RBRACE, ~"}", tok.location));
+                    log_phase!(Lexing, Debug, "ParseError on token {:?}, inserting \\}", tok.token);
+                    self.tokens.push_back(Token::new(RBRACE, "}", tok.location));
                     self.indentLevels.pop();
                     return;
                 }
@@ -402,13 +574,104 @@ impl <Stream : Iterator<char>> Lexer<Stream> {
             else if (self.indentLevels[self.indentLevels.len() - 1] != 0)//Keep pusing righ brackets
             {
                 self.indentLevels.pop();
-                self.tokens.push_back(Token::new(RBRACE, ~"}", self.location));
+                self.tokens.push_back(Token::new(RBRACE, "}", self.location));
                 return;
             }
         }
     }
 
     fn next_indent_token(&mut self, newline : &mut bool) -> Token {
+        self.tokens_lexed += 1;
+        let trivia = self.end_location();
+        let mut token = self.scan_token(newline);
+        if token.token != EOF {
+            token.trivia = trivia;
+            token.end = self.end_location();
+        }
+        match self.pending_doc.take() {
+            Some(doc) => {
+                if token.token != EOF {
+                    self.docs.insert(token.location.absolute, doc);
+                }
+            }
+            None => ()
+        }
+        token
+    }
+
+    ///Reads the rest of a line comment, returning true if it was ended by a newline
+    fn line_comment(&mut self) -> bool {
+        let mut text = ~"";
+        let mut ended_by_newline = false;
+        loop {
+            match self.read_char() {
+                Some(x) => {
+                    if self.location.column == 0 {
+                        ended_by_newline = true;
+                        break;
+                    }
+                    text.push_char(x);
+                }
+                None => break
+            }
+        }
+        let text = text.trim();
+        if text.starts_with("|") {
+            self.pending_doc = Some(text.slice_from(1).trim().to_owned());
+        }
+        else {
+            match self.pending_doc {
+                //Line comments directly after a documentation comment continues it
+                Some(ref mut doc) => {
+                    doc.push_char('\n');
+                    doc.push_str(text);
+                }
+                None => ()
+            }
+        }
+        ended_by_newline
+    }
+
+    ///Reads a block comment after its opening '{-'.
+    ///Block comments nest so the comment only ends when every '{-' inside it has been closed.
+    fn block_comment(&mut self, newline : &mut bool) {
+        let start = self.location;
+        let mut text = ~"";
+        let mut previous = ' ';
+        let mut depth = 1;
+        loop {
+            match self.read_char() {
+                Some(x) => {
+                    if self.location.column == 0 {
+                        *newline = true;
+                    }
+                    if x == '}' && previous == '-' {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                    else if x == '-' && previous == '{' {
+                        depth += 1;
+                    }
+                    text.push_char(x);
+                    //A character can only be part of one '{-' or '-}' so '{-}' does not close the comment it opens
+                    previous = if (x == '-' && previous == '{') || (x == '}' && previous == '-') { ' ' } else { x };
+                }
+                None => {
+                    self.error(start, ~"Unterminated block comment");
+                    return;
+                }
+            }
+        }
+        //Remove the '-' of the closing '-}'
+        text.pop_char();
+        if text.starts_with("|") {
+            self.pending_doc = Some(text.slice_from(1).trim().to_owned());
+        }
+    }
+
+    fn scan_token(&mut self, newline : &mut bool) -> Token {
         let mut c = ' ';
         //Skip all whitespace before the token
         while c.is_whitespace() {
@@ -429,26 +692,21 @@ impl <Stream : Iterator<char>> Lexer<Stream> {
         //ie if its an operator then more operators will follow
         if (is_operator(c))
         {
-            let mut result = c.to_str();
-            loop {
-                match self.peek() {
-                    Some(ch) => {
-                        if !is_operator(ch) {
-                            break;
-                        }
-                        self.read_char();
-                        result.push_char(ch);
-                    }
-                    None => { break; }
+            self.start_buffer(c);
+            self.scan_while(|ch| is_operator(ch));
+            if self.buffer.len() >= 2 && self.buffer.chars().all(|c| c == '-') {
+                if self.line_comment() {
+                    *newline = true;
                 }
+                return self.scan_token(newline);
             }
-            let tok = match result {
-                ~"="  => EQUALSSIGN,
-                ~"->" => ARROW,
-                ~"::" => TYPEDECL,
+            let tok = match self.buffer.as_slice() {
+                "="  => EQUALSSIGN,
+                "->" => ARROW,
+                "::" => TYPEDECL,
                 _    => OPERATOR
             };
-            return Token { token : tok, value : result, location : startLocation };
+            return Token::new(tok, self.buffer, startLocation);
         }
         else if (c.is_digit())
         {
@@ -459,43 +717,65 @@ impl <Stream : Iterator<char>> Lexer<Stream> {
             return self.scan_identifier(c, startLocation);
         }
         else if c == '`' {
-            let x = self.read_char().expect("Unexpected end of input");
-            if !x.is_alphabetic() && x != '_' {
-                fail!("Parse error on '{}'", x);
-            }
+            let x = match self.peek() {
+                Some(x) if x.is_alphabetic() || x == '_' => x,
+                Some(x) => return self.error_token(startLocation, format!("Expected an identifier after '`' but found '{}'", x)),
+                None => return self.error_token(startLocation, ~"Unexpected end of input after '`'")
+            };
+            self.read_char();
             let mut token = self.scan_identifier(x, startLocation);
-            let end_tick = self.read_char();
-            match end_tick {
-                Some('`') => (),
-                Some(x) => fail!("Parse error on '{}'", x),
-                None => fail!("Unexpected end of input")
+            if self.peek() != Some('`') {
+                let location = self.location;
+                return self.error_token(location, ~"Expected a closing '`'");
             }
+            self.read_char();
             token.token = OPERATOR;
             return token;
         }
         else if c == '"' {
-            let mut string = ~"";
+            self.buffer.truncate(0);
             loop {
                 match self.read_char() {
-                    Some('"') => return Token { token: STRING, location: startLocation, value: string },
-                    Some(x) => string.push_char(x),
-                    None => fail!("Unexpected EOF")
+                    Some('"') => return Token::new(STRING, self.buffer, startLocation),
+                    Some('\\') => {
+                        let c = self.scan_escape(startLocation);
+                        self.buffer.push_char(c);
+                    }
+                    Some(x) => self.buffer.push_char(x),
+                    None => return self.error_token(startLocation, ~"Unterminated string literal")
                 }
             }
         }
         else if c == '\'' {
-            match self.read_char() {
+            let c = match self.read_char() {
+                Some('\\') => Some(self.scan_escape(startLocation)),
+                c => c
+            };
+            match c {
                 Some(x) => {
-                    if self.read_char() == Some('\'') {
-                        return Token { token:CHAR, location: startLocation, value: ::std::str::from_char(x) };
+                    if self.peek() == Some('\'') {
+                        self.read_char();
+                        self.start_buffer(x);
+                        return Token::new(CHAR, self.buffer, startLocation);
                     }
-                    else {
-                        fail!("Multi char character")
+                    //Skip the rest of the literal if it ends on the same line
+                    loop {
+                        match self.peek() {
+                            Some('\'') => { self.read_char(); break; }
+                            Some('\n') | Some('\r') | None => break,
+                            Some(_) => { self.read_char(); }
+                        }
                     }
+                    return self.error_token(startLocation, ~"Character literals must contain exactly one character")
                 }
-                None => fail!("Unexpected EOF")
+                None => return self.error_token(startLocation, ~"Unterminated character literal")
             }
         }
+        else if c == '{' && self.peek() == Some('-') {
+            self.read_char();
+            self.block_comment(newline);
+            return self.scan_token(newline);
+        }
         let tok = match c {
             ';' => SEMICOLON,
             '(' => LPARENS,
@@ -505,25 +785,42 @@ impl <Stream : Iterator<char>> Lexer<Stream> {
             '{' => LBRACE,
             '}' => RBRACE,
             ',' => COMMA,
-            _   => EOF
+            '\\' => LAMBDA,
+            _   => {
+                self.start_buffer(c);
+                return self.error_token(startLocation, format!("Unexpected character '{}'", c));
+            }
         };
-        Token { token : tok, location : startLocation, value : c.to_str() }
+        self.start_buffer(c);
+        Token::new(tok, self.buffer, startLocation)
+    }
+
+    ///Records a problem in the input which has been recovered from
+    fn error(&mut self, location : Location, message : ~str) {
+        self.errors.push(Diagnostic::new(location, message));
+    }
+
+    ///Records a problem in the input and returns an ERROR token in place of the input which could not be lexed
+    fn error_token(&mut self, location : Location, message : ~str) -> Token {
+        self.error(location, message);
+        Token::new(ERROR, self.buffer, location)
     }
 }
 
 #[cfg(test)]
 mod tests {
 
+use std::io::mem::MemReader;
 use lexer::*;
 
 #[test]
 fn simple() {
     let mut lexer = Lexer::new("test 2 + 3".chars());
 
-    assert_eq!(*lexer.next_(), Token::new_(NAME, ~"test"));
-    assert_eq!(*lexer.next_(), Token::new_(NUMBER, ~"2"));
-    assert_eq!(*lexer.next_(), Token::new_(OPERATOR, ~"+"));
-    assert_eq!(*lexer.next_(), Token::new_(NUMBER, ~"3"));
+    assert_eq!(*lexer.next_(), Token::new_(NAME, "test"));
+    assert_eq!(*lexer.next_(), Token::new_(NUMBER, "2"));
+    assert_eq!(*lexer.next_(), Token::new_(OPERATOR, "+"));
+    assert_eq!(*lexer.next_(), Token::new_(NUMBER, "3"));
 }
 #[test]
 fn let_bind() {
@@ -532,13 +829,162 @@ r"let
     test = 2 + 3
 in test".chars());
 
-    assert_eq!(*lexer.next_(), Token::new_(LET, ~"let"));
-    assert_eq!(*lexer.next_(), Token::new_(LBRACE, ~"{"));
-    assert_eq!(*lexer.next_(), Token::new_(NAME, ~"test"));
-    assert_eq!(*lexer.next_(), Token::new_(EQUALSSIGN, ~"="));
-    assert_eq!(*lexer.next_(), Token::new_(NUMBER, ~"2"));
-    assert_eq!(*lexer.next_(), Token::new_(OPERATOR, ~"+"));
-    assert_eq!(*lexer.next_(), Token::new_(NUMBER, ~"3"));
+    assert_eq!(*lexer.next_(), Token::new_(LET, "let"));
+    assert_eq!(*lexer.next_(), Token::new_(LBRACE, "{"));
+    assert_eq!(*lexer.next_(), Token::new_(NAME, "test"));
+    assert_eq!(*lexer.next_(), Token::new_(EQUALSSIGN, "="));
+    assert_eq!(*lexer.next_(), Token::new_(NUMBER, "2"));
+    assert_eq!(*lexer.next_(), Token::new_(OPERATOR, "+"));
+    assert_eq!(*lexer.next_(), Token::new_(NUMBER, "3"));
+}
+
+#[test]
+fn comments() {
+    let mut lexer = Lexer::new(
+r"test -- a comment
+{- block
+comment -} 2 --> 3".chars());
+
+    assert_eq!(*lexer.next_(), Token::new_(NAME, "test"));
+    assert_eq!(*lexer.next_(), Token::new_(NUMBER, "2"));
+    assert_eq!(*lexer.next_(), Token::new_(OPERATOR, "-->"));
+    assert_eq!(*lexer.next_(), Token::new_(NUMBER, "3"));
+}
+
+#[test]
+fn nested_block_comments() {
+    let mut lexer = Lexer::new(
+r"a {- outer {- inner -}
+still -} b
+{- {- -} -} c".chars());
+
+    assert_eq!(*lexer.next_(), Token::new_(NAME, "a"));
+    let b = (*lexer.next_()).clone();
+    assert_eq!(b, Token::new_(NAME, "b"));
+    assert_eq!(b.location, Location { row: 2, column: 10, absolute: 32 });
+    assert_eq!(*lexer.next_(), Token::new_(NAME, "c"));
+}
+
+#[test]
+fn unterminated_nested_block_comment() {
+    let mut lexer = Lexer::new("a {- {- -} b".chars());
+    assert_eq!(*lexer.next_(), Token::new_(NAME, "a"));
+    assert_eq!(lexer.next_().token, EOF);
+    let errors = lexer.take_errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].location, Location { row: 1, column: 4, absolute: 3 });
+}
+
+#[test]
+fn character_escapes() {
+    let mut lexer = Lexer::new(r#"'\n' '\'' '\x41' '\\' '\65' '\o101' "a\"b\n""#.chars());
+
+    assert_eq!(*lexer.next_(), Token::new_(CHAR, "\n"));
+    assert_eq!(*lexer.next_(), Token::new_(CHAR, "'"));
+    assert_eq!(*lexer.next_(), Token::new_(CHAR, "A"));
+    assert_eq!(*lexer.next_(), Token::new_(CHAR, "\\"));
+    assert_eq!(*lexer.next_(), Token::new_(CHAR, "A"));
+    assert_eq!(*lexer.next_(), Token::new_(CHAR, "A"));
+    assert_eq!(*lexer.next_(), Token::new_(STRING, "a\"b\n"));
+}
+
+#[test]
+fn invalid_character_escape() {
+    let mut lexer = Lexer::new(r"'\q' x".chars());
+    assert_eq!(*lexer.next_(), Token::new_(CHAR, "\uFFFD"));
+    assert_eq!(*lexer.next_(), Token::new_(NAME, "x"));
+    assert_eq!(lexer.take_errors().len(), 1);
+}
+
+#[test]
+fn lexer_recovers_from_errors() {
+    let mut lexer = Lexer::new("a % 'bc' `1 b\n\"text".chars());
+    assert_eq!(*lexer.next_(), Token::new_(NAME, "a"));
+    assert_eq!(lexer.next_().token, ERROR);
+    assert_eq!(lexer.next_().token, ERROR);
+    assert_eq!(lexer.next_().token, ERROR);
+    assert_eq!(*lexer.next_(), Token::new_(NUMBER, "1"));
+    assert_eq!(*lexer.next_(), Token::new_(NAME, "b"));
+    assert_eq!(lexer.next_().token, ERROR);
+    assert_eq!(lexer.next_().token, EOF);
+    let messages : ~[~str] = lexer.take_errors().move_iter().map(|e| e.message).collect();
+    assert_eq!(messages, ~[~"Unexpected character '%'", ~"Character literals must contain exactly one character",
+        ~"Expected an identifier after '`' but found '1'", ~"Unterminated string literal"]);
+}
+
+#[test]
+fn locations() {
+    let mut lexer = Lexer::new("a\n  bc \u00fc d".chars());
+
+    assert_eq!(lexer.next_().location, Location { row: 1, column: 1, absolute: 0 });
+    assert_eq!(lexer.next_().location, Location { row: 2, column: 3, absolute: 4 });
+    assert_eq!(lexer.next_().location, Location { row: 2, column: 6, absolute: 7 });
+    assert_eq!(lexer.next_().location, Location { row: 2, column: 8, absolute: 10 });
+}
+
+#[test]
+fn token_spans() {
+    let mut lexer = Lexer::new("a  {- c -} bc\n  123".chars());
+
+    let a = (*lexer.next_()).clone();
+    assert_eq!(a.trivia, Location { row: 1, column: 1, absolute: 0 });
+    assert_eq!(a.end, Location { row: 1, column: 2, absolute: 1 });
+    let bc = (*lexer.next_()).clone();
+    assert_eq!(bc.trivia, Location { row: 1, column: 2, absolute: 1 });
+    assert_eq!(bc.location, Location { row: 1, column: 12, absolute: 11 });
+    assert_eq!(bc.end, Location { row: 1, column: 14, absolute: 13 });
+    let number = (*lexer.next_()).clone();
+    assert_eq!(number.trivia, Location { row: 1, column: 14, absolute: 13 });
+    assert_eq!(number.location, Location { row: 2, column: 3, absolute: 16 });
+    assert_eq!(number.end, Location { row: 2, column: 6, absolute: 19 });
+}
+
+#[test]
+fn lex_from_reader() {
+    let input = "main = f \u00fc\n  \"text\"";
+    let mut lexer = Lexer::from_reader(MemReader::new(input.as_bytes().to_owned()));
+
+    assert_eq!(*lexer.next_(), Token::new_(NAME, "main"));
+    assert_eq!(*lexer.next_(), Token::new_(EQUALSSIGN, "="));
+    assert_eq!(*lexer.next_(), Token::new_(NAME, "f"));
+    assert_eq!(*lexer.next_(), Token::new_(NAME, "\u00fc"));
+    let text = (*lexer.next_()).clone();
+    assert_eq!(text, Token::new_(STRING, "text"));
+    assert_eq!(text.location, Location { row: 2, column: 3, absolute: 14 });
+    assert_eq!(lexer.next_().token, EOF);
+}
+
+#[test]
+fn if_then_else() {
+    let mut lexer = Lexer::new("if x then y else iffy".chars());
+
+    assert_eq!(*lexer.next_(), Token::new_(IF, "if"));
+    assert_eq!(*lexer.next_(), Token::new_(NAME, "x"));
+    assert_eq!(*lexer.next_(), Token::new_(THEN, "then"));
+    assert_eq!(*lexer.next_(), Token::new_(NAME, "y"));
+    assert_eq!(*lexer.next_(), Token::new_(ELSE, "else"));
+    assert_eq!(*lexer.next_(), Token::new_(NAME, "iffy"));
+}
+
+#[test]
+fn lambda() {
+    let mut lexer = Lexer::new(r"\x -> x".chars());
+
+    assert_eq!(*lexer.next_(), Token::new_(LAMBDA, "\\"));
+    assert_eq!(*lexer.next_(), Token::new_(NAME, "x"));
+    assert_eq!(*lexer.next_(), Token::new_(ARROW, "->"));
+    assert_eq!(*lexer.next_(), Token::new_(NAME, "x"));
+}
+
+#[test]
+fn tokens_share_interned_values() {
+    let mut lexer = Lexer::new("test test2 test 'a' \"a\"".chars());
+    let first = lexer.next_().value;
+    assert!(first != lexer.next_().value);
+    assert_eq!(lexer.next_().value, first);
+    let c = lexer.next_().value;
+    assert_eq!(lexer.next_().value, c);
+    assert_eq!(c.as_slice(), "a");
 }
 
 }
@@ -0,0 +1,143 @@
+//Linking of an assembly against the assemblies which are already loaded in a VM.
+//PushGlobal instructions and instance dictionaries refer to globals by their index among the globals of every loaded assembly.
+//An assembly is compiled with the indexes the globals had when it was compiled, its own globals starting at its offset and
+//the globals of other assemblies listed in its relocations. If the assembly is loaded at another position or the assemblies
+//it uses were loaded in another order, each index is moved to the global with the same module and name.
+use std::hashmap::HashMap;
+use extra::arc::Arc;
+use compiler::{Assembly, Instruction, PushGlobal};
+use bytecode::Code;
+use interner::InternedStr;
+
+///Renumbers the globals used by 'assembly' so that it can be added after the assemblies in 'loaded'.
+///Fails if some global of another assembly which it uses is not loaded.
+pub fn link(assembly: &mut Assembly, loaded: &[Assembly]) -> Result<(), ~str> {
+    let mut globals = ~[];
+    for other in loaded.iter() {
+        for sc in other.superCombinators.iter() {
+            globals.push((other.name.as_slice(), sc.name));
+        }
+    }
+    let offset = globals.len();
+    let count = assembly.superCombinators.len();
+    //Only built if some relocation does not point at the same global anymore
+    let mut by_name : Option<HashMap<(&str, InternedStr), uint>> = None;
+    let mut moved = HashMap::new();
+    for relocation in assembly.relocations.mut_iter() {
+        let name = (relocation.module.as_slice(), relocation.name);
+        if relocation.index < offset && globals[relocation.index] == name {
+            continue;
+        }
+        if by_name.is_none() {
+            let mut map = HashMap::new();
+            //If several assemblies define the same name the first one is used, as when the assembly was compiled
+            for (index, global) in globals.iter().enumerate() {
+                map.find_or_insert(*global, index);
+            }
+            by_name = Some(map);
+        }
+        match by_name.get_ref().find(&name) {
+            Some(index) => {
+                moved.insert(relocation.index, *index);
+                relocation.index = *index;
+            }
+            None => return Err(format!("Could not link {}.{}, the module is not loaded or does not define it", relocation.module, relocation.name))
+        }
+    }
+    if moved.len() == 0 && assembly.offset == offset {
+        return Ok(());
+    }
+    let old_offset = assembly.offset;
+    let global = |index: uint| -> Result<uint, ~str> {
+        if old_offset <= index && index < old_offset + count {
+            Ok(index - old_offset + offset)
+        }
+        else {
+            match moved.find(&index) {
+                Some(new_index) => Ok(*new_index),
+                None if index < old_offset => Ok(index),
+                None => Err(format!("The global {} does not exist", index))
+            }
+        }
+    };
+    for sc in assembly.superCombinators.mut_iter() {
+        let mut instructions : ~[Instruction] = ~[];
+        for instruction in sc.code().instructions().move_iter() {
+            instructions.push(match instruction {
+                PushGlobal(index) => PushGlobal(try!(global(index))),
+                instruction => instruction
+            });
+        }
+        sc.instructions = Arc::new(Code::encode(instructions));
+    }
+    for dict in assembly.instance_dictionaries.mut_iter() {
+        for index in dict.mut_iter() {
+            *index = try!(global(*index));
+        }
+    }
+    assembly.offset = offset;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+use link::link;
+use vm::{compile_iter, run_main_with, IntResult};
+use typecheck::TypeEnvironment;
+use compiler::Compiler;
+use parser::Parser;
+
+#[test]
+fn link_assemblies_in_another_order() {
+    let first = compile_iter(r"module First where
+double x = primIntAdd x x".chars());
+    let second = {
+        let mut parser = Parser::new(r"module Second where
+triple x = primIntAdd x (primIntAdd x x)".chars());
+        let mut module = parser.module();
+        let mut type_env = TypeEnvironment::new();
+        type_env.typecheck_module(&mut module).unwrap();
+        let mut compiler = Compiler::new(&type_env);
+        compiler.assemblies.push(&first);
+        compiler.compileModule(&module)
+    };
+    let main = {
+        let mut parser = Parser::new(r"main = primIntAdd (double 1) (triple 2)".chars());
+        let mut module = parser.module();
+        let mut type_env = TypeEnvironment::new();
+        type_env.add_types(&first);
+        type_env.add_types(&second);
+        type_env.typecheck_module(&mut module).unwrap();
+        let mut compiler = Compiler::new(&type_env);
+        compiler.assemblies.push(&first);
+        compiler.assemblies.push(&second);
+        compiler.compileModule(&module)
+    };
+    assert_eq!(main.relocations.len(), 2);
+    //Load the modules in the opposite order to the one they were compiled in
+    let mut second = second;
+    let mut first = first;
+    let mut main = main;
+    link(&mut second, []).unwrap();
+    link(&mut first, [second.clone()]).unwrap();
+    link(&mut main, [second.clone(), first.clone()]).unwrap();
+    assert_eq!(run_main_with(~[second, first, main]), Some(IntResult(8)));
+}
+
+#[test]
+fn missing_global() {
+    let first = compile_iter(r"module First where
+double x = primIntAdd x x".chars());
+    let mut main = {
+        let mut parser = Parser::new(r"main = double 1".chars());
+        let mut module = parser.module();
+        let mut type_env = TypeEnvironment::new();
+        type_env.add_types(&first);
+        type_env.typecheck_module(&mut module).unwrap();
+        let mut compiler = Compiler::new(&type_env);
+        compiler.assemblies.push(&first);
+        compiler.compileModule(&module)
+    };
+    assert!(link(&mut main, []).is_err());
+}
+}
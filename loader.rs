@@ -0,0 +1,354 @@
+//Compilation of programs which are split over several files.
+//Each 'import Foo' is resolved to a file Foo.hs in one of the include paths, which is compiled
+//before the module importing it so its types and code are available when the importer is compiled.
+use typecheck::{Types, TypeEnvironment};
+use module::Module;
+use graph::{Graph, VertexIndex, strongly_connected_components};
+use compiler::{Assembly, Compiler, CompilerOptions};
+use parser::parse_module_safe;
+use patterns::check_module;
+use unused;
+use arity::eta_expand_module;
+use inline::inline_module;
+use lambda_lift::lift_module;
+use diagnostic::Diagnostic;
+use host::FileSystem;
+
+///Returns the path to the file containing the module 'name' by looking in each of the include paths.
+///Each part of a hierarchical name is a directory so 'Data.List' is found in 'Data/List.hs'.
+pub fn find_module(fs: &FileSystem, include_paths: &[~str], name: &str) -> Result<~str, ~str> {
+    let filename = format!("{}.hs", name.replace(".", "/"));
+    for dir in include_paths.iter() {
+        let path = if "." == *dir { filename.clone() } else { format!("{}/{}", *dir, filename) };
+        if fs.exists(path) {
+            return Ok(path);
+        }
+    }
+    Err(format!("Could not find module {} in any of the include paths {:?}", name, include_paths))
+}
+
+fn parse_module(contents: &str) -> Result<Module, ~str> {
+    match parse_module_safe(contents) {
+        Ok(module) => Ok(module),
+        Err(errors) => {
+            let messages : ~[~str] = errors.iter().map(|e| format!("{}", *e)).collect();
+            Err(messages.connect("\n"))
+        }
+    }
+}
+
+///Typechecks and compiles 'module', whose source is 'contents', after the assemblies in 'assemblies'
+///which must include every module it imports. Warnings about the module are added to 'warnings'.
+///The passes which run between typechecking and compiling are applied to 'module'.
+pub fn compile_module(assemblies: &[Assembly], warnings: &mut ~[Diagnostic], module: &mut Module, contents: &str, options: &CompilerOptions) -> Result<Assembly, ~str> {
+    //Unused bindings are reported before they are removed
+    warnings.push_all_move(unused::check_module(&*module));
+    module.remove_unexported_bindings();
+    //The indexes of global functions depend on the assemblies before it so every
+    //assembly is compiled with all the earlier assemblies and not just its imports
+    let mut type_env = TypeEnvironment::new();
+    for assembly in assemblies.iter() {
+        type_env.add_types(assembly as &Types);
+    }
+    match type_env.typecheck_module(module) {
+        Ok(()) => (),
+        Err(error) => return Err(error.render(contents))
+    }
+    warnings.push_all_move(check_module(&type_env, &*module));
+    if options.optimization_level > 0 {
+        eta_expand_module(module);
+        inline_module(module);
+    }
+    lift_module(module, &mut type_env);
+    let mut compiler = Compiler::new(&type_env);
+    compiler.options = options.clone();
+    for assembly in assemblies.iter() {
+        compiler.assemblies.push(assembly);
+    }
+    Ok(compiler.compileModule(&*module))
+}
+
+///Compiles the file at 'filename' together with every module it imports.
+///The returned assemblies should be added to a VM in order, the last one is compiled from 'filename'.
+pub fn compile_program(fs: &FileSystem, include_paths: ~[~str], filename: &str) -> Result<~[Assembly], ~str> {
+    let mut session = CompilationSession::new(fs, include_paths);
+    try!(session.compile(filename));
+    Ok(session.unwrap())
+}
+
+///Compiles a program by first finding every module which the root module imports, directly or indirectly,
+///and then compiling the modules in an order where each module comes after the modules it imports.
+///Import cycles are reported before anything is compiled.
+pub struct CompilationSession<'a> {
+    priv fs: &'a FileSystem,
+    priv options: CompilerOptions,
+    ///The name of each compiled module, in the same order as 'assemblies'
+    priv modules: ~[~str],
+    priv assemblies: ~[Assembly],
+    priv warnings: ~[Diagnostic]
+}
+
+impl <'a> CompilationSession<'a> {
+    pub fn new(fs: &'a FileSystem, include_paths: ~[~str]) -> CompilationSession<'a> {
+        CompilationSession::with_options(fs, CompilerOptions { include_paths: include_paths, .. Default::default() })
+    }
+
+    ///Creates a session which compiles every module with 'options' and searches 'options.include_paths' for imports
+    pub fn with_options(fs: &'a FileSystem, options: CompilerOptions) -> CompilationSession<'a> {
+        CompilationSession { fs: fs, options: options, modules: ~[], assemblies: ~[], warnings: ~[] }
+    }
+
+    ///Compiles the module in the file at 'filename' and every module it imports which has not been compiled yet
+    pub fn compile(&mut self, filename: &str) -> Result<(), ~str> {
+        let contents = try!(self.fs.read_file(filename));
+        let root = try!(parse_module(contents));
+        //Every module which must be compiled, found by following the imports from the root module
+        let mut modules = ~[root];
+        let mut sources = ~[contents];
+        let mut index = 0;
+        while index < modules.len() {
+            let imports : ~[~str] = modules[index].imports.iter().map(|import| import.module.clone()).collect();
+            for name in imports.move_iter() {
+                if !self.modules.contains(&name) && !modules.iter().any(|module| module.name == name) {
+                    let path = try!(find_module(self.fs, self.options.include_paths.as_slice(), name));
+                    let contents = try!(self.fs.read_file(path));
+                    modules.push(try!(parse_module(contents)));
+                    sources.push(contents);
+                }
+            }
+            index += 1;
+        }
+
+        let mut graph = Graph::new();
+        let vertices : ~[VertexIndex] = range(0, modules.len()).map(|i| graph.new_vertex(i)).collect();
+        for (i, module) in modules.iter().enumerate() {
+            for import in module.imports.iter() {
+                match modules.iter().position(|other| other.name == import.module) {
+                    Some(imported) => graph.connect(vertices[i], vertices[imported]),
+                    None => ()
+                }
+            }
+        }
+        //The components are in reverse topological order so each module comes after the modules it imports
+        let mut order = ~[];
+        for component in strongly_connected_components(&graph).iter() {
+            let index = graph.get_vertex(component[0]).value;
+            let imports_itself = modules[index].imports.iter().any(|import| import.module == modules[index].name);
+            if component.len() > 1 || imports_itself {
+                let mut names : ~[~str] = component.iter().map(|v| modules[graph.get_vertex(*v).value].name.clone()).collect();
+                names.sort();
+                return Err(format!("Import cycle detected between the modules {}", names.connect(", ")));
+            }
+            order.push(index);
+        }
+
+        let mut modules : ~[Option<Module>] = modules.move_iter().map(|module| Some(module)).collect();
+        for &index in order.iter() {
+            let mut module = modules[index].take_unwrap();
+            let name = module.name.clone();
+            let assembly = try!(compile_module(self.assemblies.as_slice(), &mut self.warnings, &mut module, sources[index], &self.options));
+            self.modules.push(name);
+            self.assemblies.push(assembly);
+        }
+        Ok(())
+    }
+
+    ///Returns the compiled assemblies in the order they need to be added to a VM
+    pub fn assemblies<'b>(&'b self) -> &'b [Assembly] {
+        self.assemblies.as_slice()
+    }
+
+    ///Returns the names of the compiled modules in the same order as their assemblies
+    pub fn module_names<'b>(&'b self) -> &'b [~str] {
+        self.modules.as_slice()
+    }
+
+    ///Returns a type environment which knows the types of every compiled module
+    pub fn type_environment<'b>(&'b self) -> TypeEnvironment<'b> {
+        let mut type_env = TypeEnvironment::new();
+        for assembly in self.assemblies.iter() {
+            type_env.add_types(assembly as &Types);
+        }
+        type_env
+    }
+
+    ///Removes and returns the warnings found in the modules compiled so far
+    pub fn take_warnings(&mut self) -> ~[Diagnostic] {
+        ::std::util::replace(&mut self.warnings, ~[])
+    }
+
+    ///Returns the compiled assemblies, see 'assemblies'
+    pub fn unwrap(self) -> ~[Assembly] {
+        self.assemblies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+use host::{FileSystem, MemoryFileSystem};
+use loader::{compile_program, compile_module, parse_module, CompilationSession};
+use compiler::{Assembly, CompilerOptions, Slide, PushGlobal};
+use vm::{VM, extract_result, IntResult};
+use interner::intern;
+
+fn run_program(fs: &MemoryFileSystem, filename: &str) -> Result<Option<int>, ~str> {
+    let assemblies = try!(compile_program(fs as &FileSystem, ~[~".", ~"lib"], filename));
+    let mut vm = VM::new();
+    for assembly in assemblies.move_iter() {
+        vm.add_assembly(assembly);
+    }
+    let main = vm.assembly.last().superCombinators.iter().find(|sc| sc.name == intern("main")).unwrap();
+    Ok(match extract_result(vm.evaluate(main.code(), main.assembly_id)) {
+        Some(IntResult(i)) => Some(i),
+        _ => None
+    })
+}
+
+#[test]
+fn imports_are_compiled_first() {
+    let mut fs = MemoryFileSystem::new();
+    fs.add_file(~"lib/Math.hs", ~"module Math where { double x = primIntAdd x x }");
+    fs.add_file(~"Util.hs", ~"module Util where { import Math ; quadruple x = double (double x) }");
+    fs.add_file(~"main.hs", ~"import Math\nimport Util\nmain = primIntAdd (double 1) (quadruple 2)");
+    assert_eq!(run_program(&fs, "main.hs"), Ok(Some(10)));
+}
+
+#[test]
+fn only_exported_names_are_visible() {
+    let mut fs = MemoryFileSystem::new();
+    fs.add_file(~"Math.hs", ~"module Math (double) where { double x = add x x ; add x y = primIntAdd x y }");
+    fs.add_file(~"main.hs", ~"import Math\nmain = double 2");
+    assert_eq!(run_program(&fs, "main.hs"), Ok(Some(4)));
+    fs.add_file(~"main.hs", ~"import Math\nmain = add 2 3");
+    assert!(run_program(&fs, "main.hs").is_err());
+}
+
+#[test]
+fn unused_unexported_bindings_are_dropped() {
+    let mut fs = MemoryFileSystem::new();
+    fs.add_file(~"Math.hs", ~"module Math (double) where { double x = add x x ; add x y = primIntAdd x y ; unused = 1 }");
+    let assemblies = compile_program(&fs as &FileSystem, ~[~"."], "Math.hs").unwrap();
+    let names : ~[~str] = assemblies[0].superCombinators.iter().map(|sc| sc.name.to_owned()).collect();
+    assert_eq!(names.len(), 2);
+    assert!(!names.contains(&~"unused"));
+}
+
+#[test]
+fn hierarchical_module_names() {
+    let mut fs = MemoryFileSystem::new();
+    fs.add_file(~"lib/Data/Math.hs", ~"module Data.Math where { double x = primIntAdd x x }");
+    fs.add_file(~"main.hs", ~"import Data.Math\nmain = double 3");
+    assert_eq!(run_program(&fs, "main.hs"), Ok(Some(6)));
+}
+
+#[test]
+fn missing_module() {
+    let mut fs = MemoryFileSystem::new();
+    fs.add_file(~"main.hs", ~"import Missing\nmain = 1");
+    assert!(run_program(&fs, "main.hs").is_err());
+}
+
+#[test]
+fn import_cycle() {
+    let mut fs = MemoryFileSystem::new();
+    fs.add_file(~"A.hs", ~"module A where { import B ; a = 1 }");
+    fs.add_file(~"B.hs", ~"module B where { import A ; b = 2 }");
+    fs.add_file(~"main.hs", ~"import A\nmain = a");
+    let result = run_program(&fs, "main.hs");
+    assert_eq!(result, Err(~"Import cycle detected between the modules A, B"));
+}
+
+#[test]
+fn duplicate_instance_in_imported_module() {
+    let mut fs = MemoryFileSystem::new();
+    fs.add_file(~"Classes.hs", ~"module Classes where { class Test a where { test :: a -> Int } ; instance Test Int where { test x = x } }");
+    fs.add_file(~"main.hs", ~"import Classes\ninstance Test Int where { test x = 2 }\nmain = test 1");
+    let error = run_program(&fs, "main.hs").unwrap_err();
+    assert!(error.contains("The instance 'Test Int' is already defined in an imported module"));
+}
+
+#[test]
+fn data_context_of_imported_type() {
+    let mut fs = MemoryFileSystem::new();
+    fs.add_file(~"Boxes.hs", ~"module Boxes where { class Test a where { test :: a -> Int } ; instance Test Int where { test x = x } ; data Test a => Box a = Box a }");
+    fs.add_file(~"main.hs", ~"import Boxes\nmain = case Box 2 of\n    Box x -> primIntAdd x 0");
+    assert_eq!(run_program(&fs, "main.hs"), Ok(Some(2)));
+    fs.add_file(~"main.hs", ~"import Boxes\nmain = case Box 'a' of\n    Box x -> 0");
+    let error = run_program(&fs, "main.hs").unwrap_err();
+    assert!(error.contains("The instance 'Test Char' was not found in the first argument of 'Box'"));
+}
+
+#[test]
+fn nested_instance_from_imported_module() {
+    let mut fs = MemoryFileSystem::new();
+    fs.add_file(~"Classes.hs", ~"module Classes where { class Test a where { test :: a -> Int } ; instance Test Int where { test x = x } ; instance Test a => Test [a] where { test x = 1 } }");
+    fs.add_file(~"main.hs", ~"import Classes\ndata T = T\nmain = test [[T]]");
+    let error = run_program(&fs, "main.hs").unwrap_err();
+    assert!(error.contains("The instance 'Test T' was not found in the first argument of 'test', it is required by the instance 'Test [a]'"));
+}
+
+#[test]
+fn session_compiles_modules_in_dependency_order() {
+    let mut fs = MemoryFileSystem::new();
+    fs.add_file(~"Util.hs", ~"module Util where { import Math ; quadruple x = double (double x) }");
+    fs.add_file(~"Math.hs", ~"module Math where { double x = primIntAdd x x }");
+    fs.add_file(~"main.hs", ~"import Util\nimport Math\nmain = primIntAdd (double 1) (quadruple 2)");
+    let mut session = CompilationSession::new(&fs as &FileSystem, ~[~"."]);
+    session.compile("main.hs").unwrap();
+    assert_eq!(session.module_names(), &[~"Math", ~"Util", ~"Main"]);
+    {
+        let type_env = session.type_environment();
+        assert!(type_env.type_of("double").is_some());
+        assert!(type_env.type_of("quadruple").is_some());
+    }
+    let mut vm = VM::new();
+    for assembly in session.unwrap().move_iter() {
+        vm.add_assembly(assembly);
+    }
+    let main = vm.assembly.last().superCombinators.iter().find(|sc| sc.name == intern("main")).unwrap();
+    assert_eq!(extract_result(vm.evaluate(main.code(), main.assembly_id)), Some(IntResult(10)));
+}
+
+#[test]
+fn session_reports_import_cycles() {
+    let mut fs = MemoryFileSystem::new();
+    fs.add_file(~"A.hs", ~"module A where { import B ; a = 1 }");
+    fs.add_file(~"B.hs", ~"module B where { import A ; b = 2 }");
+    fs.add_file(~"main.hs", ~"import A\nmain = a");
+    let mut session = CompilationSession::new(&fs as &FileSystem, ~[~"."]);
+    let error = session.compile("main.hs").unwrap_err();
+    assert!(error.starts_with("Import cycle detected between the modules"));
+    assert_eq!(session.assemblies().len(), 0);
+}
+
+#[test]
+fn optimizations_are_skipped_at_level_zero() {
+    let source =
+r"add x y = primIntAdd x y
+inc = add 1
+one x = let y = 1 in y
+main = inc (one 2)";
+    let compile = |level: uint| -> Assembly {
+        let options = CompilerOptions { optimization_level: level, .. Default::default() };
+        let mut module = parse_module(source).unwrap();
+        compile_module([], &mut ~[], &mut module, source, &options).unwrap()
+    };
+    let find = |assembly: &Assembly, name: &str| assembly.superCombinators.iter().find(|sc| sc.name == intern(name)).unwrap().clone();
+    let optimized = compile(1);
+    let unoptimized = compile(0);
+    //Eta expansion
+    assert_eq!(find(&optimized, "inc").arity, 1);
+    assert_eq!(find(&unoptimized, "inc").arity, 0);
+    //Inlining
+    let calls_inc = |assembly: &Assembly| {
+        let inc = assembly.superCombinators.iter().position(|sc| sc.name == intern("inc")).unwrap();
+        find(assembly, "main").code().instructions().contains(&PushGlobal(inc))
+    };
+    assert!(!calls_inc(&optimized));
+    assert!(calls_inc(&unoptimized));
+    //Peephole optimization of the slide at the end of the function
+    assert!(!find(&optimized, "one").code().instructions().contains(&Slide(1)));
+    assert!(find(&unoptimized, "one").code().instructions().contains(&Slide(1)));
+}
+
+}
@@ -0,0 +1,124 @@
+//Logging of what happens inside the different phases of the compiler and VM.
+//The logger is stored per task so an embedder can capture or silence the logging of
+//each phase independently without passing a logger through every function.
+use std::local_data;
+
+#[deriving(Clone, Eq, Ord, ToStr)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace
+}
+
+///The component a log message originated from
+#[deriving(Clone, Eq, ToStr)]
+pub enum Phase {
+    Lexing,
+    Parsing,
+    Typechecking,
+    Compiling,
+    Executing
+}
+
+pub trait Logger {
+    ///Returns true if messages for 'phase' at 'level' should be passed to 'log'.
+    ///Messages are only formatted if this returns true.
+    fn enabled(&self, phase: Phase, level: Level) -> bool;
+    fn log(&mut self, phase: Phase, level: Level, message: &str);
+}
+
+local_data_key!(logger_key: ~Logger)
+
+///Sets the logger used by the current task, returning the previous one
+pub fn set_logger(logger: ~Logger) -> Option<~Logger> {
+    let previous = local_data::pop(logger_key);
+    local_data::set(logger_key, logger);
+    previous
+}
+
+///Removes the logger of the current task, silencing all logging
+pub fn remove_logger() -> Option<~Logger> {
+    local_data::pop(logger_key)
+}
+
+pub fn enabled(phase: Phase, level: Level) -> bool {
+    local_data::get(logger_key, |logger| {
+        match logger {
+            Some(logger) => logger.enabled(phase, level),
+            None => false
+        }
+    })
+}
+
+pub fn log(phase: Phase, level: Level, message: &str) {
+    local_data::get_mut(logger_key, |logger| {
+        match logger {
+            Some(logger) => logger.log(phase, level, message),
+            None => ()
+        }
+    })
+}
+
+///Prints every message at or above 'level' to stdout
+pub struct PrintLogger {
+    level: Level,
+    ///If not empty only messages from these phases are printed
+    phases: ~[Phase]
+}
+
+impl PrintLogger {
+    pub fn new(level: Level) -> PrintLogger {
+        PrintLogger { level: level, phases: ~[] }
+    }
+}
+
+impl Logger for PrintLogger {
+    fn enabled(&self, phase: Phase, level: Level) -> bool {
+        level <= self.level && (self.phases.len() == 0 || self.phases.contains(&phase))
+    }
+    fn log(&mut self, phase: Phase, level: Level, message: &str) {
+        println!("[{}] {}: {}", phase.to_str(), level.to_str(), message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+use logging::*;
+use std::local_data;
+use parser::Parser;
+
+local_data_key!(captured: ~[(Phase, ~str)])
+
+struct CaptureLogger;
+
+impl Logger for CaptureLogger {
+    fn enabled(&self, phase: Phase, _: Level) -> bool {
+        phase == Parsing
+    }
+    fn log(&mut self, phase: Phase, _: Level, message: &str) {
+        let mut messages = local_data::pop(captured).unwrap_or(~[]);
+        messages.push((phase, message.to_owned()));
+        local_data::set(captured, messages);
+    }
+}
+
+#[test]
+fn capture_parser_logging() {
+    set_logger(~CaptureLogger as ~Logger);
+    let mut parser = Parser::new("test = 1".chars());
+    parser.module();
+    let messages = local_data::pop(captured).unwrap_or(~[]);
+    assert!(messages.len() > 0);
+    assert!(messages.iter().all(|&(phase, _)| phase == Parsing));
+    remove_logger();
+}
+
+#[test]
+fn silent_without_logger() {
+    remove_logger();
+    assert!(!enabled(Parsing, Error));
+}
+
+}
@@ -1,25 +1,70 @@
 #[crate_id = "vm#0.0"];
 #[crate_type = "bin"];
-#[feature(globs)];
+#[feature(globs, macro_rules)];
 extern mod extra;
 use std::hashmap::HashMap;
-use std::io::File;
-use std::str::from_utf8;
-use parser::Parser;
-use compiler::Compiler;
-use typecheck::{Types, TypeEnvironment};
-use vm::{VM, execute_main, compile_file};
+use vm::{run_main_with_options, compile_expression, eval_expr, show_result};
+use loader::CompilationSession;
+use host::{FileSystem, Console, NativeFileSystem, StdConsole};
+use repl::Repl;
+use config::Config;
+use prelude::cached_prelude;
+use interner::InternedStr;
+use hbc::write_assembly_file;
 
+///Returns early with the error if 'e' evaluates to Err, otherwise evaluates to the value inside Ok
+macro_rules! try(
+    ($e:expr) => (match $e { Ok(x) => x, Err(err) => return Err(err) })
+)
+
+///Logs a message for a phase at a level (see logging.rs).
+///The message is only formatted if the current logger wants it.
+macro_rules! log_phase(
+    ($phase:ident, $level:ident, $($arg:tt)*) => (
+        if ::logging::enabled(::logging::$phase, ::logging::$level) {
+            ::logging::log(::logging::$phase, ::logging::$level, format!($($arg)*));
+        }
+    )
+)
+
+mod logging;
+mod interner;
 mod compiler;
+mod bytecode;
 mod typecheck;
 mod lexer;
 mod parser;
 mod module;
 mod graph;
 mod vm;
+mod diagnostic;
+mod host;
+mod metrics;
+mod capi;
+mod introspection;
+mod docs;
+mod repl;
+mod config;
+mod prelude;
+mod loader;
+mod patterns;
+mod unused;
+mod deadcode;
+mod inline;
+mod strictness;
+mod lambda_lift;
+mod variables;
+mod arity;
+mod deriving;
+mod peephole;
+mod hbc;
+mod link;
+mod global;
+#[cfg(test)]
+mod arbitrary;
 
 struct Scope<'a, T> {
-    variables: HashMap<~str, T>,
+    variables: HashMap<InternedStr, T>,
     parent: Option<&'a Scope<'a, T>>
 }
 
@@ -29,12 +74,12 @@ impl <'a, T> Scope<'a, T> {
         Scope { variables : HashMap::new(), parent : None }
     }
 
-    fn insert(&mut self, identifier : ~str, value : T) {
+    fn insert(&mut self, identifier : InternedStr, value : T) {
         self.variables.insert(identifier, value);
     }
 
-    fn find(&'a self, identifier : &str) -> Option<&'a T> {
-       match self.variables.find_equiv(&identifier) {
+    fn find(&'a self, identifier : &InternedStr) -> Option<&'a T> {
+       match self.variables.find(identifier) {
             Some(var) => Some(var),
             None => match self.parent {
                 Some(parent) => parent.find(identifier),
@@ -48,44 +93,100 @@ impl <'a, T> Scope<'a, T> {
     }
 }
 
-fn main() {
-    match std::os::args() {
-        [_, expr_str] => {
-            let mut prelude = compile_file(&"Prelude.hs");
-            let (instr, dict) = {
-                let mut parser = Parser::new(expr_str.chars());
-                let mut expr = parser.expression_();
+///Evaluates an expression using the Prelude and prints the result, using show if possible
+fn evaluate_expression(console: &mut Console, config: &Config, expr_str: &str) {
+    let options = config.compiler_options();
+    let mut prelude = match cached_prelude(&NativeFileSystem as &FileSystem, config.prelude, &options) {
+        Ok(prelude) => prelude,
+        Err(msg) => return console.write_line(msg)
+    };
+    match compile_expression(&mut prelude, expr_str, true, &options) {
+        Ok((instructions, typ)) => {
+            match eval_expr(prelude, instructions) {
+                Some(result) => console.write_line(show_result(&result, &typ)),
+                None => console.write_line("The result of the expression can't be displayed")
+            }
+        }
+        Err(msg) => console.write_line(msg)
+    }
+}
 
-                let mut type_env = TypeEnvironment::new();
-                type_env.add_types(&prelude as &Types);
-                type_env.typecheck(&mut expr);
-                
-                let mut compiler = Compiler::new(&type_env);
-                compiler.assemblies.push(&prelude);
-                let i = compiler.compileExpression(&expr);
-                (i, compiler.instance_dictionaries.get_opt(0).map(|&(_, ref x)| x.clone()))
-            };
-            //Add the dictionary if one is needed
-            match dict {
-                Some(dict) => prelude.instance_dictionaries.push(dict),
-                None => ()
-            };
-            let mut vm = VM::new();
-            vm.add_assembly(prelude);
-            let result = vm.evaluate(instr, 0);//TODO 0 is not necessarily correct
-            println!("{}", result);
+///Compiles 'filename' (searched for in the source directories) together with the modules it imports
+///and prints the result of its main function
+fn run_file(console: &mut Console, config: &Config, filename: &str) {
+    let fs = NativeFileSystem;
+    let path = config.find_source(&fs as &FileSystem, filename);
+    let mut options = config.compiler_options();
+    options.include_paths.push_all(config.source_dirs);
+    let mut session = CompilationSession::with_options(&fs as &FileSystem, options.clone());
+    let result = session.compile(path);
+    for warning in session.take_warnings().iter() {
+        console.write_line(format!("{}", *warning));
+    }
+    match result {
+        Ok(()) => (),
+        Err(msg) => return console.write_line(msg)
+    }
+    let assemblies = session.unwrap();
+    for sc in assemblies.last().superCombinators.iter() {
+        if options.dump_types {
+            console.write_line(format!("{} :: {}", sc.name, sc.type_declaration.pretty()));
         }
-        [_, ~"-l", filename] => {
-            let path = &Path::new(filename);
-            let s  = File::open(path).read_to_end();
-            let contents : &str = from_utf8(s);
-            let result = execute_main(contents.chars());
-            match result {
-                Some(x) => println!("{:?}", x),
-                None => println!("Error running file {:?}", path)
+        if options.dump_instructions {
+            console.write_line(format!("{}:", sc.name));
+            for (i, instruction) in sc.code().instructions().iter().enumerate() {
+                match sc.location(i) {
+                    Some(location) => console.write_line(format!("    {:?} ({})", *instruction, location)),
+                    None => console.write_line(format!("    {:?}", *instruction))
+                }
             }
         }
-        _ => return println!("Expected one argument which is the expression or 2 arguments where the first is -l and the second the file to run (needs a main function)")
+    }
+    match run_main_with_options(assemblies, &options) {
+        Some(x) => console.write_line(format!("{:?}", x)),
+        None => console.write_line(format!("Error running file {}", filename))
     }
 }
 
+///Compiles 'filename' together with the modules it imports and writes the assembly of 'filename' to 'output'
+fn compile_to_file(console: &mut Console, config: &Config, filename: &str, output: &str) {
+    let fs = NativeFileSystem;
+    let path = config.find_source(&fs as &FileSystem, filename);
+    let mut options = config.compiler_options();
+    options.include_paths.push_all(config.source_dirs);
+    let mut session = CompilationSession::with_options(&fs as &FileSystem, options);
+    let result = session.compile(path);
+    for warning in session.take_warnings().iter() {
+        console.write_line(format!("{}", *warning));
+    }
+    match result {
+        Ok(()) => (),
+        Err(msg) => return console.write_line(msg)
+    }
+    let assemblies = session.unwrap();
+    match write_assembly_file(output, assemblies.last()) {
+        Ok(()) => (),
+        Err(msg) => console.write_line(msg)
+    }
+}
+
+fn main() {
+    let mut console = StdConsole;
+    let config = match Config::load(&NativeFileSystem as &FileSystem) {
+        Ok(config) => config,
+        Err(msg) => return console.write_line(msg)
+    };
+    match std::os::args() {
+        [_] | [_, ~"-i"] => {
+            let options = config.compiler_options();
+            match cached_prelude(&NativeFileSystem as &FileSystem, config.prelude, &options) {
+                Ok(prelude) => Repl::new(prelude, options).run(&mut console as &mut Console),
+                Err(msg) => console.write_line(msg)
+            }
+        }
+        [_, ~"-e", expr_str] | [_, expr_str] => evaluate_expression(&mut console as &mut Console, &config, expr_str),
+        [_, ~"-l", filename] => run_file(&mut console as &mut Console, &config, filename),
+        [_, ~"-c", filename, output] => compile_to_file(&mut console as &mut Console, &config, filename, output),
+        _ => return console.write_line("Usage: hvm -i to start an interactive session, hvm -e <expression> to evaluate an expression, hvm -l <file> to run a file (needs a main function) or hvm -c <file> <output.hbc> to compile a file to bytecode.\nOptions are read from hvm.toml in the current directory if it exists.")
+    }
+}
@@ -0,0 +1,76 @@
+use std::fmt;
+
+///Counters describing how much work each phase did.
+///Each phase only fills in its own counters, use 'add' to combine the metrics of several phases.
+#[deriving(Clone, Eq, Default, ToStr)]
+pub struct Metrics {
+    ///Tokens produced by the lexer (not counting backtracking)
+    tokens_lexed: uint,
+    ///Calls to unify two types
+    unifications: uint,
+    ///Times a substitution was applied to a type
+    substitutions: uint,
+    ///Instructions in the compiled supercombinators and expressions
+    instructions_emitted: uint,
+    ///Supercombinators which were reduced by the VM
+    reductions: uint
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Default::default()
+    }
+
+    pub fn add(&mut self, other: &Metrics) {
+        self.tokens_lexed += other.tokens_lexed;
+        self.unifications += other.unifications;
+        self.substitutions += other.substitutions;
+        self.instructions_emitted += other.instructions_emitted;
+        self.reductions += other.reductions;
+    }
+}
+
+impl fmt::Default for Metrics {
+    fn fmt(metrics: &Metrics, f: &mut fmt::Formatter) {
+        write!(f.buf, "tokens_lexed={} unifications={} substitutions={} instructions_emitted={} reductions={}",
+            metrics.tokens_lexed, metrics.unifications, metrics.substitutions,
+            metrics.instructions_emitted, metrics.reductions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+use metrics::Metrics;
+use parser::Parser;
+use typecheck::TypeEnvironment;
+use compiler::Compiler;
+use vm::VM;
+
+#[test]
+fn metrics_for_each_phase() {
+    let mut parser = Parser::new("main = primIntAdd 1 (id 2)\nid x = x".chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    env.typecheck_module(&mut module).unwrap();
+    let assembly = {
+        let mut compiler = Compiler::new(&env);
+        let assembly = compiler.compileModule(&module);
+        assert!(compiler.metrics().instructions_emitted > 0);
+        assembly
+    };
+    let mut vm = VM::new();
+    vm.add_assembly(assembly);
+    let main = vm.assembly[0].superCombinators.iter().find(|sc| "main" == sc.name).unwrap();
+    vm.evaluate(main.code(), main.assembly_id);
+
+    assert!(parser.lexer.metrics().tokens_lexed >= 12);
+    assert!(env.metrics().unifications > 0);
+    assert!(env.metrics().substitutions > 0);
+    assert!(vm.metrics().reductions >= 2);
+    let mut total = Metrics::new();
+    total.add(&parser.lexer.metrics());
+    total.add(&vm.metrics());
+    assert_eq!(total.tokens_lexed, parser.lexer.metrics().tokens_lexed);
+}
+
+}
@@ -1,6 +1,7 @@
 use std::fmt;
-use std::hashmap::HashMap;
+use std::hashmap::{HashMap, HashSet};
 pub use lexer::{Location, Located};
+pub use interner::{InternedStr, intern};
 
 pub struct Module {
     name : ~str,
@@ -8,10 +9,205 @@ pub struct Module {
     typeDeclarations : ~[TypeDeclaration],
     classes : ~[Class],
     instances : ~[Instance],
-    dataDefinitions : ~[DataDefinition]
+    dataDefinitions : ~[DataDefinition],
+    fixityDeclarations : ~[FixityDeclaration],
+    typeSynonyms : ~[TypeSynonym],
+    imports : ~[Import],
+    ///The export list of the module or None if it has none, in which case every declaration is exported
+    exports : Option<~[Export]>,
+    ///Documentation comments of the top level declarations keyed by the declared name
+    documentation : HashMap<~str, ~str>,
+    ///Positions of the named declarations, must be rebuilt with 'build_index' if any are added or removed
+    index : ModuleIndex
+}
+
+///Where a name which has a type is declared in a module
+#[deriving(Clone, Eq)]
+pub enum Declaration {
+    BindingDeclaration(uint),
+    ///The position of the class and the position of the declaration in it
+    ClassDeclaration(uint, uint),
+    ///The position of the data definition and the position of the constructor in it
+    ConstructorDeclaration(uint, uint)
+}
+
+///Maps names to the positions of their declarations so that looking up a name does not need
+///to scan through every declaration in the module
+#[deriving(Clone)]
+pub struct ModuleIndex {
+    declarations : HashMap<~str, Declaration>,
+    classes : HashMap<~str, uint>,
+    dataDefinitions : HashMap<~str, uint>,
+    exported : Option<HashSet<~str>>
+}
+
+impl ModuleIndex {
+    pub fn new() -> ModuleIndex {
+        ModuleIndex { declarations: HashMap::new(), classes: HashMap::new(), dataDefinitions: HashMap::new(), exported: None }
+    }
+}
+
+///Returns true if 'name' is in 'exported', a module without an export list exports every name.
+///Instance functions are always exported since instances can't be hidden.
+pub fn is_exported(exported: &Option<HashSet<~str>>, name: &str) -> bool {
+    match *exported {
+        Some(ref names) => name.starts_with("#") || names.contains_equiv(&name),
+        None => true
+    }
+}
+
+impl Module {
+    ///Creates a module which declares nothing
+    pub fn new(name: ~str) -> Module {
+        Module { name: name, bindings: ~[], typeDeclarations: ~[], classes: ~[], instances: ~[], dataDefinitions: ~[],
+            fixityDeclarations: ~[], typeSynonyms: ~[], imports: ~[], exports: None, documentation: HashMap::new(), index: ModuleIndex::new() }
+    }
+
+    ///Indexes every binding, class, class declaration, data definition and constructor by name.
+    ///If a name is declared more than once, bindings shadow class declarations which shadow constructors.
+    pub fn build_index(&mut self) {
+        let mut index = ModuleIndex::new();
+        for (i, bind) in self.bindings.iter().enumerate() {
+            if !index.declarations.contains_key(&bind.name) {
+                index.declarations.insert(bind.name.clone(), BindingDeclaration(i));
+            }
+        }
+        for (i, class) in self.classes.iter().enumerate() {
+            index.classes.find_or_insert(class.name.clone(), i);
+            for (j, decl) in class.declarations.iter().enumerate() {
+                if !index.declarations.contains_key(&decl.name) {
+                    index.declarations.insert(decl.name.clone(), ClassDeclaration(i, j));
+                }
+            }
+        }
+        for (i, data) in self.dataDefinitions.iter().enumerate() {
+            index.dataDefinitions.find_or_insert(data.typ.op().name.clone(), i);
+            for (j, ctor) in data.constructors.iter().enumerate() {
+                if !index.declarations.contains_key(&ctor.name) {
+                    index.declarations.insert(ctor.name.clone(), ConstructorDeclaration(i, j));
+                }
+            }
+        }
+        index.exported = self.exported_names();
+        self.index = index;
+    }
+
+    ///Returns where 'name' is declared
+    pub fn find_declaration(&self, name: &str) -> Option<Declaration> {
+        self.index.declarations.find_equiv(&name).map(|decl| *decl)
+    }
+
+    ///Returns true if 'name' is visible to the modules which import this module
+    pub fn is_exported(&self, name: &str) -> bool {
+        is_exported(&self.index.exported, name)
+    }
+
+    ///Returns every value, type, constructor, class and class method named by the export list.
+    ///Returns None if the module has no export list.
+    pub fn exported_names(&self) -> Option<HashSet<~str>> {
+        let exports = match self.exports {
+            Some(ref exports) => exports,
+            None => return None
+        };
+        let mut names = HashSet::new();
+        for export in exports.iter() {
+            match *export {
+                ExportValue(ref name) => { names.insert(name.clone()); }
+                ExportType(ref name, ref constructors) => {
+                    names.insert(name.clone());
+                    match *constructors {
+                        Some(ref constructors) => {
+                            for ctor in constructors.iter() {
+                                names.insert(ctor.clone());
+                            }
+                        }
+                        None => {
+                            for data in self.dataDefinitions.iter().filter(|data| *name == data.typ.op().name) {
+                                for ctor in data.constructors.iter() {
+                                    names.insert(ctor.name.clone());
+                                }
+                            }
+                            for class in self.classes.iter().filter(|class| *name == class.name) {
+                                for decl in class.declarations.iter() {
+                                    names.insert(decl.name.clone());
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Some(names)
+    }
+
+    ///Removes the bindings which are not exported and which are not used by an exported binding or an instance.
+    ///Does nothing if the module has no export list.
+    pub fn remove_unexported_bindings(&mut self) {
+        let used = match self.used_bindings() {
+            Some(used) => used,
+            None => return
+        };
+        self.bindings.retain(|bind| used.contains(&intern(bind.name)));
+        self.build_index();
+    }
+
+    ///Returns the names of the bindings which are exported or which are used by an exported binding or an instance.
+    ///Returns None if the module has no export list as every binding is used then.
+    pub fn used_bindings(&self) -> Option<HashSet<InternedStr>> {
+        if self.exports.is_none() {
+            return None;
+        }
+        let mut used = HashSet::new();
+        let mut unvisited = ~[];
+        for bind in self.bindings.iter().filter(|bind| self.is_exported(bind.name)) {
+            unvisited.push(intern(bind.name));
+        }
+        for instance in self.instances.iter() {
+            for bind in instance.bindings.iter() {
+                referenced_names(&bind.expression, &mut unvisited);
+            }
+        }
+        while unvisited.len() > 0 {
+            let name = unvisited.pop();
+            if used.insert(name) {
+                match self.find_declaration(name.as_slice()) {
+                    Some(BindingDeclaration(i)) => referenced_names(&self.bindings[i].expression, &mut unvisited),
+                    _ => ()
+                }
+            }
+        }
+        Some(used)
+    }
+}
+
+///Pushes every identifier used in 'expr', local variables which shadow a global are included as well
+pub fn referenced_names(expr: &TypedExpr, names: &mut ~[InternedStr]) {
+    match expr.expr {
+        Identifier(ref name) => names.push(*name),
+        Apply(~(ref f, ref a)) => {
+            referenced_names(f, names);
+            referenced_names(a, names);
+        }
+        Lambda(_, ref body) => referenced_names(*body, names),
+        Let(ref binds, ref body) => {
+            for bind in binds.iter() {
+                referenced_names(&bind.expression, names);
+            }
+            referenced_names(*body, names);
+        }
+        Case(ref body, ref alts) => {
+            referenced_names(*body, names);
+            for alt in alts.iter() {
+                referenced_names(&alt.expression, names);
+            }
+        }
+        _ => ()
+    }
 }
 #[deriving(Clone)]
 pub struct Class {
+    ///The superclasses of the class such as 'Eq a' in 'class Eq a => Ord a'
+    constraints: ~[Constraint],
     name : ~str,
     variable: TypeVariable,
     declarations : ~[TypeDeclaration]
@@ -21,10 +217,68 @@ pub struct Instance {
     bindings : ~[Binding],
     constraints: ~[Constraint],
     typ : Type,
-    classname : ~str
+    classname : ~str,
+    location : Location
+}
+
+///Returns the name which prefixes the names of the bindings of an instance for 'typ'.
+///A type constructor applied to variables, as in 'instance Eq (Maybe a)', gives the name of the constructor
+///while a nested type also encodes its arguments so that 'Maybe [a]' and 'Maybe Int' give different names.
+pub fn instance_name(typ: &Type) -> ~str {
+    match &typ.typ {
+        &TypeVariable(_) => ~"_",
+        &TypeOperator(ref op) => {
+            if typ.types().iter().all(|t| match t.typ { TypeVariable(_) => true, _ => false }) {
+                op.name.clone()
+            }
+            else {
+                let arguments : ~[~str] = typ.types().iter().map(|t| instance_name(t)).collect();
+                format!("{}({})", op.name, arguments.connect(","))
+            }
+        }
+    }
+}
+
+///A declaration such as 'type Pair a = (a, a)'.
+///Uses of the synonym are replaced by 'typ' before typechecking.
+#[deriving(Clone, Eq)]
+pub struct TypeSynonym {
+    name : ~str,
+    parameters : ~[TypeVariable],
+    typ : Type
 }
 
-#[deriving(Eq)]
+///An entry in the export list of a module
+#[deriving(Clone, Eq)]
+pub enum Export {
+    ///A function, class method or constructor such as 'foo' or '(++)'
+    ExportValue(~str),
+    ///A type or class such as 'Bar', 'Bar(A, B)' or 'Bar(..)' (None) together with the constructors or methods it exports
+    ExportType(~str, Option<~[~str]>)
+}
+
+///A declaration such as 'import Foo' which makes the declarations of the module Foo visible
+#[deriving(Clone, Eq)]
+pub struct Import {
+    module : ~str
+}
+
+#[deriving(Clone, Eq, ToStr)]
+pub enum Assoc {
+    LeftAssoc,
+    RightAssoc,
+    NoAssoc
+}
+
+///A declaration such as 'infixr 5 ++' which sets the precedence and associativity of operators
+#[deriving(Clone, Eq)]
+pub struct FixityDeclaration {
+    assoc : Assoc,
+    precedence : int,
+    operators : ~[~str]
+}
+
+#[deriving(Eq, Clone)]
 pub struct Binding {
     name : ~str,
     expression : TypedExpr,
@@ -37,21 +291,36 @@ pub struct Constructor {
     name : ~str,
     typ : Type,
     tag : int,
-    arity : int
+    arity : int,
+    ///The names of the fields if the constructor was declared with record syntax, otherwise empty
+    fields : ~[~str]
 }
 
 #[deriving(Eq, Clone)]
 pub struct DataDefinition {
     constructors: ~[Constructor],
     typ: Type,
-    parameters: HashMap<~str, int>
+    parameters: HashMap<~str, int>,
+    ///The constraints in 'data Eq a => Set a = ...' which every use of a constructor must satisfy
+    context: ~[Constraint],
+    ///The classes in the deriving clause, 'deriving (Eq, Show)'
+    deriving: ~[~str],
+    location: Location
 }
 
-#[deriving(Clone, Eq, Default)]
+#[deriving(Clone, Default)]
 pub struct TypeDeclaration {
     context : ~[Constraint],
     typ : Type,
-    name : ~str
+    name : ~str,
+    ///The location of the signature, eof for declarations which were not written in the source
+    location : Location
+}
+
+impl Eq for TypeDeclaration {
+    fn eq(&self, other: &TypeDeclaration) -> bool {
+        self.name == other.name && self.typ == other.typ && self.context == other.context
+    }
 }
 
 #[deriving(Clone, Default, Eq, ToStr, IterBytes)]
@@ -77,7 +346,10 @@ pub struct Constraint {
 #[deriving(Clone, ToStr, IterBytes)]
 pub struct Type {
     typ: Type_,
-    types: ~[Type]
+    ///The arguments of the type, None if there are none.
+    ///Most types are variables or operators without arguments so they are created and cloned
+    ///without allocating a vector, use 'types', 'mut_types' and 'push_type' to access the arguments.
+    args: Option<~[Type]>
 }
 
 impl Default for Type {
@@ -105,49 +377,114 @@ impl fmt::Default for Type_ {
 }
 impl fmt::Default for Type {
     fn fmt(typ : &Type, f: &mut fmt::Formatter) {
-        if typ.types.len() == 0 {
-            write!(f.buf, "{}", typ.typ);
+        write_type(f, typ, &HashMap::new())
+    }
+}
+
+///Writes 'typ' using the names in 'names' for its type variables, variables without a name are written as their id
+fn write_type(f: &mut fmt::Formatter, typ: &Type, names: &HashMap<TypeVariable, ~str>) {
+    match &typ.typ {
+        &TypeVariable(ref var) if typ.types().len() == 0 => {
+            match names.find(var) {
+                Some(name) => write!(f.buf, "{}", *name),
+                None => write!(f.buf, "{}", *var)
+            }
+            return;
         }
-        else {
-            let is_list = match &typ.typ {
-                &TypeOperator(ref op) => "[]" == op.name,
-                _ => false
-            };
-            let is_func = match &typ.typ {
+        _ => ()
+    }
+    if typ.types().len() == 0 {
+        write!(f.buf, "{}", typ.typ);
+    }
+    else {
+        let is_list = match &typ.typ {
+            &TypeOperator(ref op) => "[]" == op.name,
+            _ => false
+        };
+        let is_func = match &typ.typ {
+            &TypeOperator(ref op) => "->" == op.name,
+            _ => false
+        };
+        if is_list {
+            write!(f.buf, "[");
+        }
+        else if is_func {
+            let is_lhs_func = match &typ.types()[0].typ {
                 &TypeOperator(ref op) => "->" == op.name,
                 _ => false
             };
-            if is_list {
-                write!(f.buf, "[");
-            }
-            else if is_func {
-                let is_lhs_func = match &typ.types[0].typ {
-                    &TypeOperator(ref op) => "->" == op.name,
-                    _ => false
-                };
-                if is_lhs_func {
-                    write!(f.buf, "({}) -> {}", typ.types[0], typ.types[1]);
-                }
-                else {
-                    write!(f.buf, "{} -> {}", typ.types[0], typ.types[1]);
-                }
-                return;
+            if is_lhs_func {
+                write!(f.buf, "(");
+                write_type(f, &typ.types()[0], names);
+                write!(f.buf, ") -> ");
             }
             else {
-                write!(f.buf, "({}", typ.typ);
-            }
-            for t in typ.types.iter() {
-                write!(f.buf, " {}", *t);
+                write_type(f, &typ.types()[0], names);
+                write!(f.buf, " -> ");
             }
-            if is_list {
-                write!(f.buf, "]");
-            }
-            else {
-                write!(f.buf, ")");
+            write_type(f, &typ.types()[1], names);
+            return;
+        }
+        else {
+            match &typ.typ {
+                &TypeVariable(ref var) if names.contains_key(var) => write!(f.buf, "({}", *names.get(var)),
+                _ => write!(f.buf, "({}", typ.typ)
             }
         }
+        for t in typ.types().iter() {
+            write!(f.buf, " ");
+            write_type(f, t, names);
+        }
+        if is_list {
+            write!(f.buf, "]");
+        }
+        else {
+            write!(f.buf, ")");
+        }
+    }
+}
+
+///A type which is formatted using 'names' for its type variables
+struct NamedType<'a> {
+    typ: &'a Type,
+    names: &'a HashMap<TypeVariable, ~str>
+}
+
+impl <'a> fmt::Default for NamedType<'a> {
+    fn fmt(named: &NamedType<'a>, f: &mut fmt::Formatter) {
+        write_type(f, named.typ, named.names)
     }
 }
+
+///Returns the name given to the n'th type variable of a type: a, b, ... z, a1, b1 ...
+fn variable_name(index: uint) -> ~str {
+    let letter = ('a' as u8 + (index % 26) as u8) as char;
+    if index < 26 {
+        letter.to_str()
+    }
+    else {
+        format!("{}{}", letter, index / 26)
+    }
+}
+
+///Names the type variables of 'typ' which do not have a name yet, in the order they appear
+fn name_variables(names: &mut HashMap<TypeVariable, ~str>, typ: &Type) {
+    match &typ.typ {
+        &TypeVariable(ref var) => name_variable(names, var),
+        _ => ()
+    }
+    for t in typ.types().iter() {
+        name_variables(names, t);
+    }
+}
+
+fn name_variable(names: &mut HashMap<TypeVariable, ~str>, var: &TypeVariable) {
+    if !names.contains_key(var) {
+        let name = variable_name(names.len());
+        names.insert(var.clone(), name);
+    }
+}
+
 impl fmt::Default for Constraint {
     fn fmt(constraint : &Constraint, f: &mut fmt::Formatter) {
         write!(f.buf, "{}", constraint.class);
@@ -156,6 +493,75 @@ impl fmt::Default for Constraint {
         }
     }
 }
+///Names the variables of 'typ' and then the ones which only appear in 'context'
+fn context_variable_names(context: &[Constraint], typ: &Type) -> HashMap<TypeVariable, ~str> {
+    let mut names = HashMap::new();
+    name_variables(&mut names, typ);
+    for constraint in context.iter() {
+        for var in constraint.variables.iter() {
+            name_variable(&mut names, var);
+        }
+    }
+    names
+}
+
+fn write_constraint(result: &mut ~str, constraint: &Constraint, names: &HashMap<TypeVariable, ~str>) {
+    result.push_str(constraint.class);
+    for var in constraint.variables.iter() {
+        result.push_char(' ');
+        result.push_str(*names.get(var));
+    }
+}
+
+fn write_context(result: &mut ~str, context: &[Constraint], names: &HashMap<TypeVariable, ~str>) {
+    for constraint in context.iter() {
+        write_constraint(result, constraint, names);
+        result.push_char(' ');
+    }
+    if context.len() > 0 {
+        result.push_str("=> ");
+    }
+}
+
+impl TypeDeclaration {
+    ///Formats the declaration with its type variables named a, b, c ... instead of by their ids
+    pub fn pretty(&self) -> ~str {
+        let names = context_variable_names(self.context, &self.typ);
+        let mut result = ~"";
+        write_context(&mut result, self.context, &names);
+        result.push_str(format!("{}", NamedType { typ: &self.typ, names: &names }));
+        result
+    }
+}
+
+impl Instance {
+    ///Formats the head of the instance together with its context, 'Eq a => Eq [a]'
+    pub fn pretty(&self) -> ~str {
+        let names = context_variable_names(self.constraints, &self.typ);
+        let mut result = ~"";
+        write_context(&mut result, self.constraints, &names);
+        let is_func = match &self.typ.typ {
+            &TypeOperator(ref op) => "->" == op.name,
+            _ => false
+        };
+        if is_func {
+            result.push_str(format!("{} ({})", self.classname, NamedType { typ: &self.typ, names: &names }));
+        }
+        else {
+            result.push_str(format!("{} {}", self.classname, NamedType { typ: &self.typ, names: &names }));
+        }
+        result
+    }
+
+    ///Formats the constraint at 'index' of the instance's context with the same variable names as 'pretty'
+    pub fn pretty_constraint(&self, index: uint) -> ~str {
+        let names = context_variable_names(self.constraints, &self.typ);
+        let mut result = ~"";
+        write_constraint(&mut result, &self.constraints[index], &names);
+        result
+    }
+}
+
 impl fmt::Default for TypeDeclaration {
     fn fmt(typ : &TypeDeclaration, f: &mut fmt::Formatter) {
         for constraint in typ.context.iter() {
@@ -181,8 +587,8 @@ fn type_eq<'a>(mapping: &mut HashMap<&'a TypeVariable, &'a TypeVariable>, lhs: &
         }
         _ => false
     };
-    equal && lhs.types.len() == rhs.types.len()
-    && lhs.types.iter().zip(rhs.types.iter()).all(|(l, r)| type_eq(mapping, l, r))
+    equal && lhs.types().len() == rhs.types().len()
+    && lhs.types().iter().zip(rhs.types().iter()).all(|(l, r)| type_eq(mapping, l, r))
 }
 
 impl Eq for Type {
@@ -193,11 +599,54 @@ impl Eq for Type {
 }
 
 impl Type {
+    ///Formats the type with its type variables named a, b, c ... instead of by their ids
+    pub fn pretty(&self) -> ~str {
+        let mut names = HashMap::new();
+        name_variables(&mut names, self);
+        format!("{}", NamedType { typ: self, names: &names })
+    }
+
+    ///Formats each of 'types' like 'pretty' does, a type variable which is in more than one of the types
+    ///is given the same name in each of them, 'a -> b' and 'b' rather than 'a -> b' and 'a'
+    pub fn pretty_all(types: &[&Type]) -> ~[~str] {
+        let mut names = HashMap::new();
+        for typ in types.iter() {
+            name_variables(&mut names, *typ);
+        }
+        types.iter().map(|typ| format!("{}", NamedType { typ: *typ, names: &names })).collect()
+    }
+
+    pub fn new(typ: Type_, types: ~[Type]) -> Type {
+        Type { typ: typ, args: if types.len() == 0 { None } else { Some(types) } }
+    }
     pub fn new_var(id : int) -> Type {
-        Type { typ: TypeVariable(TypeVariable { id : id }), types: ~[] }
+        Type { typ: TypeVariable(TypeVariable { id : id }), args: None }
     }
     pub fn new_op(name : ~str, types : ~[Type]) -> Type {
-        Type { typ: TypeOperator(TypeOperator { name : name }), types : types }
+        Type::new(TypeOperator(TypeOperator { name : name }), types)
+    }
+
+    pub fn types<'a>(&'a self) -> &'a [Type] {
+        match self.args {
+            Some(ref types) => types.as_slice(),
+            None => &[]
+        }
+    }
+    ///Returns the arguments of the type so they can be mutated, fails if the type has no arguments
+    pub fn mut_types<'a>(&'a mut self) -> &'a mut [Type] {
+        mut_arguments(&mut self.args)
+    }
+    pub fn push_type(&mut self, typ: Type) {
+        match self.args {
+            Some(ref mut types) => return types.push(typ),
+            None => ()
+        }
+        self.args = Some(~[typ]);
+    }
+    ///Splits the type into the operator or variable and its arguments
+    pub fn unwrap(self) -> (Type_, ~[Type]) {
+        let Type { typ: typ, args: args } = self;
+        (typ, args.unwrap_or(~[]))
     }
 
     pub fn var<'a>(&'a self) -> &'a TypeVariable {
@@ -215,6 +664,17 @@ impl Type {
     }
 }
 
+///Returns the arguments in 'args' (the field of a Type), fails if there are none.
+///Taking the field instead of the type lets the arguments be mutated while 'typ' is borrowed.
+///A type without arguments has no vector to borrow so loops over the arguments should iterate 'args' instead.
+pub fn mut_arguments<'a>(args: &'a mut Option<~[Type]>) -> &'a mut [Type] {
+    match *args {
+        Some(ref mut types) => types.as_mut_slice(),
+        None => fail!("Tried to mutate the arguments of a type without arguments")
+    }
+}
+
+#[deriving(Clone)]
 pub struct TypedExpr {
     expr : Expr,
     typ : Type,
@@ -242,28 +702,62 @@ impl TypedExpr {
     }
 }
 
-#[deriving(Eq)]
+#[deriving(Eq, Clone)]
 pub struct Alternative {
     pattern : Located<Pattern>,
     expression : TypedExpr
 }
 
-#[deriving(Eq)]
+#[deriving(Clone, Eq)]
 pub enum Pattern {
     NumberPattern(int),
-    IdentifierPattern(~str),
+    CharPattern(char),
+    IdentifierPattern(InternedStr),
     ConstructorPattern(~str, ~[Pattern])
 }
 
-#[deriving(Eq)]
+impl fmt::Default for Pattern {
+    fn fmt(pattern: &Pattern, f: &mut fmt::Formatter) {
+        match pattern {
+            &NumberPattern(n) => write!(f.buf, "{}", n),
+            &CharPattern(c) => write!(f.buf, "'{}'", c),
+            &IdentifierPattern(ref name) => write!(f.buf, "{}", *name),
+            &ConstructorPattern(ref name, ref patterns) => {
+                if patterns.len() == 0 {
+                    write!(f.buf, "{}", *name);
+                }
+                else if name.starts_with("(,") {
+                    write!(f.buf, "(");
+                    for (i, p) in patterns.iter().enumerate() {
+                        if i != 0 {
+                            write!(f.buf, ", ");
+                        }
+                        write!(f.buf, "{}", *p);
+                    }
+                    write!(f.buf, ")");
+                }
+                else {
+                    write!(f.buf, "({}", *name);
+                    for p in patterns.iter() {
+                        write!(f.buf, " {}", *p);
+                    }
+                    write!(f.buf, ")");
+                }
+            }
+        }
+    }
+}
+
+#[deriving(Eq, Clone)]
 pub enum Expr {
-    Identifier(~str),
-    Apply(~TypedExpr, ~TypedExpr),
+    Identifier(InternedStr),
+    ///The function and its argument share one allocation as applications are the most common expression
+    Apply(~(TypedExpr, TypedExpr)),
     Number(int),
     Rational(f64),
     String(~str),
     Char(char),
-    Lambda(~str, ~TypedExpr),
+    Lambda(InternedStr, ~TypedExpr),
     Let(~[Binding], ~TypedExpr),
     Case(~TypedExpr, ~[Alternative])
 }
@@ -272,7 +766,7 @@ impl fmt::Default for Expr {
     fn fmt(expr: &Expr, f: &mut fmt::Formatter) {
         match expr {
             &Identifier(ref s) => write!(f.buf, "{}", *s),
-            &Apply(ref func, ref arg) => write!(f.buf, "({} {})", *func, *arg),
+            &Apply(~(ref func, ref arg)) => write!(f.buf, "({} {})", *func, *arg),
             &Number(num) => write!(f.buf, "{}", num),
             &Rational(num) => write!(f.buf, "{}", num),
             &String(ref s) => write!(f.buf, "\"{}\"", *s),
@@ -1,120 +1,253 @@
-use std::util::{swap};
-use std::hashmap::HashMap;
-use lexer::{Lexer, Token, TokenEnum,
-    EOF, NAME, OPERATOR, NUMBER, FLOAT, STRING, CHAR, LPARENS, RPARENS, LBRACKET, RBRACKET, LBRACE, RBRACE, COMMA, EQUALSSIGN, SEMICOLON, MODULE, CLASS, INSTANCE, WHERE, LET, IN, CASE, OF, ARROW, TYPEDECL, DATA
+use std::hashmap::{HashMap, HashSet};
+use std::util::replace;
+use std::task;
+use std::str::from_utf8_opt;
+use std::io::Reader;
+use lexer::{Lexer, ReaderChars, Token, TokenEnum, Location,
+    EOF, NAME, OPERATOR, NUMBER, FLOAT, STRING, CHAR, LPARENS, RPARENS, LBRACKET, RBRACKET, LBRACE, RBRACE, COMMA, EQUALSSIGN, SEMICOLON, MODULE, CLASS, INSTANCE, WHERE, LET, IN, CASE, OF, ARROW, TYPEDECL, DATA, IF, THEN, ELSE, LAMBDA, INFIXL, INFIXR, INFIX, TYPE, IMPORT, DERIVING
 };
 use module::*;
 use typecheck::function_type;
+use deriving::{can_derive, derived_instances};
+use diagnostic::{Diagnostic, failure_message};
+
+///The maximum number of expressions, patterns or types which may be nested inside each other
+static MAX_NESTING: uint = 256;
+
+pub type ParseResult<T> = Result<T, Diagnostic>;
+
+///A top level declaration of a module
+enum TopLevel {
+    TopTypeDeclaration(TypeDeclaration),
+    TopClause(Clause),
+    TopClass(Class),
+    TopInstance(Instance),
+    TopData(DataDefinition),
+    TopTypeSynonym(TypeSynonym),
+    TopFixity(FixityDeclaration),
+    TopImport(Import)
+}
+
+///A declaration in a let block
+enum LetBinding {
+    LetClause(Clause),
+    LetPattern(Located<Pattern>, TypedExpr),
+    LetTypeDeclaration(TypeDeclaration)
+}
+
+///A single equation of a binding such as 'f (Just x) [] = x'.
+///Consecutive equations of the same function are merged into one Binding.
+struct Clause {
+    name : ~str,
+    location : Location,
+    arguments : ~[Pattern],
+    expression : TypedExpr
+}
 
 pub struct Parser<Iter> {
     lexer : Lexer<Iter>,
+    priv depth : uint,
+    ///The fixity declarations which have been parsed so far.
+    ///Operator expressions are parsed with the fixities known at that point and re-associated once the whole
+    ///module has been parsed, so a declaration also affects the expressions before it.
+    priv fixities : HashMap<InternedStr, (Assoc, int)>,
+    ///The absolute location of the operator of each infix application which has been parsed
+    priv operators : HashSet<int>,
+    ///The operators in 'operators' whose application was written in parentheses, such as the '+' in '(a + b) * c'
+    priv parenthesized : HashSet<int>,
+    ///The type, the name and the field names of each record constructor which has been parsed so far
+    priv records : ~[(~str, ~str, ~[~str])]
+}
+
+impl <R : Reader> Parser<ReaderChars<R>> {
+    ///Creates a parser which reads its input from 'reader' as it parses
+    pub fn from_reader(reader : R) -> Parser<ReaderChars<R>> {
+        Parser::new_with_lexer(Lexer::from_reader(reader))
+    }
 }
 
 impl <Iter : Iterator<char>> Parser<Iter> {
 
 pub fn new(iterator : Iter) -> Parser<Iter> {
-    Parser { lexer : Lexer::new(iterator) }
+    Parser::new_with_lexer(Lexer::new(iterator))
 }
 
-fn requireNext<'a>(&'a mut self, expected : TokenEnum) -> &'a Token {
+fn new_with_lexer(lexer : Lexer<Iter>) -> Parser<Iter> {
+    Parser { lexer : lexer, depth : 0, fixities : HashMap::new(), operators : HashSet::new(), parenthesized : HashSet::new(), records : ~[] }
+}
+
+fn requireNext<'a>(&'a mut self, expected : TokenEnum) -> ParseResult<&'a Token> {
 	let tok = self.lexer.next_().token;
 	if (tok != expected) {
-		fail!(ParseError(&self.lexer, expected));
+		return Err(ParseError(&self.lexer, expected));
+    }
+	Ok(self.lexer.current())
+}
+
+///Runs 'f' one level deeper, failing if the input is nested too deeply
+fn nested<T>(&mut self, f : |&mut Parser<Iter>| -> ParseResult<T>) -> ParseResult<T> {
+    if self.depth >= MAX_NESTING {
+        let location = self.lexer.current().location;
+        return Err(Diagnostic::new(location, format!("Input is nested deeper than {} levels", MAX_NESTING)));
     }
-	return self.lexer.current();
+    self.depth += 1;
+    let result = f(self);
+    self.depth -= 1;
+    result
 }
 
 pub fn module(&mut self) -> Module {
+    match self.parse_module() {
+        Ok(module) => module,
+        Err(err) => fail!("{}", err)
+    }
+}
+
+pub fn parse_module(&mut self) -> ParseResult<Module> {
+    match self.parse_module_recovering() {
+        Ok(module) => Ok(module),
+        Err(errors) => Err(errors.move_iter().next().unwrap())
+    }
+}
+
+///Parses a module, returning every error found in it.
+///A top level declaration which fails to parse is skipped so that the rest of the module is still checked.
+pub fn parse_module_recovering(&mut self) -> Result<Module, ~[Diagnostic]> {
+    let result = self.module_declarations();
+    let mut errors = self.lexer.take_errors();
+    if errors.len() == 0 {
+        return result;
+    }
+    match result {
+        Ok(_) => (),
+        Err(parse_errors) => {
+            //An ERROR token causes a parse error at the same location which adds nothing to the lexer's error
+            for error in parse_errors.move_iter() {
+                if !errors.iter().any(|e| e.location == error.location) {
+                    errors.push(error);
+                }
+            }
+        }
+    }
+    Err(errors)
+}
+
+fn module_declarations(&mut self) -> Result<Module, ~[Diagnostic]> {
 	let lBracketOrModule = self.lexer.module_next().token;//tokenizeModule??
-	let modulename = match lBracketOrModule {
-        MODULE => {
-            let modulename = self.requireNext(NAME).value.clone();
-            self.requireNext(WHERE);
-            self.requireNext(LBRACE);
-            modulename
-	    }
+	let (modulename, exports) = match lBracketOrModule {
+        MODULE => match self.module_header() {
+            Ok(header) => header,
+            Err(err) => return Err(~[err])
+        },
         LBRACE => {
 		    //No module declaration was found so default to Main
-		    ~"Main"
+		    (~"Main", None)
 	    }
-        _ => fail!(ParseError(&self.lexer, LBRACE))
+        _ => return Err(~[ParseError(&self.lexer, LBRACE)])
     };
 
+    let mut errors = ~[];
     let mut classes = ~[];
-    let mut bindings = ~[];
+    let mut clauses = ~[];
     let mut instances = ~[];
     let mut typeDeclarations = ~[];
     let mut dataDefinitions = ~[];
+    let mut fixityDeclarations = ~[];
+    let mut typeSynonyms = ~[];
+    let mut imports = ~[];
+    let mut documentation = HashMap::new();
 	loop {
 		//Do a lookahead to see what the next top level binding is
 		let token = self.lexer.next(toplevelError).token;
-		if (token == NAME || token == LPARENS)
-		{
-            let mut equalOrType = self.lexer.next(bindingError).token;
-            {
-			    let mut numberOfLookaheads = 2;
-                while (equalOrType != TYPEDECL
-                    && equalOrType != EQUALSSIGN)
-                {
-                    equalOrType = self.lexer.next(bindingError).token;
-                    numberOfLookaheads += 1;
-                }
-                for _ in range(0, numberOfLookaheads)
-                {
-                    self.lexer.backtrack();
-                }
-            }
-
-			if (equalOrType == TYPEDECL)
-			{
-				let bind = self.typeDeclaration();
-				typeDeclarations.push(bind);
-			}
-			else
-			{
-				let bind = self.binding();
-                debug!("Parsed binding {}", bind.name);
-				bindings.push(bind);
+        let location = self.lexer.current().location;
+        let doc = self.lexer.take_doc_comment(&location);
+		match self.toplevel_declaration(token) {
+			Ok(Some(declaration)) => match declaration {
+				TopTypeDeclaration(bind) => {
+					add_documentation(&mut documentation, &bind.name, doc);
+					typeDeclarations.push(bind);
+				}
+				TopClause(clause) => {
+					log_phase!(Parsing, Debug, "Parsed binding {}", clause.name);
+					add_documentation(&mut documentation, &clause.name, doc);
+					clauses.push(clause);
+				}
+				TopClass(class) => {
+					add_documentation(&mut documentation, &class.name, doc);
+					classes.push(class);
+				}
+				TopInstance(instance) => instances.push(instance),
+				TopData(data) => {
+					add_documentation(&mut documentation, &data.typ.op().name, doc);
+					dataDefinitions.push(data);
+				}
+				TopTypeSynonym(synonym) => {
+					add_documentation(&mut documentation, &synonym.name, doc);
+					typeSynonyms.push(synonym);
+				}
+				TopFixity(fixity) => fixityDeclarations.push(fixity),
+				TopImport(import) => imports.push(import)
+			},
+			Ok(None) => break,
+			Err(err) => {
+				errors.push(err);
+				if self.skip_declaration(location.column) {
+					continue;
+				}
+				return Err(errors);
 			}
 		}
-		else if (token == CLASS)
-		{
-			self.lexer.backtrack();
-			classes.push(self.class());
-		}
-		else if (token == INSTANCE)
-		{
-			self.lexer.backtrack();
-			instances.push(self.instance());
-		}
-		else if (token == DATA)
-		{
-			self.lexer.backtrack();
-			dataDefinitions.push(self.dataDefinition());
-		}
-		else
-		{
-			break;
-		}
 		let semicolon = self.lexer.next(toplevelNewBindError);
-        debug!("More bindings? {:?}", semicolon.token);
+        log_phase!(Parsing, Debug, "More bindings? {:?}", semicolon.token);
 	    if (semicolon.token != SEMICOLON) {
             break;
         }
     }
 
-	let rBracket = self.lexer.current().token;
-	if (rBracket != RBRACE)
-	{
-		fail!(ParseError(&self.lexer, RBRACE));
+	match self.block_end() {
+		Ok(()) => (),
+		Err(err) => {
+			errors.push(err);
+			return Err(errors);
+		}
 	}
 
 	let eof = self.lexer.next_();
 	if (eof.token != EOF)
 	{
-		fail!("Unexpected token after end of module, {:?}", eof.token);
+		errors.push(Diagnostic::new(eof.location, format!("Unexpected token after end of module, {:?}", eof.token)));
+		return Err(errors);
 	}
 
+	let mut bindings = match make_bindings(clauses) {
+		Ok(bindings) => bindings,
+		Err(err) => {
+			errors.push(err);
+			return Err(errors);
+		}
+	};
+	//Every fixity declaration of the module is known now so the operators can be associated correctly
+	for bind in bindings.mut_iter() {
+		match self.reassociate(&mut bind.expression) {
+			Ok(()) => (),
+			Err(err) => errors.push(err)
+		}
+	}
+	for instance in instances.mut_iter() {
+		for bind in instance.bindings.mut_iter() {
+			match self.reassociate(&mut bind.expression) {
+				Ok(()) => (),
+				Err(err) => errors.push(err)
+			}
+		}
+	}
+	if errors.len() > 0 {
+		return Err(errors);
+	}
+	for data in dataDefinitions.iter() {
+		bindings.push_all_move(record_selectors(data));
+		instances.push_all_move(derived_instances(data));
+	}
 	for decl in typeDeclarations.mut_iter()
 	{
 		for bind in bindings.mut_iter()
@@ -125,75 +258,448 @@ pub fn module(&mut self) -> Module {
 			}
 		}
 	}
-    Module {
+    let mut module = Module {
         name : modulename,
         bindings : bindings,
         typeDeclarations : typeDeclarations,
         classes : classes,
         instances : instances,
-        dataDefinitions : dataDefinitions }
+        dataDefinitions : dataDefinitions,
+        fixityDeclarations : fixityDeclarations,
+        typeSynonyms : typeSynonyms,
+        imports : imports,
+        exports : exports,
+        documentation : documentation,
+        index : ModuleIndex::new() };
+    module.build_index();
+    Ok(module)
 }
 
-fn class(&mut self) -> Class {
-	self.requireNext(CLASS);
+///Parses the rest of 'module Name (exports) where {'
+fn module_header(&mut self) -> ParseResult<(~str, Option<~[Export]>)> {
+    let modulename = try!(self.module_name());
+    let exports = if self.lexer.next_().token == LPARENS {
+        let exports = try!(self.sepBy1(|this| this.export(), COMMA));
+        if self.lexer.current().token != RPARENS {
+            return Err(ParseError(&self.lexer, RPARENS));
+        }
+        Some(exports)
+    }
+    else {
+        self.lexer.backtrack();
+        None
+    };
+    try!(self.requireNext(WHERE));
+    try!(self.requireNext(LBRACE));
+    Ok((modulename, exports))
+}
 
-	let classname = self.requireNext(NAME).value.clone();
-	let typeVariableName = self.requireNext(NAME).value.clone();
-    let typeVariable = 1000000;
+///Parses the top level declaration which starts with 'token' (the current token).
+///Returns None if 'token' does not start a declaration.
+fn toplevel_declaration(&mut self, token : TokenEnum) -> ParseResult<Option<TopLevel>> {
+	if (token == NAME || token == LPARENS)
+	{
+		self.lexer.backtrack();
+		if (self.isTypeDeclaration())
+		{
+			Ok(Some(TopTypeDeclaration(try!(self.typeDeclaration()))))
+		}
+		else
+		{
+			Ok(Some(TopClause(try!(self.binding_clause()))))
+		}
+	}
+	else if (token == CLASS)
+	{
+		self.lexer.backtrack();
+		Ok(Some(TopClass(try!(self.class()))))
+	}
+	else if (token == INSTANCE)
+	{
+		self.lexer.backtrack();
+		Ok(Some(TopInstance(try!(self.instance()))))
+	}
+	else if (token == DATA)
+	{
+		self.lexer.backtrack();
+		Ok(Some(TopData(try!(self.dataDefinition()))))
+	}
+	else if (token == TYPE)
+	{
+		self.lexer.backtrack();
+		Ok(Some(TopTypeSynonym(try!(self.typeSynonym()))))
+	}
+	else if (token == INFIXL || token == INFIXR || token == INFIX)
+	{
+		self.lexer.backtrack();
+		Ok(Some(TopFixity(try!(self.fixityDeclaration()))))
+	}
+	else if (token == IMPORT)
+	{
+		let name = try!(self.module_name());
+		Ok(Some(TopImport(Import { module: name })))
+	}
+	else
+	{
+		Ok(None)
+	}
+}
 
-	self.requireNext(WHERE);
-	self.requireNext(LBRACE);
-	let mut typeVariableMapping = HashMap::new();
-	typeVariableMapping.insert(typeVariableName, typeVariable);
-	let declarations = self.sepBy1(|this| this.typeDeclaration_(&mut typeVariableMapping), SEMICOLON);
-	
-	self.lexer.backtrack();
-	self.requireNext(RBRACE);
+///Skips the tokens of a top level declaration which failed to parse, stopping after the semicolon which
+///precedes the next declaration at 'column'. Returns false if the end of the input was reached instead.
+fn skip_declaration(&mut self, column : int) -> bool {
+	let mut token = self.lexer.current().clone();
+	loop {
+		if (token.token == SEMICOLON && token.location.column <= column)
+		{
+			return true;
+		}
+		if (token.token == EOF)
+		{
+			return false;
+		}
+		token = self.lexer.next_().clone();
+	}
+}
+
+///Parses a module name which may consist of several names separated by dots such as 'Data.List'
+fn module_name(&mut self) -> ParseResult<~str> {
+    let mut name = try!(self.requireNext(NAME)).value.to_owned();
+    loop {
+        let isDot = {
+            let token = self.lexer.next_();
+            token.token == OPERATOR && token.value.as_slice() == "."
+        };
+        if !isDot {
+            break;
+        }
+        let part = try!(self.requireNext(NAME)).value;
+        name.push_char('.');
+        name.push_str(part.as_slice());
+    }
+    self.lexer.backtrack();
+    Ok(name)
+}
+
+///Parses an entry of the export list, 'foo', '(++)', 'Bar', 'Bar(A, B)' or 'Bar(..)'
+fn export(&mut self) -> ParseResult<Export> {
+    let token = self.lexer.next_().token;
+    if token == LPARENS {
+        let name = try!(self.requireNext(OPERATOR)).value.to_owned();
+        try!(self.requireNext(RPARENS));
+        return Ok(ExportValue(name));
+    }
+    if token != NAME {
+        return Err(ParseError(&self.lexer, NAME));
+    }
+    let name = self.lexer.current().value.to_owned();
+    if !name.char_at(0).is_uppercase() {
+        return Ok(ExportValue(name));
+    }
+    if self.lexer.next_().token != LPARENS {
+        self.lexer.backtrack();
+        return Ok(ExportType(name, Some(~[])));
+    }
+    let token = self.lexer.next_();
+    let constructors = if token.token == OPERATOR && token.value.as_slice() == ".." {
+        None
+    }
+    else if token.token == RPARENS {
+        self.lexer.backtrack();
+        Some(~[])
+    }
+    else {
+        self.lexer.backtrack();
+        let constructors = try!(self.sepBy1(|this| this.requireNext(NAME).map(|tok| tok.value.to_owned()), COMMA));
+        self.lexer.backtrack();
+        Some(constructors)
+    };
+    try!(self.requireNext(RPARENS));
+    Ok(ExportType(name, constructors))
+}
+
+fn fixityDeclaration(&mut self) -> ParseResult<FixityDeclaration> {
+    let assoc = match self.lexer.next_().token {
+        INFIXL => LeftAssoc,
+        INFIXR => RightAssoc,
+        INFIX => NoAssoc,
+        _ => return Err(ParseError2(&self.lexer, &[INFIXL, INFIXR, INFIX]))
+    };
+    let precedence = match self.lexer.next_().token {
+        NUMBER => {
+            let token = self.lexer.current();
+            match from_str::<int>(token.value.as_slice()) {
+                Some(value) if value >= 0 && value <= 9 => value,
+                _ => return Err(Diagnostic::new(token.location, format!("Precedence must be between 0 and 9, found {}", token.value)))
+            }
+        }
+        _ => {
+            self.lexer.backtrack();
+            9
+        }
+    };
+    let operators = try!(self.sepBy1(|this| this.requireNext(OPERATOR).map(|op| op.value.to_owned()), COMMA));
+    self.lexer.backtrack();
+    for op in operators.iter() {
+        self.fixities.insert(intern(*op), (assoc, precedence));
+    }
+    Ok(FixityDeclaration { assoc : assoc, precedence : precedence, operators : operators })
+}
+
+///Returns the associativity and precedence of the operator, taken from its fixity declaration if it has one
+fn fixity(&self, op : &InternedStr) -> (Assoc, int) {
+    match self.fixities.find(op) {
+        Some(&fixity) => fixity,
+        None => default_fixity(op.as_slice())
+    }
+}
+
+fn precedence(&self, op : &InternedStr) -> int {
+    let (_, precedence) = self.fixity(op);
+    precedence
+}
+
+///Returns the location of the operator if 'expr' is an infix application such as 'a + b' (but not '(+) a b')
+fn operator_location(&self, expr : &TypedExpr) -> Option<int> {
+    match &expr.expr {
+        &Apply(~(ref func, _)) => match &func.expr {
+            &Apply(~(ref op, _)) => {
+                if self.operators.contains(&op.location.absolute) { Some(op.location.absolute) } else { None }
+            }
+            _ => None
+        },
+        _ => None
+    }
+}
 
-	Class { name : classname, variable: TypeVariable { id: typeVariable }, declarations : declarations }
+///Associates every operator expression in 'expr' again using the fixities which are known now
+fn reassociate(&self, expr : &mut TypedExpr) -> ParseResult<()> {
+    if self.operator_location(expr).is_some() {
+        let mut operands = ~[];
+        let mut operators = ~[];
+        let e = replace(expr, TypedExpr::new(Number(0)));
+        self.split_operators(e, &mut operands, &mut operators);
+        for operand in operands.mut_iter() {
+            try!(self.reassociate(operand));
+        }
+        *expr = try!(self.associate(operands, operators));
+        return Ok(());
+    }
+    match expr.expr {
+        Apply(~(ref mut func, ref mut arg)) => {
+            try!(self.reassociate(func));
+            try!(self.reassociate(arg));
+        }
+        Lambda(_, ref mut body) => try!(self.reassociate(&mut **body)),
+        Let(ref mut bindings, ref mut body) => {
+            for bind in bindings.mut_iter() {
+                try!(self.reassociate(&mut bind.expression));
+            }
+            try!(self.reassociate(&mut **body));
+        }
+        Case(ref mut scrutinee, ref mut alternatives) => {
+            try!(self.reassociate(&mut **scrutinee));
+            for alt in alternatives.mut_iter() {
+                try!(self.reassociate(&mut alt.expression));
+            }
+        }
+        Identifier(_) | Number(_) | Rational(_) | String(_) | Char(_) => ()
+    }
+    Ok(())
 }
 
-fn instance(&mut self) -> Instance {
-	self.requireNext(INSTANCE);
+///Splits the infix application 'expr' into its operands and operators
+fn split_operators(&self, expr : TypedExpr, operands : &mut ~[TypedExpr], operators : &mut ~[TypedExpr]) {
+    match expr.expr {
+        Apply(~(Apply(~(op, lhs)), rhs)) => {
+            self.split_operand(lhs, operands, operators);
+            operators.push(op);
+            self.split_operand(rhs, operands, operators);
+        }
+        _ => fail!("Expected an infix application")
+    }
+}
+
+///Adds 'expr' to the operands, an infix application is split as well unless it was written in parentheses
+fn split_operand(&self, expr : TypedExpr, operands : &mut ~[TypedExpr], operators : &mut ~[TypedExpr]) {
+    match self.operator_location(&expr) {
+        Some(location) if !self.parenthesized.contains(&location) => self.split_operators(expr, operands, operators),
+        _ => operands.push(expr)
+    }
+}
+
+///Builds the expression where 'operators' are applied to the 'operands' between them according to their fixities
+fn associate(&self, operands : ~[TypedExpr], operators : ~[TypedExpr]) -> ParseResult<TypedExpr> {
+    let mut remaining = operands.move_iter();
+    let mut output = ~[remaining.next().unwrap()];
+    let mut pending : ~[TypedExpr] = ~[];
+    for (op, operand) in operators.move_iter().zip(remaining) {
+        let (assoc, precedence) = self.fixity(operator_name(&op));
+        loop {
+            let reduce = match pending.last_opt() {
+                Some(previous) => {
+                    let (previousAssoc, previousPrecedence) = self.fixity(operator_name(previous));
+                    if previousPrecedence == precedence && (previousAssoc != assoc || assoc == NoAssoc) {
+                        return Err(Diagnostic::new(op.location,
+                            format!("The operators '{}' and '{}' have the same precedence and can't be mixed without parentheses",
+                                operator_name(previous), operator_name(&op))));
+                    }
+                    previousPrecedence > precedence || (previousPrecedence == precedence && assoc == LeftAssoc)
+                }
+                None => false
+            };
+            if !reduce {
+                break;
+            }
+            apply_operator(&mut output, pending.pop());
+        }
+        pending.push(op);
+        output.push(operand);
+    }
+    while pending.len() > 0 {
+        apply_operator(&mut output, pending.pop());
+    }
+    Ok(output.pop())
+}
+
+fn class(&mut self) -> ParseResult<Class> {
+	try!(self.requireNext(CLASS));
+    let location = self.lexer.current().location;
 
     let mut mapping = HashMap::new();
-    let (constraints, instance_type) = self.constrained_type(&mut mapping);
-    match instance_type {
-        Type { typ: TypeOperator(TypeOperator { name: classname}), types: types } => {
-            self.requireNext(WHERE);
-            self.requireNext(LBRACE);
+    let (mut constraints, class_type) = try!(self.constrained_type(&mut mapping));
+    let (classname, header_variable) = match class_type.unwrap() {
+        (TypeOperator(TypeOperator { name: classname }), types) => {
+            if types.len() != 1 {
+                return Err(Diagnostic::new(location, format!("Expected exactly one type variable in the class {}", classname)));
+            }
+            match &types[0].typ {
+                &TypeVariable(ref var) => (classname, var.id),
+                &TypeOperator(_) => return Err(Diagnostic::new(location, format!("Expected a type variable in the class {}", classname)))
+            }
+        }
+        _ => return Err(Diagnostic::new(location, ~"Expected a class name"))
+    };
+    let typeVariableName = mapping.iter().find(|&(_, id)| *id == header_variable).map(|(name, _)| name.clone()).unwrap();
+    let typeVariable = 1000000;
+    //The superclasses constrain the class variable, which is given the same id as in the declarations
+    for constraint in constraints.mut_iter() {
+        for var in constraint.variables.mut_iter() {
+            if var.id != header_variable {
+                return Err(Diagnostic::new(location, format!("The superclass {} must constrain the class variable {}", constraint.class, typeVariableName)));
+            }
+            *var = TypeVariable { id: typeVariable };
+        }
+    }
 
-            let mut bindings = self.sepBy1(|this| this.binding(), SEMICOLON);
+	//The where block may be left out for classes without declarations such as 'class Marker a'
+	let declarations = if self.lexer.next_().token == WHERE {
+		try!(self.requireNext(LBRACE));
+		if self.empty_block() {
+			~[]
+		}
+		else {
+			let mut typeVariableMapping = HashMap::new();
+			typeVariableMapping.insert(typeVariableName, typeVariable);
+			let declarations = try!(self.sepBy1(|this| this.typeDeclaration_(&mut typeVariableMapping), SEMICOLON));
+			try!(self.block_end());
+			declarations
+		}
+	}
+	else {
+		self.lexer.backtrack();
+		~[]
+	};
+
+	Ok(Class { constraints: constraints, name : classname, variable: TypeVariable { id: typeVariable }, declarations : declarations })
+}
+
+fn instance(&mut self) -> ParseResult<Instance> {
+	try!(self.requireNext(INSTANCE));
+    let location = self.lexer.current().location;
+
+    let mut mapping = HashMap::new();
+    let (constraints, instance_type) = try!(self.constrained_type(&mut mapping));
+    match instance_type.unwrap() {
+        (TypeOperator(TypeOperator { name: classname}), types) => {
+            if types.len() != 1 {
+                return Err(Diagnostic::new(location, format!("Expected exactly one type in the instance of {}", classname)));
+            }
+            let typename = match &types[0].typ {
+                &TypeOperator(_) => instance_name(&types[0]),
+                &TypeVariable(_) => return Err(Diagnostic::new(location, ~"TypeVariable in instance"))
+            };
+            //An instance without a where block such as 'instance Eq Foo' defines no functions
+            let mut bindings = if self.lexer.next_().token == WHERE {
+                try!(self.requireNext(LBRACE));
+                if self.empty_block() {
+                    ~[]
+                }
+                else {
+                    let clauses = try!(self.sepBy1(|this| this.binding_clause(), SEMICOLON));
+                    let bindings = try!(make_bindings(clauses));
+                    try!(self.block_end());
+                    bindings
+                }
+            }
+            else {
+                self.lexer.backtrack();
+                ~[]
+            };
             for bind in bindings.mut_iter()
             {
-                bind.name = encodeBindingIdentifier(types[0].op().name, bind.name);
+                bind.name = encodeBindingIdentifier(typename, bind.name);
             }
-
-            self.lexer.backtrack();
-            self.requireNext(RBRACE);
-            Instance { typ : types[0], classname : classname, bindings : bindings, constraints: constraints }
+            Ok(Instance { typ : types[0], classname : classname, bindings : bindings, constraints: constraints, location: location })
         }
-        _ => fail!("TypeVariable in instance")
+        _ => Err(Diagnostic::new(location, ~"TypeVariable in instance"))
     }
 }
 
 pub fn expression_(&mut self) -> TypedExpr {
     match self.expression() {
-        Some(expr) => expr,
-        None => fail!("Failed to parse expression at {:?}", self.lexer.current().location)
+        Ok(Some(expr)) => expr,
+        Ok(None) => fail!("Failed to parse expression at {}", self.lexer.current().location),
+        Err(err) => fail!("{}", err)
     }
 }
 
-pub fn expression(&mut self) -> Option<TypedExpr> {
-	let app = self.application();
-	self.parseOperatorExpression(app, 0)
+///Parses an expression and associates its operators using the fixity declarations which have been parsed
+pub fn expression(&mut self) -> ParseResult<Option<TypedExpr>> {
+    match try!(self.operator_expression()) {
+        Some(mut expr) => {
+            try!(self.reassociate(&mut expr));
+            Ok(Some(expr))
+        }
+        None => Ok(None)
+    }
 }
 
+fn operator_expression(&mut self) -> ParseResult<Option<TypedExpr>> {
+    self.nested(|this| {
+        match this.application() {
+            Ok(app) => this.parseOperatorExpression(app, 0),
+            Err(err) => Err(err)
+        }
+    })
+}
 
-fn parseList(&mut self) -> TypedExpr {
+///Parses an expression, returning an error if there was no expression
+fn required_expression(&mut self) -> ParseResult<TypedExpr> {
+    match try!(self.operator_expression()) {
+        Some(expr) => Ok(expr),
+        None => {
+            let token = self.lexer.next_();
+            Err(Diagnostic::new(token.location, format!("Expected an expression but found {:?}", token.token)))
+        }
+    }
+}
+
+
+fn parseList(&mut self) -> ParseResult<TypedExpr> {
 	let mut expressions = ~[];
 	loop {
-		match self.expression() {
+		match try!(self.operator_expression()) {
             Some(expr) => expressions.push(expr),
             None => break
         }
@@ -203,199 +709,312 @@ fn parseList(&mut self) -> TypedExpr {
             break;
         }
 	}
-    self.requireNext(RBRACKET);
+    try!(self.requireNext(RBRACKET));
 
-	if (expressions.len() == 0)
+	//Build the list from the back so that no placeholder expressions need to be allocated
+	let mut application = TypedExpr::new(Identifier(intern("[]")));
+	for expr in expressions.move_rev_iter()
 	{
-		return TypedExpr::new(Identifier(~"[]"));
-	}
-
-	let mut application;
-	{
-		let mut arguments = ~[TypedExpr::new(Number(0)), TypedExpr::new(Number(0))];//Must be 2 in length
-		swap(&mut arguments[0], &mut expressions[expressions.len() - 1]);
-		expressions.pop();
-		arguments[1] = TypedExpr::new(Identifier(~"[]"));
-
-		application = makeApplication(TypedExpr::new(Identifier(~":")), arguments);
+		let arguments = ~[expr, application];
+		application = makeApplication(TypedExpr::new(Identifier(intern(":"))), arguments);
 	}
-	while (expressions.len() > 0)
-	{
-		let mut arguments = ~[TypedExpr::new(Number(0)), TypedExpr::new(Number(0))];//Must be 2 in length
-		swap(&mut arguments[0], &mut expressions[expressions.len() - 1]);
-		expressions.pop();
-		arguments[1] = application;
-
-		application = makeApplication(TypedExpr::new(Identifier(~":")), arguments);
-	}
-    application
+    Ok(application)
 }
 
-fn subExpression(&mut self, parseError : |&Token| -> bool) -> Option<TypedExpr> {
+fn subExpression(&mut self, parseError : |&Token| -> bool) -> ParseResult<Option<TypedExpr>> {
 	let token = self.lexer.next(parseError).token;
-    debug!("Begin SubExpr {:?}", self.lexer.current());
+    log_phase!(Parsing, Debug, "Begin SubExpr {:?}", self.lexer.current());
 	match token {
 	    LPARENS =>
 		{
-			let expressions = self.sepBy1(|this| this.expression_(), COMMA);
+			let location = self.lexer.current().location;
+			//An operator in parentheses such as '(+)' is the function it names
+			if (self.lexer.next_().token == OPERATOR)
+			{
+				let op = self.lexer.current().value;
+				if (self.lexer.next_().token == RPARENS)
+				{
+					return Ok(Some(TypedExpr::with_location(Identifier(op), location)));
+				}
+				self.lexer.backtrack();
+			}
+			self.lexer.backtrack();
+			//A tuple constructor such as '(,)' is the function which builds the tuple
+			if (self.lexer.next_().token == COMMA)
+			{
+				let mut size = 2;
+				while (self.lexer.next_().token == COMMA)
+				{
+					size += 1;
+				}
+				if (self.lexer.current().token != RPARENS)
+				{
+					return Err(ParseError(&self.lexer, RPARENS));
+				}
+				return Ok(Some(TypedExpr::with_location(Identifier(intern(tuple_name(size))), location)));
+			}
+			self.lexer.backtrack();
+			let expressions = try!(self.sepBy1(|this| this.required_expression(), COMMA));
 
 			let maybeParens = self.lexer.current();
 
 			if (maybeParens.token != RPARENS)
 			{
-				fail!(ParseError(&self.lexer, RPARENS));
+				return Err(ParseError(&self.lexer, RPARENS));
 			}
 			if (expressions.len() == 1)
 			{
-				Some(expressions[0])
+				match self.operator_location(&expressions[0]) {
+					Some(location) => { self.parenthesized.insert(location); }
+					None => ()
+				}
+				Ok(Some(expressions[0]))
 			}
 			else
 			{
-				Some(newTuple(expressions))
+				Ok(Some(newTuple(expressions)))
 			}
 		}
-	    LBRACKET => Some(self.parseList()),
+	    LBRACKET => Ok(Some(try!(self.parseList()))),
 	    LET =>
 		{
-			self.requireNext(LBRACE);
-
-			let binds = self.sepBy1(|this| this.binding(), SEMICOLON);
-
-			let rBracket = self.lexer.current().token;
-			if (rBracket != RBRACE)
-			{
-				fail!(ParseError(&self.lexer, RBRACE));
+			try!(self.requireNext(LBRACE));
+
+			let letBindings = try!(self.sepBy1(|this| this.let_binding(), SEMICOLON));
+			let mut clauses = ~[];
+			let mut typeDeclarations = ~[];
+			for (i, letBinding) in letBindings.move_iter().enumerate() {
+				match letBinding {
+					LetClause(clause) => clauses.push(clause),
+					LetPattern(pattern, expression) => clauses.push_all_move(pattern_binding_clauses(i, pattern, expression)),
+					LetTypeDeclaration(decl) => typeDeclarations.push(decl)
+				}
+			}
+			let mut binds = try!(make_bindings(clauses));
+			for decl in typeDeclarations.move_iter() {
+				match binds.mut_iter().find(|bind| bind.name == decl.name) {
+					Some(bind) => bind.typeDecl = decl,
+					None => {
+						let location = self.lexer.current().location;
+						return Err(Diagnostic::new(location, format!("The type declaration for '{}' has no binding", decl.name)));
+					}
+				}
 			}
+
+			try!(self.block_end());
 			let inToken = self.lexer.next(letExpressionEndError).token;
 			if (inToken != IN) {
-				fail!(ParseError(&self.lexer, IN));
-            }
-			match self.expression() {
-                Some(e) => {
-                    Some(TypedExpr::new(Let(binds, ~e)))
-                }
-                None => None
+				return Err(ParseError(&self.lexer, IN));
             }
+			let e = try!(self.required_expression());
+            Ok(Some(TypedExpr::new(Let(binds, ~e))))
 		}
 	    CASE =>
 		{
             let location = self.lexer.current().location;
-			let expr = self.expression();
-
-			self.requireNext(OF);
-			self.requireNext(LBRACE);
+			let expr = try!(self.required_expression());
 
-			let alts = self.sepBy1(|this| this.alternative(), SEMICOLON);
-			let rBrace = self.lexer.current();
-			if (rBrace.token != RBRACE)
-			{
-				fail!(ParseError(&self.lexer, RBRACE));
-			}
-			match expr {
-                Some(e) => Some(TypedExpr::with_location(Case(~e, alts), location)),
-                None => None
-            }
+			try!(self.requireNext(OF));
+			let alts = try!(self.alternatives());
+            Ok(Some(TypedExpr::with_location(Case(~expr, alts), location)))
 		}
+        IF => {
+            //if c then t else f is the same as case c of { True -> t; False -> f }
+            let location = self.lexer.current().location;
+            let condition = try!(self.required_expression());
+            try!(self.requireNext(THEN));
+            let then_location = self.lexer.current().location;
+            let then_expr = try!(self.required_expression());
+            try!(self.requireNext(ELSE));
+            let else_location = self.lexer.current().location;
+            let else_expr = try!(self.required_expression());
+            let alts = ~[
+                Alternative {
+                    pattern: Located { location: then_location, node: ConstructorPattern(~"True", ~[]) },
+                    expression: then_expr
+                },
+                Alternative {
+                    pattern: Located { location: else_location, node: ConstructorPattern(~"False", ~[]) },
+                    expression: else_expr
+                }
+            ];
+            Ok(Some(TypedExpr::with_location(Case(~condition, alts), location)))
+        }
+        LAMBDA => {
+            let location = self.lexer.current().location;
+            if self.lexer.next_().token == CASE {
+                //\case { alts } is the same as \#arg0 -> case #arg0 of { alts }
+                let alts = try!(self.alternatives());
+                let name = intern("#arg0");
+                let scrutinee = TypedExpr::with_location(Identifier(name.clone()), location);
+                let mut lambda = makeLambda(~[name], TypedExpr::with_location(Case(~scrutinee, alts), location));
+                lambda.location = location;
+                return Ok(Some(lambda));
+            }
+            self.lexer.backtrack();
+            let patterns = try!(self.patternParameter());
+            if patterns.len() == 0 {
+                let token = self.lexer.next_();
+                return Err(Diagnostic::new(token.location, format!("Expected an argument to the lambda but found {:?}", token.token)));
+            }
+            try!(self.requireNext(ARROW));
+            let body = try!(self.required_expression());
+            Ok(Some(makePatternLambda(location, patterns, body)))
+        }
         NAME => {
-            let token = self.lexer.current();
-            Some(TypedExpr::with_location(Identifier(token.value.clone()), token.location))
+            let expr = {
+                let token = self.lexer.current();
+                TypedExpr::with_location(Identifier(token.value.clone()), token.location)
+            };
+            Ok(Some(try!(self.record_expression(expr))))
         }
         NUMBER => {
             let token = self.lexer.current();
-            Some(TypedExpr::with_location(Number(from_str(token.value).unwrap()), token.location))
+            match from_str(token.value.as_slice()) {
+                Some(value) => Ok(Some(TypedExpr::with_location(Number(value), token.location))),
+                None => Err(Diagnostic::new(token.location, format!("Invalid number literal {}", token.value)))
+            }
         }
 	    FLOAT => {
             let token = self.lexer.current();
-            Some(TypedExpr::with_location(Rational(from_str(token.value).unwrap()), token.location))
+            match from_str(token.value.as_slice()) {
+                Some(value) => Ok(Some(TypedExpr::with_location(Rational(value), token.location))),
+                None => Err(Diagnostic::new(token.location, format!("Invalid floating point literal {}", token.value)))
+            }
         }
         STRING => {
             let token = self.lexer.current();
-            Some(TypedExpr::with_location(String(token.value.clone()), token.location))
+            Ok(Some(TypedExpr::with_location(String(token.value.to_owned()), token.location)))
         }
         CHAR => {
             let token = self.lexer.current();
-            Some(TypedExpr::with_location(Char(token.value.char_at(0)), token.location))
+            Ok(Some(TypedExpr::with_location(Char(token.value.as_slice().char_at(0)), token.location)))
         }
 	    _ => {
             self.lexer.backtrack();
-            None
+            Ok(None)
         }
     }
 }
 
-fn alternative(&mut self) -> Alternative {
-	let pat = self.located_pattern();
+///Parses the alternatives of a case expression, '{ pattern -> expr ; ... }'
+fn alternatives(&mut self) -> ParseResult<~[Alternative]> {
+	try!(self.requireNext(LBRACE));
 
-	self.requireNext(ARROW);
+	let alts = try!(self.sepBy1(|this| this.alternative(), SEMICOLON));
+	try!(self.block_end());
+	Ok(alts)
+}
 
-	Alternative { pattern : pat, expression : self.expression_() }
+///Returns true and consumes the '}' if the block which was just opened is empty
+fn empty_block(&mut self) -> bool {
+	if (self.lexer.next_().token == RBRACE)
+	{
+		true
+	}
+	else
+	{
+		self.lexer.backtrack();
+		false
+	}
+}
+
+///Checks that the current token ends a block. Blocks opened by layout are also ended by any
+///token which can not continue them, the '}' is then inserted before that token.
+fn block_end(&mut self) -> ParseResult<()> {
+	if (self.lexer.current().token == RBRACE || self.lexer.close_implicit_block())
+	{
+		Ok(())
+	}
+	else
+	{
+		Err(ParseError(&self.lexer, RBRACE))
+	}
 }
 
-fn parseOperatorExpression(&mut self, inL : Option<TypedExpr>, minPrecedence : int) -> Option<TypedExpr> {
+fn alternative(&mut self) -> ParseResult<Alternative> {
+	let pat = try!(self.located_pattern());
+
+	try!(self.requireNext(ARROW));
+
+	Ok(Alternative { pattern : pat, expression : try!(self.required_expression()) })
+}
+
+fn parseOperatorExpression(&mut self, inL : Option<TypedExpr>, minPrecedence : int) -> ParseResult<Option<TypedExpr>> {
 	let mut lhs = inL;
     self.lexer.next_();
-    debug!("Parse operator exression, {:?}", self.lexer.current());
+    log_phase!(Parsing, Debug, "Parse operator exression, {:?}", self.lexer.current());
 	while (self.lexer.valid() && self.lexer.current().token == OPERATOR
-		&& precedence(self.lexer.current().value) >= minPrecedence)
+		&& self.precedence(&self.lexer.current().value) >= minPrecedence)
 	{
 		let op = (*self.lexer.current()).clone();
-		let mut rhs = self.application();
+		let (assoc, precedence) = self.fixity(&op.value);
+		let mut rhs = try!(self.application());
 		self.lexer.next_();
-        debug!("Parsing operator? {:?}", self.lexer.current());
-		while (self.lexer.valid() && self.lexer.current().token == OPERATOR
-			&& precedence(self.lexer.current().value) >= precedence(op.value))
+        log_phase!(Parsing, Debug, "Parsing operator? {:?}", self.lexer.current());
+		while (self.lexer.valid() && self.lexer.current().token == OPERATOR)
 		{
-			let lookaheadPrecedence = precedence(self.lexer.current().value);
+			let lookahead = (*self.lexer.current()).clone();
+			let (lookaheadAssoc, lookaheadPrecedence) = self.fixity(&lookahead.value);
+			//Operators which can't be mixed are reported when the expression is re-associated
+			//since a fixity declaration after the expression may still change them
+			if (lookaheadPrecedence == precedence && (assoc != lookaheadAssoc || assoc == NoAssoc))
+			{
+				break;
+			}
+			//The rhs is the left operand of an operator which binds tighter or which associates to the right
+			if (lookaheadPrecedence < precedence || (lookaheadPrecedence == precedence && assoc != RightAssoc))
+			{
+				break;
+			}
 			self.lexer.backtrack();
-			rhs = self.parseOperatorExpression(rhs, lookaheadPrecedence);
+			rhs = try!(self.parseOperatorExpression(rhs, lookaheadPrecedence));
             self.lexer.next_();
 		}
-		let mut name = TypedExpr::with_location(Identifier(op.value.clone()), op.location);
+		let name = TypedExpr::with_location(Identifier(op.value.clone()), op.location);
 		let loc = match &lhs {
             &Some(ref l) => l.location,
             &None => op.location
         };
         lhs = match (lhs, rhs) {
             (Some(lhs), Some(rhs)) => {
+                self.operators.insert(op.location.absolute);
                 let args = ~[lhs, rhs];
                 Some(makeApplication(name, args))
             }
             (Some(lhs), None) => {
-                Some(TypedExpr::with_location(Apply(~name, ~lhs), loc))
+                Some(TypedExpr::with_location(Apply(~(name, lhs)), loc))
             }
             (None, Some(rhs)) => {
-                if (op.value == ~"-")
+                if (op.value.as_slice() == "-")
                 {
-                    match name.expr {
-                        Identifier(ref mut n) => *n = ~"negate",
-                        _ => fail!("WTF")
-                    }
+                    let negate = TypedExpr::with_location(Identifier(intern("negate")), op.location);
                     let args = ~[rhs];
-                    Some(makeApplication(name, args))
+                    Some(makeApplication(negate, args))
                 }
                 else
                 {
-                    let args = ~[TypedExpr::with_location(Identifier(~"#"), loc), rhs];
+                    let args = ~[TypedExpr::with_location(Identifier(intern("#")), loc), rhs];
                     let mut apply = makeApplication(name, args);
                     apply.location = loc;
-                    let params = ~[~"#"];
+                    let params = ~[intern("#")];
                     Some(makeLambda(params, apply))
                 }
             }
-            (None, None) => return None
+            (None, None) => return Ok(None)
         };
 	}
 	self.lexer.backtrack();
-	lhs
+	Ok(lhs)
 }
 
-fn application(&mut self) -> Option<TypedExpr> {
-    let e = self.subExpression(|_| false);
+fn application(&mut self) -> ParseResult<Option<TypedExpr>> {
+    let e = try!(self.subExpression(|_| false));
 	match e {
         Some(mut lhs) => {
             let mut expressions = ~[];
             loop {
-                let expr = self.subExpression(applicationError);
+                let expr = try!(self.subExpression(applicationError));
                 match expr {
                     Some(e) => expressions.push(e),
                     None => break
@@ -407,113 +1026,336 @@ fn application(&mut self) -> Option<TypedExpr> {
                 lhs = makeApplication(lhs, expressions);//, loc);
                 lhs.location = loc;
             }
-            Some(lhs)
+            Ok(Some(lhs))
         }
-        None => None
+        None => Ok(None)
     }
 }
 
-fn constructor(&mut self, dataDef : &DataDefinition) -> Constructor {
-	let name = self.requireNext(NAME).value.clone();
+fn constructor(&mut self, dataDef : &DataDefinition) -> ParseResult<Constructor> {
+	let name = try!(self.requireNext(NAME)).value.to_owned();
 	let mut arity = 0;
     let mut mapping = dataDef.parameters.clone();
-	let typ = self.constructorType(&mut arity, dataDef, &mut mapping);
+	if (self.lexer.next(constructorError).token == LBRACE)
+	{
+		//Record syntax, C { field1 :: Type1, field2 :: Type2 }
+		//Variables which are not parameters of the data type get ids after the ones already used by the parameters
+		let mut variableIndex = 0;
+		let fields = try!(self.sepBy1(|this| this.field_declaration(&mut variableIndex, &mut mapping), COMMA));
+		if (self.lexer.current().token != RBRACE)
+		{
+			return Err(ParseError(&self.lexer, RBRACE));
+		}
+		let mut typ = dataDef.typ.clone();
+		for &(_, ref field_type) in fields.rev_iter() {
+			typ = function_type(field_type, &typ);
+		}
+		let names : ~[~str] = fields.move_iter().map(|(field, _)| field).collect();
+		self.records.push((dataDef.typ.op().name.clone(), name.clone(), names.clone()));
+		return Ok(Constructor { name : name, typ : typ, tag : 0, arity : names.len() as int, fields : names });
+	}
+	self.lexer.backtrack();
+	let typ = try!(self.constructorType(&mut arity, dataDef, &mut mapping));
 	self.lexer.backtrack();
-	Constructor { name : name, typ : typ, tag : 0, arity : arity }
+	Ok(Constructor { name : name, typ : typ, tag : 0, arity : arity, fields : ~[] })
 }
 
-fn binding(&mut self) -> Binding {
-    debug!("Begin binding");
+fn field_declaration(&mut self, variableIndex : &mut int, mapping : &mut HashMap<~str, int>) -> ParseResult<(~str, Type)> {
+	let name = try!(self.requireNext(NAME)).value.to_owned();
+	try!(self.requireNext(TYPEDECL));
+	let typ = try!(self.parse_type_(variableIndex, mapping));
+	Ok((name, typ))
+}
+
+fn field_assignment(&mut self) -> ParseResult<(~str, TypedExpr)> {
+	let name = try!(self.requireNext(NAME)).value.to_owned();
+	try!(self.requireNext(EQUALSSIGN));
+	Ok((name, try!(self.required_expression())))
+}
+
+///Parses a record construction 'C { field = expr }' or update 'expr { field = expr }' if a '{' follows 'expr'
+fn record_expression(&mut self, expr : TypedExpr) -> ParseResult<TypedExpr> {
+	if (self.lexer.next(applicationError).token != LBRACE)
+	{
+		self.lexer.backtrack();
+		return Ok(expr);
+	}
+	let location = expr.location;
+	let assignments = try!(self.sepBy1(|this| this.field_assignment(), COMMA));
+	if (self.lexer.current().token != RBRACE)
+	{
+		return Err(ParseError(&self.lexer, RBRACE));
+	}
+	let constructor = match &expr.expr {
+		&Identifier(ref name) if name.as_slice().char_at(0).is_uppercase() => Some(name.to_owned()),
+		_ => None
+	};
+	match constructor {
+		Some(name) => self.record_construction(location, name, assignments),
+		None => self.record_update(location, expr, assignments)
+	}
+}
+
+///Creates the application of the record constructor 'name' to the assigned fields
+fn record_construction(&self, location : Location, name : ~str, assignments : ~[(~str, TypedExpr)]) -> ParseResult<TypedExpr> {
+	let fields = match self.records.iter().find(|&&(_, ref constructor, _)| *constructor == name) {
+		Some(&(_, _, ref fields)) => fields.clone(),
+		None => return Err(Diagnostic::new(location, format!("'{}' is not a record constructor", name)))
+	};
+	let mut values = ::std::vec::from_fn(fields.len(), |_| None);
+	for (field, value) in assignments.move_iter() {
+		let index = match fields.iter().position(|f| *f == field) {
+			Some(index) => index,
+			None => return Err(Diagnostic::new(value.location, format!("'{}' does not have the field '{}'", name, field)))
+		};
+		if values[index].is_some() {
+			return Err(Diagnostic::new(value.location, format!("The field '{}' is assigned more than once", field)));
+		}
+		values[index] = Some(value);
+	}
+	let mut arguments = ~[];
+	for (field, value) in fields.iter().zip(values.move_iter()) {
+		match value {
+			Some(value) => arguments.push(value),
+			None => return Err(Diagnostic::new(location, format!("The field '{}' of '{}' is not assigned", *field, name)))
+		}
+	}
+	let mut application = makeApplication(TypedExpr::with_location(Identifier(intern(name)), location), arguments);
+	application.location = location;
+	Ok(application)
+}
+
+///Creates a case expression which rebuilds 'expr' with the assigned fields replaced.
+///The new values are bound in a let so that each alternative can refer to them.
+fn record_update(&self, location : Location, expr : TypedExpr, assignments : ~[(~str, TypedExpr)]) -> ParseResult<TypedExpr> {
+	let assigned : ~[~str] = assignments.iter().map(|&(ref field, _)| field.clone()).collect();
+	for (i, &(ref field, ref value)) in assignments.iter().enumerate() {
+		if assigned.slice_to(i).contains(field) {
+			return Err(Diagnostic::new(value.location, format!("The field '{}' is assigned more than once", *field)));
+		}
+	}
+	//Every field must belong to the same type and only the constructors of that type which have all of them are matched
+	let mut types : ~[~str] = ~[];
+	for &(ref typename, _, _) in self.records.iter() {
+		if !types.contains(typename) && assigned.iter().all(|field| self.records.iter().any(|&(ref t, _, ref fields)| t == typename && fields.contains(field))) {
+			types.push(typename.clone());
+		}
+	}
+	if types.len() > 1 {
+		return Err(Diagnostic::new(location, format!("The fields {} are fields of more than one type, {}", assigned.connect(", "), types.connect(", "))));
+	}
+	let candidates : ~[(~str, ~[~str])] = self.records.iter()
+		.filter(|&&(ref typename, _, ref fields)| types.contains(typename) && assigned.iter().all(|field| fields.contains(field)))
+		.map(|&(_, ref constructor, ref fields)| (constructor.clone(), fields.clone()))
+		.collect();
+	if candidates.len() == 0 {
+		return Err(Diagnostic::new(location, format!("No constructor has all of the fields {}", assigned.connect(", "))));
+	}
+	let mut bindings = ~[];
+	for (i, (_, value)) in assignments.move_iter().enumerate() {
+		bindings.push(Binding {
+			name : format!("{}{}", "#update", i),
+			typeDecl : TypeDeclaration { context : ~[], typ : Type::new_var(-1), name : ~"", location : Location::eof() },
+			expression : value,
+			arity : 0
+		});
+	}
+	let alts = candidates.move_iter().map(|(constructor, fields)| {
+		let patterns = range(0, fields.len()).map(|i| IdentifierPattern(intern(format!("{}{}", "#field", i)))).collect();
+		let arguments : ~[TypedExpr] = fields.iter().enumerate().map(|(i, field)| {
+			let name = match assigned.iter().position(|f| f == field) {
+				Some(index) => format!("{}{}", "#update", index),
+				None => format!("{}{}", "#field", i)
+			};
+			TypedExpr::with_location(Identifier(intern(name)), location)
+		}).collect();
+		Alternative {
+			pattern : Located { location : location, node : ConstructorPattern(constructor.clone(), patterns) },
+			expression : makeApplication(TypedExpr::with_location(Identifier(intern(constructor)), location), arguments)
+		}
+	}).collect();
+	let case = TypedExpr::with_location(Case(~expr, alts), location);
+	Ok(TypedExpr::with_location(Let(bindings, ~case), location))
+}
+
+fn binding(&mut self) -> ParseResult<Binding> {
+    let clause = try!(self.binding_clause());
+    make_binding(~[clause])
+}
+
+///Parses a binding in a let block which is either a type declaration, an equation of a function
+///or a pattern binding such as '(x, y) = pair'
+fn let_binding(&mut self) -> ParseResult<LetBinding> {
+	if (self.isTypeDeclaration())
+	{
+		return Ok(LetTypeDeclaration(try!(self.typeDeclaration())));
+	}
+	if (!self.isPatternBinding())
+	{
+		return Ok(LetClause(try!(self.binding_clause())));
+	}
+	let pattern = try!(self.located_pattern());
+	if (self.lexer.next(errorIfNotNameOrEqual).token != EQUALSSIGN)
+	{
+		return Err(ParseError(&self.lexer, EQUALSSIGN));
+	}
+	let expression = try!(self.required_expression());
+	Ok(LetPattern(pattern, expression))
+}
+
+///Returns true if the declaration which is about to be parsed is a type declaration, 'name :: type'
+fn isTypeDeclaration(&mut self) -> bool {
+	let mut equalOrType = self.lexer.next(bindingError).token;
+	let mut numberOfLookaheads = 1;
+	while (equalOrType != TYPEDECL
+		&& equalOrType != EQUALSSIGN
+		&& equalOrType != EOF)
+	{
+		equalOrType = self.lexer.next(bindingError).token;
+		numberOfLookaheads += 1;
+	}
+	for _ in range(0, numberOfLookaheads)
+	{
+		self.lexer.backtrack();
+	}
+	equalOrType == TYPEDECL
+}
+
+///Returns true if the binding which is about to be parsed binds a pattern instead of defining a function.
+///A pattern binding starts with a constructor, a list or a parenthesized pattern
+///and has no operator outside of parentheses before the '='.
+fn isPatternBinding(&mut self) -> bool {
+	let first = self.lexer.next(bindingError).token;
+	let second = self.lexer.next(bindingError).token;
+	self.lexer.backtrack();
+	let mut isPattern = match first {
+		LPARENS => second != OPERATOR,
+		LBRACKET => true,
+		NAME => self.lexer.current().value.as_slice().char_at(0).is_uppercase(),
+		_ => false
+	};
+	let mut lookaheads = 1;
+	let mut depth = match first { LPARENS | LBRACKET => 1, _ => 0 };
+	while (isPattern)
+	{
+		let token = self.lexer.next(bindingError).token;
+		lookaheads += 1;
+		match token
+		{
+			LPARENS | LBRACKET => depth += 1,
+			RPARENS | RBRACKET => depth -= 1,
+			OPERATOR if depth == 0 => isPattern = false,
+			EQUALSSIGN | EOF => break,
+			_ => ()
+		}
+	}
+	for _ in range(0, lookaheads)
+	{
+		self.lexer.backtrack();
+	}
+	isPattern
+}
+
+fn binding_clause(&mut self) -> ParseResult<Clause> {
+    log_phase!(Parsing, Debug, "Begin binding");
 	//name1 = expr
 	//or
-	//name2 x y = expr
+	//name2 x (Just y) = expr
+	//or
+	//x <+> y = expr
 	let nameToken = self.lexer.next(errorIfNotNameOrLParens).token;
-	let mut name = self.lexer.current().value.clone();
+    let location = self.lexer.current().location;
+	let mut name = self.lexer.current().value.to_owned();
+    //The patterns to the left of the operator if this is an infix definition
+	let mut leftOperand = None;
 	if (nameToken == LPARENS)
 	{
-		//Parse a name within parentheses
+		//Parse an operator within parentheses
 		let functionName = self.lexer.next(errorIfNotNameOrOperator).token;
-		if (functionName != NAME && functionName != OPERATOR)
+		if (functionName == OPERATOR)
 		{
-			fail!("Expected NAME or OPERATOR on left side of binding {:?}", self.lexer.current().token);
+			name = self.lexer.current().value.to_owned();
+			let rParens = self.lexer.next(errorIfNotRParens).token;
+			if (rParens != RPARENS)
+			{
+				return Err(ParseError(&self.lexer, RPARENS));
+			}
 		}
-		name = self.lexer.current().value.clone();
-
-		let rParens = self.lexer.next(errorIfNotRParens).token;
-		if (rParens != RPARENS)
+		else
 		{
-			fail!(ParseError(&self.lexer, RPARENS));
+			//A parenthesized pattern to the left of an operator such as '(Just x) <+> y' or a parenthesized name
+			self.lexer.backtrack();
+			self.lexer.backtrack();
+			leftOperand = Some(try!(self.patternParameter()));
 		}
 	}
 	else if (nameToken != NAME)
 	{
-		fail!(ParseError(&self.lexer, NAME));
+		return Err(ParseError(&self.lexer, NAME));
 	}
 
 	//Parse the arguments for the binding
-	let mut arguments = ~[];
-	while (true)
+	let mut arguments = match leftOperand {
+        Some(_) => ~[],
+        None => try!(self.patternParameter())
+    };
+	if (self.lexer.next_().token == OPERATOR && (leftOperand.is_some() || (nameToken == NAME && arguments.len() == 0)))
 	{
-		let token = self.lexer.next(errorIfNotNameOrEqual);
-		if (token.token == NAME)
+		//Infix definitions are stored under the name of the operator, the same as '(<+>) x y = expr'
+		let left = match leftOperand.take() {
+			Some(patterns) => patterns,
+			None => ~[variable_or_constructor_pattern(intern(name))]
+		};
+		name = self.lexer.current().value.to_owned();
+		let right = try!(self.patternParameter());
+		if (left.len() != 1 || right.len() != 1)
 		{
-			arguments.push(token.value.clone());
-		}
-		else
-		{
-			break;
+			return Err(Diagnostic::new(location, format!("The operator '{}' must be defined with exactly one pattern on each side", name)));
 		}
+		arguments = left;
+		arguments.push_all_move(right);
 	}
-	if (self.lexer.current().token != EQUALSSIGN)
+	else
 	{
-		fail!(ParseError(&self.lexer, EQUALSSIGN));
-	}
-	if (arguments.len() > 0)
-    {
-        let arity = arguments.len();
-		let lambda = makeLambda(arguments, self.expression_());
-		Binding { name : name, typeDecl : TypeDeclaration { context : ~[], typ : Type::new_var(-1), name : ~"" }, expression : lambda, arity : arity }
+		self.lexer.backtrack();
+		match leftOperand
+		{
+			//A name within parentheses followed by the arguments such as '(f) x = expr'
+			Some(mut patterns) => match patterns.shift()
+			{
+				IdentifierPattern(function) => {
+					name = function.to_owned();
+					arguments = patterns;
+				}
+				_ => return Err(ParseError(&self.lexer, OPERATOR))
+			},
+			None => ()
+		}
 	}
-	else
+	if (self.lexer.next(errorIfNotNameOrEqual).token != EQUALSSIGN)
 	{
-		Binding { name : name, typeDecl : TypeDeclaration { context : ~[], typ : Type::new_var(-1), name : ~"" }, expression : self.expression_(), arity : 0 }
+		return Err(ParseError(&self.lexer, EQUALSSIGN));
 	}
+    let expression = try!(self.required_expression());
+    Ok(Clause { name : name, location : location, arguments : arguments, expression : expression })
 }
 
 
-fn patternParameter(&mut self) -> ~[Pattern] {
+fn patternParameter(&mut self) -> ParseResult<~[Pattern]> {
 	let mut parameters = ~[];
 	loop {
 		let token = self.lexer.next_().token;
 		match token
 		{
-            NAME => parameters.push(IdentifierPattern(self.lexer.current().value.clone())),
-            NUMBER => parameters.push(NumberPattern(from_str(self.lexer.current().value.clone()).unwrap())),
-		    LPARENS =>
-			{
-				let pat = self.pattern();
-				let maybeComma = self.lexer.next_().token;
-				if (maybeComma == COMMA)
-				{
-					let mut tupleArgs = self.sepBy1(|this| this.pattern(), COMMA);
-
-					let rParens = self.lexer.current();
-					if (rParens.token != RPARENS)
-					{
-						fail!(ParseError(&self.lexer, RPARENS));
-					}
-					tupleArgs.unshift(pat);
-					parameters.push(ConstructorPattern(tuple_name(tupleArgs.len()), tupleArgs));
-				}
-				else
-				{
-                    //TODO?
-				}
-			}
+            NAME => parameters.push(variable_or_constructor_pattern(self.lexer.current().value.clone())),
+            NUMBER => parameters.push(NumberPattern(try!(self.number_pattern()))),
+            CHAR => parameters.push(CharPattern(self.lexer.current().value.as_slice().char_at(0))),
+            STRING => parameters.push(string_pattern(self.lexer.current().value.as_slice())),
+		    LPARENS => parameters.push(try!(self.parenthesized_pattern())),
             LBRACKET => {
                 if (self.lexer.next_().token != RBRACKET)
                 {
-                    fail!(ParseError(&self.lexer, RBRACKET));
+                    return Err(ParseError(&self.lexer, RBRACKET));
                 }
                 parameters.push(ConstructorPattern(~"[]", ~[]));
             }
@@ -521,16 +1363,45 @@ fn patternParameter(&mut self) -> ~[Pattern] {
 		}
 	}
 	self.lexer.backtrack();
-	return parameters;
+	return Ok(parameters);
+}
+
+///Parses the rest of a pattern after its opening parenthesis, either a tuple or
+///a single pattern such as '(Just (Left x))'
+fn parenthesized_pattern(&mut self) -> ParseResult<Pattern> {
+    let mut patterns = try!(self.sepBy1(|this| this.pattern(), COMMA));
+    let rParens = self.lexer.current().token;
+    if (rParens != RPARENS) {
+        return Err(ParseError(&self.lexer, RPARENS));
+    }
+    if (patterns.len() == 1) {
+        Ok(patterns.pop())
+    }
+    else {
+        Ok(ConstructorPattern(tuple_name(patterns.len()), patterns))
+    }
+}
+
+///Converts the current NUMBER token into the value of a number pattern
+fn number_pattern(&self) -> ParseResult<int> {
+    let token = self.lexer.current();
+    match from_str(token.value.as_slice()) {
+        Some(number) => Ok(number),
+        None => Err(Diagnostic::new(token.location, format!("Invalid number pattern {}", token.value)))
+    }
 }
 
-fn located_pattern(&mut self) -> Located<Pattern> {
+fn located_pattern(&mut self) -> ParseResult<Located<Pattern>> {
     let location = self.lexer.next_().location;
     self.lexer.backtrack();
-    Located { location: location, node: self.pattern() }
+    Ok(Located { location: location, node: try!(self.pattern()) })
+}
+
+fn pattern(&mut self) -> ParseResult<Pattern> {
+    self.nested(|this| this.pattern_())
 }
 
-fn pattern(&mut self) -> Pattern {
+fn pattern_(&mut self) -> ParseResult<Pattern> {
 	let nameToken = self.lexer.next_().token;
     let name = self.lexer.current().value.clone();
 	match nameToken {
@@ -538,178 +1409,297 @@ fn pattern(&mut self) -> Pattern {
 		{
 			if (self.lexer.next_().token != RBRACKET)
 			{
-				fail!(ParseError(&self.lexer, RBRACKET));
+				return Err(ParseError(&self.lexer, RBRACKET));
 			}
-			ConstructorPattern(~"[]", ~[])
+			Ok(ConstructorPattern(~"[]", ~[]))
 		}
 	    NAME | OPERATOR =>
 		{
-			let patterns = self.patternParameter();
-			if (name.char_at(0).is_uppercase() || name == ~":")
+            let location = self.lexer.current().location;
+			let patterns = try!(self.patternParameter());
+			if (name.as_slice().char_at(0).is_uppercase() || name.as_slice() == ":")
 			{
-				ConstructorPattern(name, patterns)
+				Ok(ConstructorPattern(name.to_owned(), patterns))
 			}
-			else
+			else if (patterns.len() == 0)
 			{
-				assert!(patterns.len() == 0);
-				IdentifierPattern(name)
+				Ok(IdentifierPattern(name))
 			}
+            else
+            {
+                Err(Diagnostic::new(location, format!("The variable pattern '{}' can not have arguments", name)))
+            }
 		}
-	    NUMBER => NumberPattern(from_str(name).unwrap()),
-	    LPARENS =>
-		{
-			let tupleArgs = self.sepBy1(|this| this.pattern(), COMMA);
-			let rParens = self.lexer.current().token;
-			if (rParens != RPARENS) {
-				fail!(ParseError(&self.lexer, RPARENS));
-			}
-			ConstructorPattern(tuple_name(tupleArgs.len()), tupleArgs)
-		}
-	    _ => { fail!("Error parsing pattern") }
+	    NUMBER => Ok(NumberPattern(try!(self.number_pattern()))),
+	    CHAR => Ok(CharPattern(self.lexer.current().value.as_slice().char_at(0))),
+	    STRING => Ok(string_pattern(self.lexer.current().value.as_slice())),
+	    LPARENS => self.parenthesized_pattern(),
+	    _ => {
+            let token = self.lexer.current();
+            Err(Diagnostic::new(token.location, format!("Error parsing pattern, found {:?}", token.token)))
+        }
 	}
 }
 
-fn typeDeclaration(&mut self) -> TypeDeclaration {
+fn typeDeclaration(&mut self) -> ParseResult<TypeDeclaration> {
 	let mut typeVariableMapping = HashMap::new();
 	self.typeDeclaration_(&mut typeVariableMapping)
 }
 
-fn typeDeclaration_(&mut self, typeVariableMapping : &mut HashMap<~str, int>) -> TypeDeclaration {
+fn typeDeclaration_(&mut self, typeVariableMapping : &mut HashMap<~str, int>) -> ParseResult<TypeDeclaration> {
     let mut name;
+    let location;
 	{
         let nameToken = self.lexer.next(errorIfNotNameOrLParens).token;
-        name = self.lexer.current().value.clone();
+        name = self.lexer.current().value.to_owned();
+        location = self.lexer.current().location;
         if (nameToken == LPARENS) {
             //Parse a name within parentheses
             let functionName = self.lexer.next(errorIfNotNameOrOperator).token;
             if (functionName != NAME && functionName != OPERATOR)
             {
-                fail!("Expected NAME or OPERATOR on left side of binding {:?}", functionName);
+                return Err(ParseError2(&self.lexer, &[NAME, OPERATOR]));
             }
-            name = self.lexer.current().value.clone();
+            name = self.lexer.current().value.to_owned();
             let rParens = self.lexer.next(errorIfNotRParens).token;
             if (rParens != RPARENS)
             {
-                fail!(ParseError(&self.lexer, RPARENS));
+                return Err(ParseError(&self.lexer, RPARENS));
             }
         }
         else if (nameToken != NAME) {
-            fail!(ParseError(&self.lexer, NAME));
+            return Err(ParseError(&self.lexer, NAME));
         }
     }
 	let decl = self.lexer.next_().token;
 	if (decl != TYPEDECL) {
-		fail!(ParseError(&self.lexer, TYPEDECL));
+		return Err(ParseError(&self.lexer, TYPEDECL));
 	}
-    let (context, typ) = self.constrained_type(typeVariableMapping);
-	TypeDeclaration { name : name, typ : typ, context : context }
+    let (context, typ) = try!(self.constrained_type(typeVariableMapping));
+	Ok(TypeDeclaration { name : name, typ : typ, context : context, location : location })
 }
 
-fn constrained_type(&mut self, typeVariableMapping : &mut HashMap<~str, int>) -> (~[Constraint], Type) {
+fn constrained_type(&mut self, typeVariableMapping : &mut HashMap<~str, int>) -> ParseResult<(~[Constraint], Type)> {
     let mut variableIndex = 0;
-	let typeOrContext = self.parse_type_(&mut variableIndex, typeVariableMapping);
+    let location = self.lexer.next_().location;
+    self.lexer.backtrack();
+	let typeOrContext = try!(self.parse_type_(&mut variableIndex, typeVariableMapping));
     {
         let maybeContextArrow = self.lexer.next_().token;
-        if (maybeContextArrow == OPERATOR && self.lexer.current().value == ~"=>") {
-            let t = self.parse_type_(&mut variableIndex, typeVariableMapping);
-            return (createTypeConstraints(typeOrContext), t);
+        if (maybeContextArrow == OPERATOR && self.lexer.current().value.as_slice() == "=>") {
+            let t = try!(self.parse_type_(&mut variableIndex, typeVariableMapping));
+            return Ok((try!(createTypeConstraints(location, typeOrContext)), t));
         }
     }
 	self.lexer.backtrack();
-	(~[], typeOrContext)
+	Ok((~[], typeOrContext))
 }
 
-fn constructorType(&mut self, arity : &mut int, dataDef: &DataDefinition, mapping : &mut HashMap<~str, int>) -> Type
+fn constructorType(&mut self, arity : &mut int, dataDef: &DataDefinition, mapping : &mut HashMap<~str, int>) -> ParseResult<Type>
 {
 	let token = self.lexer.next(constructorError).token;
 	if (token == NAME) {
 		*arity += 1;
-		let arg = if (self.lexer.current().value.char_at(0).is_lowercase())
+		let arg = if (self.lexer.current().value.as_slice().char_at(0).is_lowercase())
 		{
-			match mapping.find(&self.lexer.current().value) {
+			match mapping.find_equiv(&self.lexer.current().value.as_slice()) {
                 Some(existingVariable) => Type::new_var(*existingVariable),
-                None => fail!("Undefined type parameter {:?}", self.lexer.current().value)
+                None => {
+                    let token = self.lexer.current();
+                    return Err(Diagnostic::new(token.location, format!("Undefined type parameter {}", token.value)))
+                }
             }
 		}
 		else {
-			Type::new_op(self.lexer.current().value.clone(), ~[])
+			Type::new_op(self.lexer.current().value.to_owned(), ~[])
         };
-        function_type(&arg, &self.constructorType(arity, dataDef, mapping))
+        Ok(function_type(&arg, &try!(self.constructorType(arity, dataDef, mapping))))
 	}
 	else if token == LPARENS {
         *arity += 1;
         let mut var = 100000;
-        let arg = self.parse_type_(&mut var, mapping);
-        self.requireNext(RPARENS);
-        function_type(&arg, &self.constructorType(arity, dataDef, mapping))
+        let arg = try!(self.parse_type_(&mut var, mapping));
+        try!(self.requireNext(RPARENS));
+        Ok(function_type(&arg, &try!(self.constructorType(arity, dataDef, mapping))))
     }
     else {
-		dataDef.typ.clone()
+		Ok(dataDef.typ.clone())
 	}
 }
 
 
-fn dataDefinition(&mut self) -> DataDefinition {
-	self.requireNext(DATA);
-	let dataName = self.requireNext(NAME).value.clone();
+fn dataDefinition(&mut self) -> ParseResult<DataDefinition> {
+	try!(self.requireNext(DATA));
+	let dataLocation = self.lexer.current().location;
+	let mut contextMapping = HashMap::new();
+	let context = if self.dataHasContext() {
+		let location = self.lexer.next_().location;
+		self.lexer.backtrack();
+		let mut variableIndex = 0;
+		let contextType = try!(self.parse_type_(&mut variableIndex, &mut contextMapping));
+		let isArrow = {
+			let arrow = self.lexer.next_();
+			arrow.token == OPERATOR && arrow.value.as_slice() == "=>"
+		};
+		if (!isArrow)
+		{
+			return Err(ParseError(&self.lexer, OPERATOR));
+		}
+		try!(createTypeConstraints(location, contextType))
+	}
+	else {
+		~[]
+	};
+	let dataName = try!(self.requireNext(NAME)).value.to_owned();
 
 	let mut definition = DataDefinition {
         constructors : ~[],
         typ : Type::new_var(0),
-        parameters : HashMap::new()
+        parameters : HashMap::new(),
+        context : context,
+        deriving : ~[],
+        location : dataLocation
     };
     definition.typ.typ = TypeOperator(TypeOperator { name: dataName });
+	//Each parameter gets a variable of its own, the ids are negative so that they never have any constraints
+	//in the type environment before they are replaced while typechecking
+	let mut parameterIndex = 0;
 	while (self.lexer.next_().token == NAME)
 	{
-        //TODO use new variables isntead of only  -1
-		definition.typ.types.push(Type::new_var(-1));
-		definition.parameters.insert(self.lexer.current().value.clone(), -1);
+		parameterIndex -= 1;
+		definition.typ.push_type(Type::new_var(parameterIndex));
+		definition.parameters.insert(self.lexer.current().value.to_owned(), parameterIndex);
+	}
+	//Make the constraints refer to the variables of the parameters instead of the ones created for the context
+	for constraint in definition.context.mut_iter() {
+		for var in constraint.variables.mut_iter() {
+			let id = var.id;
+			let name = contextMapping.iter().find(|&(_, v)| *v == id).map(|(name, _)| name.clone()).unwrap();
+			match definition.parameters.find(&name) {
+				Some(parameter) => *var = TypeVariable { id : *parameter },
+				None => {
+					let location = self.lexer.current().location;
+					return Err(Diagnostic::new(location, format!("The type variable {} in the context of {} is not a parameter", name, definition.typ)));
+				}
+			}
+		}
 	}
 
 	let equalToken = self.lexer.current().token;
 	if (equalToken != EQUALSSIGN)
 	{
-		fail!(ParseError(&self.lexer, EQUALSSIGN));
+		return Err(ParseError(&self.lexer, EQUALSSIGN));
 	}
-	definition.constructors = self.sepBy1_func(|this| this.constructor(&definition),
-		|t : &Token| t.token == OPERATOR && t.value == ~"|");
+	definition.constructors = try!(self.sepBy1_func(|this| this.constructor(&definition),
+		|t : &Token| t.token == OPERATOR && t.value.as_slice() == "|"));
 	for ii in range(0, definition.constructors.len())
 	{
 		definition.constructors[ii].tag = ii as int;
 	}
 	self.lexer.backtrack();
-	definition
+	definition.deriving = try!(self.deriving());
+	Ok(definition)
+}
+
+///Parses the optional deriving clause after the constructors of a data definition, 'deriving (Eq, Show)'
+fn deriving(&mut self) -> ParseResult<~[~str]> {
+	if self.lexer.next_().token != DERIVING {
+		self.lexer.backtrack();
+		return Ok(~[]);
+	}
+	let classes = if self.lexer.next_().token == LPARENS {
+		let classes = try!(self.sepBy1(|this| this.requireNext(NAME).map(|t| t.value.to_owned()), COMMA));
+		let rParens = self.lexer.current().token;
+		if rParens != RPARENS {
+			return Err(ParseError(&self.lexer, RPARENS));
+		}
+		classes
+	}
+	else {
+		self.lexer.backtrack();
+		~[try!(self.requireNext(NAME)).value.to_owned()]
+	};
+	for class in classes.iter() {
+		if !can_derive(*class) {
+			let location = self.lexer.current().location;
+			return Err(Diagnostic::new(location, format!("Cannot derive instances of the class '{}'", *class)));
+		}
+	}
+	Ok(classes)
+}
+
+///Returns true if the data definition which is about to be parsed starts with a context, 'Eq a =>'
+fn dataHasContext(&mut self) -> bool {
+	let mut lookaheads = 0;
+	let mut hasContext = false;
+	loop {
+		let token = self.lexer.next_();
+		lookaheads += 1;
+		if (token.token == OPERATOR && token.value.as_slice() == "=>") {
+			hasContext = true;
+			break;
+		}
+		if (token.token == EQUALSSIGN || token.token == EOF) {
+			break;
+		}
+	}
+	for _ in range(0, lookaheads) {
+		self.lexer.backtrack();
+	}
+	hasContext
 }
 
-fn sub_type(&mut self, variableIndex: &mut int, typeVariableMapping: &mut HashMap<~str, int>) -> Option<Type> {
+fn typeSynonym(&mut self) -> ParseResult<TypeSynonym> {
+	try!(self.requireNext(TYPE));
+	let name = try!(self.requireNext(NAME)).value.to_owned();
+	let mut mapping = HashMap::new();
+	let mut variableIndex = 0;
+	let mut parameters = ~[];
+	while (self.lexer.next_().token == NAME)
+	{
+		let id = type_variable(&mut mapping, self.lexer.current().value, &mut variableIndex);
+		parameters.push(TypeVariable { id : id });
+	}
+	if (self.lexer.current().token != EQUALSSIGN)
+	{
+		return Err(ParseError(&self.lexer, EQUALSSIGN));
+	}
+	let typ = try!(self.parse_type_(&mut variableIndex, &mut mapping));
+	Ok(TypeSynonym { name : name, parameters : parameters, typ : typ })
+}
+
+fn sub_type(&mut self, variableIndex: &mut int, typeVariableMapping: &mut HashMap<~str, int>) -> ParseResult<Option<Type>> {
 	let token = (*self.lexer.next_()).clone();
 	match token.token {
 	    LBRACKET =>
 		{
             self.lexer.backtrack();
-            Some(self.parse_type_(variableIndex, typeVariableMapping))
+            Ok(Some(try!(self.parse_type_(variableIndex, typeVariableMapping))))
 		}
 	    LPARENS =>
 		{
             self.lexer.backtrack();
-			Some(self.parse_type_(variableIndex, typeVariableMapping))
+			Ok(Some(try!(self.parse_type_(variableIndex, typeVariableMapping))))
 		}
 	    NAME =>
 		{
-			if (token.value.char_at(0).is_uppercase()) {
-				Some(Type::new_op(token.value, ~[]))
+			if (token.value.as_slice().char_at(0).is_uppercase()) {
+				Ok(Some(Type::new_op(token.value.to_owned(), ~[])))
 			}
 			else {
-                let t = typeVariableMapping.find_or_insert(token.value, *variableIndex);
-                *variableIndex += 1;
-				Some(Type::new_var(*t))
+                let t = type_variable(typeVariableMapping, token.value, variableIndex);
+				Ok(Some(Type::new_var(t)))
 			}
 		}
-        _ => { self.lexer.backtrack(); None }
+        _ => { self.lexer.backtrack(); Ok(None) }
 	}
 }
 
-fn parse_type_(&mut self, variableIndex: &mut int, typeVariableMapping : &mut HashMap<~str, int>) -> Type {
+fn parse_type_(&mut self, variableIndex: &mut int, typeVariableMapping : &mut HashMap<~str, int>) -> ParseResult<Type> {
+    self.nested(|this| this.parse_type__(variableIndex, typeVariableMapping))
+}
+
+fn parse_type__(&mut self, variableIndex: &mut int, typeVariableMapping : &mut HashMap<~str, int>) -> ParseResult<Type> {
 	let token = (*self.lexer.next_()).clone();
 	match token.token {
 	    LBRACKET =>
@@ -720,8 +1710,8 @@ fn parse_type_(&mut self, variableIndex: &mut int, typeVariableMapping : &mut Ha
             }
             else {
                 self.lexer.backtrack();
-                let t = self.parse_type_(variableIndex, typeVariableMapping);
-                self.requireNext(RBRACKET);
+                let t = try!(self.parse_type_(variableIndex, typeVariableMapping));
+                try!(self.requireNext(RBRACKET));
                 let listType = Type::new_op(~"[]", ~[t]);
                 
                 self.parse_return_type(listType, variableIndex, typeVariableMapping)
@@ -729,14 +1719,14 @@ fn parse_type_(&mut self, variableIndex: &mut int, typeVariableMapping : &mut Ha
 		}
 	    LPARENS =>
 		{
-			let t = self.parse_type_(variableIndex, typeVariableMapping);
+			let t = try!(self.parse_type_(variableIndex, typeVariableMapping));
 			let maybeComma = self.lexer.next_().token;
 			if (maybeComma == COMMA)
 			{
-				let mut tupleArgs = self.sepBy1(|this| this.parse_type_(variableIndex, typeVariableMapping), COMMA);
+				let mut tupleArgs = try!(self.sepBy1(|this| this.parse_type_(variableIndex, typeVariableMapping), COMMA));
 				tupleArgs.unshift(t);
                 self.lexer.backtrack();
-                self.requireNext(RPARENS);
+                try!(self.requireNext(RPARENS));
 
                 self.parse_return_type(tupleType(tupleArgs), variableIndex, typeVariableMapping)
 			}
@@ -745,77 +1735,116 @@ fn parse_type_(&mut self, variableIndex: &mut int, typeVariableMapping : &mut Ha
                 self.parse_return_type(t, variableIndex, typeVariableMapping)
 			}
             else {
-                fail!(ParseError2(&self.lexer, &[COMMA, RPARENS]))
+                Err(ParseError2(&self.lexer, &[COMMA, RPARENS]))
             }
 		}
 	    NAME =>
 		{
 			let mut typeArguments = ~[];
             loop {
-                match self.sub_type(variableIndex, typeVariableMapping) {
+                match try!(self.sub_type(variableIndex, typeVariableMapping)) {
                     Some(typ) => typeArguments.push(typ),
                     None => break
                 }
             }
 
-			let thisType = if (token.value.char_at(0).is_uppercase()) {
-				Type::new_op(token.value, typeArguments)
+			let thisType = if (token.value.as_slice().char_at(0).is_uppercase()) {
+				Type::new_op(token.value.to_owned(), typeArguments)
 			}
 			else {
-                let t = typeVariableMapping.find_or_insert(token.value, *variableIndex);
-                *variableIndex += 1;
-				let mut result = Type::new_var(*t);
-                result.types = typeArguments;
-                result
+                let t = type_variable(typeVariableMapping, token.value, variableIndex);
+				Type::new(TypeVariable(TypeVariable { id: t }), typeArguments)
 			};
 			self.parse_return_type(thisType, variableIndex, typeVariableMapping)
 		}
-	    _ => fail!("Unexpected token when parsing type {:?}", self.lexer.current())
+	    _ => Err(Diagnostic::new(token.location, format!("Unexpected token when parsing type {:?}", token.token)))
 	}
 }
 
-fn parse_return_type(&mut self, typ : Type, variableIndex: &mut int, typeVariableMapping : &mut HashMap<~str, int>) -> Type {
+fn parse_return_type(&mut self, typ : Type, variableIndex: &mut int, typeVariableMapping : &mut HashMap<~str, int>) -> ParseResult<Type> {
 
     let arrow = self.lexer.next_().token;
     if (arrow == ARROW) {
-        return function_type(&typ, &self.parse_type_(variableIndex, typeVariableMapping));
+        return Ok(function_type(&typ, &try!(self.parse_type_(variableIndex, typeVariableMapping))));
     }
     else {
         self.lexer.backtrack();
-        return typ
+        return Ok(typ)
     }
 }
 
-fn sepBy1<T>(&mut self, f : |&mut Parser<Iter>| -> T, sep : TokenEnum) -> ~[T] {
+fn sepBy1<T>(&mut self, f : |&mut Parser<Iter>| -> ParseResult<T>, sep : TokenEnum) -> ParseResult<~[T]> {
     self.sepBy1_func(f, |tok| tok.token == sep)
 }
 
-fn sepBy1_func<T>(&mut self, f : |&mut Parser<Iter>| -> T, sep : |&Token| -> bool) -> ~[T] {
+fn sepBy1_func<T>(&mut self, f : |&mut Parser<Iter>| -> ParseResult<T>, sep : |&Token| -> bool) -> ParseResult<~[T]> {
     let mut result = ~[];
     loop {
-        result.push(f(self));
+        result.push(try!(f(self)));
         if (!sep(self.lexer.next_())) {
             break;
         }
     }
-    result
+    Ok(result)
 }
 }//end impl Parser
 
-fn precedence(s : &str) -> int {
+///Parses a module from untrusted input without failing the task.
+///Every problem in the input is returned as a diagnostic instead.
+pub fn parse_module_safe(input: &str) -> Result<Module, ~[Diagnostic]> {
+    let source = input.to_owned();
+    //The parser itself reports errors through Result, running it in its own task
+    //guards against any failure which still remains in the lexer
+    let result = task::try(proc() {
+        let mut parser = Parser::new(source.chars());
+        parser.parse_module_recovering()
+    });
+    match result {
+        Ok(Ok(module)) => Ok(module),
+        Ok(Err(diagnostics)) => Err(diagnostics),
+        Err(cause) => Err(~[Diagnostic::new(Location::eof(), failure_message(&cause))])
+    }
+}
+
+///Parses a module from arbitrary bytes, reporting input which is not valid UTF-8 as a diagnostic
+pub fn parse_module_bytes_safe(input: &[u8]) -> Result<Module, ~[Diagnostic]> {
+    match from_utf8_opt(input) {
+        Some(source) => parse_module_safe(source),
+        None => Err(~[Diagnostic::new(Location::eof(), ~"Input is not valid UTF-8")])
+    }
+}
+
+///Adds the documentation of a declaration, keeping the first if a name is documented twice
+///(such as on both the type signature and the binding)
+fn add_documentation(documentation: &mut HashMap<~str, ~str>, name: &~str, doc: Option<~str>) {
+    match doc {
+        Some(doc) => {
+            if !documentation.contains_key(name) {
+                documentation.insert(name.clone(), doc);
+            }
+        }
+        None => ()
+    }
+}
+
+///The fixity of operators without a fixity declaration
+fn default_fixity(s : &str) -> (Assoc, int) {
     match s {
-        "+" => 1,
-        "-" => 1,
-        "*" => 3,
-        "/" => 3,
-        "%" => 3,
-        "==" => 1,
-        "/=" => 1,
-        "<" => 1,
-        ">" => 1,
-        "<=" => 1,
-        ">=" => 1,
-        _ => 9
+        "$" => (RightAssoc, 0),
+        ":" => (RightAssoc, 5),
+        "." => (RightAssoc, 9),
+        "+" => (LeftAssoc, 1),
+        "-" => (LeftAssoc, 1),
+        "*" => (LeftAssoc, 3),
+        "/" => (LeftAssoc, 3),
+        "%" => (LeftAssoc, 3),
+        "==" => (LeftAssoc, 1),
+        "/=" => (LeftAssoc, 1),
+        "<" => (LeftAssoc, 1),
+        ">" => (LeftAssoc, 1),
+        "<=" => (LeftAssoc, 1),
+        ">=" => (LeftAssoc, 1),
+        _ => (LeftAssoc, 9)
     }
 }
 
@@ -823,6 +1852,11 @@ fn precedence(s : &str) -> int {
 fn toplevelError(t : &Token) -> bool
 {
 	return t.token != NAME
+		&& t.token != TYPE
+		&& t.token != IMPORT
+		&& t.token != INFIXL
+		&& t.token != INFIXR
+		&& t.token != INFIX
 		&& t.token != RBRACKET
 		&& t.token != SEMICOLON
 		&& t.token != DATA
@@ -843,7 +1877,12 @@ fn bindingError(t : &Token) -> bool
 		&& t.token != NAME
 		&& t.token != TYPEDECL
 		&& t.token != OPERATOR
-		&& t.token != RPARENS;
+		&& t.token != LPARENS
+		&& t.token != RPARENS
+		&& t.token != LBRACKET
+		&& t.token != RBRACKET
+		&& t.token != NUMBER
+		&& t.token != COMMA;
 }
 
 fn constructorError(tok : &Token) -> bool
@@ -863,16 +1902,31 @@ fn tuple_name(size : uint) -> ~str
 	name
 }
 
+///Returns the name of the operator 'op', which is an identifier
+fn operator_name<'a>(op : &'a TypedExpr) -> &'a InternedStr {
+    match &op.expr {
+        &Identifier(ref name) => name,
+        _ => fail!("Expected an operator")
+    }
+}
+
+///Replaces the two expressions on top of 'output' with the application of 'op' to them
+fn apply_operator(output : &mut ~[TypedExpr], op : TypedExpr) {
+    let rhs = output.pop();
+    let lhs = output.pop();
+    output.push(makeApplication(op, ~[lhs, rhs]));
+}
+
 fn makeApplication(f : TypedExpr, args : ~[TypedExpr]) -> TypedExpr {
 	assert!(args.len() >= 1);
     let mut func = f;
 	for a in args.move_iter() {
         let loc = func.location.clone();
-		func = TypedExpr::with_location(Apply(~func, ~a), loc);
+		func = TypedExpr::with_location(Apply(~(func, a)), loc);
 	}
     func
 }
-fn makeLambda(a : ~[~str], body : TypedExpr) -> TypedExpr {
+fn makeLambda(a : ~[InternedStr], body : TypedExpr) -> TypedExpr {
     let mut args = a;
 	assert!(args.len() >= 1);
 	let mut body = body;
@@ -885,9 +1939,183 @@ fn makeLambda(a : ~[~str], body : TypedExpr) -> TypedExpr {
     body
 }
 
+///Creates a function for each field of the record constructors in 'data' which extracts the field
+fn record_selectors(data : &DataDefinition) -> ~[Binding] {
+    let mut names : ~[&~str] = ~[];
+    for constructor in data.constructors.iter() {
+        for field in constructor.fields.iter() {
+            if !names.contains(&field) {
+                names.push(field);
+            }
+        }
+    }
+    names.iter().map(|&name| {
+        let argument = intern("#arg0");
+        let alts = data.constructors.iter()
+            .filter_map(|constructor| constructor.fields.iter().position(|f| f == name).map(|index| (constructor, index)))
+            .map(|(constructor, index)| {
+                let patterns = range(0, constructor.fields.len()).map(|i| IdentifierPattern(intern(format!("{}{}", "#field", i)))).collect();
+                Alternative {
+                    pattern : Located { location : Location::eof(), node : ConstructorPattern(constructor.name.clone(), patterns) },
+                    expression : TypedExpr::new(Identifier(intern(format!("{}{}", "#field", index))))
+                }
+            })
+            .collect();
+        let body = TypedExpr::new(Case(~TypedExpr::new(Identifier(argument.clone())), alts));
+        Binding {
+            name : name.clone(),
+            typeDecl : TypeDeclaration { context : ~[], typ : Type::new_var(-1), name : ~"", location : Location::eof() },
+            expression : makeLambda(~[argument], body),
+            arity : 1
+        }
+    }).collect()
+}
+
+///Creates the pattern for a name without arguments, names starting with an uppercase letter are constructors
+fn variable_or_constructor_pattern(name : InternedStr) -> Pattern {
+    if name.as_slice().char_at(0).is_uppercase() {
+        ConstructorPattern(name.to_owned(), ~[])
+    }
+    else {
+        IdentifierPattern(name)
+    }
+}
+
+///Merges each run of consecutive equations of the same function into a single binding
+fn make_bindings(clauses : ~[Clause]) -> ParseResult<~[Binding]> {
+    let mut bindings = ~[];
+    let mut group : ~[Clause] = ~[];
+    for clause in clauses.move_iter() {
+        let same_function = group.len() > 0 && clause.arguments.len() > 0 && group[0].name == clause.name;
+        if !same_function && group.len() > 0 {
+            bindings.push(try!(make_binding(::std::util::replace(&mut group, ~[]))));
+        }
+        group.push(clause);
+    }
+    if group.len() > 0 {
+        bindings.push(try!(make_binding(group)));
+    }
+    Ok(bindings)
+}
+
+///Desugars the 'index'th binding of a let block, 'pattern = expression', into a binding of the expression
+///and one binding for each variable in the pattern which extracts that variable, 'x = case #pattern0 of (x, y) -> x'
+fn pattern_binding_clauses(index : uint, pattern : Located<Pattern>, expression : TypedExpr) -> ~[Clause] {
+    let location = pattern.location;
+    let name = format!("{}{}", "#pattern", index);
+    let mut variables = ~[];
+    pattern_variables(&pattern.node, &mut variables);
+    let mut clauses = ~[Clause { name : name.clone(), location : location, arguments : ~[], expression : expression }];
+    for variable in variables.iter() {
+        let scrutinee = TypedExpr::with_location(Identifier(intern(name)), location);
+        let alt = Alternative {
+            pattern : Located { location : location, node : pattern.node.clone() },
+            expression : TypedExpr::with_location(Identifier(*variable), location)
+        };
+        let projection = TypedExpr::with_location(Case(~scrutinee, ~[alt]), location);
+        clauses.push(Clause { name : variable.to_owned(), location : location, arguments : ~[], expression : projection });
+    }
+    clauses
+}
+
+///Creates the pattern which matches the string 's', a string is a list of characters
+///so "ab" is matched by the same pattern as 'a' : 'b' : []
+fn string_pattern(s: &str) -> Pattern {
+    let mut pattern = ConstructorPattern(~"[]", ~[]);
+    for c in s.chars_rev() {
+        pattern = ConstructorPattern(~":", ~[CharPattern(c), pattern]);
+    }
+    pattern
+}
+
+///Adds the variables bound by 'pattern' to 'variables', the wildcard '_' binds nothing
+fn pattern_variables(pattern : &Pattern, variables : &mut ~[InternedStr]) {
+    match *pattern {
+        IdentifierPattern(name) if "_" == name.as_slice() => (),
+        IdentifierPattern(name) => variables.push(name),
+        ConstructorPattern(_, ref patterns) => {
+            for p in patterns.iter() {
+                pattern_variables(p, variables);
+            }
+        }
+        NumberPattern(_) => (),
+        CharPattern(_) => ()
+    }
+}
+
+///Creates the binding for the equations of a function.
+///A function with several equations becomes a case expression over a tuple of its arguments
+///with one alternative for each equation.
+fn make_binding(clauses : ~[Clause]) -> ParseResult<Binding> {
+    let mut clauses = clauses;
+    let arity = clauses[0].arguments.len();
+    let name = clauses[0].name.clone();
+    for clause in clauses.iter() {
+        if clause.arguments.len() != arity {
+            return Err(Diagnostic::new(clause.location, format!("The equations of '{}' have different numbers of arguments", name)));
+        }
+    }
+    let expression = if clauses.len() == 1 {
+        let Clause { arguments, expression, .. } = clauses.pop();
+        if arity == 0 {
+            expression
+        }
+        else {
+            let location = expression.location;
+            makePatternLambda(location, arguments, expression)
+        }
+    }
+    else {
+        let location = clauses[0].location;
+        let arguments : ~[InternedStr] = range(0, arity).map(|i| intern(format!("{}{}", "#arg", i))).collect();
+        let mut scrutinees : ~[TypedExpr] = arguments.iter()
+            .map(|arg| TypedExpr::with_location(Identifier(arg.clone()), location))
+            .collect();
+        let scrutinee = if arity == 1 { scrutinees.pop() } else { newTuple(scrutinees) };
+        let alts : ~[Alternative] = clauses.move_iter().map(|clause| {
+            let Clause { location, arguments, expression, .. } = clause;
+            let pattern = if arity == 1 {
+                let mut arguments = arguments;
+                arguments.pop()
+            }
+            else {
+                ConstructorPattern(tuple_name(arguments.len()), arguments)
+            };
+            Alternative { pattern : Located { location : location, node : pattern }, expression : expression }
+        }).collect();
+        makeLambda(arguments, TypedExpr::with_location(Case(~scrutinee, alts), location))
+    };
+    Ok(Binding { name : name, typeDecl : TypeDeclaration { context : ~[], typ : Type::new_var(-1), name : ~"", location : Location::eof() }, expression : expression, arity : arity })
+}
+
+///Creates a lambda taking one argument for each pattern.
+///Arguments which are not plain variables are bound to a generated name which is matched
+///against the pattern by a case expression around the body.
+fn makePatternLambda(location : Location, patterns : ~[Pattern], body : TypedExpr) -> TypedExpr {
+    let mut arguments = ~[];
+    let mut body = body;
+    let count = patterns.len();
+    for (i, pattern) in patterns.move_rev_iter().enumerate() {
+        let argument = match pattern {
+            IdentifierPattern(name) => name,
+            pattern => {
+                let name = intern(format!("{}{}", "#arg", count - 1 - i));
+                let scrutinee = TypedExpr::with_location(Identifier(name.clone()), location);
+                let alt = Alternative { pattern : Located { location : location, node : pattern }, expression : body };
+                body = TypedExpr::with_location(Case(~scrutinee, ~[alt]), location);
+                name
+            }
+        };
+        arguments.unshift(argument);
+    }
+    let mut lambda = makeLambda(arguments, body);
+    lambda.location = location;
+    lambda
+}
+
 //Create a tuple with the constructor name inferred from the number of arguments passed in
 fn newTuple(arguments : ~[TypedExpr]) -> TypedExpr {
-	let name = TypedExpr::new(Identifier(tuple_name(arguments.len())));
+	let name = TypedExpr::new(Identifier(intern(tuple_name(arguments.len()))));
 	makeApplication(name, arguments)
 }
 
@@ -908,7 +2136,9 @@ fn applicationError(t :&Token) -> bool
 		&& t.token != FLOAT
 		&& t.token != OPERATOR
 		&& t.token != SEMICOLON
-		&& t.token != COMMA;
+		&& t.token != COMMA
+		&& t.token != THEN
+		&& t.token != ELSE;
 }
 
 
@@ -926,45 +2156,69 @@ fn errorIfNotRParens(tok : &Token) -> bool {
 	tok.token != RPARENS
 }
 
-fn createTypeConstraints(context : Type) -> ~[Constraint] {
+fn createTypeConstraints(location : Location, context : Type) -> ParseResult<~[Constraint]> {
 	let mut mapping = ~[];
 
-	if (context.op().name.char_at(0) == '(') {
-		for t in context.types.move_iter() {
-            match t {
-                Type { typ: TypeOperator(op), types: types } => {
-		            mapping.push(Constraint { class: op.name, variables: ~[types[0].var().clone()] });
-                }
-                _ => fail!("Expected TypeOperator when creating constraints")
-            }
+    let is_tuple = match &context.typ {
+        &TypeOperator(ref op) => op.name.char_at(0) == '(',
+        &TypeVariable(_) => false
+    };
+	if (is_tuple) {
+		let (_, types) = context.unwrap();
+		for t in types.move_iter() {
+            mapping.push(try!(createTypeConstraint(location, t)));
 		}
 	}
 	else {
-        match context {
-            Type { typ: TypeOperator(op), types: types } => {
-		        mapping.push(Constraint { class: op.name, variables: ~[types[0].var().clone()] });
+        mapping.push(try!(createTypeConstraint(location, context)));
+	}
+	Ok(mapping)
+}
+
+fn createTypeConstraint(location : Location, context : Type) -> ParseResult<Constraint> {
+    match context.unwrap() {
+        (TypeOperator(op), types) => {
+            if types.len() == 1 {
+                match &types[0].typ {
+                    &TypeVariable(ref var) => return Ok(Constraint { class: op.name, variables: ~[var.clone()] }),
+                    &TypeOperator(_) => ()
+                }
             }
-            _ => fail!("Expected TypeOperator when creating constraints")
+            Err(Diagnostic::new(location, format!("Expected the constraint {} to be applied to a single type variable", op.name)))
         }
-	}
-	mapping
+        _ => Err(Diagnostic::new(location, ~"Expected TypeOperator when creating constraints"))
+    }
+}
+
+///Returns the id of the type variable called 'name', the name is only copied into the mapping the first time it is seen
+fn type_variable(mapping: &mut HashMap<~str, int>, name: InternedStr, variableIndex: &mut int) -> int {
+    let id = *variableIndex;
+    *variableIndex += 1;
+    match mapping.find_equiv(&name.as_slice()) {
+        Some(t) => return *t,
+        None => ()
+    }
+    mapping.insert(name.to_owned(), id);
+    id
 }
 
 fn tupleType(types : ~[Type]) -> Type {
 	Type::new_op(tuple_name(types.len()), types)
 }
 
-fn ParseError2<Iter : Iterator<char>>(lexer : &Lexer<Iter>, expected : &[TokenEnum]) -> ~str {
-    format!("Expected {:?} but found {:?}\\{{:?}\\}, at {}", expected, lexer.current().token, lexer.current().value, lexer.current().location)
-    
+fn ParseError2<Iter : Iterator<char>>(lexer : &Lexer<Iter>, expected : &[TokenEnum]) -> Diagnostic {
+    let message = format!("Expected {:?} but found {:?}\\{{}\\}", expected, lexer.current().token, lexer.current().value);
+    Diagnostic::new(lexer.current().location, message)
 }
-fn ParseError<Iter : Iterator<char>>(lexer : &Lexer<Iter>, expected : TokenEnum) -> ~str {
-    format!("Expected {:?} but found {:?}\\{{:?}\\}, at {}", expected, lexer.current().token, lexer.current().value, lexer.current().location)
+fn ParseError<Iter : Iterator<char>>(lexer : &Lexer<Iter>, expected : TokenEnum) -> Diagnostic {
+    let message = format!("Expected {:?} but found {:?}\\{{}\\}", expected, lexer.current().token, lexer.current().value);
+    Diagnostic::new(lexer.current().location, message)
 }
 fn encodeBindingIdentifier(instancename : &str, bindingname : &str) -> ~str {
     "#" + instancename.clone() + bindingname.clone()
 }
 
+
 #[cfg(test)]
 mod tests {
 
@@ -972,6 +2226,7 @@ use parser::*;
 use module::*;
 use typecheck::{function_type, identifier, apply, number, rational, lambda, let_, case};
 use std::io::File;
+use std::io::mem::MemReader;
 use std::str::from_utf8;
 
 
@@ -986,7 +2241,7 @@ fn simple()
 fn binding()
 {
     let mut parser = Parser::new("test x = x + 3".chars());
-    let bind = parser.binding();
+    let bind = parser.binding().unwrap();
     assert_eq!(bind.expression, lambda(~"x", apply(apply(identifier(~"+"), identifier(~"x")), number(3))));
     assert_eq!(bind.name, ~"test");
 }
@@ -995,11 +2250,32 @@ fn binding()
 fn double()
 {
     let mut parser = Parser::new("test = 3.14".chars());
-    let bind = parser.binding();
+    let bind = parser.binding().unwrap();
     assert_eq!(bind.expression, rational(3.14));
     assert_eq!(bind.name, ~"test");
 }
 
+#[test]
+fn parse_from_reader() {
+    let input = "double x = x + x\nmain = double 2";
+    let mut parser = Parser::from_reader(MemReader::new(input.as_bytes().to_owned()));
+    let module = parser.module();
+    assert_eq!(module.bindings.len(), 2);
+    assert_eq!(module.bindings[1].name, ~"main");
+}
+
+#[test]
+fn parse_module_reports_lexer_errors() {
+    let mut parser = Parser::new(
+r"a = 'xy'
+b = 2
+c = 3 % 4".chars());
+    let errors = parser.parse_module_recovering().unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].location.row, 1);
+    assert_eq!(errors[1].location.row, 3);
+}
+
 #[test]
 fn parse_let() {
     let mut parser = Parser::new(
@@ -1013,6 +2289,53 @@ in test - 2".chars());
     assert_eq!(expr, let_(~[bind], apply(apply(identifier(~"-"), identifier(~"test")), number(2))));
 }
 
+#[test]
+fn parse_let_pattern_binding() {
+    let mut parser = Parser::new(
+r"
+let
+    (x, y) = pair
+in x".chars());
+    let expr = parser.expression_();
+    let pattern = ConstructorPattern(~"(,)", ~[IdentifierPattern(intern("x")), IdentifierPattern(intern("y"))]);
+    let projection = |name: ~str| Binding { arity: 0, name: name.clone(), typeDecl: Default::default(),
+        expression: case(identifier(~"#pattern0"), ~[Alternative { pattern: Located { location: Location::eof(), node: pattern.clone() },
+            expression: identifier(name) }]) };
+    let pair = Binding { arity: 0, name: ~"#pattern0", typeDecl: Default::default(), expression: identifier(~"pair") };
+    assert_eq!(expr, let_(~[pair, projection(~"x"), projection(~"y")], identifier(~"x")));
+}
+
+#[test]
+fn parse_let_pattern_binding_wildcard() {
+    let mut parser = Parser::new(
+r"
+let
+    (x, _) = pair
+in x".chars());
+    let expr = parser.expression_();
+    match expr.expr {
+        Let(bindings, _) => {
+            let names: ~[~str] = bindings.iter().map(|bind| bind.name.clone()).collect();
+            assert_eq!(names, ~[~"#pattern0", ~"x"]);
+        }
+        _ => fail!("Expected let")
+    }
+}
+
+#[test]
+fn parse_let_type_declaration() {
+    let mut parser = Parser::new(r"let { f :: Int -> Int ; f x = x } in f".chars());
+    let expr = parser.expression_();
+    match expr.expr {
+        Let(bindings, _) => {
+            assert_eq!(bindings[0].typeDecl.name, ~"f");
+            let int_type = Type::new_op(~"Int", ~[]);
+            assert_eq!(bindings[0].typeDecl.typ, function_type(&int_type, &int_type));
+        }
+        _ => fail!("Expected let")
+    }
+}
+
 #[test]
 fn parse_case() {
     let mut parser = Parser::new(
@@ -1024,7 +2347,7 @@ r"case [] of
     let alt = Alternative {
         pattern: Located {
             location: Location::eof(),
-            node: ConstructorPattern(~":", ~[IdentifierPattern(~"x"), IdentifierPattern(~"xs")])
+            node: ConstructorPattern(~":", ~[IdentifierPattern(intern("x")), IdentifierPattern(intern("xs"))])
         },
         expression: identifier(~"x") };
     let alt2 = Alternative {
@@ -1033,11 +2356,310 @@ r"case [] of
     assert_eq!(expression, case(identifier(~"[]"), ~[alt, alt2]));
 }
 
+#[test]
+fn parse_layout_blocks_closed_by_other_tokens() {
+    let mut parser = Parser::new(
+r"f x = (case x of
+        Just y -> y
+        Nothing -> 0, 1)
+g x = if x then case x of
+        True -> 1 else 2
+h x = [case x of
+        y -> y]".chars());
+    let module = parser.module();
+    let names : ~[~str] = module.bindings.iter().map(|bind| bind.name.clone()).collect();
+    assert_eq!(names, ~[~"f", ~"g", ~"h"]);
+}
+
+#[test]
+fn parse_empty_layout_block() {
+    let mut parser = Parser::new(
+r"class Marker a where
+main = 1".chars());
+    let module = parser.module();
+    assert_eq!(module.classes[0].declarations.len(), 0);
+    assert_eq!(module.bindings[0].name, ~"main");
+}
+
+#[test]
+fn parse_lambda_case() {
+    let mut parser = Parser::new(r"\case { [] -> 1 ; : x xs -> x }".chars());
+    let expression = parser.expression_();
+    let alt = Alternative {
+        pattern: Located { location: Location::eof(), node: ConstructorPattern(~"[]", ~[]) },
+        expression: number(1) };
+    let alt2 = Alternative {
+        pattern: Located {
+            location: Location::eof(),
+            node: ConstructorPattern(~":", ~[IdentifierPattern(intern("x")), IdentifierPattern(intern("xs"))])
+        },
+        expression: identifier(~"x") };
+    assert_eq!(expression, lambda(~"#arg0", case(identifier(~"#arg0"), ~[alt, alt2])));
+}
+
+#[test]
+fn parse_string_pattern() {
+    let mut parser = Parser::new(r#"case s of { "ab" -> 1 ; : 'c' xs -> 2 }"#.chars());
+    let expression = parser.expression_();
+    let chars = ConstructorPattern(~":", ~[CharPattern('a'), ConstructorPattern(~":", ~[CharPattern('b'), ConstructorPattern(~"[]", ~[])])]);
+    let alt = Alternative {
+        pattern: Located { location: Location::eof(), node: chars },
+        expression: number(1) };
+    let alt2 = Alternative {
+        pattern: Located { location: Location::eof(), node: ConstructorPattern(~":", ~[CharPattern('c'), IdentifierPattern(intern("xs"))]) },
+        expression: number(2) };
+    assert_eq!(expression, case(identifier(~"s"), ~[alt, alt2]));
+}
+
+#[test]
+fn parse_if_then_else() {
+    let mut parser = Parser::new("if primIntLT x 2 then 1 else x".chars());
+    let expression = parser.expression_();
+    let true_alt = Alternative {
+        pattern: Located { location: Location::eof(), node: ConstructorPattern(~"True", ~[]) },
+        expression: number(1) };
+    let false_alt = Alternative {
+        pattern: Located { location: Location::eof(), node: ConstructorPattern(~"False", ~[]) },
+        expression: identifier(~"x") };
+    let condition = apply(apply(identifier(~"primIntLT"), identifier(~"x")), number(2));
+    assert_eq!(expression, case(condition, ~[true_alt, false_alt]));
+}
+
+#[test]
+fn parse_lambda() {
+    let mut parser = Parser::new(r"\x (a, b) -> primIntAdd x b".chars());
+    let expression = parser.expression_();
+    let alt = Alternative {
+        pattern: Located {
+            location: Location::eof(),
+            node: ConstructorPattern(~"(,)", ~[IdentifierPattern(intern("a")), IdentifierPattern(intern("b"))])
+        },
+        expression: apply(apply(identifier(~"primIntAdd"), identifier(~"x")), identifier(~"b")) };
+    assert_eq!(expression, lambda(~"x", lambda(~"#arg1", case(identifier(~"#arg1"), ~[alt]))));
+}
+
+#[test]
+fn parse_binding_patterns() {
+    let mut parser = Parser::new(
+r"f 0 y = y
+f x (a, b) = a
+g = 1".chars());
+    let module = parser.module();
+    assert_eq!(module.bindings.len(), 2);
+    let f = &module.bindings[0];
+    assert_eq!(f.arity, 2);
+    let alt = Alternative {
+        pattern: Located {
+            location: Location::eof(),
+            node: ConstructorPattern(~"(,)", ~[NumberPattern(0), IdentifierPattern(intern("y"))])
+        },
+        expression: identifier(~"y") };
+    let tuple = ConstructorPattern(~"(,)", ~[IdentifierPattern(intern("a")), IdentifierPattern(intern("b"))]);
+    let alt2 = Alternative {
+        pattern: Located {
+            location: Location::eof(),
+            node: ConstructorPattern(~"(,)", ~[IdentifierPattern(intern("x")), tuple])
+        },
+        expression: identifier(~"a") };
+    let scrutinee = apply(apply(identifier(~"(,)"), identifier(~"#arg0")), identifier(~"#arg1"));
+    assert_eq!(f.expression, lambda(~"#arg0", lambda(~"#arg1", case(scrutinee, ~[alt, alt2]))));
+    assert_eq!(module.bindings[1].name, ~"g");
+}
+
+#[test]
+fn parse_binding_different_arities() {
+    let mut parser = Parser::new(
+r"f 0 y = y
+f x = x".chars());
+    assert!(parser.parse_module().is_err());
+}
+
+#[test]
+fn parse_nested_patterns() {
+    let mut parser = Parser::new(
+r"case x of
+    Just (Left (a, b)) -> a
+    (Nothing) -> 2
+".chars());
+    let expression = parser.expression_();
+    let tuple = ConstructorPattern(~"(,)", ~[IdentifierPattern(intern("a")), IdentifierPattern(intern("b"))]);
+    let alt = Alternative {
+        pattern: Located {
+            location: Location::eof(),
+            node: ConstructorPattern(~"Just", ~[ConstructorPattern(~"Left", ~[tuple])])
+        },
+        expression: identifier(~"a") };
+    let alt2 = Alternative {
+        pattern: Located { location: Location::eof(), node: ConstructorPattern(~"Nothing", ~[]) },
+        expression: number(2) };
+    assert_eq!(expression, case(identifier(~"x"), ~[alt, alt2]));
+}
+
+#[test]
+fn parse_fixity_declarations() {
+    let mut parser = Parser::new(
+r"infixl 7 <+>, `op`
+infix 2 ===
+test = 1 + 2 <+> 3 === 4".chars());
+    let module = parser.module();
+    assert_eq!(module.fixityDeclarations, ~[
+        FixityDeclaration { assoc: LeftAssoc, precedence: 7, operators: ~[~"<+>", ~"op"] },
+        FixityDeclaration { assoc: NoAssoc, precedence: 2, operators: ~[~"==="] }]);
+    let rhs = apply(apply(identifier(~"==="), apply(apply(identifier(~"<+>"), number(2)), number(3))), number(4));
+    assert_eq!(module.bindings[0].expression, apply(apply(identifier(~"+"), number(1)), rhs));
+}
+
+#[test]
+fn fixity_declaration_after_use() {
+    let mut parser = Parser::new(
+r"test = 1 <+> 2 * 3 <+> (4 <+> 5)
+infixr 2 <+>".chars());
+    let module = parser.module();
+    let inner = apply(apply(identifier(~"<+>"), number(4)), number(5));
+    let rhs = apply(apply(identifier(~"<+>"), apply(apply(identifier(~"*"), number(2)), number(3))), inner);
+    assert_eq!(module.bindings[0].expression, apply(apply(identifier(~"<+>"), number(1)), rhs));
+}
+
+#[test]
+fn parse_prefix_operator_definition() {
+    let mut parser = Parser::new(
+r"(+) a b = primIntAdd a b
+(<+>) (Just x) y = x
+(f) x = x".chars());
+    let module = parser.module();
+    assert_eq!(module.bindings.len(), 3);
+    assert_eq!(module.bindings[0].name, ~"+");
+    assert_eq!(module.bindings[0].arity, 2);
+    assert_eq!(module.bindings[0].expression,
+        lambda(~"a", lambda(~"b", apply(apply(identifier(~"primIntAdd"), identifier(~"a")), identifier(~"b")))));
+    assert_eq!(module.bindings[1].name, ~"<+>");
+    assert_eq!(module.bindings[1].arity, 2);
+    assert_eq!(module.bindings[2].name, ~"f");
+    assert_eq!(module.bindings[2].arity, 1);
+}
+
+#[test]
+fn parse_infix_definition() {
+    let mut parser = Parser::new(
+r"x <+> y = primIntAdd x y
+(Just x) `orElse` y = x
+Nothing `orElse` y = y".chars());
+    let module = parser.module();
+    assert_eq!(module.bindings.len(), 2);
+    assert_eq!(module.bindings[0].name, ~"<+>");
+    assert_eq!(module.bindings[0].arity, 2);
+    assert_eq!(module.bindings[0].expression,
+        lambda(~"x", lambda(~"y", apply(apply(identifier(~"primIntAdd"), identifier(~"x")), identifier(~"y")))));
+    assert_eq!(module.bindings[1].name, ~"orElse");
+    assert_eq!(module.bindings[1].arity, 2);
+}
+
+#[test]
+fn parse_type_synonym() {
+    let mut parser = Parser::new(
+r"type Pair a b = (a, b)
+type Name = [Char]
+test = 1".chars());
+    let module = parser.module();
+    let a = Type::new_var(0);
+    let b = Type::new_var(1);
+    assert_eq!(module.typeSynonyms, ~[
+        TypeSynonym { name: ~"Pair", parameters: ~[TypeVariable { id: 0 }, TypeVariable { id: 1 }],
+            typ: Type::new_op(~"(,)", ~[a, b]) },
+        TypeSynonym { name: ~"Name", parameters: ~[], typ: Type::new_op(~"[]", ~[Type::new_op(~"Char", ~[])]) }]);
+}
+
+#[test]
+fn parse_empty_class_and_instance() {
+    let mut parser = Parser::new(
+r"class Marker a
+
+instance Marker Int
+
+test = 1".chars());
+    let module = parser.module();
+    assert_eq!(module.classes[0].name, ~"Marker");
+    assert_eq!(module.classes[0].declarations.len(), 0);
+    assert_eq!(module.instances[0].classname, ~"Marker");
+    assert_eq!(module.instances[0].bindings.len(), 0);
+    assert_eq!(module.bindings.len(), 1);
+}
+
+#[test]
+fn parse_imports() {
+    let mut parser = Parser::new(
+r"import Prelude
+import Data
+test = 1".chars());
+    let module = parser.module();
+    assert_eq!(module.imports, ~[Import { module: ~"Prelude" }, Import { module: ~"Data" }]);
+    assert_eq!(module.bindings.len(), 1);
+}
+
+#[test]
+fn parse_hierarchical_module_names() {
+    let mut parser = Parser::new(
+r"module Data.Tree.Binary where { import Data.List ; test = 1 }".chars());
+    let module = parser.module();
+    assert_eq!(module.name, ~"Data.Tree.Binary");
+    assert_eq!(module.imports, ~[Import { module: ~"Data.List" }]);
+}
+
+#[test]
+fn parse_export_list() {
+    let mut parser = Parser::new(
+r"module Test (foo, (<+>), Maybe(..), Either(Left), Abstract) where { foo = 1 }".chars());
+    let module = parser.module();
+    assert_eq!(module.exports, Some(~[ExportValue(~"foo"), ExportValue(~"<+>"), ExportType(~"Maybe", None),
+        ExportType(~"Either", Some(~[~"Left"])), ExportType(~"Abstract", Some(~[]))]));
+    assert!(module.is_exported("foo"));
+    assert!(!module.is_exported("Right"));
+}
+
+#[test]
+fn parse_records() {
+    let mut parser = Parser::new(
+r"data Person = Person { name :: [Char], age :: Int }
+test = Person { age = 3, name = [] }
+older p = p { age = 4 }".chars());
+    let module = parser.module();
+    let person = &module.dataDefinitions[0].constructors[0];
+    assert_eq!(person.fields, ~[~"name", ~"age"]);
+    assert_eq!(person.arity, 2);
+    let test = module.bindings.iter().find(|bind| bind.name == ~"test").unwrap();
+    assert_eq!(test.expression, apply(apply(identifier(~"Person"), identifier(~"[]")), number(3)));
+    assert!(module.bindings.iter().any(|bind| bind.name == ~"name" && bind.arity == 1));
+    assert!(module.bindings.iter().any(|bind| bind.name == ~"age" && bind.arity == 1));
+}
+
+#[test]
+fn parse_record_update_errors() {
+    //Both types have constructors with the fields so it is not known which type is updated
+    let mut parser = Parser::new(
+r"data Person = Person { name :: [Char], age :: Int }
+data Pet = Pet { name :: [Char], age :: Int }
+test p = p { age = 4 }".chars());
+    assert!(parser.parse_module().is_err());
+
+    let mut parser = Parser::new(
+r"data Person = Person { name :: [Char], age :: Int }
+test p = p { age = 4, age = 5 }".chars());
+    assert!(parser.parse_module().is_err());
+}
+
+#[test]
+fn parse_record_missing_field() {
+    let mut parser = Parser::new(
+r"data Person = Person { name :: [Char], age :: Int }
+test = Person { age = 3 }".chars());
+    assert!(parser.parse_module().is_err());
+}
+
 #[test]
 fn parse_type() {
     let mut parser = Parser::new(
 r"(.) :: (b -> c) -> (a -> b) -> (a -> c)".chars());
-    let typeDecl = parser.typeDeclaration();
+    let typeDecl = parser.typeDeclaration().unwrap();
     let a = &Type::new_var(0);
     let b = &Type::new_var(1);
     let c = &Type::new_var(2);
@@ -1050,11 +2672,11 @@ r"(.) :: (b -> c) -> (a -> b) -> (a -> c)".chars());
 fn parse_data() {
     let mut parser = Parser::new(
 r"data Bool = True | False".chars());
-    let data = parser.dataDefinition();
+    let data = parser.dataDefinition().unwrap();
 
     let Bool = Type::new_op(~"Bool", ~[]);
-    let True = Constructor { name: ~"True", tag:0, arity:0, typ: Bool.clone() };
-    let False = Constructor { name: ~"False", tag:1, arity:0, typ: Bool.clone() };
+    let True = Constructor { name: ~"True", tag:0, arity:0, typ: Bool.clone(), fields: ~[] };
+    let False = Constructor { name: ~"False", tag:1, arity:0, typ: Bool.clone(), fields: ~[] };
     assert_eq!(data.typ, Bool);
     assert_eq!(data.constructors[0], True);
     assert_eq!(data.constructors[1], False);
@@ -1064,16 +2686,57 @@ r"data Bool = True | False".chars());
 fn parse_data_2() {
     let mut parser = Parser::new(
 r"data List a = Cons a (List a) | Nil".chars());
-    let data = parser.dataDefinition();
+    let data = parser.dataDefinition().unwrap();
 
     let List = Type::new_op(~"List", ~[Type::new_var(0)]);
-    let Cons = Constructor { name: ~"Cons", tag:0, arity:2, typ: function_type(&Type::new_var(0), &function_type(&List, &List))};
-    let Nil = Constructor { name: ~"Nil", tag:1, arity:0, typ: List.clone() };
+    let Cons = Constructor { name: ~"Cons", tag:0, arity:2, typ: function_type(&Type::new_var(0), &function_type(&List, &List)), fields: ~[] };
+    let Nil = Constructor { name: ~"Nil", tag:1, arity:0, typ: List.clone(), fields: ~[] };
     assert_eq!(data.typ, List);
     assert_eq!(data.constructors[0], Cons);
     assert_eq!(data.constructors[1], Nil);
 }
 
+#[test]
+fn parse_data_parameters() {
+    let mut parser = Parser::new(
+r"data Either a b = Left a | Right b".chars());
+    let data = parser.dataDefinition().unwrap();
+    let (a, b) = (Type::new_var(-1), Type::new_var(-2));
+    let Either = Type::new_op(~"Either", ~[a.clone(), b.clone()]);
+    assert_eq!(data.typ.types()[0].var().id, -1);
+    assert_eq!(data.typ.types()[1].var().id, -2);
+    assert_eq!(data.constructors[0].typ.types()[0].var().id, -1);
+    assert_eq!(data.constructors[1].typ.types()[0].var().id, -2);
+    assert_eq!(data.constructors[0].typ, function_type(&a, &Either));
+    assert_eq!(data.constructors[1].typ, function_type(&b, &Either));
+}
+
+#[test]
+fn parse_data_context() {
+    let mut parser = Parser::new(
+r"data Eq a => Set a = Set [a]".chars());
+    let data = parser.dataDefinition().unwrap();
+    assert_eq!(data.context, ~[Constraint { class: ~"Eq", variables: ~[TypeVariable { id: -1 }] }]);
+    assert_eq!(data.constructors[0].name, ~"Set");
+
+    let mut parser = Parser::new(r"data Eq b => Set a = Set [a]".chars());
+    assert!(parser.dataDefinition().is_err());
+}
+
+#[test]
+fn parse_data_deriving() {
+    let mut parser = Parser::new(r"data Maybe a = Just a | Nothing deriving (Eq, Show)".chars());
+    let data = parser.dataDefinition().unwrap();
+    assert_eq!(data.constructors.len(), 2);
+    assert_eq!(data.deriving, ~[~"Eq", ~"Show"]);
+
+    let mut parser = Parser::new(r"data Bool = True | False deriving Ord".chars());
+    assert_eq!(parser.dataDefinition().unwrap().deriving, ~[~"Ord"]);
+
+    let mut parser = Parser::new(r"data Bool = True | False deriving (Num)".chars());
+    assert!(parser.dataDefinition().is_err());
+}
+
 #[test]
 fn parse_tuple() {
     let mut parser = Parser::new(
@@ -1083,6 +2746,14 @@ r"(1, x)".chars());
     assert_eq!(expr, apply(apply(identifier(~"(,)"), number(1)), identifier(~"x")));
 }
 
+#[test]
+fn parse_tuple_constructor() {
+    let mut parser = Parser::new(r"(,,) 1 x".chars());
+    let expr = parser.expression_();
+
+    assert_eq!(expr, apply(apply(identifier(~"(,,)"), number(1)), identifier(~"x")));
+}
+
 #[test]
 fn test_operators() {
     let mut parser = Parser::new("1 : 2 : []".chars());
@@ -1090,6 +2761,64 @@ fn test_operators() {
     assert_eq!(expr, apply(apply(identifier(~":"), number(1)), apply(apply(identifier(~":"), number(2)), identifier(~"[]"))));
 }
 
+#[test]
+fn operator_associativity() {
+    let mut parser = Parser::new("1 - 2 - 3".chars());
+    let expr = parser.expression_();
+    assert_eq!(expr, apply(apply(identifier(~"-"), apply(apply(identifier(~"-"), number(1)), number(2))), number(3)));
+
+    let mut parser = Parser::new("f $ g $ x".chars());
+    let expr = parser.expression_();
+    assert_eq!(expr, apply(apply(identifier(~"$"), identifier(~"f")), apply(apply(identifier(~"$"), identifier(~"g")), identifier(~"x"))));
+
+    let mut parser = Parser::new("f . g . h $ 1 * 2 : []".chars());
+    let expr = parser.expression_();
+    let compose = |f: TypedExpr, g: TypedExpr| apply(apply(identifier(~"."), f), g);
+    let functions = compose(identifier(~"f"), compose(identifier(~"g"), identifier(~"h")));
+    let list = apply(apply(identifier(~":"), apply(apply(identifier(~"*"), number(1)), number(2))), identifier(~"[]"));
+    assert_eq!(expr, apply(apply(identifier(~"$"), functions), list));
+}
+
+#[test]
+fn non_associative_operators_can_not_be_chained() {
+    let mut parser = Parser::new(
+r"infix 4 ===
+test = 1 === 2 === 3".chars());
+    assert!(parser.parse_module().is_err());
+}
+
+#[test]
+fn non_associative_operator_declared_after_use() {
+    let mut parser = Parser::new(
+r"test = 1 === 2 === 3
+infix 4 ===".chars());
+    assert!(parser.parse_module().is_err());
+}
+
+#[test]
+fn parse_instance_compound_head() {
+    let mut parser = Parser::new(
+r"instance Eq a => Eq (Maybe [a]) where
+    (==) x y = undefined
+
+instance Show (a, b) where
+    show x = undefined".chars());
+    let module = parser.module();
+    let list = Type::new_op(~"[]", ~[Type::new_var(0)]);
+    assert_eq!(module.instances[0].typ, Type::new_op(~"Maybe", ~[list]));
+    assert_eq!(module.instances[0].bindings[0].name, ~"#Maybe([])==");
+    assert_eq!(module.instances[1].bindings[0].name, ~"#(,)show");
+    assert_eq!(module.instances[1].typ, Type::new_op(~"(,)", ~[Type::new_var(0), Type::new_var(1)]));
+}
+
+#[test]
+fn parse_operator_as_expression() {
+    let mut parser = Parser::new("foldr (:) [] (map (+) xs)".chars());
+    let expr = parser.expression_();
+    let map = apply(apply(identifier(~"map"), identifier(~"+")), identifier(~"xs"));
+    assert_eq!(expr, apply(apply(apply(identifier(~"foldr"), identifier(~":")), identifier(~"[]")), map));
+}
+
 #[test]
 fn parse_instance_class() {
     let mut parser = Parser::new(
@@ -1106,6 +2835,19 @@ instance Eq a => Eq [a] where
     assert_eq!(module.instances[0].typ, Type::new_op(~"[]", ~[Type::new_var(0)]));
 }
 
+#[test]
+fn parse_superclass() {
+    let mut parser = Parser::new(
+r"class Eq a => Ord a where
+    (<) :: a -> a -> Bool".chars());
+    let module = parser.module();
+
+    let class = &module.classes[0];
+    assert_eq!(class.name, ~"Ord");
+    assert_eq!(class.constraints, ~[Constraint { class: ~"Eq", variables: ~[class.variable.clone()] }]);
+    assert_eq!(class.declarations[0].typ, function_type(&Type::new_var(class.variable.id), &function_type(&Type::new_var(class.variable.id), &Type::new_op(~"Bool", ~[]))));
+}
+
 #[test]
 fn parse_prelude() {
     let path = &Path::new("Prelude.hs");
@@ -1119,4 +2861,57 @@ fn parse_prelude() {
     assert!(module.classes.iter().any(|class| class.name == ~"Eq"));
 }
 
+
+#[test]
+fn parse_module_safe_errors() {
+    assert!(parse_module_safe("main = (1, ").is_err());
+    assert!(parse_module_safe("main = case x of").is_err());
+    assert!(parse_module_safe("data = ").is_err());
+    assert!(parse_module_safe("main").is_err());
+    assert!(parse_module_bytes_safe([0xff, 0xfe, 0x00]).is_err());
+
+    let module = parse_module_safe("main = primIntAdd 1 2");
+    assert!(module.is_ok());
+}
+
+#[test]
+fn parse_module_recovers_from_errors() {
+    let result = parse_module_safe(
+r"first = (1,
+second = 2
+third = case x
+fourth = 4");
+    match result {
+        Err(errors) => assert_eq!(errors.len(), 2),
+        Ok(_) => fail!("Expected errors")
+    }
+}
+
+#[test]
+fn parse_module_safe_deep_nesting() {
+    let mut source = ~"main = ";
+    for _ in range(0, 10000) {
+        source.push_char('(');
+    }
+    let result = parse_module_safe(source);
+    assert!(result.is_err());
+}
+
+#[test]
+fn module_index() {
+    let mut parser = Parser::new(
+r"class Test a where
+    test :: a -> Int
+
+data Maybe a = Just a | Nothing
+
+id x = x".chars());
+    let module = parser.module();
+
+    assert_eq!(module.find_declaration("id"), Some(BindingDeclaration(0)));
+    assert_eq!(module.find_declaration("test"), Some(ClassDeclaration(0, 0)));
+    assert_eq!(module.find_declaration("Nothing"), Some(ConstructorDeclaration(0, 1)));
+    assert_eq!(module.find_declaration("Maybe"), None);
+}
+
 }
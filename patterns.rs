@@ -0,0 +1,329 @@
+//Checks of the patterns in case expressions which are done after typechecking.
+//Functions defined with several equations are parsed into a case over a tuple of their arguments
+//so they are checked as well.
+//Each case is treated as a matrix with one row per alternative, a pattern vector which no row of
+//the matrix matches is a witness that the alternatives are not exhaustive and an alternative
+//which matches nothing that the rows before it do not match can never be reached.
+use interner::intern;
+use module::{Module, Binding, TypedExpr, Alternative, Pattern, IdentifierPattern, ConstructorPattern,
+    Identifier, Apply, Number, Rational, String, Char, Lambda, Let, Case, Type, TypeOperator, TypeVariable};
+use typecheck::TypeEnvironment;
+use diagnostic::Diagnostic;
+
+///Returns warnings for every case expression in 'module' which does not match all values of its scrutinee
+///or which has alternatives that can never be matched
+pub fn check_module<'a, 'b>(env: &'a TypeEnvironment<'a>, module: &'b Module) -> ~[Diagnostic] {
+    let mut checker = PatternChecker { env: env, module: module, warnings: ~[] };
+    for bind in module.bindings.iter() {
+        checker.check_binding(bind);
+    }
+    for instance in module.instances.iter() {
+        for bind in instance.bindings.iter() {
+            checker.check_binding(bind);
+        }
+    }
+    checker.warnings
+}
+
+struct PatternChecker<'a, 'b> {
+    env: &'a TypeEnvironment<'a>,
+    module: &'b Module,
+    warnings: ~[Diagnostic]
+}
+
+impl <'a, 'b> PatternChecker<'a, 'b> {
+
+    fn check_binding(&mut self, bind: &Binding) {
+        self.check_expr(&bind.expression);
+    }
+
+    fn check_expr(&mut self, expr: &TypedExpr) {
+        match &expr.expr {
+            &Apply(~(ref func, ref arg)) => {
+                self.check_expr(func);
+                self.check_expr(arg);
+            }
+            &Lambda(_, ref body) => self.check_expr(&**body),
+            &Let(ref bindings, ref body) => {
+                for bind in bindings.iter() {
+                    self.check_binding(bind);
+                }
+                self.check_expr(&**body);
+            }
+            &Case(ref scrutinee, ref alternatives) => {
+                self.check_expr(&**scrutinee);
+                for alt in alternatives.iter() {
+                    self.check_expr(&alt.expression);
+                }
+                self.check_alternatives(expr, alternatives.as_slice());
+            }
+            &Identifier(_) | &Number(_) | &Rational(_) | &String(_) | &Char(_) => ()
+        }
+    }
+
+    fn check_alternatives(&mut self, expr: &TypedExpr, alternatives: &[Alternative]) {
+        let rows : ~[~[Pattern]] = alternatives.iter().map(|alt| ~[alt.pattern.node.clone()]).collect();
+        for i in range(1, rows.len()) {
+            if !self.useful(rows.slice_to(i), rows[i].as_slice()) {
+                let message = format!("The alternative '{}' is unreachable, the alternatives before it match every value it matches", rows[i][0]);
+                self.warnings.push(Diagnostic::warning(alternatives[i].pattern.location, message));
+            }
+        }
+        match self.missing(rows, 1) {
+            Some(witness) => {
+                let message = format!("The patterns of the case expression are not exhaustive, '{}' is not matched", witness[0]);
+                self.warnings.push(Diagnostic::warning(expr.location, message));
+            }
+            None => ()
+        }
+    }
+
+    ///Returns true if 'vector' matches some value which none of 'rows' match
+    fn useful(&self, rows: &[~[Pattern]], vector: &[Pattern]) -> bool {
+        if vector.len() == 0 {
+            return rows.len() == 0;
+        }
+        match &vector[0] {
+            &ConstructorPattern(ref name, ref patterns) => {
+                let mut rest = patterns.clone();
+                rest.push_all(vector.slice_from(1));
+                self.useful(specialize(rows, name.as_slice(), patterns.len()).as_slice(), rest.as_slice())
+            }
+            &IdentifierPattern(_) => {
+                let used = first_constructors(rows);
+                let all_constructors = if used.len() == 0 { None } else { self.constructors(used[0].as_slice()) };
+                match all_constructors {
+                    Some(ref constructors) if constructors.iter().all(|&(ref name, _)| used.contains(name)) => {
+                        constructors.iter().any(|&(ref name, arity)| {
+                            let mut rest = ::std::vec::from_elem(arity, wildcard());
+                            rest.push_all(vector.slice_from(1));
+                            self.useful(specialize(rows, name.as_slice(), arity).as_slice(), rest.as_slice())
+                        })
+                    }
+                    _ => {
+                        let defaults : ~[~[Pattern]] = rows.iter()
+                            .filter(|&row| is_wildcard(&row[0]))
+                            .map(|row| row.slice_from(1).to_owned())
+                            .collect();
+                        self.useful(defaults.as_slice(), vector.slice_from(1))
+                    }
+                }
+            }
+            literal => {
+                let matching : ~[~[Pattern]] = rows.iter()
+                    .filter(|&row| is_wildcard(&row[0]) || row[0] == *literal)
+                    .map(|row| row.slice_from(1).to_owned())
+                    .collect();
+                self.useful(matching.as_slice(), vector.slice_from(1))
+            }
+        }
+    }
+
+    ///Returns a vector of 'width' patterns which is not matched by any of the rows in 'rows'
+    ///or None if every value is matched by some row
+    fn missing(&self, rows: ~[~[Pattern]], width: uint) -> Option<~[Pattern]> {
+        if width == 0 {
+            return if rows.len() == 0 { Some(~[]) } else { None };
+        }
+        let used = first_constructors(rows.as_slice());
+        let all_constructors = if used.len() == 0 { None } else { self.constructors(used[0].as_slice()) };
+        match all_constructors {
+            Some(ref constructors) if constructors.iter().all(|&(ref name, _)| used.contains(name)) => {
+                //Every constructor is matched so the values missing must be inside one of them
+                for &(ref name, arity) in constructors.iter() {
+                    match self.missing(specialize(rows.as_slice(), name.as_slice(), arity), arity + width - 1) {
+                        Some(mut witness) => {
+                            let rest = witness.slice_from(arity).to_owned();
+                            witness.truncate(arity);
+                            return Some(~[ConstructorPattern(name.clone(), witness)] + rest);
+                        }
+                        None => ()
+                    }
+                }
+                None
+            }
+            _ => {
+                let defaults : ~[~[Pattern]] = rows.iter()
+                    .filter(|&row| is_wildcard(&row[0]))
+                    .map(|row| row.slice_from(1).to_owned())
+                    .collect();
+                self.missing(defaults, width - 1).map(|witness| {
+                    //Report one of the constructors which no row matches if possible
+                    let first = match all_constructors {
+                        Some(ref constructors) => {
+                            match constructors.iter().find(|&&(ref name, _)| !used.contains(name)) {
+                                Some(&(ref name, arity)) => {
+                                    ConstructorPattern(name.clone(), ::std::vec::from_elem(arity, wildcard()))
+                                }
+                                None => wildcard()
+                            }
+                        }
+                        None => wildcard()
+                    };
+                    ~[first] + witness
+                })
+            }
+        }
+    }
+
+    ///Returns the name and arity of every constructor of the type which the constructor 'name' belongs to
+    fn constructors(&self, name: &str) -> Option<~[(~str, uint)]> {
+        if "[]" == name || ":" == name {
+            return Some(~[(~"[]", 0), (~":", 2)]);
+        }
+        if name.starts_with("(") {
+            return Some(~[(name.to_owned(), name.char_len() - 1)]);
+        }
+        let data_def = self.module.dataDefinitions.iter()
+            .find(|data| data.constructors.iter().any(|ctor| name == ctor.name));
+        let data_def = match data_def {
+            Some(data) => Some(data),
+            None => {
+                let type_name = match self.env.find_interned(intern(name)).and_then(return_type_name) {
+                    Some(type_name) => type_name,
+                    None => return None
+                };
+                self.env.find_data_definition(type_name.as_slice())
+            }
+        };
+        data_def.map(|data| data.constructors.iter().map(|ctor| (ctor.name.clone(), ctor.arity as uint)).collect())
+    }
+}
+
+///Returns the names of the constructors which appear in the first column of 'rows'
+fn first_constructors(rows: &[~[Pattern]]) -> ~[~str] {
+    let mut used : ~[~str] = ~[];
+    for row in rows.iter() {
+        match &row[0] {
+            &ConstructorPattern(ref name, _) if !used.contains(name) => used.push(name.clone()),
+            _ => ()
+        }
+    }
+    used
+}
+
+///Returns the rows which can match the constructor 'name', with the patterns of its arguments
+///replacing the pattern for the constructor
+fn specialize(rows: &[~[Pattern]], name: &str, arity: uint) -> ~[~[Pattern]] {
+    let mut result = ~[];
+    for row in rows.iter() {
+        match &row[0] {
+            &ConstructorPattern(ref ctor, ref patterns) => {
+                if name == *ctor {
+                    let mut new_row = patterns.clone();
+                    new_row.push_all(row.slice_from(1));
+                    result.push(new_row);
+                }
+            }
+            pattern => {
+                if is_wildcard(pattern) {
+                    let mut new_row = ::std::vec::from_elem(arity, wildcard());
+                    new_row.push_all(row.slice_from(1));
+                    result.push(new_row);
+                }
+            }
+        }
+    }
+    result
+}
+
+///Returns the name of the type which a constructor of type 'typ' creates
+fn return_type_name(typ: &Type) -> Option<~str> {
+    match &typ.typ {
+        &TypeOperator(ref op) if "->" == op.name => return_type_name(&typ.types()[1]),
+        &TypeOperator(ref op) => Some(op.name.clone()),
+        &TypeVariable(_) => None
+    }
+}
+
+fn is_wildcard(pattern: &Pattern) -> bool {
+    match pattern {
+        &IdentifierPattern(_) => true,
+        _ => false
+    }
+}
+
+fn wildcard() -> Pattern {
+    IdentifierPattern(intern("_"))
+}
+
+#[cfg(test)]
+mod tests {
+use typecheck::TypeEnvironment;
+use parser::Parser;
+use patterns::check_module;
+
+fn warnings(source: &str) -> ~[~str] {
+    let mut parser = Parser::new(source.chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    env.typecheck_module(&mut module).unwrap();
+    check_module(&env, &module).iter().map(|warning| warning.message.clone()).collect()
+}
+
+#[test]
+fn missing_constructor() {
+    let result = warnings(
+r"data Maybe a = Just a | Nothing
+
+fromJust x = case x of
+    Just y -> y");
+    assert_eq!(result, ~[~"The patterns of the case expression are not exhaustive, 'Nothing' is not matched"]);
+}
+
+#[test]
+fn exhaustive_patterns() {
+    let result = warnings(
+r"data Maybe a = Just a | Nothing
+
+test (Just (Just x)) y = x
+test (Just Nothing) (Just y) = y
+test (Just x) Nothing = 0
+test Nothing z = 1
+
+count 0 = 0
+count n = 1");
+    assert_eq!(result, ~[]);
+}
+
+#[test]
+fn missing_nested_pattern() {
+    let result = warnings(
+r"data Maybe a = Just a | Nothing
+
+test (Just (Just x)) = x
+test Nothing = 0");
+    assert_eq!(result, ~[~"The patterns of the case expression are not exhaustive, '(Just Nothing)' is not matched"]);
+}
+
+#[test]
+fn unreachable_alternatives() {
+    let result = warnings(
+r"data Maybe a = Just a | Nothing
+
+test x = case x of
+    y -> 1
+    Just z -> 2
+
+test2 (Just x) = 1
+test2 Nothing = 2
+test2 (Just (Just y)) = 3
+
+test3 0 = 0
+test3 1 = 1
+test3 0 = 2
+test3 n = 3");
+    assert_eq!(result.len(), 3);
+    assert!(result[0].starts_with("The alternative '(Just z)' is unreachable"));
+    assert!(result[1].starts_with("The alternative '(Just (Just y))' is unreachable"));
+    assert!(result[2].starts_with("The alternative '0' is unreachable"));
+}
+
+#[test]
+fn missing_literal() {
+    let result = warnings(
+r"test 0 = 1
+test 1 = 2");
+    assert_eq!(result, ~[~"The patterns of the case expression are not exhaustive, '_' is not matched"]);
+}
+}
@@ -0,0 +1,131 @@
+//Peephole optimization of the instructions of a compiled function.
+//The compiler emits code for each expression without looking at the code around it which leaves behind
+//sequences such as 'Push(n), Pop(1)' or 'Slide(1), Slide(1)' that can be removed or merged.
+//Instructions which are jumped to are never merged with the instruction before them, after rewriting
+//the targets of the jumps are moved to the new position of the instruction they pointed at.
+use std::hashmap::HashSet;
+use compiler::{Instruction, Push, PushInt, PushFloat, PushChar, Pack, Eval, Update, Pop, Slide, Jump, JumpFalse};
+
+///Rewrites wasteful instruction sequences in 'instructions' until no more rewrites apply
+pub fn optimize(instructions: ~[Instruction]) -> ~[Instruction] {
+    let (instructions, _) = optimize_with_positions(instructions);
+    instructions
+}
+
+///Same as 'optimize' but also returns the position in the optimized instructions of each of the original
+///instructions, followed by the position of the end of the instructions.
+///Removed instructions are given the position of the instruction which followed them.
+pub fn optimize_with_positions(instructions: ~[Instruction]) -> (~[Instruction], ~[uint]) {
+    let mut positions : ~[uint] = range(0, instructions.len() + 1).collect();
+    let mut instructions = instructions;
+    loop {
+        let (optimized, pass_positions, changed) = optimize_pass(instructions);
+        instructions = optimized;
+        for position in positions.mut_iter() {
+            *position = pass_positions[*position];
+        }
+        if !changed {
+            return (instructions, positions);
+        }
+    }
+}
+
+///Makes one pass over 'instructions', returns the rewritten instructions, the position of each instruction
+///in the rewritten instructions and whether anything was changed
+fn optimize_pass(instructions: ~[Instruction]) -> (~[Instruction], ~[uint], bool) {
+    let mut targets = HashSet::new();
+    for instruction in instructions.iter() {
+        match *instruction {
+            Jump(to) | JumpFalse(to) => { targets.insert(to); }
+            _ => ()
+        }
+    }
+    let mut result = ~[];
+    //The position in 'result' of each instruction in 'instructions'
+    let mut positions = ~[];
+    let mut changed = false;
+    let mut i = 0;
+    while i < instructions.len() {
+        //The number of the following instructions which can be rewritten together with the current one
+        let mut window = 1;
+        while i + window < instructions.len() && window < 3 && !targets.contains(&(i + window)) {
+            window += 1;
+        }
+        let (consumed, replacement) = rewrite(instructions.slice(i, i + window));
+        for _ in range(0, consumed) {
+            positions.push(result.len());
+        }
+        match replacement {
+            Some(replacement) => {
+                changed = true;
+                result.push_all_move(replacement);
+            }
+            None => result.push(instructions[i].clone())
+        }
+        i += consumed;
+    }
+    //A jump to the end of the function
+    positions.push(result.len());
+    for instruction in result.mut_iter() {
+        match *instruction {
+            Jump(to) => *instruction = Jump(positions[to]),
+            JumpFalse(to) => *instruction = JumpFalse(positions[to]),
+            _ => ()
+        }
+    }
+    (result, positions, changed)
+}
+
+///Tries to rewrite the start of 'window'. Returns the number of instructions consumed
+///and the instructions which replace them or None if the first instruction is kept as it is.
+fn rewrite(window: &[Instruction]) -> (uint, Option<~[Instruction]>) {
+    match window {
+        [Pop(0), .._] | [Slide(0), .._] => (1, Some(~[])),
+        //The pushed value is removed immediately
+        [Push(_), Pop(1), .._] => (2, Some(~[])),
+        [Push(_), Pop(n), .._] if n > 1 => (2, Some(~[Pop(n - 1)])),
+        //Constructors and literals are already evaluated
+        [Pack(tag, arity), Eval, .._] => (2, Some(~[Pack(tag, arity)])),
+        [PushInt(i), Eval, .._] => (2, Some(~[PushInt(i)])),
+        [PushFloat(f), Eval, .._] => (2, Some(~[PushFloat(f)])),
+        [PushChar(c), Eval, .._] => (2, Some(~[PushChar(c)])),
+        [Eval, Eval, .._] => (2, Some(~[Eval])),
+        [Pop(a), Pop(b), .._] => (2, Some(~[Pop(a + b)])),
+        [Slide(a), Slide(b), .._] => (2, Some(~[Slide(a + b)])),
+        //Popping the top also pops the values the slide would have removed
+        [Slide(a), Pop(b), .._] if b > 0 => (2, Some(~[Pop(a + b)])),
+        //The end of a function, the values below the result are popped anyway after the update
+        [Slide(a), Update(0), Pop(b)] if b > 0 => (3, Some(~[Update(0), Pop(a + b)])),
+        _ => (1, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+use peephole::{optimize, optimize_with_positions};
+use compiler::{Push, PushInt, Pack, Add, Eval, Update, Pop, Slide, Split, CaseJump, Jump, Unwind};
+
+#[test]
+fn remove_redundant_instructions() {
+    let instructions = ~[Push(0), Pop(1), PushInt(1), Eval, Pack(0, 0), Eval, Add, Slide(1), Slide(0), Slide(2), Update(0), Pop(2), Unwind];
+    assert_eq!(optimize(instructions), ~[PushInt(1), Pack(0, 0), Add, Update(0), Pop(5), Unwind]);
+}
+
+#[test]
+fn jumps_are_moved() {
+    let instructions = ~[Push(0), Eval,
+        Push(1), CaseJump(1), Jump(10), Split(2), Push(2), Slide(2), Jump(18), Pop(2),
+        Push(1), CaseJump(0), Jump(18), Split(0), PushInt(2), Slide(0), Jump(18), Pop(0), Slide(1)];
+    assert_eq!(optimize(instructions), ~[Push(0), Eval,
+        Push(1), CaseJump(1), Jump(10), Split(2), Push(2), Slide(2), Jump(16), Pop(2),
+        Push(1), CaseJump(0), Jump(16), Split(0), PushInt(2), Jump(16), Slide(1)]);
+}
+
+#[test]
+fn positions_of_removed_instructions() {
+    let instructions = ~[PushInt(1), Push(0), Pop(1), Eval, Slide(1), Unwind];
+    let (optimized, positions) = optimize_with_positions(instructions);
+    assert_eq!(optimized, ~[PushInt(1), Slide(1), Unwind]);
+    assert_eq!(positions, ~[0, 0, 0, 0, 1, 2, 3]);
+}
+}
@@ -0,0 +1,138 @@
+//Caching of the compiled Prelude so that it is only parsed, typechecked and compiled once per process.
+//Every expression evaluated from the command line, the REPL and most tests need the Prelude,
+//compiling it again for each of them dominates the time they take.
+use std::hashmap::HashMap;
+use std::unstable::mutex::{Mutex, MUTEX_INIT};
+use global::with_global;
+use module::Module;
+use compiler::{Assembly, CompilerOptions};
+use host::FileSystem;
+use vm::compile_module_with;
+
+///Holds the contents, compiler options, typechecked module and compiled assembly of each prelude file which has been loaded.
+///An entry is only used while the file system returns the same contents for the file and it is compiled with the same options,
+///so different file systems (or a file which has changed) never see an assembly compiled from another file.
+pub struct PreludeCache {
+    priv entries: HashMap<~str, (~str, CompilerOptions, Module, Assembly)>
+}
+
+impl PreludeCache {
+    pub fn new() -> PreludeCache {
+        PreludeCache { entries: HashMap::new() }
+    }
+
+    ///Compiles the file at 'filename' unless it has already been compiled from the same contents with the same options
+    pub fn load(&mut self, fs: &FileSystem, filename: &str, options: &CompilerOptions) -> Result<(), ~str> {
+        let contents = try!(fs.read_file(filename));
+        if self.find(filename, contents.as_slice(), options).is_none() {
+            let (module, assembly) = compile_module_with(contents.chars(), options);
+            self.insert(filename, contents, options.clone(), module, assembly);
+        }
+        Ok(())
+    }
+
+    ///Returns the assembly compiled from 'filename' if it was compiled from 'contents' with 'options'
+    fn find<'a>(&'a self, filename: &str, contents: &str, options: &CompilerOptions) -> Option<&'a Assembly> {
+        match self.entries.find_equiv(&filename) {
+            Some(&(ref cached, ref cached_options, _, ref assembly)) if contents == cached.as_slice() && options == cached_options => Some(assembly),
+            _ => None
+        }
+    }
+
+    fn insert(&mut self, filename: &str, contents: ~str, options: CompilerOptions, module: Module, assembly: Assembly) {
+        self.entries.insert(filename.to_owned(), (contents, options, module, assembly));
+    }
+
+    ///Returns the typechecked module of a file added with 'load'
+    pub fn module<'a>(&'a self, filename: &str) -> Option<&'a Module> {
+        self.entries.find_equiv(&filename).map(|&(_, _, ref module, _)| module)
+    }
+
+    ///Returns the assembly of a file added with 'load'
+    pub fn assembly<'a>(&'a self, filename: &str) -> Option<&'a Assembly> {
+        self.entries.find_equiv(&filename).map(|&(_, _, _, ref assembly)| assembly)
+    }
+
+    pub fn len(&self) -> uint {
+        self.entries.len()
+    }
+}
+
+static mut LOCK: Mutex = MUTEX_INIT;
+static mut CACHE: *mut PreludeCache = 0 as *mut PreludeCache;
+
+fn with_cache<T>(f: |&mut PreludeCache| -> T) -> T {
+    unsafe { with_global(&mut LOCK, &mut CACHE, || PreludeCache::new(), f) }
+}
+
+///Returns the assembly compiled from 'filename', which is only compiled the first time it is requested in the process
+///with the contents it has in 'fs' and with 'options'. The file is read on every call so that a file system with other
+///contents for it gets its own assembly.
+///The cache is shared by every task, a copy of the assembly is returned so that it can be moved into a VM.
+pub fn cached_prelude(fs: &FileSystem, filename: &str, options: &CompilerOptions) -> Result<Assembly, ~str> {
+    let contents = try!(fs.read_file(filename));
+    match with_cache(|cache| cache.find(filename, contents.as_slice(), options).map(|assembly| assembly.clone())) {
+        Some(assembly) => return Ok(assembly),
+        None => ()
+    }
+    //Compile without holding the lock since compiling fails on errors in the file
+    let (module, assembly) = compile_module_with(contents.chars(), options);
+    let result = assembly.clone();
+    let mut entry = Some((contents, module, assembly));
+    with_cache(|shared| {
+        let (contents, module, assembly) = entry.take_unwrap();
+        //Another task may have compiled the same contents in the meantime in which case its entry is kept
+        if shared.find(filename, contents.as_slice(), options).is_none() {
+            shared.insert(filename, contents, options.clone(), module, assembly);
+        }
+    });
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+use prelude::{PreludeCache, cached_prelude};
+use compiler::CompilerOptions;
+use host::{FileSystem, MemoryFileSystem};
+
+#[test]
+fn compile_prelude_once() {
+    let mut fs = MemoryFileSystem::new();
+    fs.add_file(~"Test.hs", ~"main = primIntAdd 1 2");
+    let mut cache = PreludeCache::new();
+    cache.load(&fs as &FileSystem, "Test.hs", &Default::default()).unwrap();
+    assert!(cache.module("Test.hs").is_some());
+    let code = cache.assembly("Test.hs").unwrap().superCombinators[0].code().as_ptr();
+    cache.load(&fs as &FileSystem, "Test.hs", &Default::default()).unwrap();
+    assert_eq!(cache.len(), 1);
+    assert_eq!(cache.assembly("Test.hs").unwrap().superCombinators[0].code().as_ptr(), code);
+    fs.add_file(~"Test.hs", ~"main = primIntAdd 3 4");
+    cache.load(&fs as &FileSystem, "Test.hs", &Default::default()).unwrap();
+    assert_eq!(cache.len(), 1);
+    assert!(cache.assembly("Test.hs").unwrap().superCombinators[0].code().as_ptr() != code);
+    assert!(cache.load(&fs as &FileSystem, "DoesNotExist.hs", &Default::default()).is_err());
+}
+
+#[test]
+fn shared_prelude_cache() {
+    let mut fs = MemoryFileSystem::new();
+    fs.add_file(~"SharedTest.hs", ~"main = primIntAdd 1 2");
+    let first = cached_prelude(&fs as &FileSystem, "SharedTest.hs", &Default::default()).unwrap();
+    let mut same = MemoryFileSystem::new();
+    same.add_file(~"SharedTest.hs", ~"main = primIntAdd 1 2");
+    let second = cached_prelude(&same as &FileSystem, "SharedTest.hs", &Default::default()).unwrap();
+    assert_eq!(first.superCombinators[0].code().as_ptr(), second.superCombinators[0].code().as_ptr());
+    //A file system which does not have the file or has other contents for it does not get the cached assembly
+    assert!(cached_prelude(&MemoryFileSystem::new() as &FileSystem, "SharedTest.hs", &Default::default()).is_err());
+    let mut other = MemoryFileSystem::new();
+    other.add_file(~"SharedTest.hs", ~"main = primIntAdd 3 4");
+    let third = cached_prelude(&other as &FileSystem, "SharedTest.hs", &Default::default()).unwrap();
+    assert!(first.superCombinators[0].code().as_ptr() != third.superCombinators[0].code().as_ptr());
+    assert!(first.superCombinators[0].code().instructions() != third.superCombinators[0].code().instructions());
+    //The same file compiled with other options gets its own assembly
+    let optimized = CompilerOptions { optimization_level: 1, .. Default::default() };
+    let fourth = cached_prelude(&fs as &FileSystem, "SharedTest.hs", &optimized).unwrap();
+    assert!(first.superCombinators[0].code().as_ptr() != fourth.superCombinators[0].code().as_ptr());
+}
+
+}
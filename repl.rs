@@ -0,0 +1,187 @@
+//Interactive loop which evaluates expressions using the Prelude.
+//Lines starting with ':' are commands which inspect the types in scope instead of evaluating anything.
+use std::task;
+use compiler::{Assembly, CompilerOptions};
+use module::{TypeDeclaration, Location};
+use parser::Parser;
+use typecheck::{Types, TypeEnvironment};
+use vm::{compile_expression, eval_expr, show_result};
+use docs::{class_signature, data_signature};
+use host::Console;
+use diagnostic::failure_message;
+
+pub struct Repl {
+    ///The assembly which every expression is compiled against
+    priv prelude: Assembly,
+    priv options: CompilerOptions
+}
+
+///Typechecks 'source' as an expression without evaluating it and returns its type, including the class constraints
+fn type_of(assembly: &Assembly, source: &str) -> Result<~str, ~str> {
+    let mut parser = Parser::new(source.chars());
+    let mut expr = match parser.expression() {
+        Ok(Some(expr)) => expr,
+        Ok(None) => return Err(~"Expected an expression"),
+        Err(diagnostic) => return Err(format!("{}", diagnostic))
+    };
+    let mut type_env = TypeEnvironment::new();
+    type_env.add_types(assembly as &Types);
+    match type_env.typecheck(&mut expr) {
+        Ok(()) => (),
+        Err(error) => return Err(error.render(source))
+    }
+    let decl = TypeDeclaration { context: type_env.find_constraints(&expr.typ), typ: expr.typ.clone(), name: source.to_owned(), location: Location::eof() };
+    Ok(format!("{} :: {}", source, decl.pretty()))
+}
+
+///Returns the class, data type, instances and type of everything called 'name'
+fn info(assembly: &Assembly, name: &str) -> Result<~str, ~str> {
+    let mut type_env = TypeEnvironment::new();
+    type_env.add_types(assembly as &Types);
+    let mut lines = ~[];
+    match type_env.find_class(name) {
+        Some(class) => lines.push(class_signature(class)),
+        None => ()
+    }
+    match type_env.find_data_definition(name) {
+        Some(data) => lines.push(data_signature(data)),
+        None => ()
+    }
+    for &(ref classname, ref constraints, ref typ) in type_env.find_instances(name).iter() {
+        let mut line = ~"instance ";
+        for constraint in constraints.iter() {
+            line.push_str(format!("{} ", *constraint));
+        }
+        if constraints.len() > 0 {
+            line.push_str("=> ");
+        }
+        line.push_str(format!("{} ({})", *classname, *typ));
+        lines.push(line);
+    }
+    match type_env.type_of(name) {
+        Some(decl) => lines.push(format!("{} :: {}", name, decl.pretty())),
+        None => ()
+    }
+    if lines.len() == 0 {
+        Err(format!("Not in scope: {}", name))
+    }
+    else {
+        Ok(lines.connect("\n"))
+    }
+}
+
+///Returns the declarations of all the globals in scope
+fn browse(assembly: &Assembly) -> ~str {
+    let mut type_env = TypeEnvironment::new();
+    type_env.add_types(assembly as &Types);
+    let mut lines = ~[];
+    type_env.each_global(|decl| lines.push(format!("{} :: {}", decl.name, decl.pretty())));
+    lines.connect("\n")
+}
+
+fn evaluate(assembly: Assembly, source: &str, options: &CompilerOptions) -> Result<~str, ~str> {
+    let mut assembly = assembly;
+    let (instructions, typ) = try!(compile_expression(&mut assembly, source, true, options));
+    match eval_expr(assembly, instructions) {
+        Some(result) => Ok(show_result(&result, &typ)),
+        None => Err(~"The result of the expression can't be displayed")
+    }
+}
+
+impl Repl {
+    pub fn new(prelude: Assembly, options: CompilerOptions) -> Repl {
+        Repl { prelude: prelude, options: options }
+    }
+
+    ///Executes a single line of input and returns the text which should be displayed.
+    ///Errors are returned as text as well so a mistake does not end the session.
+    pub fn execute_command(&mut self, line: &str) -> ~str {
+        let line = line.trim().to_owned();
+        if line.len() == 0 {
+            return ~"";
+        }
+        let (command, argument) = match line.find(' ') {
+            Some(index) if line.starts_with(":") => (line.slice_to(index), line.slice_from(index).trim()),
+            _ => (line.as_slice(), "")
+        };
+        let result = match command {
+            ":type" | ":t" => Ok(type_of(&self.prelude, argument)),
+            ":info" | ":i" => Ok(info(&self.prelude, argument)),
+            ":browse" | ":b" => Ok(Ok(browse(&self.prelude))),
+            _ if command.starts_with(":") => Ok(Err(format!("Unknown command {}", command))),
+            _ => {
+                //Evaluating adds to the assembly and the VM fails on errors so it runs in its own task with a copy of the prelude
+                let assembly = self.prelude.clone();
+                let options = self.options.clone();
+                let source = line.clone();
+                task::try(proc() evaluate(assembly, source, &options))
+            }
+        };
+        match result {
+            Ok(Ok(output)) => output,
+            Ok(Err(msg)) => msg,
+            Err(cause) => failure_message(&cause)
+        }
+    }
+
+    ///Reads and executes lines from 'console' until the input ends or ':quit' is entered
+    pub fn run(&mut self, console: &mut Console) {
+        loop {
+            let line = match console.read_line() {
+                Some(line) => line,
+                None => return
+            };
+            if ":quit" == line.trim() || ":q" == line.trim() {
+                return
+            }
+            let output = self.execute_command(line);
+            if output.len() > 0 {
+                console.write_line(output);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+use repl::Repl;
+use prelude::cached_prelude;
+use host::{FileSystem, NativeFileSystem};
+
+#[test]
+fn type_command() {
+    let mut repl = Repl::new(cached_prelude(&NativeFileSystem as &FileSystem, "Prelude.hs", &Default::default()).unwrap(), Default::default());
+    assert_eq!(repl.execute_command(":type primIntAdd 1"), ~"primIntAdd 1 :: Int -> Int");
+    let output = repl.execute_command(":type show");
+    assert!(output.starts_with("show :: Show "));
+    assert_eq!(repl.execute_command(r":type \x y -> x"), ~r"\x y -> x :: a -> b -> a");
+}
+
+#[test]
+fn info_command() {
+    let mut repl = Repl::new(cached_prelude(&NativeFileSystem as &FileSystem, "Prelude.hs", &Default::default()).unwrap(), Default::default());
+    let output = repl.execute_command(":info Maybe");
+    assert!(output.starts_with("data Maybe"));
+    let output = repl.execute_command(":info Eq");
+    assert!(output.starts_with("class Eq"));
+    assert!(output.contains("instance Eq (Int)"));
+    assert!(repl.execute_command(":info DoesNotExist").starts_with("Not in scope"));
+}
+
+#[test]
+fn browse_command() {
+    let mut repl = Repl::new(cached_prelude(&NativeFileSystem as &FileSystem, "Prelude.hs", &Default::default()).unwrap(), Default::default());
+    let output = repl.execute_command(":browse");
+    assert!(output.contains("map :: "));
+    assert!(!output.contains("#"));
+}
+
+#[test]
+fn evaluate_and_recover_from_errors() {
+    let mut repl = Repl::new(cached_prelude(&NativeFileSystem as &FileSystem, "Prelude.hs", &Default::default()).unwrap(), Default::default());
+    assert_eq!(repl.execute_command("primIntAdd 1 2"), ~"3");
+    assert!(repl.execute_command("undefinedVariable").len() > 0);
+    assert_eq!(repl.execute_command("primIntAdd 2 2"), ~"4");
+}
+
+}
@@ -0,0 +1,181 @@
+//Strictness analysis of the top level functions of a module.
+//A function is strict in an argument if evaluating a call of the function always evaluates that argument,
+//so when the call itself is evaluated the argument can be evaluated before the call instead of being passed as a thunk.
+//Recursive functions are handled by assuming every function is strict in all of its arguments and
+//removing the arguments which are not demanded until nothing changes.
+use std::hashmap::HashMap;
+use module::{Module, TypedExpr, Identifier, Apply, Number, Rational, String, Char, Lambda, Let, Case};
+use variables::pattern_variables;
+use interner::{InternedStr, intern};
+
+///Returns for each function of 'module' which of its arguments it is strict in
+pub fn analyze_module(module: &Module) -> HashMap<InternedStr, ~[bool]> {
+    let mut functions = ~[];
+    for bind in module.bindings.iter() {
+        let mut arguments = ~[];
+        let mut body = &bind.expression;
+        loop {
+            match &body.expr {
+                &Lambda(ref arg, ref inner) if arguments.len() < bind.arity => {
+                    arguments.push(*arg);
+                    body = &**inner;
+                }
+                _ => break
+            }
+        }
+        if arguments.len() > 0 && arguments.len() == bind.arity {
+            functions.push((intern(bind.name), arguments, body));
+        }
+    }
+    let mut strictness = HashMap::new();
+    for &(name, ref arguments, _) in functions.iter() {
+        strictness.insert(name, arguments.map(|_| true));
+    }
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &(name, ref arguments, body) in functions.iter() {
+            let mut demanded = ~[];
+            demanded_variables(&strictness, &mut arguments.clone(), body, &mut demanded);
+            let strict = arguments.map(|arg| demanded.contains(arg));
+            if strict != *strictness.get(&name) {
+                strictness.insert(name, strict);
+                changed = true;
+            }
+        }
+    }
+    strictness
+}
+
+///Returns the number of arguments of the primitive function 'name', all of which it evaluates
+pub fn primitive_arity(name: &str) -> Option<uint> {
+    match name {
+        "primIntAdd" | "primIntSubtract" | "primIntMultiply" | "primIntDivide" | "primIntRemainder" |
+        "primIntEQ" | "primIntLT" | "primIntLE" | "primIntGT" | "primIntGE" |
+        "primDoubleAdd" | "primDoubleSubtract" | "primDoubleMultiply" | "primDoubleDivide" | "primDoubleRemainder" |
+        "primDoubleEQ" | "primDoubleLT" | "primDoubleLE" | "primDoubleGT" | "primDoubleGE" => Some(2),
+        "primIntToDouble" | "primDoubleToInt" => Some(1),
+        _ => None
+    }
+}
+
+///Adds the variables which are always evaluated when 'expr' is evaluated to 'demanded'.
+///'locals' holds the arguments and local variables in scope, which shadow the functions in 'strictness'
+fn demanded_variables(strictness: &HashMap<InternedStr, ~[bool]>, locals: &mut ~[InternedStr], expr: &TypedExpr, demanded: &mut ~[InternedStr]) {
+    match &expr.expr {
+        &Identifier(ref name) => add(demanded, *name),
+        &Apply(..) => {
+            //The arguments of the call from the last to the first
+            let mut arguments = ~[];
+            let mut head = expr;
+            loop {
+                match &head.expr {
+                    &Apply(~(ref func, ref arg)) => {
+                        arguments.push(arg);
+                        head = func;
+                    }
+                    _ => break
+                }
+            }
+            let name = match &head.expr {
+                &Identifier(ref name) => *name,
+                _ => {
+                    demanded_variables(strictness, locals, head, demanded);
+                    return;
+                }
+            };
+            add(demanded, name);
+            if locals.contains(&name) {
+                return;
+            }
+            let strict = match strictness.find(&name) {
+                Some(strict) => strict.clone(),
+                None => match primitive_arity(name.as_slice()) {
+                    Some(arity) => ::std::vec::from_elem(arity, true),
+                    None => return
+                }
+            };
+            if arguments.len() < strict.len() {
+                return;
+            }
+            let count = arguments.len();
+            for (i, is_strict) in strict.iter().enumerate() {
+                if *is_strict {
+                    demanded_variables(strictness, locals, arguments[count - 1 - i], demanded);
+                }
+            }
+        }
+        &Case(ref scrutinee, ref alternatives) => {
+            demanded_variables(strictness, locals, &**scrutinee, demanded);
+            //Only the variables which every alternative evaluates are demanded
+            let mut common: Option<~[InternedStr]> = None;
+            for alt in alternatives.iter() {
+                let mut variables = ~[];
+                pattern_variables(&alt.pattern.node, &mut variables);
+                let before = locals.len();
+                locals.push_all(variables.as_slice());
+                let mut alt_demanded = ~[];
+                demanded_variables(strictness, locals, &alt.expression, &mut alt_demanded);
+                locals.truncate(before);
+                alt_demanded.retain(|name| !variables.contains(name));
+                common = Some(match common {
+                    Some(common) => common.move_iter().filter(|name| alt_demanded.contains(name)).collect(),
+                    None => alt_demanded
+                });
+            }
+            for name in common.unwrap_or(~[]).move_iter() {
+                add(demanded, name);
+            }
+        }
+        &Let(ref bindings, ref body) => {
+            let names : ~[InternedStr] = bindings.iter().map(|bind| intern(bind.name)).collect();
+            let before = locals.len();
+            locals.push_all(names.as_slice());
+            let mut body_demanded = ~[];
+            demanded_variables(strictness, locals, &**body, &mut body_demanded);
+            locals.truncate(before);
+            for name in body_demanded.move_iter() {
+                if !names.contains(&name) {
+                    add(demanded, name);
+                }
+            }
+        }
+        //A lambda is already in weak head normal form
+        &Lambda(..) => (),
+        &Number(_) | &Rational(_) | &String(_) | &Char(_) => ()
+    }
+}
+
+fn add(demanded: &mut ~[InternedStr], name: InternedStr) {
+    if !demanded.contains(&name) {
+        demanded.push(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+use strictness::analyze_module;
+use parser::Parser;
+use interner::intern;
+
+#[test]
+fn strict_arguments() {
+    let mut parser = Parser::new(
+r"add x y = primIntAdd x y
+first x y = x
+sum acc xs = case xs of
+    : y ys -> sum (primIntAdd acc y) ys
+    [] -> acc
+loop x y = loop x y
+choose b x y = case b of
+    True -> primIntAdd x y
+    False -> x".chars());
+    let module = parser.module();
+    let strictness = analyze_module(&module);
+    assert_eq!(strictness.get(&intern("add")), &~[true, true]);
+    assert_eq!(strictness.get(&intern("first")), &~[true, false]);
+    assert_eq!(strictness.get(&intern("sum")), &~[true, true]);
+    assert_eq!(strictness.get(&intern("loop")), &~[true, true]);
+    assert_eq!(strictness.get(&intern("choose")), &~[true, true, false]);
+}
+}
@@ -1,7 +1,10 @@
-use std::hashmap::HashMap;
-use module::{TypeVariable, TypeOperator, Identifier, Number, Rational, String, Char, Apply, Lambda, Let, Case, TypedExpr, Module, Constraint, Pattern, IdentifierPattern, NumberPattern, ConstructorPattern, Binding, Class, TypeDeclaration};
+use std::fmt;
+use diagnostic::source_excerpt;
+use std::hashmap::{HashMap, HashSet};
+use module::{InternedStr, intern, TypeVariable, TypeOperator, TypeSynonym, Identifier, Number, Rational, String, Char, Apply, Lambda, Let, Case, TypedExpr, Module, Constraint, Pattern, mut_arguments, IdentifierPattern, NumberPattern, CharPattern, ConstructorPattern, Binding, Class, Instance, TypeDeclaration, DataDefinition, BindingDeclaration, ClassDeclaration, ConstructorDeclaration, instance_name};
 use graph::{Graph, VertexIndex, strongly_connected_components};
 use std::iter::range_step;
+use metrics::Metrics;
 
 pub use lexer::Location;
 pub use module::Type;
@@ -20,41 +23,40 @@ pub trait Types {
         }
     }
     fn find_instance<'a>(&'a self, classname: &str, typ: &Type) -> Option<(&'a [Constraint], &'a Type)>;
+    fn find_data_definition<'a>(&'a self, name: &str) -> Option<&'a DataDefinition>;
+    fn find_type_synonym<'a>(&'a self, name: &str) -> Option<&'a TypeSynonym>;
     fn each_typedeclaration(&self, |&TypeDeclaration|);
+    ///Calls 'func' with each data definition which is visible
+    fn each_data_definition(&self, func: |&DataDefinition|);
+    ///Calls 'func' with the class name, constraints and type of each instance
+    fn each_instance(&self, func: |&str, &[Constraint], &Type|);
 }
 
+///Only the names in the export list of the module are visible
 impl Types for Module {
     fn find_type<'a>(&'a self, name: &str) -> Option<&'a Type> {
-        for bind in self.bindings.iter() {
-            if bind.name.equiv(&name) {
-                return Some(&bind.expression.typ);
-            }
-        }
-
-        for class in self.classes.iter() {
-            for decl in class.declarations.iter() {
-                if decl.name.equiv(&name) {
-                    return Some(&decl.typ);
-                }
-            }
+        if !self.is_exported(name) {
+            return None;
         }
-        for data in self.dataDefinitions.iter() {
-            for ctor in data.constructors.iter() {
-                if ctor.name.equiv(&name) {
-                    return Some(&ctor.typ);
-                }
+        self.find_declaration(name).map(|decl| {
+            match decl {
+                BindingDeclaration(i) => &self.bindings[i].expression.typ,
+                ClassDeclaration(i, j) => &self.classes[i].declarations[j].typ,
+                ConstructorDeclaration(i, j) => &self.dataDefinitions[i].constructors[j].typ
             }
-        }
-        return None;
+        })
     }
 
     fn find_class<'a>(&'a self, name: &str) -> Option<&'a Class> {
-        self.classes.iter().find(|class| name == class.name)
+        if !self.is_exported(name) {
+            return None;
+        }
+        self.index.classes.find_equiv(&name).map(|i| &self.classes[*i])
     }
 
     fn find_instance<'a>(&'a self, classname: &str, typ: &Type) -> Option<(&'a [Constraint], &'a Type)> {
         for instance in self.instances.iter() {
-            if classname == instance.classname && &instance.typ == typ {//test name
+            if classname == instance.classname && match_instance_type(&mut HashMap::new(), &instance.typ, typ) {
                 let c : &[Constraint] = instance.constraints;
                 return Some((c, &instance.typ));
             }
@@ -62,30 +64,141 @@ impl Types for Module {
         None
     }
 
+    fn find_data_definition<'a>(&'a self, name: &str) -> Option<&'a DataDefinition> {
+        if !self.is_exported(name) {
+            return None;
+        }
+        self.index.dataDefinitions.find_equiv(&name).map(|i| &self.dataDefinitions[*i])
+    }
+
+    fn find_type_synonym<'a>(&'a self, name: &str) -> Option<&'a TypeSynonym> {
+        if !self.is_exported(name) {
+            return None;
+        }
+        self.typeSynonyms.iter().find(|synonym| name == synonym.name)
+    }
+
     fn each_typedeclaration(&self, func: |&TypeDeclaration|) {
-        for bind in self.bindings.iter() {
+        for bind in self.bindings.iter().filter(|bind| self.is_exported(bind.name)) {
             func(&bind.typeDecl);
         }
 
         for class in self.classes.iter() {
-            for decl in class.declarations.iter() {
+            for decl in class.declarations.iter().filter(|decl| self.is_exported(decl.name)) {
                 func(decl);
             }
         }
     }
+
+    fn each_data_definition(&self, func: |&DataDefinition|) {
+        for data in self.dataDefinitions.iter().filter(|data| self.is_exported(data.typ.op().name)) {
+            func(data);
+        }
+    }
+
+    fn each_instance(&self, func: |&str, &[Constraint], &Type|) {
+        for instance in self.instances.iter() {
+            func(instance.classname, instance.constraints, &instance.typ);
+        }
+    }
 }
 
+///The number of type synonyms which may be expanded inside each other before the synonym is assumed to be recursive
+static MAX_SYNONYM_EXPANSIONS: uint = 100;
+
 pub struct TypeEnvironment<'a> {
     assemblies: ~[&'a Types],
-    namedTypes : HashMap<~str, Type>,
+    namedTypes : HashMap<InternedStr, Type>,
+    ///Maps each type variable to the names in namedTypes whose type may contain it,
+    ///so that substitutions only need to visit the types they can change
+    namedTypeVariables : HashMap<TypeVariable, ~[InternedStr]>,
     types : ~[Type],
-    constraints: HashMap<TypeVariable, ~[~str]>,
-    instances: ~[(~str, Type)],
-    variableIndex : TypeVariable
+    constraints: VariableConstraints,
+    ///The class name, constraints and type of each instance in the module being typechecked
+    instances: ~[(~str, ~[Constraint], Type)],
+    ///The instances which the literals of the primitive types need, see 'primitive_instances'.
+    ///They are only searched when no other instance matches and are not returned by 'instances_of'.
+    primitive_instances: ~[(~str, ~[Constraint], Type)],
+    variableIndex : TypeVariable,
+    ///Skeletons of the types found in 'assemblies', created the first time each is instantiated
+    instantiations: HashMap<InternedStr, TypeSkeleton>,
+    ///The variables of the type signatures of the bindings being checked, these can't be unified with anything except
+    ///variables which are not from a signature since the signature must be as general as the binding
+    skolems: HashSet<TypeVariable>,
+    ///When true, simple pattern bindings such as 'x = 1' which have no type signature are not
+    ///generalized over their constrained type variables, as the Haskell report specifies.
+    ///By default every binding is generalized.
+    monomorphism_restriction: bool,
+    ///When true, the contexts of instances do not need to be smaller than their heads.
+    ///Finding an instance may then loop forever, as with 'instance Eq a => Eq a'.
+    undecidable_instances: bool,
+    ///The holes found while typechecking which have not been reported yet
+    holes: ~[Hole],
+    metrics: Metrics
+}
+
+///A hole such as '_' in an expression, it is reported as an error together with the bindings
+///which could be used in its place
+struct Hole {
+    location: Location,
+    name: InternedStr,
+    typ: Type,
+    ///The bindings in scope at the hole and their types
+    candidates: ~[(InternedStr, Type)]
+}
+
+///The number of bindings which are suggested for a hole
+static MAX_HOLE_FITS: uint = 10;
+
+///A type where each variable is replaced by its index in 'variables' so that it can be
+///instantiated without looking up every variable in a mapping
+struct TypeSkeleton {
+    typ: Type,
+    variables: ~[TypeVariable]
+}
+
+impl TypeSkeleton {
+    fn new(typ: &Type) -> TypeSkeleton {
+        let mut variables = ~[];
+        let typ = skeleton_type(&mut variables, typ);
+        TypeSkeleton { typ: typ, variables: variables }
+    }
+
+    ///Creates a copy of the type where the variable at index i is replaced by 'replacements[i]'
+    fn instantiate(&self, replacements: &[Type]) -> Type {
+        instantiate_skeleton(&self.typ, replacements)
+    }
+}
+
+fn skeleton_type(variables: &mut ~[TypeVariable], typ: &Type) -> Type {
+    let result = match &typ.typ {
+        &TypeVariable(ref var) => {
+            let index = match variables.iter().position(|v| v == var) {
+                Some(index) => index,
+                None => {
+                    variables.push(var.clone());
+                    variables.len() - 1
+                }
+            };
+            TypeVariable(TypeVariable { id: index as int })
+        }
+        &TypeOperator(ref op) => TypeOperator(op.clone())
+    };
+    Type::new(result, typ.types().iter().map(|t| skeleton_type(variables, t)).collect())
+}
+
+fn instantiate_skeleton(typ: &Type, replacements: &[Type]) -> Type {
+    let result = match &typ.typ {
+        &TypeVariable(ref var) => replacements[var.id as uint].typ.clone(),
+        &TypeOperator(ref op) => TypeOperator(op.clone())
+    };
+    Type::new(result, typ.types().iter().map(|t| instantiate_skeleton(t, replacements)).collect())
 }
 
 struct TypeScope<'a, 'b> {
-    vars: ~[(~str, Type)],
+    ///The names inserted by the scope together with the type each of them had before, if any,
+    ///which is restored when the scope is dropped
+    vars: ~[(InternedStr, Option<Type>)],
     env: &'a mut TypeEnvironment<'b>,
     parent: Option<&'a TypeScope<'a, 'b>>,
     non_generic: ~[Type]
@@ -97,9 +210,224 @@ struct Substitution {
     constraints: HashMap<TypeVariable, ~[~str]>
 }
 
-///Signals that a type error has occured and the top level types as well as the location is needed
-condition! {
-    type_error: () -> (Location, Type, Type);
+///The classes which each type variable is constrained by.
+///Variables are numbered sequentially by the environment so the constraints are stored in a
+///slot per variable, indexed by its id, rather than being looked up by hashing the variable.
+struct VariableConstraints {
+    slots: ~[~[~str]]
+}
+
+impl VariableConstraints {
+    fn new() -> VariableConstraints {
+        VariableConstraints { slots: ~[] }
+    }
+
+    ///Returns the slot of 'var', variables with negative ids are never constrained so they have no slot
+    fn slot<'a>(&'a mut self, var: &TypeVariable) -> Option<&'a mut ~[~str]> {
+        if var.id < 0 {
+            return None;
+        }
+        let index = var.id as uint;
+        if index >= self.slots.len() {
+            self.slots.grow_fn(index + 1 - self.slots.len(), |_| ~[]);
+        }
+        Some(&mut self.slots[index])
+    }
+
+    ///Returns the constraints of 'var', None if it has none
+    fn find<'a>(&'a self, var: &TypeVariable) -> Option<&'a ~[~str]> {
+        if var.id < 0 || var.id as uint >= self.slots.len() || self.slots[var.id as uint].len() == 0 {
+            None
+        }
+        else {
+            Some(&self.slots[var.id as uint])
+        }
+    }
+
+    ///Adds 'class' to the constraints of 'var' unless it is already one of them
+    fn add(&mut self, var: &TypeVariable, class: &str) {
+        match self.slot(var) {
+            Some(classes) => {
+                if !classes.iter().any(|c| class == *c) {
+                    classes.push(class.to_owned());
+                }
+            }
+            None => ()
+        }
+    }
+
+    fn insert(&mut self, var: TypeVariable, constraints: ~[~str]) {
+        match self.slot(&var) {
+            Some(classes) => *classes = constraints,
+            None => ()
+        }
+    }
+
+    ///Moves the constraints of each variable after 'base' to the variable it is mapped to in 'mapping'.
+    ///The constraints of the variables after 'base' which are not mapped are removed.
+    fn renumber(&mut self, base: int, mapping: &HashMap<TypeVariable, TypeVariable>) {
+        let mut moved = ~[];
+        for (var, new) in mapping.iter() {
+            match self.pop(var) {
+                Some(constraints) => moved.push((new.clone(), constraints)),
+                None => ()
+            }
+        }
+        let keep = ::std::cmp::min((base + 1) as uint, self.slots.len());
+        self.slots.truncate(keep);
+        for (var, constraints) in moved.move_iter() {
+            self.insert(var, constraints);
+        }
+    }
+
+    ///Removes and returns the constraints of 'var'
+    fn pop(&mut self, var: &TypeVariable) -> Option<~[~str]> {
+        if var.id < 0 || var.id as uint >= self.slots.len() || self.slots[var.id as uint].len() == 0 {
+            None
+        }
+        else {
+            Some(::std::util::replace(&mut self.slots[var.id as uint], ~[]))
+        }
+    }
+}
+
+///Maps the type variables of a module to new variables, numbered in the order they are first visited
+struct Renumbering {
+    mapping: HashMap<TypeVariable, TypeVariable>,
+    ///Variables up to and including 'base' existed before the module and keep their ids
+    base: int,
+    next: int
+}
+
+impl Renumbering {
+    fn var(&mut self, var: &mut TypeVariable) {
+        if var.id <= self.base {
+            return;
+        }
+        if !self.mapping.contains_key(var) {
+            self.next += 1;
+            self.mapping.insert(var.clone(), TypeVariable { id: self.next });
+        }
+        let new = self.mapping.get(var).clone();
+        *var = new;
+    }
+
+    fn typ(&mut self, typ: &mut Type) {
+        match typ.typ {
+            TypeVariable(ref mut var) => self.var(var),
+            TypeOperator(_) => ()
+        }
+        for types in typ.args.mut_iter() {
+            for t in types.mut_iter() {
+                self.typ(t);
+            }
+        }
+    }
+
+    fn constraint(&mut self, constraint: &mut Constraint) {
+        for var in constraint.variables.mut_iter() {
+            self.var(var);
+        }
+    }
+
+    fn declaration(&mut self, decl: &mut TypeDeclaration) {
+        self.typ(&mut decl.typ);
+        for constraint in decl.context.mut_iter() {
+            self.constraint(constraint);
+        }
+    }
+
+    fn binding(&mut self, bind: &mut Binding) {
+        self.declaration(&mut bind.typeDecl);
+        self.expr(&mut bind.expression);
+    }
+
+    fn expr(&mut self, expr: &mut TypedExpr) {
+        self.typ(&mut expr.typ);
+        match expr.expr {
+            Apply(~(ref mut func, ref mut arg)) => {
+                self.expr(func);
+                self.expr(arg);
+            }
+            Lambda(_, ref mut body) => self.expr(&mut **body),
+            Let(ref mut bindings, ref mut body) => {
+                for bind in bindings.mut_iter() {
+                    self.binding(bind);
+                }
+                self.expr(&mut **body);
+            }
+            Case(ref mut scrutinee, ref mut alternatives) => {
+                self.expr(&mut **scrutinee);
+                for alt in alternatives.mut_iter() {
+                    self.expr(&mut alt.expression);
+                }
+            }
+            Identifier(_) | Number(_) | Rational(_) | String(_) | Char(_) => ()
+        }
+    }
+}
+
+///An error found while typechecking
+#[deriving(Clone)]
+pub struct TypeError {
+    location: Location,
+    message: ~str,
+    ///The expected and the inferred type which were being unified when the error was found
+    types: Option<(Type, Type)>,
+    ///The innermost expression which contains the error
+    context: Option<~str>,
+    ///The name of the innermost binding which contains the error
+    binding: Option<~str>
+}
+
+pub type TypeResult<T> = Result<T, TypeError>;
+
+impl TypeError {
+    pub fn new(location: Location, message: ~str) -> TypeError {
+        TypeError { location: location, message: message, types: None, context: None, binding: None }
+    }
+
+    ///Renders the error together with the line of 'source' which it occured in
+    pub fn render(&self, source: &str) -> ~str {
+        self.describe(source_excerpt(source, &self.location))
+    }
+
+    ///Describes the error, showing 'excerpt' of the source below the binding if there is one
+    fn describe(&self, excerpt: Option<~str>) -> ~str {
+        let mut result = format!("{} Error: {}", self.location, self.message);
+        match self.binding {
+            Some(ref binding) => result.push_str(format!("\nIn the binding '{}'", *binding)),
+            None => ()
+        }
+        match excerpt {
+            Some(excerpt) => { result.push_char('\n'); result.push_str(excerpt); }
+            None => ()
+        }
+        match self.types {
+            Some((ref expected, ref inferred)) => {
+                let names = Type::pretty_all([expected, inferred]);
+                result.push_str(format!("\nExpected type: {}\nInferred type: {}", names[0], names[1]));
+            }
+            None => ()
+        }
+        match self.context {
+            Some(ref context) => result.push_str(format!("\nIn the expression: {}", *context)),
+            None => ()
+        }
+        result
+    }
+}
+
+impl fmt::Default for TypeError {
+    fn fmt(error: &TypeError, f: &mut fmt::Formatter) {
+        write!(f.buf, "{}", error.describe(None));
+    }
+}
+
+impl ToStr for TypeError {
+    fn to_str(&self) -> ~str {
+        format!("{}", *self)
+    }
 }
 
 
@@ -148,23 +476,23 @@ impl <'a> Bindings for BindingsWrapper<'a> {
     }
 }
 
-fn add_primitives(globals: &mut HashMap<~str, Type>, typename: &str) {
+fn add_primitives(globals: &mut HashMap<InternedStr, Type>, typename: &str) {
     let typ = Type::new_op(typename.to_owned(), ~[]);
     {
         let binop = function_type(&typ, &function_type(&typ, &typ));
-        globals.insert("prim" + typename + "Add", binop.clone());
-        globals.insert("prim" + typename + "Subtract", binop.clone());
-        globals.insert("prim" + typename + "Multiply", binop.clone());
-        globals.insert("prim" + typename + "Divide", binop.clone());
-        globals.insert("prim" + typename + "Remainder", binop.clone());
+        globals.insert(intern("prim" + typename + "Add"), binop.clone());
+        globals.insert(intern("prim" + typename + "Subtract"), binop.clone());
+        globals.insert(intern("prim" + typename + "Multiply"), binop.clone());
+        globals.insert(intern("prim" + typename + "Divide"), binop.clone());
+        globals.insert(intern("prim" + typename + "Remainder"), binop.clone());
     }
     {
         let binop = function_type(&typ, &function_type(&typ, &Type::new_op(~"Bool", ~[])));
-        globals.insert("prim" + typename + "EQ", binop.clone());
-        globals.insert("prim" + typename + "LT", binop.clone());
-        globals.insert("prim" + typename + "LE", binop.clone());
-        globals.insert("prim" + typename + "GT", binop.clone());
-        globals.insert("prim" + typename + "GE", binop.clone());
+        globals.insert(intern("prim" + typename + "EQ"), binop.clone());
+        globals.insert(intern("prim" + typename + "LT"), binop.clone());
+        globals.insert(intern("prim" + typename + "LE"), binop.clone());
+        globals.insert(intern("prim" + typename + "GT"), binop.clone());
+        globals.insert(intern("prim" + typename + "GE"), binop.clone());
     }
 }
 
@@ -182,6 +510,15 @@ fn create_tuple_type(size: uint) -> (~str, Type) {
     (ident, typ)
 }
 
+///Literals are desugared to calls to 'fromInteger' and 'fromRational' but the literals of the
+///primitive types are compiled to a single instruction, so these instances exist even when
+///the numeric classes are not in scope
+fn primitive_instances() -> ~[(~str, ~[Constraint], Type)] {
+    ~[(~"Num", ~[], Type::new_op(~"Int", ~[])),
+      (~"Num", ~[], Type::new_op(~"Double", ~[])),
+      (~"Fractional", ~[], Type::new_op(~"Double", ~[]))]
+}
+
 impl <'a> TypeEnvironment<'a> {
 
     ///Creates a new TypeEnvironment and adds all the primitive types
@@ -189,49 +526,89 @@ impl <'a> TypeEnvironment<'a> {
         let mut globals = HashMap::new();
         add_primitives(&mut globals, &"Int");
         add_primitives(&mut globals, &"Double");
-        globals.insert(~"primIntToDouble", function_type(&Type::new_op(~"Int", ~[]), &Type::new_op(~"Double", ~[])));
-        globals.insert(~"primDoubleToInt", function_type(&Type::new_op(~"Double", ~[]), &Type::new_op(~"Int", ~[])));
+        globals.insert(intern("primIntToDouble"), function_type(&Type::new_op(~"Int", ~[]), &Type::new_op(~"Double", ~[])));
+        globals.insert(intern("primDoubleToInt"), function_type(&Type::new_op(~"Double", ~[]), &Type::new_op(~"Int", ~[])));
         let var = Type::new_var(-10);
         let list = Type::new_op(~"[]", ~[var.clone()]);
-        globals.insert(~"[]", list.clone());
-        globals.insert(~":", function_type(&var, &function_type(&list, &list)));
+        globals.insert(intern("[]"), list.clone());
+        globals.insert(intern(":"), function_type(&var, &function_type(&list, &list)));
         for i in range(0 as uint, 10) {
             let (name, typ) = create_tuple_type(i);
-            globals.insert(name, typ);
+            globals.insert(intern(name), typ);
         }
-        TypeEnvironment {
+        let mut env = TypeEnvironment {
             assemblies: ~[],
-            namedTypes : globals,
+            namedTypes : HashMap::new(),
+            namedTypeVariables : HashMap::new(),
             types : ~[] ,
-            constraints: HashMap::new(),
+            constraints: VariableConstraints::new(),
             instances: ~[],
-            variableIndex : TypeVariable { id : 0 } }
+            primitive_instances: primitive_instances(),
+            variableIndex : TypeVariable { id : 0 },
+            instantiations: HashMap::new(),
+            skolems: HashSet::new(),
+            monomorphism_restriction: false,
+            undecidable_instances: false,
+            holes: ~[],
+            metrics: Metrics::new() };
+        for (name, typ) in globals.move_iter() {
+            env.insert_named(name, typ);
+        }
+        env
+    }
+
+    ///Returns the number of unifications and substitutions done so far
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
     }
 
     pub fn add_types(&'a mut self, types: &'a Types) {
         let mut max_id = 0;
         types.each_typedeclaration(|decl| {
+            each_type(&decl.typ, |var| max_id = ::std::cmp::max(var.id, max_id), |_| ());
             for constraint in decl.context.iter() {
-                let var = constraint.variables[0].clone();
-                max_id = ::std::cmp::max(var.id, max_id);
-                self.constraints.find_or_insert(var, ~[]).push(constraint.class.clone());
+                for var in constraint.variables.iter() {
+                    max_id = ::std::cmp::max(var.id, max_id);
+                }
+                self.add_constraint(constraint);
+            }
+        });
+        //The constructors of imported data types must satisfy the context of their data type as well
+        types.each_data_definition(|data| {
+            for constraint in data.context.iter() {
+                for var in constraint.variables.iter() {
+                    max_id = ::std::cmp::max(var.id, max_id);
+                }
+                self.add_constraint(constraint);
             }
         });
-        self.variableIndex.id = max_id;
+        //The variables of the assemblies must not be reused, whatever order they are added in
+        self.variableIndex.id = ::std::cmp::max(self.variableIndex.id, max_id);
         self.assemblies.push(types);
     }
 
     ///Typechecks a module by updating all the types in place
-    pub fn typecheck_module(&mut self, module: &mut Module) {
+    pub fn typecheck_module(&mut self, module: &mut Module) -> TypeResult<()> {
+        let base = self.variableIndex.id;
+        try!(self.expand_module_synonyms(module));
+        try!(self.check_type_arities(module));
         for data_def in module.dataDefinitions.mut_iter() {
             let mut subs = Substitution { subs: HashMap::new(), constraints: HashMap::new() };
             {
                 let scope = TypeScope { env: self, vars: ~[], non_generic: ~[], parent: None };
                 freshen(&scope, &mut subs.subs, &mut data_def.typ);
             }
+            //The constraints are attached to the new variables so they are copied whenever a constructor is instantiated
+            for constraint in data_def.context.mut_iter() {
+                for var in constraint.variables.mut_iter() {
+                    let new = subs.subs.get(var).var().clone();
+                    *var = new;
+                }
+                self.add_constraint(constraint);
+            }
             for constructor in data_def.constructors.mut_iter() {
                 replace(&mut self.constraints, &mut constructor.typ, &subs);
-                self.namedTypes.insert(constructor.name.clone(), constructor.typ.clone());
+                self.insert_named(intern(constructor.name), constructor.typ.clone());
             }
         }
         for class in module.classes.mut_iter() {
@@ -240,43 +617,123 @@ impl <'a> TypeEnvironment<'a> {
             let new = self.new_var();
             class.variable = new.var().clone();
             self.constraints.insert(class.variable.clone(), ~[class.name.clone()]);
+            for constraint in class.constraints.mut_iter() {
+                for var in constraint.variables.mut_iter() {
+                    *var = class.variable.clone();
+                }
+            }
 
+            let class_variable = class.variable.clone();
             for type_decl in class.declarations.mut_iter() {
                 let c = Constraint { class: class.name.clone(), variables: ~[class.variable.clone()] };
                 let mut mapping = HashMap::new();
                 mapping.insert(replaced.clone(), new.clone());
                 self.freshen_declaration2(type_decl, mapping);
+                //A method may have a context of its own such as 'foo :: Eq b => a -> b -> Bool'.
+                //The class variable is shared by every method so only the other variables get the constraints.
+                type_decl.context.retain(|constraint| *constraint != c);
+                for constraint in type_decl.context.iter().filter(|constraint| !constraint.variables.contains(&class_variable)) {
+                    self.add_constraint(constraint);
+                }
                 type_decl.context.push(c);
-                self.namedTypes.insert(type_decl.name.clone(), type_decl.typ.clone());
+                self.insert_named(intern(type_decl.name), type_decl.typ.clone());
             }
         }
-        for instance in module.instances.mut_iter() {
-            let class = module.classes.iter().find(|class| class.name == instance.classname)
-                .expect(format!("Could not find class {}", instance.classname));
+        try!(self.check_overlapping_instances(module));
+        //The class of an instance may be defined in the module or in one of the assemblies it imports
+        let mut instance_classes = ~[];
+        for instance in module.instances.iter() {
+            match module.classes.iter().find(|class| class.name == instance.classname) {
+                Some(class) => instance_classes.push(class.clone()),
+                None => match self.find_class(instance.classname) {
+                    Some(class) => instance_classes.push(class.clone()),
+                    None => return Err(TypeError::new(instance.location, format!("Could not find class {}", instance.classname)))
+                }
+            }
+        }
+        for (instance, class) in module.instances.mut_iter().zip(instance_classes.iter()) {
+            if !self.undecidable_instances {
+                try!(check_instance_termination(instance));
+            }
+            //The names of instance bindings are prefixed with '#' and the name of the instance (see 'instance_name')
+            let prefix = "#" + instance_name(&instance.typ);
+            for binding in instance.bindings.iter() {
+                let method = binding.name.slice_from(prefix.len());
+                if !class.declarations.iter().any(|decl| method == decl.name.as_slice()) {
+                    return Err(TypeError::new(binding.expression.location, format!("'{}' is not a method of the class {}", method, class.name)));
+                }
+            }
+            let missing : ~[~str] = class.declarations.iter()
+                .filter(|decl| !instance.bindings.iter().any(|binding| binding.name == prefix + decl.name))
+                .map(|decl| format!("'{}'", decl.name))
+                .collect();
+            if missing.len() != 0 {
+                return Err(TypeError::new(instance.location, format!("The instance '{} {}' does not define the class methods {}",
+                    instance.classname, instance.typ.pretty(), missing.connect(", "))));
+            }
             {
                 let mut mapping = HashMap::new();
                 for constraint in instance.constraints.mut_iter() {
-                    let new = mapping.find_or_insert(constraint.variables[0].clone(), self.new_var());
-                    constraint.variables[0] = new.var().clone();
+                    self.freshen_constraint(&mut mapping, constraint);
                 }
                 let mut scope = TypeScope { env: self, vars: ~[], non_generic: ~[], parent: None };
                 instance.typ = freshen(&mut scope, &mut mapping, &instance.typ);
             }
-            for binding in instance.bindings.mut_iter() {
-                let decl = class.declarations.iter().find(|decl| binding.name.ends_with(decl.name))
-                    .expect(format!("Could not find {} in class {}", binding.name, class.name));
+            self.instances.push((instance.classname.clone(), instance.constraints.clone(), instance.typ.clone()));
+        }
+        //The superclasses are checked after every instance of the module is known so that instances can be in any order
+        for (instance, class) in module.instances.iter().zip(instance_classes.iter()) {
+            for superclass in class.constraints.iter() {
+                if !self.has_instance(superclass.class, &instance.typ) {
+                    return Err(TypeError::new(instance.location, format!("The instance '{} {}' requires an instance '{} {}' since {} is a superclass of {}",
+                        instance.classname, instance.typ.pretty(), superclass.class, instance.typ.pretty(), superclass.class, class.name)));
+                }
+            }
+        }
+        for (instance, class) in module.instances.mut_iter().zip(instance_classes.iter()) {
+            let prefix = "#" + instance_name(&instance.typ);
+            //Every binding is a method of the class as that was checked above
+            for decl in class.declarations.iter() {
+                let binding = match instance.bindings.mut_iter().find(|binding| binding.name == prefix + decl.name) {
+                    Some(binding) => binding,
+                    None => continue
+                };
                 binding.typeDecl = decl.clone();
                 replace_var(&mut binding.typeDecl.typ, &class.variable, &instance.typ);
+                //A method may constrain the class variable further, such as 'Eq a' in 'nub :: Eq a => [a] -> [a]'.
+                //Those constraints must hold for the instance type and are reduced to constraints on its variables
+                //while the constraint of the class itself is satisfied by the instance.
+                let method_context = ::std::util::replace(&mut binding.typeDecl.context, ~[]);
+                for constraint in method_context.move_iter() {
+                    if !constraint.variables.contains(&class.variable) {
+                        binding.typeDecl.context.push(constraint);
+                    }
+                    else if constraint.class != class.name {
+                        match self.reduce_constraint(constraint.class, &instance.typ) {
+                            Ok(reduced) => {
+                                for c in reduced.move_iter() {
+                                    if !binding.typeDecl.context.contains(&c) {
+                                        binding.typeDecl.context.push(c);
+                                    }
+                                }
+                            }
+                            Err(_) => {
+                                return Err(TypeError::new(instance.location, format!("The method '{}' of the instance '{} {}' requires an instance '{} {}'",
+                                    decl.name, instance.classname, instance.typ.pretty(), constraint.class, instance.typ.pretty())));
+                            }
+                        }
+                    }
+                }
                 for constraint in instance.constraints.iter() {
-                    binding.typeDecl.context.push(constraint.clone());
+                    if !binding.typeDecl.context.contains(constraint) {
+                        binding.typeDecl.context.push(constraint.clone());
+                    }
                 }
                 self.freshen_declaration(&mut binding.typeDecl);
                 for constraint in binding.typeDecl.context.iter() {
-                    self.constraints.find_or_insert(constraint.variables[0].clone(), ~[])
-                        .push(constraint.class.clone());
+                    self.add_constraint(constraint);
                 }
             }
-            self.instances.push((instance.classname.clone(), instance.typ.clone()));
         }
         
         for type_decl in module.typeDeclarations.mut_iter() {
@@ -286,121 +743,558 @@ impl <'a> TypeEnvironment<'a> {
                 Some(bind) => {
                     bind.typeDecl = type_decl.clone();
                 }
-                None => fail!("Error: Type declaration for '{}' has no binding", type_decl.name)
+                None => return Err(TypeError::new(type_decl.location, format!("Type declaration for '{}' has no binding", type_decl.name)))
             }
         }
 
         {
-            let mut scope = TypeScope { env: self, vars: ~[], non_generic: ~[], parent: None };
             let mut subs = Substitution { subs: HashMap::new(), constraints: HashMap::new() }; 
-            scope.typecheck_mutually_recursive_bindings(&mut subs, module);
+            let result = {
+                let mut scope = TypeScope { env: self, vars: ~[], non_generic: ~[], parent: None };
+                scope.typecheck_mutually_recursive_bindings(&mut subs, module)
+            };
+            try!(self.report_holes(result, &subs));
         }
         for bind in module.bindings.iter() {
-            self.namedTypes.insert(bind.name.clone(), bind.expression.typ.clone());
+            self.insert_named(intern(bind.name), bind.expression.typ.clone());
         }
+        self.renumber_module(base, module);
+        Ok(())
     }
 
-    pub fn typecheck(&mut self, expr : &mut TypedExpr) {
-        let mut subs = Substitution { subs: HashMap::new(), constraints: HashMap::new() }; 
-        {
-            let mut scope = TypeScope { env: self, vars: ~[], non_generic: ~[], parent: None };
-            scope.typecheck(expr, &mut subs);
+    ///Renumbers the type variables created while typechecking 'module' in the order they appear in it,
+    ///starting after 'base', so the inferred types do not depend on the order the bindings were typechecked in.
+    ///Variables which no longer appear in the module or the environment are dropped.
+    fn renumber_module(&mut self, base: int, module: &mut Module) {
+        let mut renumbering = Renumbering { mapping: HashMap::new(), base: base, next: base };
+        for data_def in module.dataDefinitions.mut_iter() {
+            renumbering.typ(&mut data_def.typ);
+            for constraint in data_def.context.mut_iter() {
+                renumbering.constraint(constraint);
+            }
+            for constructor in data_def.constructors.mut_iter() {
+                renumbering.typ(&mut constructor.typ);
+            }
         }
-        self.substitute(&mut subs, expr);
-    }
-
-    pub fn find(&'a self, ident: &str) -> Option<&'a Type> {
-        self.namedTypes.find_equiv(&ident).or_else(|| {
-            for types in self.assemblies.iter() {
-                let v = types.find_type(ident);
-                if v != None {
-                    return v;
-                }
+        for class in module.classes.mut_iter() {
+            renumbering.var(&mut class.variable);
+            for constraint in class.constraints.mut_iter() {
+                renumbering.constraint(constraint);
             }
-            None
-        })
-    }
-
-    ///Finds all the constraints for a type
-    pub fn find_constraints(&self, typ: &Type) -> ~[Constraint] {
-        let mut result : ~[Constraint] = ~[];
-        each_type(typ,
-        |var| {
-            match self.constraints.find(var) {
-                Some(constraints) => {
-                    for c in constraints.iter() {
-                        if result.iter().find(|x| x.class == *c) == None {
-                            result.push(Constraint { class: c.clone(), variables: ~[var.clone()] });
-                        }
+            for decl in class.declarations.mut_iter() {
+                renumbering.declaration(decl);
+            }
+        }
+        for instance in module.instances.mut_iter() {
+            renumbering.typ(&mut instance.typ);
+            for constraint in instance.constraints.mut_iter() {
+                renumbering.constraint(constraint);
+            }
+            for bind in instance.bindings.mut_iter() {
+                renumbering.binding(bind);
+            }
+        }
+        for decl in module.typeDeclarations.mut_iter() {
+            renumbering.declaration(decl);
+        }
+        for bind in module.bindings.mut_iter() {
+            renumbering.binding(bind);
+        }
+        for instance in self.instances.mut_iter() {
+            match *instance {
+                (_, ref mut constraints, ref mut typ) => {
+                    renumbering.typ(typ);
+                    for constraint in constraints.mut_iter() {
+                        renumbering.constraint(constraint);
                     }
                 }
-                None => ()
-            }
-        },
-        |_| ());
-        result
-    }
-    
-    ///Searches through a type, comparing it with the type on the identifier, returning all the specialized constraints
-    pub fn find_specialized_instances(&self, name: &str, actual_type: &Type) -> ~[(~str, Type)] {
-        match self.find(name) {
-            Some(typ) => {
-                let mut constraints = ~[];
-                self.find_specialized(&mut constraints, actual_type, typ);
-                constraints
             }
-            None => fail!("Could not find '{}' in type environment", name)
         }
+        //Globals which are not in the module are visited by name so that the numbering stays deterministic
+        let mut names : ~[InternedStr] = self.namedTypes.keys().map(|name| name.clone()).collect();
+        names.sort_by(|l, r| l.as_slice().cmp(&r.as_slice()));
+        self.namedTypeVariables.clear();
+        for name in names.iter() {
+            let typ = self.namedTypes.get_mut(name);
+            renumbering.typ(typ);
+            index_type_variables(&mut self.namedTypeVariables, *name, typ);
+        }
+        self.constraints.renumber(base, &renumbering.mapping);
+        self.variableIndex.id = renumbering.next;
     }
-    fn find_specialized(&self, constraints: &mut ~[(~str, Type)], actual_type: &Type, typ: &Type) {
-        match (&actual_type.typ, &typ.typ) {
-            (&TypeOperator(_), &TypeVariable(ref var)) => {
-                match self.constraints.find(var) {
-                    Some(cons) => {
-                        for c in cons.iter() {
-                            if constraints.iter().find(|x| x.n0_ref() == c) == None {
-                                constraints.push((c.clone(), actual_type.clone()));
-                            }
-                        }
+
+    ///Returns an error if two instances of the same class in 'module' or in 'module' and one of the
+    ///assemblies could be used for the same type, as which of them is used would then be arbitrary
+    fn check_overlapping_instances(&self, module: &Module) -> TypeResult<()> {
+        for (i, instance) in module.instances.iter().enumerate() {
+            for previous in module.instances.slice_to(i).iter() {
+                if instance.classname == previous.classname && instance_types_overlap(&previous.typ, &instance.typ) {
+                    return Err(TypeError::new(instance.location, format!("The instance '{} {}' overlaps with the instance '{} {}' at {}",
+                        instance.classname, instance.typ.pretty(), previous.classname, previous.typ.pretty(), previous.location)));
+                }
+            }
+            for types in self.assemblies.iter() {
+                let mut overlapping = None;
+                types.each_instance(|classname, _, typ| {
+                    if overlapping.is_none() && classname == instance.classname.as_slice() && instance_types_overlap(typ, &instance.typ) {
+                        overlapping = Some(typ.clone());
+                    }
+                });
+                match overlapping {
+                    Some(ref typ) if *typ == instance.typ => {
+                        return Err(TypeError::new(instance.location, format!("The instance '{} {}' is already defined in an imported module",
+                            instance.classname, instance.typ.pretty())));
+                    }
+                    Some(typ) => {
+                        return Err(TypeError::new(instance.location, format!("The instance '{} {}' overlaps with the instance '{} {}' from an imported module",
+                            instance.classname, instance.typ.pretty(), instance.classname, typ.pretty())));
                     }
                     None => ()
                 }
             }
-            _ => ()
-        }
-        for ii in range(0, actual_type.types.len()) {
-            self.find_specialized(constraints, &actual_type.types[ii], &typ.types[ii]);
         }
+        Ok(())
     }
 
-    fn freshen_declaration2(&mut self, decl: &mut TypeDeclaration, mut mapping: HashMap<TypeVariable, Type>) {
-        for constraint in decl.context.mut_iter() {
-            let old = constraint.variables[0].clone();
-            let new = mapping.find_or_insert(old.clone(), self.new_var());
-            constraint.variables[0] = new.var().clone();
+    ///Returns an error if a declared type in 'module' applies a type constructor to more or fewer
+    ///arguments than its data definition has parameters, such as 'Maybe Int Int'
+    fn check_type_arities(&self, module: &Module) -> TypeResult<()> {
+        let mut arities = HashMap::new();
+        arities.insert(~"->", 2u);
+        arities.insert(~"[]", 1u);
+        for name in [~"Int", ~"Double", ~"Char"].iter() {
+            arities.insert(name.clone(), 0u);
+        }
+        for types in self.assemblies.iter() {
+            types.each_data_definition(|data| {
+                arities.insert(data.typ.op().name.clone(), data.typ.types().len());
+            });
+        }
+        for data in module.dataDefinitions.iter() {
+            arities.insert(data.typ.op().name.clone(), data.typ.types().len());
+        }
+        for data in module.dataDefinitions.iter() {
+            for constructor in data.constructors.iter() {
+                try!(check_arity(&arities, &constructor.typ, constructor.name, data.location));
+            }
+        }
+        for class in module.classes.iter() {
+            for decl in class.declarations.iter() {
+                try!(check_arity(&arities, &decl.typ, decl.name, decl.location));
+            }
+        }
+        for decl in module.typeDeclarations.iter() {
+            try!(check_arity(&arities, &decl.typ, decl.name, decl.location));
+        }
+        Ok(())
+    }
+
+    ///Replaces every use of a type synonym in the declared types of the module with the type it stands for
+    fn expand_module_synonyms(&self, module: &mut Module) -> TypeResult<()> {
+        let mut synonyms = HashMap::new();
+        for synonym in module.typeSynonyms.iter() {
+            synonyms.insert(synonym.name.clone(), synonym.clone());
+        }
+        for data_def in module.dataDefinitions.mut_iter() {
+            for constructor in data_def.constructors.mut_iter() {
+                try!(self.expand_synonyms(&synonyms, &mut constructor.typ, 0, data_def.location));
+            }
+        }
+        for class in module.classes.mut_iter() {
+            for decl in class.declarations.mut_iter() {
+                try!(self.expand_synonyms(&synonyms, &mut decl.typ, 0, decl.location));
+            }
+        }
+        for instance in module.instances.mut_iter() {
+            try!(self.expand_synonyms(&synonyms, &mut instance.typ, 0, instance.location));
+            for bind in instance.bindings.mut_iter() {
+                try!(self.expand_expression_synonyms(&synonyms, &mut bind.expression));
+            }
+        }
+        for decl in module.typeDeclarations.mut_iter() {
+            try!(self.expand_synonyms(&synonyms, &mut decl.typ, 0, decl.location));
+        }
+        for bind in module.bindings.mut_iter() {
+            try!(self.expand_synonyms(&synonyms, &mut bind.typeDecl.typ, 0, bind.typeDecl.location));
+            try!(self.expand_expression_synonyms(&synonyms, &mut bind.expression));
+        }
+        Ok(())
+    }
+
+    ///Expands the type synonyms in the signatures of the let bindings in 'expr'
+    fn expand_expression_synonyms(&self, local: &HashMap<~str, TypeSynonym>, expr: &mut TypedExpr) -> TypeResult<()> {
+        match expr.expr {
+            Apply(~(ref mut func, ref mut arg)) => {
+                try!(self.expand_expression_synonyms(local, func));
+                try!(self.expand_expression_synonyms(local, arg));
+            }
+            Lambda(_, ref mut body) => try!(self.expand_expression_synonyms(local, &mut **body)),
+            Let(ref mut bindings, ref mut body) => {
+                for bind in bindings.mut_iter() {
+                    try!(self.expand_synonyms(local, &mut bind.typeDecl.typ, 0, bind.typeDecl.location));
+                    try!(self.expand_expression_synonyms(local, &mut bind.expression));
+                }
+                try!(self.expand_expression_synonyms(local, &mut **body));
+            }
+            Case(ref mut scrutinee, ref mut alternatives) => {
+                try!(self.expand_expression_synonyms(local, &mut **scrutinee));
+                for alt in alternatives.mut_iter() {
+                    try!(self.expand_expression_synonyms(local, &mut alt.expression));
+                }
+            }
+            Identifier(_) | Number(_) | Rational(_) | String(_) | Char(_) => ()
+        }
+        Ok(())
+    }
+
+    ///Expands the type synonyms in 'typ', errors are reported at 'location' which is where the type was written
+    fn expand_synonyms(&self, local: &HashMap<~str, TypeSynonym>, typ: &mut Type, depth: uint, location: Location) -> TypeResult<()> {
+        for types in typ.args.mut_iter() {
+            for t in types.mut_iter() {
+                try!(self.expand_synonyms(local, t, depth, location));
+            }
+        }
+        let expanded = match &typ.typ {
+            &TypeOperator(ref op) => {
+                match local.find(&op.name).or_else(|| self.find_type_synonym(op.name.as_slice())) {
+                    Some(synonym) => {
+                        if depth >= MAX_SYNONYM_EXPANSIONS {
+                            return Err(TypeError::new(location, format!("The type synonym '{}' is recursive", synonym.name)));
+                        }
+                        let arity = synonym.parameters.len();
+                        if typ.types().len() < arity {
+                            return Err(TypeError::new(location, format!("The type synonym '{}' expects {} arguments but was given {}", synonym.name, arity, typ.types().len())));
+                        }
+                        let mut result = substitute_parameters(&synonym.typ, synonym.parameters, typ.types());
+                        for extra in typ.types().slice_from(arity).iter() {
+                            result.push_type(extra.clone());
+                        }
+                        Some(result)
+                    }
+                    None => None
+                }
+            }
+            &TypeVariable(_) => None
+        };
+        match expanded {
+            Some(mut result) => {
+                try!(self.expand_synonyms(local, &mut result, depth + 1, location));
+                *typ = result;
+            }
+            None => ()
+        }
+        Ok(())
+    }
+
+    ///Finds the type synonym 'name' in any of the assemblies
+    pub fn find_type_synonym<'b>(&'b self, name: &str) -> Option<&'b TypeSynonym> {
+        for types in self.assemblies.iter() {
+            match types.find_type_synonym(name) {
+                Some(synonym) => return Some(synonym),
+                None => ()
+            }
+        }
+        None
+    }
+
+    pub fn typecheck(&mut self, expr : &mut TypedExpr) -> TypeResult<()> {
+        let mut subs = Substitution { subs: HashMap::new(), constraints: HashMap::new() }; 
+        let result = {
+            let mut scope = TypeScope { env: self, vars: ~[], non_generic: ~[], parent: None };
+            scope.typecheck(expr, &mut subs)
+        };
+        try!(self.report_holes(result, &subs));
+        self.substitute(&mut subs, expr);
+        Ok(())
+    }
+
+    ///Returns an error for the first hole found while typechecking, listing the bindings in scope
+    ///whose types fit in it with the most specific ones first.
+    ///'result' is the result of the typechecking, if it failed that error is returned instead.
+    ///The holes are removed in either case so that they are not reported by a later typecheck.
+    fn report_holes(&mut self, result: TypeResult<()>, subs: &Substitution) -> TypeResult<()> {
+        let holes = ::std::util::replace(&mut self.holes, ~[]);
+        try!(result);
+        if holes.len() == 0 {
+            return Ok(());
+        }
+        let hole = &holes[0];
+        let mut typ = hole.typ.clone();
+        replace(&mut self.constraints, &mut typ, subs);
+        let mut fits = ~[];
+        for &(ref name, ref candidate) in hole.candidates.iter() {
+            let mut candidate = candidate.clone();
+            replace(&mut self.constraints, &mut candidate, subs);
+            if self.fits_hole(&candidate, &typ) {
+                let mut variables = ~[];
+                each_type(&candidate, |var| if !variables.contains(var) { variables.push(var.clone()) }, |_| ());
+                fits.push((variables.len(), name.as_slice().to_owned(), candidate));
+            }
+        }
+        fits.sort_by(|&(ref l_count, ref l_name, _), &(ref r_count, ref r_name, _)| {
+            match l_count.cmp(r_count) {
+                ::std::cmp::Equal => l_name.cmp(r_name),
+                ordering => ordering
+            }
+        });
+        let mut message = format!("Found hole '{}' of type '{}'", hole.name, typ.pretty());
+        if fits.len() != 0 {
+            message.push_str("\nValid hole fits include:");
+            for &(_, ref name, ref candidate) in fits.iter().take(MAX_HOLE_FITS) {
+                message.push_str(format!("\n    {} :: {}", *name, candidate.pretty()));
+            }
+        }
+        Err(TypeError::new(hole.location, message))
+    }
+
+    ///Returns true if a binding of type 'candidate' can be used where a value of type 'hole' is expected
+    fn fits_hole(&self, candidate: &Type, hole: &Type) -> bool {
+        let mut matched = HashMap::new();
+        match_instance_type(&mut matched, candidate, hole) && matched.iter().all(|(var, typ)| {
+            match self.constraints.find(var) {
+                Some(classes) => classes.iter().all(|class| self.has_instance(*class, typ)),
+                None => true
+            }
+        })
+    }
+
+    pub fn find(&'a self, ident: &str) -> Option<&'a Type> {
+        self.find_interned(intern(ident))
+    }
+
+    ///Looks up the type of an identifier which has already been interned
+    pub fn find_interned(&'a self, ident: InternedStr) -> Option<&'a Type> {
+        self.namedTypes.find(&ident).or_else(|| {
+            for types in self.assemblies.iter() {
+                let v = types.find_type(ident.as_slice());
+                if v != None {
+                    return v;
+                }
+            }
+            None
+        })
+    }
+
+    ///Finds the class 'name' in any of the assemblies
+    pub fn find_class(&'a self, name: &str) -> Option<&'a Class> {
+        for types in self.assemblies.iter() {
+            match types.find_class(name) {
+                Some(class) => return Some(class),
+                None => ()
+            }
+        }
+        None
+    }
+
+    ///Finds the data definition of the type 'name' in any of the assemblies
+    pub fn find_data_definition(&'a self, name: &str) -> Option<&'a DataDefinition> {
+        for types in self.assemblies.iter() {
+            match types.find_data_definition(name) {
+                Some(data) => return Some(data),
+                None => ()
+            }
+        }
+        None
+    }
+
+    ///Returns the class name, constraints and type of all instances which are either of the class
+    ///'name' or for the type 'name'
+    pub fn find_instances(&self, name: &str) -> ~[(~str, ~[Constraint], Type)] {
+        let mut result = ~[];
+        for types in self.assemblies.iter() {
+            types.each_instance(|classname, constraints, typ| {
+                let is_type = match &typ.typ {
+                    &TypeOperator(ref op) => name == op.name,
+                    &TypeVariable(_) => false
+                };
+                if name == classname || is_type {
+                    result.push((classname.to_owned(), constraints.to_owned(), typ.clone()));
+                }
+            });
+        }
+        result
+    }
+
+    ///Returns the constraints and type of every instance of the class 'class', both from the module
+    ///being typechecked and from the assemblies
+    pub fn instances_of(&self, class: &str) -> ~[(~[Constraint], Type)] {
+        let mut result : ~[(~[Constraint], Type)] = self.instances.iter()
+            .filter(|&&(ref name, _, _)| class == *name)
+            .map(|&(_, ref constraints, ref typ)| (constraints.clone(), typ.clone()))
+            .collect();
+        for types in self.assemblies.iter() {
+            types.each_instance(|classname, constraints, typ| {
+                if class == classname {
+                    result.push((constraints.to_owned(), typ.clone()));
+                }
+            });
+        }
+        result
+    }
+
+    ///Returns the declarations of the methods of the class 'class' or None if there is no such class
+    pub fn methods_of(&self, class: &str) -> Option<~[TypeDeclaration]> {
+        self.find_class(class).map(|class| class.declarations.clone())
+    }
+
+    ///Returns the names of the classes which the type variable 'var' is constrained by
+    pub fn constraints_on(&self, var: &TypeVariable) -> ~[~str] {
+        match self.constraints.find(var) {
+            Some(classes) => classes.clone(),
+            None => ~[]
+        }
+    }
+
+    ///Returns the type of 'name' together with the constraints on its type variables
+    pub fn type_of(&self, name: &str) -> Option<TypeDeclaration> {
+        self.find(name).map(|typ| {
+            TypeDeclaration { context: self.find_constraints(typ), typ: typ.clone(), name: name.to_owned(), location: Location::eof() }
+        })
+    }
+
+    ///Adds the type of a global which is created after its module is typechecked, such as a lifted function
+    pub fn insert_global(&mut self, name: InternedStr, typ: Type) {
+        self.insert_named(name, typ);
+    }
+
+    ///Calls 'func' with the declaration of every global function and class method in the assemblies.
+    ///Functions generated for instances are skipped as they can't be referred to by name.
+    pub fn each_global(&self, func: |&TypeDeclaration|) {
+        for types in self.assemblies.iter() {
+            types.each_typedeclaration(|decl| {
+                if !decl.name.starts_with("#") {
+                    func(decl);
+                }
+            });
         }
-        let mut scope = TypeScope { env: self, vars: ~[], non_generic: ~[], parent: None };
-        decl.typ = freshen(&mut scope, &mut mapping, &decl.typ);
-    }
-    fn freshen_declaration(&mut self, decl: &mut TypeDeclaration) {
-        let mapping = HashMap::new();
-        self.freshen_declaration2(decl, mapping);
     }
 
-    ///Applies a substitution on all global types
+    ///Finds all the constraints for a type
+    pub fn find_constraints(&self, typ: &Type) -> ~[Constraint] {
+        let mut result : ~[Constraint] = ~[];
+        each_type(typ,
+        |var| {
+            match self.constraints.find(var) {
+                Some(constraints) => {
+                    for c in constraints.iter() {
+                        if result.iter().find(|x| x.class == *c) == None {
+                            result.push(Constraint { class: c.clone(), variables: ~[var.clone()] });
+                        }
+                    }
+                }
+                None => ()
+            }
+        },
+        |_| ());
+        result
+    }
+    
+    ///Searches through a type, comparing it with the type on the identifier, returning all the specialized constraints
+    pub fn find_specialized_instances(&self, name: &str, actual_type: &Type, location: Location) -> TypeResult<~[(~str, Type)]> {
+        match self.find(name) {
+            Some(typ) => {
+                let mut constraints = ~[];
+                self.find_specialized(&mut constraints, actual_type, typ);
+                Ok(constraints)
+            }
+            None => Err(TypeError::new(location, format!("Could not find '{}' in type environment", name)))
+        }
+    }
+    fn find_specialized(&self, constraints: &mut ~[(~str, Type)], actual_type: &Type, typ: &Type) {
+        match (&actual_type.typ, &typ.typ) {
+            (&TypeOperator(_), &TypeVariable(ref var)) => {
+                match self.constraints.find(var) {
+                    Some(cons) => {
+                        for c in cons.iter() {
+                            if constraints.iter().find(|x| x.n0_ref() == c) == None {
+                                constraints.push((c.clone(), actual_type.clone()));
+                            }
+                        }
+                    }
+                    None => ()
+                }
+            }
+            _ => ()
+        }
+        for ii in range(0, actual_type.types().len()) {
+            self.find_specialized(constraints, &actual_type.types()[ii], &typ.types()[ii]);
+        }
+    }
+
+    fn freshen_declaration2(&mut self, decl: &mut TypeDeclaration, mut mapping: HashMap<TypeVariable, Type>) {
+        for constraint in decl.context.mut_iter() {
+            self.freshen_constraint(&mut mapping, constraint);
+        }
+        let mut scope = TypeScope { env: self, vars: ~[], non_generic: ~[], parent: None };
+        decl.typ = freshen(&mut scope, &mut mapping, &decl.typ);
+    }
+    fn freshen_declaration(&mut self, decl: &mut TypeDeclaration) {
+        let mapping = HashMap::new();
+        self.freshen_declaration2(decl, mapping);
+    }
+
+    ///Replaces every variable of 'constraint' with the variable it is mapped to in 'mapping',
+    ///variables which are not mapped yet are mapped to new variables
+    fn freshen_constraint(&mut self, mapping: &mut HashMap<TypeVariable, Type>, constraint: &mut Constraint) {
+        for var in constraint.variables.mut_iter() {
+            if !mapping.contains_key(var) {
+                let new = self.new_var();
+                mapping.insert(var.clone(), new);
+            }
+            let new = mapping.get(var).var().clone();
+            *var = new;
+        }
+    }
+
+    ///Adds the class of 'constraint' to the constraints of each of its variables
+    fn add_constraint(&mut self, constraint: &Constraint) {
+        for var in constraint.variables.iter() {
+            self.constraints.add(var, constraint.class.as_slice());
+        }
+    }
+
+    ///Adds or replaces the type of a global and records which type variables it contains
+    fn insert_named(&mut self, name: InternedStr, typ: Type) {
+        index_type_variables(&mut self.namedTypeVariables, name, &typ);
+        self.namedTypes.insert(name, typ);
+    }
+
+    ///Applies a substitution on the global types which contain any of the substituted variables.
+    ///The index may contain names whose types no longer contain the variable, which is harmless
+    ///as replacing in those types does nothing.
     fn apply(&mut self, subs: &Substitution) {
-        for (_, typ) in self.namedTypes.mut_iter() {
-            replace(&mut self.constraints, typ, subs);
+        let mut affected = HashSet::new();
+        for var in subs.subs.keys() {
+            match self.namedTypeVariables.find(var) {
+                Some(names) => {
+                    for name in names.iter() {
+                        affected.insert(*name);
+                    }
+                }
+                None => ()
+            }
+        }
+        for name in affected.move_iter() {
+            match self.namedTypes.find_mut(&name) {
+                Some(typ) => {
+                    self.metrics.substitutions += 1;
+                    replace(&mut self.constraints, typ, subs);
+                    //The substitution may have introduced new variables into the type
+                    index_type_variables(&mut self.namedTypeVariables, name, typ);
+                }
+                None => ()
+            }
         }
     }
 
     ///Walks through an expression and applies the substitution on each of its types
     fn substitute(&mut self, subs : &Substitution, expr: &mut TypedExpr) {
+        self.metrics.substitutions += 1;
         replace(&mut self.constraints, &mut expr.typ, subs);
         match &mut expr.expr {
-            &Apply(ref mut func, ref mut arg) => {
-                self.substitute(subs, *func);
-                self.substitute(subs, *arg);
+            &Apply(~(ref mut func, ref mut arg)) => {
+                self.substitute(subs, func);
+                self.substitute(subs, arg);
             }
             &Let(ref mut bindings, ref mut let_expr) => {
                 for bind in bindings.mut_iter() {
@@ -419,46 +1313,86 @@ impl <'a> TypeEnvironment<'a> {
         }
     }
 
-    ///Returns whether the type 'op' has an instance for 'class'
-    fn has_instance(&self, class: &str, searched_type: &Type) -> bool {
-        for &(ref name, ref typ) in self.instances.iter() {
-            if class == *name && typ.typ == searched_type.typ {
-                return true;
-            }
+    ///Returns whether the type 'searched_type' has an instance for 'class', checking the constraints of the instance
+    ///as well so that 'Eq [Int]' requires both 'Eq a => Eq [a]' and 'Eq Int'
+    pub fn has_instance(&self, class: &str, searched_type: &Type) -> bool {
+        self.reduce_constraint(class, searched_type).is_ok()
+    }
+
+    ///Returns true if 'typ' is an instance of 'class'. Instances are searched for in the module and in every
+    ///assembly, recursing through the constraints of each instance found so that 'Eq [[Int]]' is entailed by
+    ///'Eq a => Eq [a]' and 'Eq Int' wherever they are defined.
+    ///The constraints which reach a type variable of 'typ' are added to that variable so they are checked
+    ///once the variable is known.
+    ///If an instance is missing the error names it together with the instance whose context required it.
+    fn entail(&mut self, class: &str, typ: &Type) -> Result<(), UnifyError> {
+        let reduced = try!(self.reduce_constraint(class, typ));
+        for constraint in reduced.iter() {
+            self.constraints.add(&constraint.variables[0], constraint.class.as_slice());
         }
-        
-        for types in self.assemblies.iter() {
-            match types.find_instance(class, searched_type) {
-                Some((constraints, unspecialized_type)) => {
-                    return self.check_instance_constraints(constraints, unspecialized_type.types, searched_type.types);
+        Ok(())
+    }
+
+    ///Reduces the constraint that 'typ' is an instance of 'class' to constraints on the type variables of 'typ'
+    ///using the instances in scope. If an instance is missing the error names it together with the instance
+    ///whose context required it.
+    fn reduce_constraint(&self, class: &str, typ: &Type) -> Result<~[Constraint], UnifyError> {
+        match &typ.typ {
+            &TypeVariable(ref var) => Ok(~[Constraint { class: class.to_owned(), variables: ~[var.clone()] }]),
+            &TypeOperator(_) => {
+                let (constraints, instance_type, matched) = match self.find_matching_instance(class, typ) {
+                    Some(instance) => instance,
+                    None => return Err(UnifyError::new(MissingInstance(class.to_owned(), typ.clone(), None)))
+                };
+                let mut result = ~[];
+                for constraint in constraints.iter() {
+                    match matched.find(&constraint.variables[0]) {
+                        Some(typ) => {
+                            match self.reduce_constraint(constraint.class, typ) {
+                                Ok(reduced) => result.push_all_move(reduced),
+                                Err(UnifyError { kind: MissingInstance(missing_class, missing_type, None), path }) => {
+                                    let required_by = Some((class.to_owned(), instance_type.clone()));
+                                    return Err(UnifyError { kind: MissingInstance(missing_class, missing_type, required_by), path: path });
+                                }
+                                Err(error) => return Err(error)
+                            }
+                        }
+                        None => ()
+                    }
                 }
-                None => ()
+                Ok(result)
             }
         }
-        false
     }
 
-    fn check_instance_constraints(&self, constraints: &[Constraint], vars: &[Type], types: &[Type]) -> bool {
-        for constraint in constraints.iter() {
-            //Constraint is such as (Eq a, Eq b) => Eq (Either a b)
-            //Find the position in the types vector
-            let variable = &constraint.variables[0];
-            let maybe_pos = vars.iter().position(|typ| {
-                match &typ.typ {
-                    &TypeVariable(ref var) => var == variable,
-                    _ => false
+    ///Returns the constraints and type of the instance of 'class' whose type matches 'typ' together with
+    ///the type matched by each variable of the instance
+    pub fn find_matching_instance(&self, class: &str, typ: &Type) -> Option<(~[Constraint], Type, HashMap<TypeVariable, Type>)> {
+        for &(ref name, ref constraints, ref instance_type) in self.instances.iter() {
+            let mut matched = HashMap::new();
+            if class == *name && match_instance_type(&mut matched, instance_type, typ) {
+                return Some((constraints.clone(), instance_type.clone(), matched));
+            }
+        }
+        for types in self.assemblies.iter() {
+            let mut result = None;
+            types.each_instance(|classname, constraints, instance_type| {
+                let mut matched = HashMap::new();
+                if result.is_none() && class == classname && match_instance_type(&mut matched, instance_type, typ) {
+                    result = Some((constraints.to_owned(), instance_type.clone(), matched));
                 }
             });
-            match maybe_pos {
-                Some(pos) => {
-                    if !self.has_instance(constraint.class, &types[pos]) {
-                        return false;
-                    }
-                }
-                None => ()
+            if result.is_some() {
+                return result;
+            }
+        }
+        for &(ref name, ref constraints, ref instance_type) in self.primitive_instances.iter() {
+            let mut matched = HashMap::new();
+            if class == *name && match_instance_type(&mut matched, instance_type, typ) {
+                return Some((constraints.clone(), instance_type.clone(), matched));
             }
         }
-        return true;
+        None
     }
 
     fn new_var(&mut self) -> Type {
@@ -470,8 +1404,10 @@ impl <'a> TypeEnvironment<'a> {
 impl <'a, 'b> Drop for TypeScope<'a, 'b> {
     fn drop(&mut self) {
         while self.vars.len() > 0 {
-            let (name, typ) = self.vars.pop();
-            self.env.namedTypes.insert(name, typ);
+            match self.vars.pop() {
+                (name, Some(typ)) => self.env.insert_named(name, typ),
+                (name, None) => { self.env.namedTypes.pop(&name); }
+            }
         }
     }
 }
@@ -479,10 +1415,26 @@ impl <'a, 'b> Drop for TypeScope<'a, 'b> {
 impl <'a, 'b> TypeScope<'a, 'b> {
 
     fn apply(&mut self, subs: &Substitution) {
-        self.env.apply(subs)
+        self.env.apply(subs);
+        for typ in self.non_generic.mut_iter() {
+            replace(&mut self.env.constraints, typ, subs);
+        }
     }
 
-    fn typecheck(&mut self, expr : &mut TypedExpr, subs: &mut Substitution) {
+    fn typecheck(&mut self, expr : &mut TypedExpr, subs: &mut Substitution) -> TypeResult<()> {
+        match self.typecheck_(expr, subs) {
+            Ok(()) => Ok(()),
+            Err(mut error) => {
+                //The innermost expression which contains the error is the most useful one to report
+                if error.context.is_none() {
+                    error.context = Some(format!("{}", expr.expr));
+                }
+                Err(error)
+            }
+        }
+    }
+
+    fn typecheck_(&mut self, expr : &mut TypedExpr, subs: &mut Substitution) -> TypeResult<()> {
         if expr.typ == Type::new_var(0) {
             expr.typ = self.env.new_var();
         }
@@ -501,22 +1453,36 @@ impl <'a, 'b> TypeScope<'a, 'b> {
                 expr.typ = Type::new_op(~"Char", ~[]);
             }
             &Identifier(ref name) => {
-                match self.fresh(*name) {
-                    Some(t) => {
+                match self.fresh(name.clone()) {
+                    Some(t) if "_" != name.as_slice() => {
                         expr.typ = t;
                     }
-                    None => fail!("Undefined identifier '{}' at {}", *name, expr.location)
+                    _ if name.as_slice().starts_with("_") => {
+                        //The type of a hole is what is expected in its place so it is reported once that is known
+                        let candidates = self.hole_candidates();
+                        self.env.holes.push(Hole { location: expr.location, name: name.clone(), typ: expr.typ.clone(), candidates: candidates });
+                    }
+                    _ => return Err(TypeError::new(expr.location, format!("Undefined identifier '{}'", *name)))
                 }
             }
-            &Apply(ref mut func, ref mut arg) => {
-                self.typecheck(*func, subs);
+            &Apply(~(ref mut func, ref mut arg)) => {
+                try!(self.typecheck(func, subs));
                 replace(&mut self.env.constraints, &mut func.typ, subs);
-                self.typecheck(*arg, subs);
+                try!(self.typecheck(arg, subs));
                 replace(&mut self.env.constraints, &mut arg.typ, subs);
                 expr.typ = function_type(&arg.typ, &self.env.new_var());
-                unify_location(self.env, subs, &expr.location, &mut func.typ, &mut expr.typ);
+                match unify_types(self.env, subs, &mut func.typ, &mut expr.typ) {
+                    Ok(()) => (),
+                    Err(error) => {
+                        //Mismatches in the argument are described by which argument of the function it is
+                        let (function, index) = applied_function(func);
+                        let mut error = type_error(&expr.location, &error, function.map(|name| (name, index)));
+                        error.types = Some((expr.typ.clone(), func.typ.clone()));
+                        return Err(error);
+                    }
+                }
                 replace(&mut self.env.constraints, &mut expr.typ, subs);
-                expr.typ = expr.typ.types[1].clone();
+                expr.typ = expr.typ.types()[1].clone();
             }
             &Lambda(ref arg, ref mut body) => {
                 let argType = self.env.new_var();
@@ -525,30 +1491,40 @@ impl <'a, 'b> TypeScope<'a, 'b> {
                     let mut childScope = self.child();
                     childScope.insert(arg.clone(), &argType);
                     childScope.non_generic.push(argType.clone());
-                    childScope.typecheck(*body, subs);
+                    try!(childScope.typecheck(*body, subs));
                 }
                 replace(&mut self.env.constraints, &mut expr.typ, subs);
-                expr.typ.types[1] = body.typ.clone();
+                expr.typ.mut_types()[1] = body.typ.clone();
             }
             &Let(ref mut bindings, ref mut body) => {
+                //Bindings with a type declaration get fresh variables for it, the same as
+                //is done for the type declarations of a module
+                for bind in bindings.mut_iter() {
+                    if bind.typeDecl.name == bind.name {
+                        self.env.freshen_declaration(&mut bind.typeDecl);
+                        for constraint in bind.typeDecl.context.iter() {
+                            self.env.add_constraint(constraint);
+                        }
+                    }
+                }
                 {
                     let mut childScope = self.child();
-                    childScope.typecheck_mutually_recursive_bindings(subs, &mut BindingsWrapper { value: *bindings });
+                    try!(childScope.typecheck_mutually_recursive_bindings(subs, &mut BindingsWrapper { value: *bindings }));
                     childScope.apply(subs);
-                    childScope.typecheck(*body, subs);
+                    try!(childScope.typecheck(*body, subs));
                 }
                 replace(&mut self.env.constraints, &mut body.typ, subs);
                 expr.typ = body.typ.clone();
             }
             &Case(ref mut case_expr, ref mut alts) => {
-                self.typecheck(*case_expr, subs);
-                self.typecheck_pattern(&alts[0].pattern.location, subs, &alts[0].pattern.node, &mut case_expr.typ);
-                self.typecheck(&mut alts[0].expression, subs);
+                try!(self.typecheck(*case_expr, subs));
+                try!(self.typecheck_pattern(&alts[0].pattern.location, subs, &alts[0].pattern.node, &mut case_expr.typ));
+                try!(self.typecheck(&mut alts[0].expression, subs));
                 let mut alt0_ = alts[0].expression.typ.clone();
                 for alt in alts.mut_iter().skip(1) {
-                    self.typecheck_pattern(&alt.pattern.location, subs, &alt.pattern.node, &mut case_expr.typ);
-                    self.typecheck(&mut alt.expression, subs);
-                    unify_location(self.env, subs, &alt.expression.location, &mut alt0_, &mut alt.expression.typ);
+                    try!(self.typecheck_pattern(&alt.pattern.location, subs, &alt.pattern.node, &mut case_expr.typ));
+                    try!(self.typecheck(&mut alt.expression, subs));
+                    try!(unify_location(self.env, subs, &alt.expression.location, &mut alt0_, &mut alt.expression.typ));
                     replace(&mut self.env.constraints, &mut alt.expression.typ, subs);
                 }
                 replace(&mut self.env.constraints, &mut alts[0].expression.typ, subs);
@@ -556,14 +1532,15 @@ impl <'a, 'b> TypeScope<'a, 'b> {
                 expr.typ = alt0_;
             }
         };
+        Ok(())
     }
 
-    fn typecheck_pattern(&mut self, location: &Location, subs: &mut Substitution, pattern: &Pattern, match_type: &mut Type) {
+    fn typecheck_pattern(&mut self, location: &Location, subs: &mut Substitution, pattern: &Pattern, match_type: &mut Type) -> TypeResult<()> {
         match pattern {
             &IdentifierPattern(ref ident) => {
                 let mut typ = self.env.new_var();
                 {
-                    unify_location(self.env, subs, location, &mut typ, match_type);
+                    try!(unify_inferred(self.env, subs, location, &mut typ, match_type));
                     replace(&mut self.env.constraints, match_type, subs);
                     replace(&mut self.env.constraints, &mut typ, subs);
                 }
@@ -573,45 +1550,75 @@ impl <'a, 'b> TypeScope<'a, 'b> {
             &NumberPattern(_) => {
                 let mut typ = Type::new_op(~"Int", ~[]);
                 {
-                    unify_location(self.env, subs, location, &mut typ, match_type);
+                    try!(unify_inferred(self.env, subs, location, &mut typ, match_type));
+                    replace(&mut self.env.constraints, match_type, subs);
+                    replace(&mut self.env.constraints, &mut typ, subs);
+                }
+            }
+            &CharPattern(_) => {
+                let mut typ = Type::new_op(~"Char", ~[]);
+                {
+                    try!(unify_inferred(self.env, subs, location, &mut typ, match_type));
                     replace(&mut self.env.constraints, match_type, subs);
                     replace(&mut self.env.constraints, &mut typ, subs);
                 }
             }
             &ConstructorPattern(ref ctorname, ref patterns) => {
-                let mut t = self.fresh(*ctorname).expect(format!("Undefined constructer '{}' when matching pattern", *ctorname));
+                let mut t = match self.fresh(intern(*ctorname)) {
+                    Some(t) => t,
+                    None => return Err(TypeError::new(location.clone(), format!("Undefined constructor '{}' when matching pattern", *ctorname)))
+                };
                 let mut data_type = get_returntype(&t);
                 
-                unify_location(self.env, subs, location, &mut data_type, match_type);
+                try!(unify_inferred(self.env, subs, location, &mut data_type, match_type));
                 replace(&mut self.env.constraints, match_type, subs);
                 replace(&mut self.env.constraints, &mut t, subs);
                 self.env.apply(subs);
-                self.pattern_rec(0, location, subs, *patterns, &mut t);
+                try!(self.pattern_rec(0, location, subs, *patterns, &mut t));
             }
         }
+        Ok(())
     }
 
-    fn pattern_rec(&mut self, i: uint, location: &Location, subs: &mut Substitution, patterns: &[Pattern], func_type: &mut Type) {
+    fn pattern_rec(&mut self, i: uint, location: &Location, subs: &mut Substitution, patterns: &[Pattern], func_type: &mut Type) -> TypeResult<()> {
         if i < patterns.len() {
             let p = &patterns[i];
-            self.typecheck_pattern(location, subs, p, &mut func_type.types[0]);
-            self.pattern_rec(i + 1, location, subs, patterns, &mut func_type.types[1]);
+            try!(self.typecheck_pattern(location, subs, p, &mut func_type.mut_types()[0]));
+            try!(self.pattern_rec(i + 1, location, subs, patterns, &mut func_type.mut_types()[1]));
         }
+        Ok(())
     }
 
-    pub fn typecheck_mutually_recursive_bindings(&mut self, subs: &mut Substitution, bindings: &mut Bindings) {
+    pub fn typecheck_mutually_recursive_bindings(&mut self, subs: &mut Substitution, bindings: &mut Bindings) -> TypeResult<()> {
         
         let graph = build_graph(bindings);
         let groups = strongly_connected_components(&graph);
+        //The bindings which the monomorphism restriction prevents from being generalized
+        let mut restricted = ~[];
+        //A binding with a type signature can be used with that type before it is typechecked
+        //so the bindings which use it do not need to be in its group (see 'build_graph')
+        bindings.each_binding(|bind, _| {
+            if has_signature(bind) {
+                self.insert(intern(bind.name), &bind.typeDecl.typ);
+            }
+        });
 
         for i in range(0, groups.len()) {
             let group = &groups[i];
+            //The bindings in the group which have a type signature
+            let mut declared = ~[];
             for index in group.iter() {
                 let bindIndex = graph.get_vertex(*index).value;
                 let bind = bindings.get_mut(bindIndex);
                 bind.expression.typ = self.env.new_var();
-                self.insert(bind.name.clone(), &bind.expression.typ);
-                if bind.typeDecl.typ == Type::new_var(0) {
+                self.insert(intern(bind.name), &bind.expression.typ);
+                //Every binding of the group is monomorphic until the whole group is typechecked,
+                //including the ones which are used before they are typechecked themselves
+                self.non_generic.push(bind.expression.typ.clone());
+                if has_signature(bind) {
+                    declared.push(bindIndex);
+                }
+                else {
                     bind.typeDecl.typ = self.env.new_var();
                 }
             }
@@ -620,50 +1627,106 @@ impl <'a, 'b> TypeScope<'a, 'b> {
                 {
                     let bindIndex = graph.get_vertex(*index).value;
                     let bind = bindings.get_mut(bindIndex);
-                    debug!("Begin typecheck {} :: {}", bind.name, bind.expression.typ);
-                    self.non_generic.push(bind.expression.typ.clone());
+                    log_phase!(Typechecking, Debug, "Begin typecheck {} :: {}", bind.name, bind.expression.typ);
                     let type_var = bind.expression.typ.var().clone();
-                    self.typecheck(&mut bind.expression, subs);
-                    unify_location(self.env, subs, &bind.expression.location, &mut bind.typeDecl.typ, &mut bind.expression.typ);
+                    //The variables of the signature are only skolems while the binding it belongs to is checked
+                    let mut skolems = ~[];
+                    if declared.contains(&bindIndex) {
+                        each_type(&bind.typeDecl.typ, |var| skolems.push(var.clone()), |_| ());
+                    }
+                    for var in skolems.iter() {
+                        self.env.skolems.insert(var.clone());
+                    }
+                    let result = match in_binding(bind.name.as_slice(), self.typecheck(&mut bind.expression, subs)) {
+                        Ok(()) => in_binding(bind.name.as_slice(), unify_location(self.env, subs, &bind.expression.location, &mut bind.typeDecl.typ, &mut bind.expression.typ)),
+                        Err(error) => Err(error)
+                    };
+                    for var in skolems.iter() {
+                        self.env.skolems.remove(var);
+                    }
+                    try!(result);
                     self.env.substitute(subs, &mut bind.expression);
                     subs.subs.insert(type_var, bind.expression.typ.clone());
                     self.apply(subs);
-                    debug!("End typecheck {} :: {}", bind.name, bind.expression.typ);
+                    log_phase!(Typechecking, Debug, "End typecheck {} :: {}", bind.name, bind.expression.typ);
                 }
             }
             
+            let mut monomorphic = ~[];
             for index in group.iter() {
                 let bindIndex = graph.get_vertex(*index).value;
                 let bind = bindings.get_mut(bindIndex);
                 self.non_generic.pop();
                 self.env.substitute(subs, &mut bind.expression);
+                let context = self.env.find_constraints(&bind.expression.typ);
+                //Constraints on the variables of the signature must be declared in the signature
+                let mut signature_variables = ~[];
+                if declared.contains(&bindIndex) {
+                    each_type(&bind.typeDecl.typ, |var| signature_variables.push(var.clone()), |_| ());
+                }
+                for constraint in context.iter() {
+                    if signature_variables.contains(&constraint.variables[0]) && !bind.typeDecl.context.contains(constraint) {
+                        let mut expected = bind.typeDecl.clone();
+                        expected.context.push(constraint.clone());
+                        let message = format!("The type signature of '{}' is missing a {} constraint, it should be '{}'",
+                            bind.name, constraint.class, expected.pretty());
+                        return Err(TypeError::new(bind.expression.location, message));
+                    }
+                }
+                if self.env.monomorphism_restriction && bind.arity == 0 && !declared.contains(&bindIndex) && context.len() != 0 {
+                    monomorphic.push(bind.expression.typ.clone());
+                    restricted.push(bindIndex);
+                }
                 bind.typeDecl.typ = bind.expression.typ.clone();
-                bind.typeDecl.context = self.env.find_constraints(&bind.typeDecl.typ);
+                bind.typeDecl.context = context;
             }
+            //The constrained variables are decided by how the bindings are used in the rest of the scope
+            self.non_generic.push_all_move(monomorphic);
+        }
+        for bindIndex in restricted.iter() {
+            let bind = bindings.get_mut(*bindIndex);
+            self.env.substitute(subs, &mut bind.expression);
+            bind.typeDecl.typ = bind.expression.typ.clone();
+            bind.typeDecl.context = self.env.find_constraints(&bind.expression.typ);
         }
+        Ok(())
     }
 
-    fn insert(&mut self, name: ~str, t : &Type) {
-        match self.env.namedTypes.pop(&name) {
-            Some(typ) => self.vars.push((name.clone(), typ)),
-            None => ()
+    fn insert(&mut self, name: InternedStr, t : &Type) {
+        let previous = self.env.namedTypes.pop(&name);
+        //The globals inserted by the outermost scope stay in the environment, local variables are
+        //removed again so that they are not found (or suggested for a hole) outside of their scope
+        if previous.is_some() || self.parent.is_some() {
+            self.vars.push((name.clone(), previous));
         }
-        self.env.namedTypes.insert(name, t.clone());
+        self.env.insert_named(name, t.clone());
     }
-    fn find(&'a self, name: &str) -> Option<&'a Type> {
-        self.env.find(name)
+    fn find(&'a self, name: InternedStr) -> Option<&'a Type> {
+        self.env.find_interned(name)
     }
 
     ///Instantiates new typevariables for every typevariable in the type found at 'name'
-    fn fresh(&'a self, name: &str) -> Option<Type> {
-        match self.find(name) {
+    fn fresh(&'a self, name: InternedStr) -> Option<Type> {
+        match self.env.namedTypes.find(&name) {
             Some(x) => {
                 let mut mapping = HashMap::new();
-                let typ = x;
-                Some(freshen(self, &mut mapping, typ))
+                return Some(freshen(self, &mut mapping, x))
             }
-            None => None
+            None => ()
+        }
+        //The types of the assemblies never change so their skeletons can be reused
+        if !self.env.instantiations.contains_key(&name) {
+            let skeleton = match self.env.find_interned(name.clone()) {
+                Some(typ) => TypeSkeleton::new(typ),
+                None => return None
+            };
+            self.env.instantiations.insert(name.clone(), skeleton);
         }
+        let variables = self.env.instantiations.get(&name).variables.clone();
+        let replacements : ~[Type] = variables.iter().map(|var| {
+            if self.is_generic(var) { fresh_variable(self, var) } else { Type::new_var(var.id) }
+        }).collect();
+        Some(self.env.instantiations.get(&name).instantiate(replacements))
     }
 
     fn is_generic(&'a self, var: &TypeVariable) -> bool {
@@ -682,6 +1745,24 @@ impl <'a, 'b> TypeScope<'a, 'b> {
         }
     }
 
+    ///Returns the bindings in scope, both the local variables and the globals, together with their instantiated types.
+    ///The ones which fit in a hole are reported once the type of the hole is known.
+    fn hole_candidates(&'a self) -> ~[(InternedStr, Type)] {
+        let mut names : ~[InternedStr] = self.env.namedTypes.keys().map(|name| name.clone()).collect();
+        self.env.each_global(|decl| {
+            let name = intern(decl.name);
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        });
+        //Each candidate is instantiated with new variables so they are visited in a fixed order
+        names.sort_by(|l, r| l.as_slice().cmp(&r.as_slice()));
+        names.iter()
+            .filter(|name| !name.as_slice().starts_with("#") && !name.as_slice().starts_with("_"))
+            .filter_map(|name| self.fresh(name.clone()).map(|typ| (name.clone(), typ)))
+            .collect()
+    }
+
     fn child(&'a self) -> TypeScope<'a, 'b> {
         TypeScope { env: self.env, vars: ~[], non_generic: ~[], parent: Some(self) }
     }
@@ -705,7 +1786,7 @@ fn replace_var(typ: &mut Type, var: &TypeVariable, replacement: &Type) {
                 &TypeVariable(_) => true,
                 &TypeOperator(_) => false
             };
-            if typ.types.len() > 0 && is_var {
+            if typ.types().len() > 0 && is_var {
                 typ.typ = x.typ.clone();
             }
             else {
@@ -714,8 +1795,10 @@ fn replace_var(typ: &mut Type, var: &TypeVariable, replacement: &Type) {
         }
         None => ()
     }
-    for t in typ.types.mut_iter() {
-        replace_var(t, var, replacement);
+    for types in typ.args.mut_iter() {
+        for t in types.mut_iter() {
+            replace_var(t, var, replacement);
+        }
     }
 }
 
@@ -723,7 +1806,7 @@ fn get_returntype(typ: &Type) -> Type {
     match &typ.typ {
         &TypeOperator(ref op) => {
             if op.name == ~"->" {
-                get_returntype(&typ.types[1])
+                get_returntype(&typ.types()[1])
             }
             else {
                 typ.clone()
@@ -734,16 +1817,13 @@ fn get_returntype(typ: &Type) -> Type {
 }
 
 ///Update the constraints when replacing the variable 'old' with 'new'
-fn update_constraints(constraints: &mut HashMap<TypeVariable, ~[~str]>, old: &TypeVariable, new: &Type, subs: &Substitution) {
+fn update_constraints(constraints: &mut VariableConstraints, old: &TypeVariable, new: &Type, subs: &Substitution) {
     match &new.typ {
         &TypeVariable(ref new_var) => {
             match subs.constraints.find(old) {
                 Some(subs_constraints) => {
-                    let to_update = constraints.find_or_insert(new_var.clone(), ~[]);
                     for c in subs_constraints.iter() {
-                        if to_update.iter().find(|x| *x == c) == None {
-                            to_update.push(c.clone());
-                        }
+                        constraints.add(new_var, c.as_slice());
                     }
                 }
                 None => ()
@@ -754,7 +1834,7 @@ fn update_constraints(constraints: &mut HashMap<TypeVariable, ~[~str]>, old: &Ty
 }
 
 ///Replace all typevariables using the substitution 'subs'
-fn replace(constraints: &mut HashMap<TypeVariable, ~[~str]>, old : &mut Type, subs : &Substitution) {
+fn replace(constraints: &mut VariableConstraints, old : &mut Type, subs : &Substitution) {
     let replaced = match &mut old.typ {
         &TypeVariable(ref id) => {
             match subs.subs.find(id) {
@@ -773,7 +1853,7 @@ fn replace(constraints: &mut HashMap<TypeVariable, ~[~str]>, old : &mut Type, su
                 &TypeVariable(_) => true,
                 &TypeOperator(_) => false
             };
-            if old.types.len() > 0 && is_var {
+            if old.types().len() > 0 && is_var {
                 old.typ = x.typ;
             }
             else {
@@ -782,60 +1862,342 @@ fn replace(constraints: &mut HashMap<TypeVariable, ~[~str]>, old : &mut Type, su
         }
         None => ()
     }
-    for t in old.types.mut_iter() {
-        replace(constraints, t, subs); 
+    for types in old.args.mut_iter() {
+        for t in types.mut_iter() {
+            replace(constraints, t, subs);
+        }
+    }
+}
+
+///Replaces the head of 'typ' until it is no longer a variable bound in 'subs'.
+///Bindings may refer to variables which were bound later in the same unification so this has to
+///follow the chain rather than look up the variable once.
+fn resolve_head(constraints: &mut VariableConstraints, typ: &mut Type, subs: &Substitution) {
+    loop {
+        let new = match &typ.typ {
+            &TypeVariable(ref id) => {
+                match subs.subs.find(id) {
+                    Some(new) => {
+                        update_constraints(constraints, id, new, subs);
+                        new.clone()
+                    }
+                    None => break
+                }
+            }
+            &TypeOperator(_) => break
+        };
+        if typ.types().len() > 0 {
+            typ.typ = new.typ;
+        }
+        else {
+            *typ = new;
+        }
+    }
+}
+
+///Checks that finding 'instance' terminates, each constraint of its context must be smaller than its head
+///and must not mention a variable more often than the head does
+fn check_instance_termination(instance: &Instance) -> TypeResult<()> {
+    let mut head_variables = ~[];
+    each_type(&instance.typ, |var| head_variables.push(var.clone()), |_| ());
+    let head_size = type_size(&instance.typ);
+    for (i, constraint) in instance.constraints.iter().enumerate() {
+        for var in constraint.variables.iter() {
+            let in_constraint = constraint.variables.iter().filter(|v| *v == var).count();
+            let in_head = head_variables.iter().filter(|v| *v == var).count();
+            if in_constraint > in_head {
+                return Err(TypeError::new(instance.location, format!("The constraint '{}' of the instance '{}' mentions a type variable more often than the instance head, finding the instance may not terminate",
+                    instance.pretty_constraint(i), instance.pretty())));
+            }
+        }
+        if constraint.variables.len() >= head_size {
+            return Err(TypeError::new(instance.location, format!("The constraint '{}' of the instance '{}' is no smaller than the instance head, finding the instance may not terminate",
+                instance.pretty_constraint(i), instance.pretty())));
+        }
+    }
+    Ok(())
+}
+
+///Returns the number of variables and type constructors in 'typ'
+fn type_size(typ: &Type) -> uint {
+    typ.types().iter().fold(1, |size, t| size + type_size(t))
+}
+
+///Checks that every type constructor in 'typ' which has a known arity is applied to that many arguments.
+///Tuple constructors such as '(,)' take one more argument than they have commas.
+///Errors are reported at 'location', where the type of 'name' was declared.
+fn check_arity(arities: &HashMap<~str, uint>, typ: &Type, name: &str, location: Location) -> TypeResult<()> {
+    match &typ.typ {
+        &TypeOperator(ref op) => {
+            let arity = if op.name.starts_with("(") && op.name.len() > 2 {
+                Some(op.name.len() - 1)
+            }
+            else {
+                arities.find(&op.name).map(|arity| *arity)
+            };
+            match arity {
+                Some(arity) if arity != typ.types().len() => {
+                    return Err(TypeError::new(location, format!("The type '{}' expects {} arguments but was given {} in the type of '{}'",
+                        op.name, arity, typ.types().len(), name)));
+                }
+                _ => ()
+            }
+        }
+        &TypeVariable(_) => ()
+    }
+    for t in typ.types().iter() {
+        try!(check_arity(arities, t, name, location));
+    }
+    Ok(())
+}
+
+///Creates a copy of 'typ' where each variable in 'parameters' is replaced by the argument at the same position
+fn substitute_parameters(typ: &Type, parameters: &[TypeVariable], arguments: &[Type]) -> Type {
+    let head = match &typ.typ {
+        &TypeVariable(ref var) => {
+            match parameters.iter().position(|p| p == var) {
+                Some(index) => {
+                    let mut argument = arguments[index].clone();
+                    for t in typ.types().iter() {
+                        argument.push_type(substitute_parameters(t, parameters, arguments));
+                    }
+                    return argument
+                }
+                None => typ.typ.clone()
+            }
+        }
+        &TypeOperator(_) => typ.typ.clone()
+    };
+    Type::new(head, typ.types().iter().map(|t| substitute_parameters(t, parameters, arguments)).collect())
+}
+
+///Checks whether a typevariable occurs in another type
+fn occurs(type_var: &TypeVariable, inType: &Type) -> bool {
+    (match &inType.typ {
+        &TypeVariable(ref var) => type_var.id == var.id,
+        &TypeOperator(_) => false
+    }) || inType.types().iter().any(|t| occurs(type_var, t))
+}
+
+///Returns a new variable with the same constraints as 'var'
+fn fresh_variable(env: &TypeScope, var: &TypeVariable) -> Type {
+    let new = env.env.new_var();
+    let maybe_constraints = match env.env.constraints.find(var) {
+        Some(constraints) => Some(constraints.clone()),
+        None => None
+    };
+    match (maybe_constraints, new.typ.clone()) {
+        (Some(c), TypeVariable(newid)) => { env.env.constraints.insert(newid, c); }
+        _ => ()
+    }
+    new
+}
+
+fn freshen(env: &TypeScope, mapping: &mut HashMap<TypeVariable, Type>, typ: &Type) -> Type {
+    let result = match &typ.typ {
+        &TypeVariable(ref id) => {
+            if env.is_generic(id) {
+                let new = fresh_variable(env, id);
+                mapping.find_or_insert(id.clone(), new).typ.clone()
+            }
+            else {
+                typ.typ.clone()
+            }
+        }
+        &TypeOperator(_) => {
+            typ.typ.clone()
+        }
+    };
+    Type::new(result, typ.types().iter().map(|t| freshen(env, mapping, t)).collect())
+}
+
+///Matches the type of an instance against 'typ', recording the type matched by each variable of the instance.
+///Variables in 'typ' are not known yet and so they match any part of the instance.
+pub fn match_instance_type(matched: &mut HashMap<TypeVariable, Type>, instance_type: &Type, typ: &Type) -> bool {
+    match (&instance_type.typ, &typ.typ) {
+        (&TypeVariable(ref var), _) => {
+            match matched.find(var) {
+                Some(previous) => return previous == typ,
+                None => ()
+            }
+            matched.insert(var.clone(), typ.clone());
+            true
+        }
+        (_, &TypeVariable(_)) => true,
+        (&TypeOperator(ref l), &TypeOperator(ref r)) => {
+            let (lhs, rhs) = (instance_type.types(), typ.types());
+            l.name == r.name && lhs.len() == rhs.len()
+                && lhs.iter().zip(rhs.iter()).all(|(l, r)| match_instance_type(matched, l, r))
+        }
+    }
+}
+
+///Returns true if some type is matched by both of the instance types 'lhs' and 'rhs'
+fn instance_types_overlap(lhs: &Type, rhs: &Type) -> bool {
+    match (&lhs.typ, &rhs.typ) {
+        (&TypeVariable(_), _) | (_, &TypeVariable(_)) => true,
+        (&TypeOperator(ref l), &TypeOperator(ref r)) => {
+            l.name == r.name && lhs.types().len() == rhs.types().len()
+                && lhs.types().iter().zip(rhs.types().iter()).all(|(l, r)| instance_types_overlap(l, r))
+        }
+    }
+}
+
+///Takes two types and attempts to make them the same type
+fn unify_location(env: &mut TypeEnvironment, subs: &mut Substitution, location: &Location, lhs: &mut Type, rhs: &mut Type) -> TypeResult<()> {
+    match unify_types(env, subs, lhs, rhs) {
+        Ok(()) => Ok(()),
+        Err(error) => {
+            let mut error = type_error(location, &error, None);
+            error.types = Some((lhs.clone(), rhs.clone()));
+            Err(error)
+        }
+    }
+}
+
+///Takes two types and attempts to make them the same type, returning why they could not be unified otherwise
+fn unify_types(env: &mut TypeEnvironment, subs: &mut Substitution, lhs: &mut Type, rhs: &mut Type) -> Result<(), UnifyError> {
+    log_phase!(Typechecking, Debug, "Unifying {} <-> {}", *lhs, *rhs);
+    env.metrics.unifications += 1;
+    try!(unify_(env, subs, lhs, rhs));
+    let subs2 = subs.clone();
+    for (_, ref mut typ) in subs.subs.mut_iter() {
+        replace(&mut env.constraints, *typ, &subs2);
+    }
+    Ok(())
+}
+
+///The reason why two types could not be unified
+struct UnifyError {
+    kind: UnifyErrorKind,
+    ///The type operators and the positions of their arguments which lead from the unified types
+    ///to the parts where the error was found, outermost first
+    path: ~[(~str, uint)]
+}
+
+enum UnifyErrorKind {
+    ///Two different types, the innermost parts which differ
+    TypeMismatch(Type, Type),
+    ///A type variable was unified with a type which contains it
+    RecursiveType(TypeVariable, Type),
+    ///A type variable applied to arguments was unified with a type operator with a different number of arguments
+    ArityMismatch(Type, Type),
+    ///The class and type of an instance which does not exist and the class and type of the instance, if any,
+    ///whose context required it
+    MissingInstance(~str, Type, Option<(~str, Type)>),
+    ///A variable of a type signature was unified with something other than a variable of the binding
+    SignatureTooGeneral
+}
+
+impl UnifyError {
+    fn new(kind: UnifyErrorKind) -> UnifyError {
+        UnifyError { kind: kind, path: ~[] }
+    }
+}
+
+///Creates the error reported for a failed unification. 'argument' is the name of the function and the position
+///of its argument when the types are those of a function application.
+fn type_error(location: &Location, error: &UnifyError, argument: Option<(~str, uint)>) -> TypeError {
+    let mut message = match error.kind {
+        TypeMismatch(ref expected, ref actual) => {
+            let names = Type::pretty_all([expected, actual]);
+            format!("Could not match '{}' with '{}'", names[0], names[1])
+        }
+        RecursiveType(ref var, ref typ) => {
+            let names = Type::pretty_all([&Type::new_var(var.id), typ]);
+            format!("Could not construct the infinite type {} = {}", names[0], names[1])
+        }
+        ArityMismatch(ref lhs, ref rhs) => {
+            let names = Type::pretty_all([lhs, rhs]);
+            format!("The types '{}' and '{}' do not have the same number of arguments", names[0], names[1])
+        }
+        MissingInstance(ref class, ref typ, _) => format!("The instance '{} {}' was not found", *class, typ.pretty()),
+        SignatureTooGeneral => ~"The type signature is more general than the type of the binding"
+    };
+    match argument {
+        Some((ref function, index)) if error.path.head_opt() == Some(&(~"->", 0)) => {
+            message.push_str(format!(" in the {} argument of '{}'", ordinal(index), *function));
+        }
+        _ => ()
+    }
+    match error.kind {
+        MissingInstance(_, _, Some((ref class, ref typ))) => {
+            message.push_str(format!(", it is required by the instance '{} {}'", *class, typ.pretty()));
+        }
+        _ => ()
+    }
+    TypeError::new(location.clone(), message)
+}
+
+///Returns the name of the function at the head of 'func', if it is a name, and which argument of it
+///the argument applied to 'func' is
+fn applied_function(func: &TypedExpr) -> (Option<~str>, uint) {
+    match &func.expr {
+        &Apply(~(ref inner, _)) => {
+            let (name, index) = applied_function(inner);
+            (name, index + 1)
+        }
+        &Identifier(ref name) => (Some(name.as_slice().to_owned()), 1),
+        _ => (None, 1)
     }
 }
 
-///Checks whether a typevariable occurs in another type
-fn occurs(type_var: &TypeVariable, inType: &Type) -> bool {
-    (match &inType.typ {
-        &TypeVariable(ref var) => type_var.id == var.id,
-        &TypeOperator(_) => false
-    }) || inType.types.iter().any(|t| occurs(type_var, t))
+fn ordinal(n: uint) -> ~str {
+    match n {
+        1 => ~"first",
+        2 => ~"second",
+        3 => ~"third",
+        4 => ~"fourth",
+        5 => ~"fifth",
+        //11th, 12th and 13th but 21st, 22nd and 23rd
+        _ if n % 100 / 10 == 1 => format!("{}th", n),
+        _ => match n % 10 {
+            1 => format!("{}st", n),
+            2 => format!("{}nd", n),
+            3 => format!("{}rd", n),
+            _ => format!("{}th", n)
+        }
+    }
 }
 
-fn freshen(env: &TypeScope, mapping: &mut HashMap<TypeVariable, Type>, typ: &Type) -> Type {
-    let result = match &typ.typ {
-        &TypeVariable(ref id) => {
-            if env.is_generic(id) {
-                let new = env.env.new_var();
-                let maybe_constraints = match env.env.constraints.find(id) {
-                    Some(constraints) => Some(constraints.clone()),
-                    None => None
-                };
-                match (maybe_constraints, new.typ.clone()) {
-                    (Some(c), TypeVariable(newid)) => { env.env.constraints.insert(newid, c); }
-                    _ => ()
-                }
-                mapping.find_or_insert(id.clone(), new.clone()).typ.clone()
-            }
-            else {
-                typ.typ.clone()
-            }
-        }
-        &TypeOperator(_) => {
-            typ.typ.clone()
+///Unifies 'inferred' with 'expected' and reports the types in the same order as 'unify_location' on an error
+fn unify_inferred(env: &mut TypeEnvironment, subs: &mut Substitution, location: &Location, inferred: &mut Type, expected: &mut Type) -> TypeResult<()> {
+    match unify_location(env, subs, location, inferred, expected) {
+        Ok(()) => Ok(()),
+        Err(mut error) => {
+            error.types = error.types.map(|(inferred, expected)| (expected, inferred));
+            Err(error)
         }
-    };
-    Type { typ: result, types: typ.types.iter().map(|t| freshen(env, mapping, t)).collect() }
+    }
 }
 
-///Takes two types and attempts to make them the same type
-fn unify_location(env: &mut TypeEnvironment, subs: &mut Substitution, location: &Location, lhs: &mut Type, rhs: &mut Type) {
-    debug!("Unifying {} <-> {}", *lhs, *rhs);
-    type_error::cond.trap(|_| (location.clone(), lhs.clone(), rhs.clone())).inside(|| {
-        unify_(env, subs, lhs, rhs);
-        
-        let subs2 = subs.clone();
-        for (_, ref mut typ) in subs.subs.mut_iter() {
-            replace(&mut env.constraints, *typ, &subs2);
+///Marks an error as occuring in the binding 'name' unless it is already known to be in an inner binding
+fn in_binding<T>(name: &str, result: TypeResult<T>) -> TypeResult<T> {
+    match result {
+        Err(mut error) => {
+            if error.binding.is_none() {
+                error.binding = Some(name.to_owned());
+            }
+            Err(error)
         }
-    })
+        result => result
+    }
 }
 
-fn unify_(env : &mut TypeEnvironment, subs : &mut Substitution, lhs : &mut Type, rhs : &mut Type) {
+///Unifies 'lhs' and 'rhs', returning a description of the problem if they can't be unified
+fn unify_(env : &mut TypeEnvironment, subs : &mut Substitution, lhs : &mut Type, rhs : &mut Type) -> Result<(), UnifyError> {
+    //Only the heads are resolved here, the arguments are resolved as the recursion reaches them
+    //so that each part of the spine of a type is only visited once
+    resolve_head(&mut env.constraints, lhs, subs);
+    resolve_head(&mut env.constraints, rhs, subs);
     let unified = match (& &lhs.typ, & &rhs.typ) {
+        (& &TypeVariable(ref lid), & &TypeVariable(ref rid)) if lid != rid && env.skolems.contains(lid) => {
+            if env.skolems.contains(rid) {
+                return Err(UnifyError::new(SignatureTooGeneral));
+            }
+            //Bind the variable which is not from a signature instead
+            false
+        }
         (& &TypeVariable(ref lid), & &TypeVariable(ref rid)) => {
             if lid != rid {
                 let mut t = Type::new_var(rid.id);
@@ -849,61 +2211,52 @@ fn unify_(env : &mut TypeEnvironment, subs : &mut Substitution, lhs : &mut Type,
             true
         }
         (& &TypeOperator(ref l), & &TypeOperator(ref r)) => {
-            if l.name != r.name || lhs.types.len() != rhs.types.len() {
-                let (location, l, r) = type_error::cond.raise(());
-                fail!("{} Error: Could not unify types {}\nand\n{}", location, l, r)
-            }
-            for i in range(0, lhs.types.len()) {
-                unify_(env, subs, &mut lhs.types[i], &mut rhs.types[i]);
-                if i < lhs.types.len() - 1 {
-                    replace(&mut env.constraints, &mut lhs.types[i+1], subs);
-                    replace(&mut env.constraints, &mut rhs.types[i+1], subs);
+            if l.name != r.name || lhs.types().len() != rhs.types().len() {
+                let mut expected = lhs.clone();
+                let mut actual = rhs.clone();
+                replace(&mut env.constraints, &mut expected, subs);
+                replace(&mut env.constraints, &mut actual, subs);
+                return Err(UnifyError::new(TypeMismatch(expected, actual)));
+            }
+            for i in range(0, lhs.types().len()) {
+                match unify_(env, subs, &mut mut_arguments(&mut lhs.args)[i], &mut mut_arguments(&mut rhs.args)[i]) {
+                    Ok(()) => (),
+                    Err(mut error) => {
+                        error.path.unshift((l.name.clone(), i));
+                        return Err(error);
+                    }
                 }
             }
             true
         }
         (& &TypeVariable(ref lid), & &TypeOperator(ref op)) => {
-            if (occurs(lid, rhs)) {
-                let (location, l, r) = type_error::cond.raise(());
-                fail!("{} Error: Recursive unification between {}\nand\n{}", location, l, r);
+            if env.skolems.contains(lid) {
+                return Err(UnifyError::new(SignatureTooGeneral));
             }
             let mut t = (*rhs).clone();
-            if lhs.types.len() == 0 {
-                replace(&mut env.constraints, &mut t, subs);
-                subs.subs.insert(lid.clone(), t);
+            replace(&mut env.constraints, &mut t, subs);
+            if (occurs(lid, &t)) {
+                return Err(UnifyError::new(RecursiveType(lid.clone(), t)));
+            }
+            if lhs.types().len() == 0 {
+                subs.subs.insert(lid.clone(), t.clone());
             }
             else {
-                if lhs.types.len() != rhs.types.len() {
-                let (location, l, r) = type_error::cond.raise(());
-                    fail!("{} Error: Types do not have the same arity.\n{}\nand\n{}", location, l, r);
+                if lhs.types().len() != rhs.types().len() {
+                    return Err(UnifyError::new(ArityMismatch(lhs.clone(), rhs.clone())));
                 }
                 let mut x = Type::new_op(op.name.clone(), ~[]);
                 replace(&mut env.constraints, &mut x, subs);
                 subs.subs.insert(lid.clone(), x);
-                for i in range(0, lhs.types.len()) {
-                    unify_(env, subs, &mut lhs.types[i], &mut rhs.types[i]);
-                    if i < lhs.types.len() - 1 {
-                        replace(&mut env.constraints, &mut lhs.types[i+1], subs);
-                        replace(&mut env.constraints, &mut rhs.types[i+1], subs);
-                    }
+                for i in range(0, lhs.types().len()) {
+                    try!(unify_(env, subs, &mut mut_arguments(&mut lhs.args)[i], &mut mut_arguments(&mut rhs.args)[i]));
                 }
             }
             //Check that the type operator has an instance for all the constraints of the variable
-            match env.constraints.find(lid) {
+            match env.constraints.find(lid).map(|constraints| constraints.clone()) {
                 Some(constraints) => {
                     for c in constraints.iter() {
-                        if !env.has_instance(*c, rhs) {
-                            if c.equiv(& &"Num") && (op.name.equiv(& &"Int") || op.name.equiv(& &"Double")) && rhs.types.len() == 0 {
-                                continue;
-                            }
-                            else if c.equiv(& &"Fractional") && "Double" == op.name && rhs.types.len() == 0 {
-                                continue;
-                            }
-                            else {
-                                let (location, l, r) = type_error::cond.raise(());
-                                fail!("{} Error: The instance {} {} was not found as required by {} when unifying {}\nand\n{}", location, *c, *op, *lid, l, r);
-                            }
-                        }
+                        try!(env.entail(*c, &t));
                     }
                 }
                 None => ()
@@ -915,27 +2268,65 @@ fn unify_(env : &mut TypeEnvironment, subs : &mut Substitution, lhs : &mut Type,
     if !unified {
         return unify_(env, subs, rhs, lhs);
     }
+    Ok(())
+}
 
+///Returns true if 'bind' has a type signature
+fn has_signature(bind: &Binding) -> bool {
+    bind.typeDecl.typ != Type::new_var(0)
 }
 
-///Creates a graph containing a vertex for each binding and edges for each 
+///Creates a graph with a vertex for each binding and an edge from a binding to each binding it uses.
+///Bindings with a type signature get no edges to them as their type is known before they are typechecked,
+///that way mutually recursive bindings with signatures are not typechecked as one monomorphic group.
 fn build_graph(bindings: &Bindings) -> Graph<(uint, uint)> {
     let mut graph = Graph::new();
+    let mut vertices = HashMap::new();
+    //The bindings which other bindings get edges to
     let mut map = HashMap::new();
     bindings.each_binding(|bind, i| {
         let index = graph.new_vertex(i);
-        map.insert(bind.name.clone(), index);
+        vertices.insert(intern(bind.name), index);
+        if !has_signature(bind) {
+            map.insert(intern(bind.name), index);
+        }
     });
     bindings.each_binding(|bind, _| {
-        add_edges(&mut graph, &map, *map.get(&bind.name), &bind.expression);
+        let index = *vertices.get(&intern(bind.name));
+        add_edges(&mut graph, &map, index, &bind.expression);
+        //The variables of a pattern binding depend on the expression they are bound from, the expression
+        //is made to depend on them as well so that the whole pattern binding is a single component
+        match pattern_binding_source(&bind.expression) {
+            Some(source) => {
+                match map.find(&source) {
+                    Some(source_index) => graph.connect(*source_index, index),
+                    None => ()
+                }
+            }
+            None => ()
+        }
     });
     graph
 }
 
-fn add_edges<T>(graph: &mut Graph<T>, map: &HashMap<~str, VertexIndex>, function_index: VertexIndex, expr: &TypedExpr) {
+///Returns the name of the binding which a variable of a pattern binding is extracted from.
+///The parser turns 'let (x, y) = pair' into '#pattern0 = pair' and 'x = case #pattern0 of (x, y) -> x'.
+fn pattern_binding_source(expr: &TypedExpr) -> Option<InternedStr> {
+    match &expr.expr {
+        &Case(ref scrutinee, ref alts) if alts.len() == 1 => {
+            match &scrutinee.expr {
+                &Identifier(ref name) if name.as_slice().starts_with("#pattern") => Some(*name),
+                _ => None
+            }
+        }
+        _ => None
+    }
+}
+
+fn add_edges<T>(graph: &mut Graph<T>, map: &HashMap<InternedStr, VertexIndex>, function_index: VertexIndex, expr: &TypedExpr) {
     match &expr.expr {
         &Identifier(ref n) => {
-            match map.find_equiv(n) {
+            match map.find(n) {
                 Some(index) => graph.connect(function_index, *index),
                 None => ()
             }
@@ -943,9 +2334,9 @@ fn add_edges<T>(graph: &mut Graph<T>, map: &HashMap<~str, VertexIndex>, function
         &Lambda(_, ref body) => {
             add_edges(graph, map, function_index, *body);
         }
-        &Apply(ref f, ref a) => {
-            add_edges(graph, map, function_index, *f);
-            add_edges(graph, map, function_index, *a);
+        &Apply(~(ref f, ref a)) => {
+            add_edges(graph, map, function_index, f);
+            add_edges(graph, map, function_index, a);
         }
         &Let(ref binds, ref body) => {
             add_edges(graph, map, function_index, *body);
@@ -963,6 +2354,16 @@ fn add_edges<T>(graph: &mut Graph<T>, map: &HashMap<~str, VertexIndex>, function
     }
 }
 
+///Records 'name' as containing each of the type variables in 'typ'
+fn index_type_variables(index: &mut HashMap<TypeVariable, ~[InternedStr]>, name: InternedStr, typ: &Type) {
+    each_type(typ, |var| {
+        let names = index.find_or_insert(var.clone(), ~[]);
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }, |_| ());
+}
+
 fn each_type(typ: &Type, var_fn: |&TypeVariable|, op_fn: |&TypeOperator|) {
     each_type_(typ, &var_fn, &op_fn);
 }
@@ -971,7 +2372,7 @@ fn each_type_(typ: &Type, var_fn: &|&TypeVariable|, op_fn: &|&TypeOperator|) {
         &TypeVariable(ref var) => (*var_fn)(var),
         &TypeOperator(ref op) => (*op_fn)(op)
     }
-    for t in typ.types.iter() {
+    for t in typ.types().iter() {
         each_type_(t, var_fn, op_fn);
     }
 }
@@ -982,11 +2383,11 @@ pub fn function_type(func : &Type, arg : &Type) -> Type {
 
 #[cfg(test)]
 pub fn identifier(i : ~str) -> TypedExpr {
-    TypedExpr::new(Identifier(i))
+    TypedExpr::new(Identifier(intern(i)))
 }
 #[cfg(test)]
 pub fn lambda(arg : ~str, body : TypedExpr) -> TypedExpr {
-    TypedExpr::new(Lambda(arg, ~body))
+    TypedExpr::new(Lambda(intern(arg), ~body))
 }
 #[cfg(test)]
 pub fn number(i : int) -> TypedExpr {
@@ -998,7 +2399,7 @@ pub fn rational(i : f64) -> TypedExpr {
 }
 #[cfg(test)]
 pub fn apply(func : TypedExpr, arg : TypedExpr) -> TypedExpr {
-    TypedExpr::new(Apply(~func, ~arg))
+    TypedExpr::new(Apply(~(func, arg)))
 }
 #[cfg(test)]
 pub fn let_(bindings : ~[Binding], expr : TypedExpr) -> TypedExpr {
@@ -1013,6 +2414,10 @@ pub fn case(expr : TypedExpr, alts: ~[Alternative]) -> TypedExpr {
 mod test {
 use module::*;
 use typecheck::*;
+use typecheck::{Substitution, Bindings, build_graph, VariableConstraints, TypeSkeleton, unify_location, replace, each_type, Renumbering};
+use typecheck::{UnifyError, TypeMismatch, type_error, ordinal};
+use graph::strongly_connected_components;
+use std::hashmap::HashMap;
 
 use parser::Parser;
 use std::io::File;
@@ -1021,14 +2426,14 @@ use std::str::from_utf8;
 #[test]
 fn application() {
     let mut env = TypeEnvironment::new();
-    let n = ~TypedExpr::new(Identifier(~"add"));
-    let num = ~TypedExpr::new(Number(1));
-    let mut expr = TypedExpr::new(Apply(n, num));
+    let n = TypedExpr::new(Identifier(intern("add")));
+    let num = TypedExpr::new(Number(1));
+    let mut expr = TypedExpr::new(Apply(~(n, num)));
     let type_int = Type::new_op(~"Int", ~[]);
     let unary_func = function_type(&type_int, &type_int);
     let add_type = function_type(&type_int, &unary_func);
-    env.namedTypes.insert(~"add", add_type);
-    env.typecheck(&mut expr);
+    env.insert_named(intern("add"), add_type);
+    env.typecheck(&mut expr).unwrap();
 
     let expr_type = expr.typ;
     assert!(expr_type == unary_func);
@@ -1042,8 +2447,8 @@ fn typecheck_lambda() {
     let add_type = function_type(&type_int, &unary_func);
 
     let mut expr = lambda(~"x", apply(apply(identifier(~"add"), identifier(~"x")), number(1)));
-    env.namedTypes.insert(~"add", add_type);
-    env.typecheck(&mut expr);
+    env.insert_named(intern("add"), add_type);
+    env.typecheck(&mut expr).unwrap();
 
     assert_eq!(expr.typ, unary_func);
 }
@@ -1058,12 +2463,236 @@ fn typecheck_let() {
     //let test x = add x in test
     let unary_bind = lambda(~"x", apply(apply(identifier(~"add"), identifier(~"x")), number(1)));
     let mut expr = let_(~[Binding { arity: 1, name: ~"test", expression: unary_bind, typeDecl: Default::default() }], identifier(~"test"));
-    env.namedTypes.insert(~"add", add_type);
-    env.typecheck(&mut expr);
+    env.insert_named(intern("add"), add_type);
+    env.typecheck(&mut expr).unwrap();
 
     assert_eq!(expr.typ, unary_func);
 }
 
+#[test]
+fn typecheck_let_type_declaration() {
+    let mut env = TypeEnvironment::new();
+    let type_int = Type::new_op(~"Int", ~[]);
+
+    let mut parser = Parser::new(r"let { f :: Int -> Int ; f x = x } in f".chars());
+    let mut expr = parser.expression_();
+    env.typecheck(&mut expr).unwrap();
+
+    assert_eq!(expr.typ, function_type(&type_int, &type_int));
+}
+
+#[test]
+fn type_error_is_returned() {
+    let mut env = TypeEnvironment::new();
+    let mut parser = Parser::new("primIntAdd 1 'a'".chars());
+    let mut expr = parser.expression_();
+    let error = env.typecheck(&mut expr).unwrap_err();
+    assert_eq!(error.message, ~"Could not match 'Int' with 'Char' in the second argument of 'primIntAdd'");
+    assert!(error.types.is_some());
+    assert!(error.context.is_some());
+    assert_eq!(error.location.column, 1);
+}
+
+#[test]
+fn undefined_identifier_error() {
+    let mut env = TypeEnvironment::new();
+    let mut parser = Parser::new("main = primIntAdd 1 undefinedName".chars());
+    let mut module = parser.module();
+    let error = env.typecheck_module(&mut module).unwrap_err();
+    assert_eq!(error.message, ~"Undefined identifier 'undefinedName'");
+    assert_eq!(error.location.column, 21);
+}
+
+#[test]
+fn declaration_errors_have_locations() {
+    let mut env = TypeEnvironment::new();
+    let mut parser = Parser::new(
+r"main = 1
+test :: Int".chars());
+    let mut module = parser.module();
+    let error = env.typecheck_module(&mut module).unwrap_err();
+    assert_eq!(error.message, ~"Type declaration for 'test' has no binding");
+    assert_eq!((error.location.row, error.location.column), (2, 1));
+
+    let mut env = TypeEnvironment::new();
+    let mut parser = Parser::new(
+r"main = 1
+instance Missing Int where
+    missing x = x".chars());
+    let mut module = parser.module();
+    let error = env.typecheck_module(&mut module).unwrap_err();
+    assert_eq!(error.message, ~"Could not find class Missing");
+    assert_eq!(error.location.row, 2);
+}
+
+#[test]
+fn freshen_every_constraint_variable() {
+    let mut env = TypeEnvironment::new();
+    let mut decl = TypeDeclaration {
+        context: ~[Constraint { class: ~"Test", variables: ~[TypeVariable { id: 1 }, TypeVariable { id: 2 }] }],
+        typ: function_type(&Type::new_var(1), &Type::new_op(~"[]", ~[Type::new_var(2)])),
+        name: ~"f",
+        location: Location::eof()
+    };
+    env.freshen_declaration(&mut decl);
+    let a = decl.typ.types()[0].var().clone();
+    let b = decl.typ.types()[1].types()[0].var().clone();
+    assert!(a.id != 1 && b.id != 2 && a != b);
+    assert_eq!(decl.context[0].variables, ~[a, b]);
+}
+
+#[test]
+fn query_classes_and_instances() {
+    let mut env = TypeEnvironment::new();
+    let mut parser = Parser::new(
+r"class Test a where
+    test :: a -> Int
+
+instance Test Int where
+    test x = x
+
+instance Test a => Test [a] where
+    test x = 1
+
+main = test".chars());
+    let mut module = parser.module();
+    env.typecheck_module(&mut module).unwrap();
+
+    let instances = env.instances_of("Test");
+    let list = Type::new_op(~"[]", ~[Type::new_var(0)]);
+    assert_eq!(instances.len(), 2);
+    assert_eq!(instances[0], (~[], Type::new_op(~"Int", ~[])));
+    assert_eq!(instances[1].n1_ref(), &list);
+    assert_eq!(instances[1].n0_ref().len(), 1);
+    assert_eq!(instances[1].n0_ref()[0].class, ~"Test");
+    assert_eq!(&instances[1].n0_ref()[0].variables[0], instances[1].n1_ref().types()[0].var());
+    //The instances of the primitive types which literals need are not listed
+    assert_eq!(env.instances_of("Num"), ~[]);
+
+    let main = env.type_of("main").unwrap();
+    assert_eq!(main.context.len(), 1);
+    assert_eq!(main.context[0].class, ~"Test");
+    assert_eq!(env.constraints_on(&main.context[0].variables[0]), ~[~"Test"]);
+    assert!(env.type_of("undefined_name").is_none());
+}
+
+#[test]
+fn typed_hole() {
+    let mut env = TypeEnvironment::new();
+    let mut parser = Parser::new(
+r"double x = primIntAdd x x
+main = primIntAdd (_ (double 1)) 2".chars());
+    let mut module = parser.module();
+    let error = env.typecheck_module(&mut module).unwrap_err();
+    let lines : ~[&str] = error.message.lines().collect();
+    assert_eq!(lines[0], "Found hole '_' of type 'Int -> Int'");
+    assert_eq!(lines[1], "Valid hole fits include:");
+    assert!(lines.contains(&"    double :: Int -> Int"));
+    assert_eq!(error.location.column, 20);
+}
+
+#[test]
+fn typed_hole_local_candidates() {
+    let mut env = TypeEnvironment::new();
+    let mut parser = Parser::new(
+r"other y = primIntAdd y 1
+test :: Int -> Int
+test x = primIntAdd _ 1".chars());
+    let mut module = parser.module();
+    let error = env.typecheck_module(&mut module).unwrap_err();
+    let lines : ~[&str] = error.message.lines().collect();
+    assert_eq!(lines[0], "Found hole '_' of type 'Int'");
+    assert!(lines.contains(&"    x :: Int"));
+    //The argument of another binding is not in scope
+    assert!(!lines.iter().any(|line| line.starts_with("    y ::")));
+}
+
+#[test]
+fn holes_are_cleared_after_errors() {
+    let mut env = TypeEnvironment::new();
+    let mut expr = Parser::new("primIntAdd _ 'a'".chars()).expression_();
+    let error = env.typecheck(&mut expr).unwrap_err();
+    assert!(!error.message.starts_with("Found hole"));
+    let mut expr = Parser::new("primIntAdd 1 2".chars()).expression_();
+    env.typecheck(&mut expr).unwrap();
+}
+
+#[test]
+fn pretty_type_variable_names() {
+    let typ = function_type(&Type::new_var(-2), &function_type(&Type::new_op(~"[]", ~[Type::new_var(5)]), &Type::new_var(-2)));
+    assert_eq!(typ.pretty(), ~"a -> [b] -> a");
+    let decl = TypeDeclaration {
+        context: ~[Constraint { class: ~"Eq", variables: ~[TypeVariable { id: 5 }] }],
+        typ: typ,
+        name: ~"f",
+        location: Location::eof()
+    };
+    assert_eq!(decl.pretty(), ~"Eq b => a -> [b] -> a");
+}
+
+#[test]
+fn type_error_rendering() {
+    let source = "main = 1\ntest = primIntAdd 1 'a'";
+    let mut env = TypeEnvironment::new();
+    let mut parser = Parser::new(source.chars());
+    let mut module = parser.module();
+    let error = env.typecheck_module(&mut module).unwrap_err();
+    assert_eq!(error.binding, Some(~"test"));
+    let rendered = error.render(source);
+    assert!(rendered.contains("In the binding 'test'"));
+    assert!(rendered.contains("2 | test = primIntAdd 1 'a'\n  |        ^^^^^^^^^^"));
+    assert!(rendered.contains("Expected type: Char -> "));
+    assert!(rendered.contains("Inferred type: Int -> Int"));
+}
+
+#[test]
+fn type_error_types_share_variable_names() {
+    let mut error = TypeError::new(Location::eof(), ~"Test");
+    error.types = Some((function_type(&Type::new_var(1), &Type::new_var(2)), Type::new_var(2)));
+    assert!(error.render("").contains("Expected type: a -> b\nInferred type: b"));
+    assert_eq!(format!("{}", error), error.render(""));
+}
+
+#[test]
+fn type_mismatch_shares_variable_names() {
+    let var = Type::new_var(3);
+    let error = UnifyError::new(TypeMismatch(Type::new_op(~"Maybe", ~[var.clone()]), Type::new_op(~"Either", ~[Type::new_var(4), var])));
+    assert_eq!(type_error(&Location::eof(), &error, None).message, ~"Could not match 'Maybe a' with 'Either b a'");
+}
+
+#[test]
+fn ordinal_suffixes() {
+    assert_eq!(ordinal(2), ~"second");
+    assert_eq!(ordinal(6), ~"6th");
+    assert_eq!(ordinal(11), ~"11th");
+    assert_eq!(ordinal(12), ~"12th");
+    assert_eq!(ordinal(13), ~"13th");
+    assert_eq!(ordinal(21), ~"21st");
+    assert_eq!(ordinal(22), ~"22nd");
+    assert_eq!(ordinal(23), ~"23rd");
+    assert_eq!(ordinal(111), ~"111th");
+}
+
+#[test]
+fn missing_instance_provenance() {
+    let mut env = TypeEnvironment::new();
+    let mut parser = Parser::new(
+r"data Bool = True | False
+
+class Eq a where
+    (==) :: a -> a -> Bool
+
+instance Eq a => Eq [a] where
+    (==) xs ys = True
+
+data T = T
+
+main = [[T]] == [[T]]".chars());
+    let mut module = parser.module();
+    let error = env.typecheck_module(&mut module).unwrap_err();
+    assert_eq!(error.message, ~"The instance 'Eq T' was not found in the first argument of '==', it is required by the instance 'Eq [a]'");
+}
+
 #[test]
 fn typecheck_case() {
     let mut env = TypeEnvironment::new();
@@ -1073,8 +2702,8 @@ fn typecheck_case() {
 
     let mut parser = Parser::new("case [] of { : x xs -> add x 2 ; [] -> 3}".chars());
     let mut expr = parser.expression_();
-    env.namedTypes.insert(~"add", add_type);
-    env.typecheck(&mut expr);
+    env.insert_named(intern("add"), add_type);
+    env.typecheck(&mut expr).unwrap();
 
     assert_eq!(expr.typ, type_int);
     match &expr.expr {
@@ -1096,7 +2725,7 @@ main = case [mult2 123, 0] of
     : x xs -> x
     [] -> 10".chars());
     let mut module = parser.module();
-    env.typecheck_module(&mut module);
+    env.typecheck_module(&mut module).unwrap();
 
     assert_eq!(module.bindings[1].expression.typ, Type::new_op(~"Int", ~[]));
 }
@@ -1107,7 +2736,7 @@ fn typecheck_string() {
 
     let mut parser = Parser::new("\"hello\"".chars());
     let mut expr = parser.expression_();
-    env.typecheck(&mut expr);
+    env.typecheck(&mut expr).unwrap();
 
     assert_eq!(expr.typ, Type::new_op(~"[]", ~[Type::new_op(~"Char", ~[])]));
 }
@@ -1118,7 +2747,7 @@ fn typecheck_tuple() {
 
     let mut parser = Parser::new("(primIntAdd 0 0, \"a\")".chars());
     let mut expr = parser.expression_();
-    env.typecheck(&mut expr);
+    env.typecheck(&mut expr).unwrap();
 
     let list = Type::new_op(~"[]", ~[Type::new_op(~"Char", ~[])]);
     assert_eq!(expr.typ, Type::new_op(~"(,)", ~[Type::new_op(~"Int", ~[]), list]));
@@ -1132,13 +2761,29 @@ fn typecheck_module() {
 r"data Bool = True | False
 test x = True".chars());
     let mut module = parser.module();
-    env.typecheck_module(&mut module);
+    env.typecheck_module(&mut module).unwrap();
 
     let typ = function_type(&Type::new_var(0), &Type::new_op(~"Bool", ~[]));
     let bind_type0 = module.bindings[0].expression.typ;
     assert_eq!(bind_type0, typ);
 }
 
+#[test]
+fn typecheck_data_parameters() {
+    let mut env = TypeEnvironment::new();
+
+    let mut parser = Parser::new(
+r"data Pair a b = Pair a b
+first (Pair x y) = x
+test = first (Pair 'a' (primIntAdd 1 2))".chars());
+    let mut module = parser.module();
+    env.typecheck_module(&mut module).unwrap();
+
+    let first = module.bindings.iter().find(|bind| bind.name == ~"first").unwrap();
+    assert_eq!(first.typeDecl.pretty(), ~"Pair a b -> a");
+    let test = module.bindings.iter().find(|bind| bind.name == ~"test").unwrap();
+    assert_eq!(test.expression.typ, Type::new_op(~"Char", ~[]));
+}
 
 #[test]
 fn typecheck_recursive_let() {
@@ -1152,7 +2797,7 @@ r"let
     b = test
 in b".chars());
     let mut expr = parser.expression_();
-    env.typecheck(&mut expr);
+    env.typecheck(&mut expr).unwrap();
 
     
     let int_type = Type::new_op(~"Int", ~[]);
@@ -1169,6 +2814,30 @@ in b".chars());
     }
 }
 
+#[test]
+fn typecheck_pattern_binding() {
+    let mut env = TypeEnvironment::new();
+
+    let mut parser = Parser::new(
+r"let
+    (x, y) = (primIntAdd 1 2, x)
+in y".chars());
+    let mut expr = parser.expression_();
+    env.typecheck(&mut expr).unwrap();
+
+    let int_type = Type::new_op(~"Int", ~[]);
+    assert_eq!(expr.typ, int_type);
+    match &expr.expr {
+        &Let(ref binds, _) => {
+            assert_eq!(binds[0].name, ~"#pattern0");
+            assert_eq!(binds[0].expression.typ, Type::new_op(~"(,)", ~[int_type.clone(), int_type.clone()]));
+            assert_eq!(binds[2].name, ~"y");
+            assert_eq!(binds[2].expression.typ, int_type);
+        }
+        _ => fail!("Expected let")
+    }
+}
+
 #[test]
 fn typecheck_constraints() {
     let mut parser = Parser::new(
@@ -1181,40 +2850,182 @@ instance Test Int where
 main = test 1".chars());
 
     let mut module = parser.module();
-
+
+    let mut env = TypeEnvironment::new();
+    env.typecheck_module(&mut module).unwrap();
+
+    let typ = &module.bindings[0].expression.typ;
+    assert_eq!(typ, &Type::new_op(~"Int", ~[]));
+}
+
+//Test that calling a function with constraints will propagate the constraints to
+//the type of the caller
+#[test]
+fn typecheck_constraints2() {
+    let mut parser = Parser::new(
+r"class Test a where
+    test :: a -> Int
+
+instance Test Int where
+    test x = 10
+
+main x y = primIntAdd (test x) (test y)".chars());
+
+    let mut module = parser.module();
+
+    let mut env = TypeEnvironment::new();
+    env.typecheck_module(&mut module).unwrap();
+
+    let typ = &module.bindings[0].expression.typ;
+    let int_type = Type::new_op(~"Int", ~[]);
+    let test = function_type(&Type::new_var(-1),  &function_type(&Type::new_var(-2), &int_type));
+    assert_eq!(typ, &test);
+    let test_cons = ~[~"Test"];
+    assert_eq!(env.constraints.find(typ.types()[0].var()), Some(&test_cons));
+    let second_fn = &typ.types()[1];
+    assert_eq!(env.constraints.find(second_fn.types()[0].var()), Some(&test_cons));
+}
+
+#[test]
+fn instance_over_compound_types() {
+    let mut parser = Parser::new(
+r"data Maybe a = Just a | Nothing
+
+class Test a where
+    test :: a -> Int
+
+instance Test Int where
+    test x = 1
+
+instance Test a => Test (Maybe [a]) where
+    test x = 2
+
+instance Test (a, b) where
+    test x = 3
+
+main = primIntAdd (test (Just [1])) (test (1, Nothing))".chars());
+    let mut module = parser.module();
+
+    let mut env = TypeEnvironment::new();
+    env.typecheck_module(&mut module).unwrap();
+    assert_eq!(module.bindings[0].expression.typ, Type::new_op(~"Int", ~[]));
+}
+
+#[test]
+#[should_fail]
+fn instance_over_compound_types_no_match() {
+    let mut parser = Parser::new(
+r"data Maybe a = Just a | Nothing
+
+class Test a where
+    test :: a -> Int
+
+instance Test Int where
+    test x = 1
+
+instance Test a => Test (Maybe [a]) where
+    test x = 2
+
+main = test (Just 1)".chars());
+    let mut module = parser.module();
+
+    let mut env = TypeEnvironment::new();
+    env.typecheck_module(&mut module).unwrap();
+}
+
+#[test]
+fn class_method_with_context() {
+    let mut parser = Parser::new(
+r"class Eq a where
+    (==) :: a -> a -> Bool
+
+instance Eq Int where
+    (==) x y = primIntEQ x y
+
+class Test a where
+    test :: Eq b => a -> b -> b -> Bool
+
+instance Test Int where
+    test x y z = y == z
+
+main = test 1 2 3".chars());
+    let mut module = parser.module();
+
+    let mut env = TypeEnvironment::new();
+    env.typecheck_module(&mut module).unwrap();
+    let test = &module.classes[1].declarations[0];
+    let classes : ~[~str] = test.context.iter().map(|c| c.class.clone()).collect();
+    assert_eq!(classes, ~[~"Eq", ~"Test"]);
+    let b = test.context[0].variables[0].clone();
+    assert_eq!(env.constraints.find(&b), Some(&~[~"Eq"]));
+}
+
+#[test]
+fn instance_termination() {
+    let source =
+r"class Test a where
+    test :: a -> Int
+
+data Box a = Box a
+
+instance Test b => Test (Box a) where
+    test x = 1
+";
+    let mut parser = Parser::new(source.chars());
+    let mut module = parser.module();
     let mut env = TypeEnvironment::new();
-    env.typecheck_module(&mut module);
+    let error = env.typecheck_module(&mut module).unwrap_err();
+    assert_eq!(error.message, ~"The constraint 'Test b' of the instance 'Test b => Test (Box a)' mentions a type variable more often than the instance head, finding the instance may not terminate");
 
-    let typ = &module.bindings[0].expression.typ;
-    assert_eq!(typ, &Type::new_op(~"Int", ~[]));
+    let mut parser = Parser::new(source.chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    env.undecidable_instances = true;
+    env.typecheck_module(&mut module).unwrap();
 }
 
-//Test that calling a function with constraints will propagate the constraints to
-//the type of the caller
 #[test]
-fn typecheck_constraints2() {
-    let mut parser = Parser::new(
-r"class Test a where
+fn class_method_constrains_class_variable() {
+    let source =
+r"data Bool = True | False
+
+class Eq a where
+    (==) :: a -> a -> Bool
+
+instance Eq Int where
+    (==) x y = primIntEQ x y
+
+instance Eq a => Eq [a] where
+    (==) xs ys = True
+
+class Test a where
     test :: a -> Int
+    same :: Eq a => a -> a -> Bool
 
 instance Test Int where
-    test x = 10
+    test x = x
+    same x y = x == y
 
-main x y = primIntAdd (test x) (test y)".chars());
+instance Test [a] where
+    test x = 1
+    same x y = x == y
 
+main = same [test 1] [2]
+";
+    let mut parser = Parser::new(source.chars());
     let mut module = parser.module();
-
     let mut env = TypeEnvironment::new();
-    env.typecheck_module(&mut module);
+    env.typecheck_module(&mut module).unwrap();
+    let same = &module.instances[3].bindings[1];
+    assert_eq!(same.typeDecl.context.len(), 1);
+    assert_eq!(same.typeDecl.context[0].class, ~"Eq");
 
-    let typ = &module.bindings[0].expression.typ;
-    let int_type = Type::new_op(~"Int", ~[]);
-    let test = function_type(&Type::new_var(-1),  &function_type(&Type::new_var(-2), &int_type));
-    assert_eq!(typ, &test);
-    let test_cons = ~[~"Test"];
-    assert_eq!(env.constraints.find(typ.types[0].var()), Some(&test_cons));
-    let second_fn = &typ.types[1];
-    assert_eq!(env.constraints.find(second_fn.types[0].var()), Some(&test_cons));
+    let source = source + "data T = T\ninstance Test T where\n    test x = 1\n    same x y = True\n";
+    let mut parser = Parser::new(source.chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    let error = env.typecheck_module(&mut module).unwrap_err();
+    assert_eq!(error.message, ~"The method 'same' of the instance 'Test T' requires an instance 'Eq T'");
 }
 
 #[test]
@@ -1232,7 +3043,7 @@ main = test [1]".chars());
     let mut module = parser.module();
 
     let mut env = TypeEnvironment::new();
-    env.typecheck_module(&mut module);
+    env.typecheck_module(&mut module).unwrap();
 }
 
 #[test]
@@ -1261,16 +3072,81 @@ instance Eq a => Eq [a] where
     let mut module = parser.module();
 
     let mut env = TypeEnvironment::new();
-    env.typecheck_module(&mut module);
+    env.typecheck_module(&mut module).unwrap();
 
     let typ = &module.instances[0].bindings[0].expression.typ;
     let list_type = Type::new_op(~"[]", ~[Type::new_var(100)]);
     assert_eq!(*typ, function_type(&list_type, &function_type(&list_type, &Type::new_op(~"Bool", ~[]))));
-    let var = typ.types[0].types[0].var();
+    let var = typ.types()[0].types()[0].var();
     let eq = ~[~"Eq"];
     assert_eq!(env.constraints.find(var), Some(&eq));
 }
 
+#[test]
+fn instance_constraints_reach_type_variables() {
+    let mut parser = Parser::new(
+r"data Bool = True | False
+
+class Eq a where
+    (==) :: a -> a -> Bool
+
+instance Eq a => Eq [a] where
+    (==) xs ys = True
+
+test x = [[x]] == [[x]]
+".chars());
+
+    let mut module = parser.module();
+
+    let mut env = TypeEnvironment::new();
+    env.typecheck_module(&mut module).unwrap();
+
+    let typ = &module.bindings[0].expression.typ;
+    let var = typ.types()[0].var();
+    assert_eq!(env.constraints.find(var), Some(&~[~"Eq"]));
+}
+
+#[test]
+fn monomorphism_restriction() {
+    let source =
+r"x = 1
+main = primIntAdd x 2
+";
+    let mut parser = Parser::new(source.chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    env.monomorphism_restriction = true;
+    env.typecheck_module(&mut module).unwrap();
+    let x = module.bindings.iter().find(|bind| bind.name == ~"x").unwrap();
+    assert_eq!(x.typeDecl.typ, Type::new_op(~"Int", ~[]));
+    assert_eq!(x.typeDecl.context, ~[]);
+
+    let mut parser = Parser::new(source.chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    env.typecheck_module(&mut module).unwrap();
+    let x = module.bindings.iter().find(|bind| bind.name == ~"x").unwrap();
+    assert_eq!(x.typeDecl.context.len(), 1);
+}
+
+#[test]
+fn monomorphism_restriction_conflicting_uses() {
+    let source =
+r"x = 1
+main = (primIntAdd x 2, primDoubleAdd x 1.5)
+";
+    let mut parser = Parser::new(source.chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    env.typecheck_module(&mut module).unwrap();
+
+    let mut parser = Parser::new(source.chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    env.monomorphism_restriction = true;
+    assert!(env.typecheck_module(&mut module).is_err());
+}
+
 #[test]
 fn typecheck_num_double() {
     let mut env = TypeEnvironment::new();
@@ -1278,7 +3154,7 @@ fn typecheck_num_double() {
     let mut parser = Parser::new(
 r"test x = primDoubleAdd 0 x".chars());
     let mut module = parser.module();
-    env.typecheck_module(&mut module);
+    env.typecheck_module(&mut module).unwrap();
 
     let typ = function_type(&Type::new_op(~"Double", ~[]), &Type::new_op(~"Double", ~[]));
     let bind_type0 = module.bindings[0].expression.typ;
@@ -1303,12 +3179,37 @@ instance Functor Maybe where
 add2 x = primIntAdd x 2
 main = fmap add2 (Just 3)".chars());
     let mut module = parser.module();
-    env.typecheck_module(&mut module);
+    env.typecheck_module(&mut module).unwrap();
 
     let main = &module.bindings[1];
     assert_eq!(main.expression.typ, Type::new_op(~"Maybe", ~[Type::new_op(~"Int", ~[])]));
 }
 
+#[test]
+fn type_variables_are_numbered_in_order() {
+    let source =
+r"id x = const x x
+const x y = x
+";
+    let variables = |typ: &Type| {
+        let mut ids = ~[];
+        each_type(typ, |var| ids.push(var.id), |_| ());
+        ids
+    };
+    let mut results = ~[];
+    for _ in range(0, 2) {
+        let mut parser = Parser::new(source.chars());
+        let mut module = parser.module();
+        let mut env = TypeEnvironment::new();
+        env.typecheck_module(&mut module).unwrap();
+        //'const' is typechecked first but 'id' appears first so its variables are numbered first
+        assert_eq!(variables(&module.bindings[0].typeDecl.typ), ~[1, 1]);
+        assert_eq!(variables(&module.bindings[1].typeDecl.typ), ~[2, 3, 2]);
+        results.push(module.bindings.map(|bind| variables(&bind.expression.typ)));
+    }
+    assert_eq!(results[0], results[1]);
+}
+
 #[test]
 fn typecheck_prelude() {
     let path = &Path::new("Prelude.hs");
@@ -1317,7 +3218,7 @@ fn typecheck_prelude() {
     let mut parser = Parser::new(contents.chars());
     let mut module = parser.module();
     let mut env = TypeEnvironment::new();
-    env.typecheck_module(&mut module);
+    env.typecheck_module(&mut module).unwrap();
 
     let id = module.bindings.iter().find(|bind| bind.name == ~"id");
     assert!(id != None);
@@ -1335,7 +3236,7 @@ fn typecheck_import() {
         let mut parser = Parser::new(contents.chars()); 
         let mut module = parser.module();
         let mut env = TypeEnvironment::new();
-        env.typecheck_module(&mut module);
+        env.typecheck_module(&mut module).unwrap();
         module
     };
 
@@ -1347,7 +3248,7 @@ test2 = id (primIntAdd 2 0)".chars());
 
     let mut env = TypeEnvironment::new();
     env.add_types(&prelude as &Types);
-    env.typecheck_module(&mut module);
+    env.typecheck_module(&mut module).unwrap();
 
     assert_eq!(module.bindings[0].name, ~"test1");
     assert_eq!(module.bindings[0].expression.typ, Type::new_op(~"[]", ~[Type::new_op(~"Bool", ~[])]));
@@ -1355,6 +3256,200 @@ test2 = id (primIntAdd 2 0)".chars());
     assert_eq!(module.bindings[1].expression.typ, Type::new_op(~"Int", ~[]));
 }
 
+#[test]
+fn instantiate_skeleton() {
+    let a = Type::new_var(5);
+    let b = Type::new_var(7);
+    let int_type = Type::new_op(~"Int", ~[]);
+    let skeleton = TypeSkeleton::new(&function_type(&a, &function_type(&b, &a)));
+    assert_eq!(skeleton.variables, ~[TypeVariable { id: 5 }, TypeVariable { id: 7 }]);
+    let replacements = [int_type.clone(), Type::new_var(9)];
+    assert_eq!(skeleton.instantiate(replacements), function_type(&int_type, &function_type(&Type::new_var(9), &int_type)));
+}
+
+#[test]
+fn assembly_types_are_cached() {
+    let prelude = {
+        let path = &Path::new("Prelude.hs");
+        let s  = File::open(path).read_to_end();
+        let contents : &str = from_utf8(s);
+        let mut parser = Parser::new(contents.chars()); 
+        let mut module = parser.module();
+        let mut env = TypeEnvironment::new();
+        env.typecheck_module(&mut module).unwrap();
+        module
+    };
+    let mut parser = Parser::new(
+r"
+test1 = map not [True, False]
+test2 = map id [True]".chars());
+    let mut module = parser.module();
+
+    let mut env = TypeEnvironment::new();
+    env.add_types(&prelude as &Types);
+    env.typecheck_module(&mut module).unwrap();
+
+    assert!(env.instantiations.contains_key(&intern("map")));
+    assert!(!env.instantiations.contains_key(&intern("test1")));
+    let list_bool = Type::new_op(~"[]", ~[Type::new_op(~"Bool", ~[])]);
+    assert_eq!(module.bindings[0].expression.typ, list_bool);
+    assert_eq!(module.bindings[1].expression.typ, list_bool);
+}
+
+#[test]
+fn type_synonyms() {
+    let mut parser = Parser::new(
+r"type Pair a b = (a, b)
+type IntPair = Pair Int Int
+
+swap :: Pair a b -> Pair b a
+swap (x, y) = (y, x)
+
+test :: IntPair
+test = swap (1, 2)".chars());
+    let mut module = parser.module();
+
+    let mut env = TypeEnvironment::new();
+    env.typecheck_module(&mut module).unwrap();
+
+    let int_type = Type::new_op(~"Int", ~[]);
+    let test = module.bindings.iter().find(|bind| bind.name == ~"test").unwrap();
+    assert_eq!(test.expression.typ, Type::new_op(~"(,)", ~[int_type.clone(), int_type.clone()]));
+    let swap = module.bindings.iter().find(|bind| bind.name == ~"swap").unwrap();
+    let (a, b) = match swap.typeDecl.typ.types()[0].types() {
+        [ref a, ref b] => (a.clone(), b.clone()),
+        _ => fail!("Expected a tuple")
+    };
+    assert_eq!(swap.typeDecl.typ, function_type(&Type::new_op(~"(,)", ~[a.clone(), b.clone()]), &Type::new_op(~"(,)", ~[b, a])));
+}
+
+#[test]
+fn type_synonyms_in_let_signatures() {
+    let mut parser = Parser::new(
+r"type IntPair = (Int, Int)
+
+test = let
+        pair :: IntPair
+        pair = (1, 2)
+    in pair".chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    env.typecheck_module(&mut module).unwrap();
+    let int_type = Type::new_op(~"Int", ~[]);
+    assert_eq!(module.bindings[0].expression.typ, Type::new_op(~"(,)", ~[int_type.clone(), int_type]));
+
+    let mut parser = Parser::new(
+r"type Pair a b = (a, b)
+
+test = let
+        pair :: Pair Int
+        pair = pair
+    in 1".chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    let error = env.typecheck_module(&mut module).unwrap_err();
+    assert_eq!(error.location.row, 4);
+}
+
+#[test]
+fn type_constructor_arity() {
+    let mut parser = Parser::new(
+r"data Maybe a = Just a | Nothing
+
+fromMaybe :: Maybe Int Int -> Int
+fromMaybe x = 0".chars());
+    let mut module = parser.module();
+
+    let mut env = TypeEnvironment::new();
+    let error = env.typecheck_module(&mut module).unwrap_err();
+    assert_eq!(error.message, ~"The type 'Maybe' expects 1 arguments but was given 2 in the type of 'fromMaybe'");
+    assert_eq!((error.location.row, error.location.column), (3, 1));
+}
+
+#[test]
+fn data_context() {
+    let mut parser = Parser::new(
+r"class Test a where
+    test :: a -> Int
+
+instance Test Int where
+    test x = 10
+
+data Test a => Box a = Box a
+
+main = Box 1".chars());
+    let mut module = parser.module();
+
+    let mut env = TypeEnvironment::new();
+    env.typecheck_module(&mut module).unwrap();
+    assert_eq!(module.bindings[0].expression.typ, Type::new_op(~"Box", ~[Type::new_op(~"Int", ~[])]));
+}
+
+#[test]
+#[should_fail]
+fn data_context_no_instance() {
+    let mut parser = Parser::new(
+r"class Test a where
+    test :: a -> Int
+
+instance Test Int where
+    test x = 10
+
+data Test a => Box a = Box a
+
+main = Box [1]".chars());
+    let mut module = parser.module();
+
+    let mut env = TypeEnvironment::new();
+    env.typecheck_module(&mut module).unwrap();
+}
+
+#[test]
+fn type_arguments() {
+    let int_type = Type::new_op(~"Int", ~[]);
+    assert!(int_type.args.is_none());
+    let mut list = Type::new_op(~"[]", ~[]);
+    list.push_type(int_type.clone());
+    assert_eq!(list.types(), &[int_type.clone()]);
+    let mut func = function_type(&Type::new_var(1), &int_type);
+    func.mut_types()[0] = int_type.clone();
+    assert_eq!(func, function_type(&int_type, &int_type));
+}
+
+#[test]
+fn variable_constraints() {
+    let mut constraints = VariableConstraints::new();
+    let a = TypeVariable { id: 3 };
+    let b = TypeVariable { id: 10 };
+    assert_eq!(constraints.find(&a), None);
+    constraints.insert(a.clone(), ~[~"Eq"]);
+    constraints.add(&a, "Show");
+    constraints.add(&a, "Eq");
+    assert_eq!(constraints.find(&a), Some(&~[~"Eq", ~"Show"]));
+    assert_eq!(constraints.find(&b), None);
+    assert_eq!(constraints.pop(&a), Some(~[~"Eq", ~"Show"]));
+    assert_eq!(constraints.find(&a), None);
+    let negative = TypeVariable { id: -1 };
+    constraints.add(&negative, "Eq");
+    constraints.insert(negative.clone(), ~[~"Show"]);
+    assert_eq!(constraints.find(&negative), None);
+    assert_eq!(constraints.pop(&negative), None);
+}
+
+#[test]
+fn apply_only_visits_affected_globals() {
+    let mut env = TypeEnvironment::new();
+    let var = env.new_var();
+    env.insert_named(intern("poly"), function_type(&var, &var));
+    let mut subs = Substitution { subs: HashMap::new(), constraints: HashMap::new() };
+    subs.subs.insert(var.var().clone(), Type::new_op(~"Int", ~[]));
+    let before = env.metrics().substitutions;
+    env.apply(&subs);
+    assert_eq!(env.metrics().substitutions - before, 1);
+    let int_type = Type::new_op(~"Int", ~[]);
+    assert_eq!(env.find("poly"), Some(&function_type(&int_type, &int_type)));
+}
+
 #[test]
 fn type_declaration() {
     
@@ -1371,7 +3466,7 @@ test x y = primIntAdd (test x) y".chars());
     let mut module = parser.module();
 
     let mut env = TypeEnvironment::new();
-    env.typecheck_module(&mut module);
+    env.typecheck_module(&mut module).unwrap();
 
     assert_eq!(module.bindings[0].typeDecl.typ, module.typeDeclarations[0].typ);
 }
@@ -1387,7 +3482,192 @@ test x y = primIntAdd x y".chars());
     let mut module = parser.module();
 
     let mut env = TypeEnvironment::new();
-    env.typecheck_module(&mut module);
+    env.typecheck_module(&mut module).unwrap();
+}
+
+#[test]
+fn signature_too_general() {
+    let mut parser = Parser::new(
+r"test :: a -> a
+test x = primIntAdd x 1".chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    let error = env.typecheck_module(&mut module).unwrap_err();
+    assert_eq!(error.message, ~"The type signature is more general than the type of the binding");
+    assert_eq!(error.binding, Some(~"test"));
+}
+
+#[test]
+fn mutually_recursive_bindings_with_signatures() {
+    let mut parser = Parser::new(
+r"count :: a -> Int -> Int
+count x n = case primIntEQ n 0 of
+    True -> 0
+    False -> primIntAdd 1 (countBool True (primIntSubtract n 1))
+
+countBool :: Bool -> Int -> Int
+countBool b n = count b n".chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    env.typecheck_module(&mut module).unwrap();
+    let int = Type::new_op(~"Int", ~[]);
+    let bool = Type::new_op(~"Bool", ~[]);
+    assert_eq!(module.bindings[1].expression.typ, function_type(&bool, &function_type(&int, &int)));
+    //The variables of the signatures are only skolems while their bindings are checked
+    assert!(env.skolems.is_empty());
+}
+
+#[test]
+fn signature_missing_constraint() {
+    let mut parser = Parser::new(
+r"class Test a where
+    test :: a -> Int
+
+f :: a -> Int
+f x = test x".chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    let error = env.typecheck_module(&mut module).unwrap_err();
+    assert_eq!(error.message, ~"The type signature of 'f' is missing a Test constraint, it should be 'Test a => a -> Int'");
+}
+
+#[test]
+fn overlapping_instances() {
+    let mut parser = Parser::new(
+r"class Test a where
+    test :: a -> Int
+
+instance Test [a] where
+    test x = 1
+
+instance Test [Int] where
+    test x = 2".chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    let error = env.typecheck_module(&mut module).unwrap_err();
+    assert_eq!(error.message, ~"The instance 'Test [Int]' overlaps with the instance 'Test [a]' at 4:1");
+    assert_eq!(error.location.row, 7);
+}
+
+#[test]
+fn missing_instance_methods() {
+    let mut parser = Parser::new(
+r"class Test a where
+    test :: a -> Int
+    test2 :: a -> a
+    test3 :: a -> Int
+
+instance Test Int where
+    test2 x = x".chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    let error = env.typecheck_module(&mut module).unwrap_err();
+    assert_eq!(error.message, ~"The instance 'Test Int' does not define the class methods 'test', 'test3'");
+}
+
+#[test]
+fn instance_method_not_in_class() {
+    let mut parser = Parser::new(
+r"class Test a where
+    test :: a -> Int
+
+instance Test Int where
+    test x = x
+    mytest x = x".chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    let error = env.typecheck_module(&mut module).unwrap_err();
+    assert_eq!(error.message, ~"'mytest' is not a method of the class Test");
+}
+
+#[test]
+fn missing_superclass_instance() {
+    let source =
+r"class Test a where
+    test :: a -> Int
+
+class Test a => Test2 a where
+    test2 :: a -> Int
+
+instance Test2 Int where
+    test2 x = x
+";
+    let mut parser = Parser::new(source.chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    let error = env.typecheck_module(&mut module).unwrap_err();
+    assert_eq!(error.message, ~"The instance 'Test2 Int' requires an instance 'Test Int' since Test is a superclass of Test2");
+
+    let with_superclass = source + "\ninstance Test Int where\n    test x = x";
+    let mut parser = Parser::new(with_superclass.chars());
+    let mut module = parser.module();
+    let mut env = TypeEnvironment::new();
+    env.typecheck_module(&mut module).unwrap();
+}
+
+#[test]
+fn unify_resolves_variables_bound_earlier_in_the_spine() {
+    let mut env = TypeEnvironment::new();
+    let mut subs = Substitution { subs: HashMap::new(), constraints: HashMap::new() };
+    let a = env.new_var();
+    let b = env.new_var();
+    let int_type = Type::new_op(~"Int", ~[]);
+    //a -> a -> b  <->  Int -> b -> a
+    let mut lhs = function_type(&a, &function_type(&a, &b));
+    let mut rhs = function_type(&int_type, &function_type(&b, &a));
+    unify_location(&mut env, &mut subs, &Location::eof(), &mut lhs, &mut rhs).unwrap();
+    replace(&mut env.constraints, &mut lhs, &subs);
+    assert_eq!(lhs, function_type(&int_type, &function_type(&int_type, &int_type)));
+}
+
+#[bench]
+fn bench_unify_curried(b: &mut ::extra::test::BenchHarness) {
+    //Unifies two signatures of 200 arguments where each argument of the left side is a new variable
+    let mut env = TypeEnvironment::new();
+    let int_type = Type::new_op(~"Int", ~[]);
+    let mut lhs = env.new_var();
+    let mut rhs = int_type.clone();
+    for _ in range(0, 200) {
+        lhs = function_type(&env.new_var(), &lhs);
+        rhs = function_type(&int_type, &rhs);
+    }
+    b.iter(|| {
+        let mut subs = Substitution { subs: HashMap::new(), constraints: HashMap::new() };
+        let mut l = lhs.clone();
+        let mut r = rhs.clone();
+        unify_location(&mut env, &mut subs, &Location::eof(), &mut l, &mut r).unwrap();
+    });
+}
+
+#[bench]
+fn bench_clone_types(b: &mut ::extra::test::BenchHarness) {
+    //Clones and renumbers a signature of 200 arguments, most of the types in it have no arguments of their own
+    let int_type = Type::new_op(~"Int", ~[]);
+    let mut typ = Type::new_var(0);
+    for i in range(1, 200) {
+        typ = function_type(&Type::new_var(i), &function_type(&int_type, &typ));
+    }
+    b.iter(|| {
+        let mut t = typ.clone();
+        let mut renumbering = Renumbering { mapping: HashMap::new(), base: -1, next: -1 };
+        renumbering.typ(&mut t);
+    });
+}
+
+#[bench]
+fn bench_binding_groups(b: &mut ::extra::test::BenchHarness) {
+    //A module where each binding refers to the one before it, so that every binding is its own group
+    let mut source = ~"f0 = 0\n";
+    for i in range(1, 10000) {
+        source.push_str(format!("f{} = f{}\n", i, i - 1));
+    }
+    let mut parser = Parser::new(source.chars());
+    let module = parser.module();
+    b.iter(|| {
+        let graph = build_graph(&module as &Bindings);
+        let groups = strongly_connected_components(&graph);
+        assert_eq!(groups.len(), 10000);
+    });
 }
 
 }
@@ -0,0 +1,118 @@
+//Warnings for names which are bound but never used.
+//Top level bindings are only reported for modules with an export list since every binding of a module
+//without one can be used by the modules which import it.
+//Names starting with '_' are never reported, neither are the names which the parser generates.
+use module::{Module, TypedExpr, Identifier, Apply, Number, Rational, String, Char, Lambda, Let, Case};
+use interner::intern;
+use diagnostic::Diagnostic;
+use variables::is_free;
+
+///Returns warnings for the unexported top level bindings, let bindings and lambda arguments
+///in 'module' which are never referenced
+pub fn check_module(module: &Module) -> ~[Diagnostic] {
+    let mut warnings = ~[];
+    match module.used_bindings() {
+        Some(used) => {
+            for bind in module.bindings.iter() {
+                if is_checked(bind.name) && !used.contains(&intern(bind.name)) {
+                    let message = format!("The binding '{}' is never used", bind.name);
+                    warnings.push(Diagnostic::warning(bind.expression.location, message));
+                }
+            }
+        }
+        None => ()
+    }
+    for bind in module.bindings.iter() {
+        check_expr(&mut warnings, &bind.expression);
+    }
+    for instance in module.instances.iter() {
+        for bind in instance.bindings.iter() {
+            check_expr(&mut warnings, &bind.expression);
+        }
+    }
+    warnings
+}
+
+fn check_expr(warnings: &mut ~[Diagnostic], expr: &TypedExpr) {
+    match &expr.expr {
+        &Apply(~(ref func, ref arg)) => {
+            check_expr(warnings, func);
+            check_expr(warnings, arg);
+        }
+        &Lambda(ref arg, ref body) => {
+            if is_checked(arg.as_slice()) && !is_free(&**body, *arg) {
+                let message = format!("The variable '{}' is never used", *arg);
+                warnings.push(Diagnostic::warning(expr.location, message));
+            }
+            check_expr(warnings, &**body);
+        }
+        &Let(ref bindings, ref body) => {
+            for (i, bind) in bindings.iter().enumerate() {
+                let name = intern(bind.name);
+                //A binding which is only used by itself is still unused
+                let used = is_free(&**body, name)
+                    || bindings.iter().enumerate().any(|(j, other)| i != j && is_free(&other.expression, name));
+                if is_checked(bind.name) && !used {
+                    let message = format!("The binding '{}' is never used", bind.name);
+                    warnings.push(Diagnostic::warning(bind.expression.location, message));
+                }
+                check_expr(warnings, &bind.expression);
+            }
+            check_expr(warnings, &**body);
+        }
+        &Case(ref scrutinee, ref alternatives) => {
+            check_expr(warnings, &**scrutinee);
+            for alt in alternatives.iter() {
+                check_expr(warnings, &alt.expression);
+            }
+        }
+        &Identifier(_) | &Number(_) | &Rational(_) | &String(_) | &Char(_) => ()
+    }
+}
+
+///Returns false for the names which are never reported
+fn is_checked(name: &str) -> bool {
+    !name.starts_with("_") && !name.starts_with("#")
+}
+
+#[cfg(test)]
+mod tests {
+use parser::Parser;
+use unused::check_module;
+
+fn warnings(source: &str) -> ~[~str] {
+    let mut parser = Parser::new(source.chars());
+    let module = parser.module();
+    check_module(&module).iter().map(|warning| warning.message.clone()).collect()
+}
+
+#[test]
+fn unused_variables() {
+    let result = warnings(
+r"test x y = let
+        a = 1
+        b = primIntAdd x 2
+        c = c
+    in b
+
+test2 _ = 3");
+    assert_eq!(result, ~[~"The variable 'y' is never used", ~"The binding 'a' is never used", ~"The binding 'c' is never used"]);
+}
+
+#[test]
+fn shadowed_variable_is_unused() {
+    let result = warnings(r"test x = \x -> x");
+    assert_eq!(result, ~[~"The variable 'x' is never used"]);
+}
+
+#[test]
+fn unused_unexported_binding() {
+    let result = warnings(
+r"module Math (double) where {
+double x = add x x ;
+add x y = primIntAdd x y ;
+unused = 1 ;
+unused2 = unused2 }");
+    assert_eq!(result, ~[~"The binding 'unused' is never used", ~"The binding 'unused2' is never used"]);
+}
+}
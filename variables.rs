@@ -0,0 +1,149 @@
+//Functions for finding and replacing the variables of expressions which are shared by the passes
+//that transform a module after it has been typechecked, such as inlining and lambda lifting.
+use module::{TypedExpr, Type, Pattern, IdentifierPattern, ConstructorPattern,
+    Identifier, Apply, Number, Rational, String, Char, Lambda, Let, Case};
+use interner::{InternedStr, intern};
+
+///Adds the variables referenced in 'expr' which are not in 'bound' or bound inside 'expr' to 'free' together with their types
+pub fn free_variables(expr: &TypedExpr, bound: &mut ~[InternedStr], free: &mut ~[(InternedStr, Type)]) {
+    match &expr.expr {
+        &Identifier(ref name) => {
+            if !bound.contains(name) && !free.iter().any(|&(ref var, _)| var == name) {
+                free.push((*name, expr.typ.clone()));
+            }
+        }
+        &Apply(~(ref func, ref arg)) => {
+            free_variables(func, bound, free);
+            free_variables(arg, bound, free);
+        }
+        &Lambda(ref arg, ref body) => {
+            bound.push(*arg);
+            free_variables(&**body, bound, free);
+            bound.pop();
+        }
+        &Let(ref bindings, ref body) => {
+            let before = bound.len();
+            for bind in bindings.iter() {
+                bound.push(intern(bind.name));
+            }
+            for bind in bindings.iter() {
+                free_variables(&bind.expression, bound, free);
+            }
+            free_variables(&**body, bound, free);
+            bound.truncate(before);
+        }
+        &Case(ref scrutinee, ref alternatives) => {
+            free_variables(&**scrutinee, bound, free);
+            for alt in alternatives.iter() {
+                let before = bound.len();
+                pattern_variables(&alt.pattern.node, bound);
+                free_variables(&alt.expression, bound, free);
+                bound.truncate(before);
+            }
+        }
+        &Number(_) | &Rational(_) | &String(_) | &Char(_) => ()
+    }
+}
+
+///Returns true if 'name' is referenced in 'expr' without being shadowed by a variable bound inside 'expr'
+pub fn is_free(expr: &TypedExpr, name: InternedStr) -> bool {
+    let mut free = ~[];
+    free_variables(expr, &mut ~[], &mut free);
+    free.iter().any(|&(ref var, _)| *var == name)
+}
+
+///Adds every variable which is bound somewhere inside 'expr' to 'bound'
+pub fn bound_variables(expr: &TypedExpr, bound: &mut ~[InternedStr]) {
+    match &expr.expr {
+        &Apply(~(ref func, ref arg)) => {
+            bound_variables(func, bound);
+            bound_variables(arg, bound);
+        }
+        &Lambda(ref arg, ref body) => {
+            bound.push(*arg);
+            bound_variables(&**body, bound);
+        }
+        &Let(ref bindings, ref body) => {
+            for bind in bindings.iter() {
+                bound.push(intern(bind.name));
+                bound_variables(&bind.expression, bound);
+            }
+            bound_variables(&**body, bound);
+        }
+        &Case(ref scrutinee, ref alternatives) => {
+            bound_variables(&**scrutinee, bound);
+            for alt in alternatives.iter() {
+                pattern_variables(&alt.pattern.node, bound);
+                bound_variables(&alt.expression, bound);
+            }
+        }
+        &Identifier(_) | &Number(_) | &Rational(_) | &String(_) | &Char(_) => ()
+    }
+}
+
+///Adds the variables which 'pattern' binds to 'variables'
+pub fn pattern_variables(pattern: &Pattern, variables: &mut ~[InternedStr]) {
+    match pattern {
+        &IdentifierPattern(ref name) => variables.push(*name),
+        &ConstructorPattern(_, ref patterns) => {
+            for p in patterns.iter() {
+                pattern_variables(p, variables);
+            }
+        }
+        _ => ()
+    }
+}
+
+///Replaces the variables in 'replacements' which are not shadowed in 'expr'
+pub fn replace_variables(expr: &mut TypedExpr, replacements: &[(InternedStr, TypedExpr)]) {
+    let names : ~[InternedStr] = replacements.iter().map(|&(ref var, _)| *var).collect();
+    replace_variables_with(expr, names.as_slice(), |variable| {
+        match variable.expr {
+            Identifier(ref name) => replacements.iter().find(|&&(ref var, _)| var == name).map(|&(_, ref replacement)| replacement.clone()).unwrap(),
+            _ => fail!("Expected a variable")
+        }
+    });
+}
+
+///Replaces each variable in 'names' which is not shadowed in 'expr' by the expression which 'replace' returns for it
+pub fn replace_variables_with(expr: &mut TypedExpr, names: &[InternedStr], replace: |&TypedExpr| -> TypedExpr) {
+    let is_replaced = match expr.expr {
+        Identifier(ref name) => names.contains(name),
+        _ => false
+    };
+    if is_replaced {
+        let replacement = replace(expr);
+        *expr = replacement;
+        return;
+    }
+    match expr.expr {
+        Apply(~(ref mut func, ref mut arg)) => {
+            replace_variables_with(func, names, |var| replace(var));
+            replace_variables_with(arg, names, |var| replace(var));
+        }
+        Lambda(ref arg, ref mut body) => {
+            let inner : ~[InternedStr] = names.iter().filter(|var| *var != arg).map(|var| *var).collect();
+            replace_variables_with(&mut **body, inner.as_slice(), |var| replace(var));
+        }
+        Let(ref mut bindings, ref mut body) => {
+            let inner : ~[InternedStr] = names.iter()
+                .filter(|var| !bindings.iter().any(|bind| var.as_slice() == bind.name))
+                .map(|var| *var)
+                .collect();
+            for bind in bindings.mut_iter() {
+                replace_variables_with(&mut bind.expression, inner.as_slice(), |var| replace(var));
+            }
+            replace_variables_with(&mut **body, inner.as_slice(), |var| replace(var));
+        }
+        Case(ref mut scrutinee, ref mut alternatives) => {
+            replace_variables_with(&mut **scrutinee, names, |var| replace(var));
+            for alt in alternatives.mut_iter() {
+                let mut variables = ~[];
+                pattern_variables(&alt.pattern.node, &mut variables);
+                let inner : ~[InternedStr] = names.iter().filter(|var| !variables.contains(*var)).map(|var| *var).collect();
+                replace_variables_with(&mut alt.expression, inner.as_slice(), |var| replace(var));
+            }
+        }
+        Identifier(_) | Number(_) | Rational(_) | String(_) | Char(_) => ()
+    }
+}
@@ -1,12 +1,24 @@
 use std::fmt;
 use std::rc::Rc;
-use std::path::Path;
-use std::io::File;
-use std::str::from_utf8;
+use std::cell::{Cell, RefCell};
 use std::vec::from_fn;
-use typecheck::TypeEnvironment;
+use std::cmp::max;
+use typecheck::{Types, TypeEnvironment};
+use module::{Module, Type, TypeOperator, TypeVariable, TypedExpr, Apply, Identifier, Location, intern};
 use compiler::*;
+use bytecode::*;
 use parser::Parser;    
+use host::FileSystem;
+use metrics::Metrics;
+use deadcode::remove_dead_code;
+use lambda_lift::lift_expression;
+use interner::{MAIN, SHOW};
+use loader;
+use link::link;
+#[cfg(not(target_arch = "wasm32"))]
+use hbc::read_assembly_file;
+#[cfg(not(target_arch = "wasm32"))]
+use host::NativeFileSystem;
 
 #[deriving(Clone)]
 enum Node_<'a> {
@@ -16,6 +28,8 @@ enum Node_<'a> {
     Char(char),
     Combinator(&'a SuperCombinator),
     Indirection(Node<'a>),
+    ///A binding of a recursive let which Fill sets to the node of the binding once it has been created
+    Hole(RefCell<Option<Node<'a>>>),
     Constructor(u16, ~[Node<'a>]),
     Dictionary(&'a [uint])
 }
@@ -52,6 +66,8 @@ impl <'a> fmt::Default for Node_<'a> {
             &Char(c) => write!(f.buf, "'{}'", c),
             &Combinator(ref sc) => write!(f.buf, "{}", sc.name),
             &Indirection(ref n) => write!(f.buf, "(~> {})", *n),
+            //The node in a hole may refer back to the hole so it is not printed
+            &Hole(_) => write!(f.buf, "(hole)"),
             &Constructor(ref tag, ref args) => {
                 let mut cons = args;
                 if cons.len() > 0 {
@@ -102,15 +118,32 @@ pub struct VM<'a> {
     assembly : ~[Assembly],
     globals: ~[(uint, uint)],
     heap : ~[Node<'a>],
+    priv reductions: Cell<uint>,
+    ///Empty stacks which were used by earlier calls, reused so that calls do not allocate a new stack each time
+    priv stack_pool: RefCell<~[~[Node<'a>]]>
 }
 
 impl <'a> VM<'a> {
     pub fn new() -> VM {
-        VM { assembly : ~[], heap : ~[], globals: ~[] }
+        VM { assembly : ~[], heap : ~[], globals: ~[], reductions: Cell::new(0), stack_pool: RefCell::new(~[]) }
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        Metrics { reductions: self.reductions.get(), .. Metrics::new() }
     }
 
-    ///Adds an assembly to the VM, adding entries to the global table as necessary
+    ///Adds an assembly to the VM, adding entries to the global table as necessary.
+    ///The assembly is linked against the assemblies already added and fails if it uses a global which is not loaded.
     pub fn add_assembly(&mut self, assembly: Assembly) {
+        let mut assembly = assembly;
+        match link(&mut assembly, self.assembly.as_slice()) {
+            Ok(()) => (),
+            Err(msg) => fail!("{}", msg)
+        }
+        let assembly_id = self.assembly.len();
+        for sc in assembly.superCombinators.mut_iter() {
+            sc.assembly_id = assembly_id;
+        }
         self.assembly.push(assembly);
         let assembly_index = self.assembly.len() - 1;
         let mut index = 0;
@@ -120,100 +153,215 @@ impl <'a> VM<'a> {
         }
     }
 
-    pub fn evaluate(&'a self, code: &[Instruction], assembly_id: uint) -> Node_<'a> {
+    ///Checks that the instructions of 'assembly' only refer to existing globals, dictionaries and addresses
+    ///before adding it to the VM, used for assemblies which were not compiled in this process.
+    ///Returns the index of the assembly.
+    pub fn add_checked_assembly(&mut self, assembly: Assembly) -> Result<uint, ~str> {
+        let mut assembly = assembly;
+        try!(link(&mut assembly, self.assembly.as_slice()));
+        let dictionary_size = self.assembly.iter()
+            .flat_map(|assembly| assembly.instance_dictionaries.iter())
+            .fold(0, |size, dict| max(size, dict.len()));
+        try!(validate_assembly(&assembly, self.globals.len(), dictionary_size));
+        self.add_assembly(assembly);
+        Ok(self.assembly.len() - 1)
+    }
+
+    ///Reads the assembly in the .hbc file at 'path' and adds it to the VM, see 'add_checked_assembly'
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_assembly(&mut self, path: &str) -> Result<uint, ~str> {
+        let assembly = try!(read_assembly_file(path));
+        self.add_checked_assembly(assembly).map_err(|msg| format!("{}: {}", path, msg))
+    }
+
+    ///Returns the location in the source of the instruction at 'index' in the global 'global',
+    ///for tools which need to show where the code being executed came from
+    pub fn find_location(&self, global: uint, index: uint) -> Option<Location> {
+        if global >= self.globals.len() {
+            return None;
+        }
+        let (assembly_index, sc_index) = self.globals[global];
+        self.assembly[assembly_index].superCombinators[sc_index].location(index)
+    }
+
+    ///Fails with 'message' at the location in the source of the instruction at 'index' in 'code'.
+    ///The code of the builtin functions, such as Eval, has no location so only the message is shown for it.
+    fn runtime_error(&self, code: CodeSlice, index: uint, message: ~str) -> ! {
+        let global = self.globals.iter().position(|&(assembly_index, sc_index)| {
+            self.assembly[assembly_index].superCombinators[sc_index].code().as_ptr() == code.as_ptr()
+        });
+        match global.and_then(|global| self.find_location(global, index)) {
+            Some(location) => fail!("{} Error: {}", location, message),
+            None => fail!("Error: {}", message)
+        }
+    }
+
+    fn take_stack(&self) -> ~[Node<'a>] {
+        let mut pool = self.stack_pool.borrow_mut();
+        match pool.get().pop_opt() {
+            Some(stack) => stack,
+            None => ~[]
+        }
+    }
+
+    fn return_stack(&self, stack: ~[Node<'a>]) {
+        let mut stack = stack;
+        stack.truncate(0);
+        let mut pool = self.stack_pool.borrow_mut();
+        pool.get().push(stack);
+    }
+
+    pub fn evaluate(&'a self, code: CodeSlice, assembly_id: uint) -> Node_<'a> {
         let mut stack = ~[];
         self.execute(&mut stack, code, assembly_id);
-        static evalCode : &'static [Instruction] = &[Eval];
-        self.execute(&mut stack, evalCode, assembly_id);
+        self.execute(&mut stack, eval_code(), assembly_id);
         assert_eq!(stack.len(), 1);
         stack[0].borrow().clone()
     }
 
-    pub fn execute(&'a self, stack: &mut ~[Node<'a>], code: &[Instruction], assembly_id: uint) {
-        debug!("----------------------------");
-        debug!("Entering frame with stack");
-        for x in stack.iter() {
-            debug!("{}", x.borrow());
+    ///Evaluates 'node' and all of its fields
+    fn deep_evaluate(&'a self, node: Node<'a>) -> Option<VMResult> {
+        let mut stack = ~[node];
+        self.execute(&mut stack, eval_code(), 0);
+        let evaluated = stack.pop();
+        match evaluated.borrow() {
+            &Constructor(tag, ref fields) => {
+                let mut result = ~[];
+                for field in fields.iter() {
+                    match self.deep_evaluate(field.clone()) {
+                        Some(x) => result.push(x),
+                        None => return None
+                    }
+                }
+                Some(ConstructorResult(tag, result))
+            }
+            &Int(i) => Some(IntResult(i)),
+            &Float(f) => Some(DoubleResult(f)),
+            &Char(c) => Some(CharResult(c)),
+            x => {
+                log_phase!(Executing, Debug, "Can't evaluate result {}", x);
+                None
+            }
+        }
+    }
+
+    pub fn execute(&'a self, stack: &mut ~[Node<'a>], code: CodeSlice, assembly_id: uint) {
+        //Checked once per frame so that untraced execution does not look up the log level for every instruction
+        let trace = ::logging::enabled(::logging::Executing, ::logging::Trace);
+        if trace {
+            ::logging::log(::logging::Executing, ::logging::Trace, "----------------------------");
+            ::logging::log(::logging::Executing, ::logging::Trace, "Entering frame with stack");
+            for x in stack.iter() {
+                ::logging::log(::logging::Executing, ::logging::Trace, format!("{}", x.borrow()));
+            }
+            ::logging::log(::logging::Executing, ::logging::Trace, "");
         }
-        debug!("");
         let mut i = 0;
         while i < code.len() {
-            debug!("Executing instruction : {:?}", code[i]);
-            match &code[i] {
-                &Add => primitive(stack, |l, r| { l + r }),
-                &Sub => primitive(stack, |l, r| { l - r }),
-                &Multiply => primitive(stack, |l, r| { l * r }),
-                &Divide => primitive(stack, |l, r| { l / r }),
-                &Remainder => primitive(stack, |l, r| { l % r }),
-                &IntEQ => primitive_int(stack, |l, r| { if l == r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
-                &IntLT => primitive_int(stack, |l, r| { if l < r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
-                &IntLE => primitive_int(stack, |l, r| { if l <= r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
-                &IntGT => primitive_int(stack, |l, r| { if l > r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
-                &IntGE => primitive_int(stack, |l, r| { if l >= r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
-                &DoubleAdd => primitive_float(stack, |l, r| { Float(l + r) }),
-                &DoubleSub => primitive_float(stack, |l, r| { Float(l - r) }),
-                &DoubleMultiply => primitive_float(stack, |l, r| { Float(l * r) }),
-                &DoubleDivide => primitive_float(stack, |l, r| { Float(l / r) }),
-                &DoubleRemainder => primitive_float(stack, |l, r| { Float(l % r) }),
-                &DoubleEQ => primitive_float(stack, |l, r| { if l == r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
-                &DoubleLT => primitive_float(stack, |l, r| { if l < r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
-                &DoubleLE => primitive_float(stack, |l, r| { if l <= r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
-                &DoubleGT => primitive_float(stack, |l, r| { if l > r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
-                &DoubleGE => primitive_float(stack, |l, r| { if l >= r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
-                &IntToDouble => {
+            let op = code.op(i);
+            let value = op.value();
+            if trace {
+                ::logging::log(::logging::Executing, ::logging::Trace, format!("Executing instruction : {:?}", code.get(i)));
+            }
+            match op.opcode {
+                ADD => primitive(stack, |l, r| { l + r }),
+                SUB => primitive(stack, |l, r| { l - r }),
+                MULTIPLY => primitive(stack, |l, r| { l * r }),
+                DIVIDE => primitive(stack, |l, r| { l / r }),
+                REMAINDER => primitive(stack, |l, r| { l % r }),
+                INT_EQ => primitive_int(stack, |l, r| { if l == r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
+                INT_LT => primitive_int(stack, |l, r| { if l < r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
+                INT_LE => primitive_int(stack, |l, r| { if l <= r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
+                INT_GT => primitive_int(stack, |l, r| { if l > r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
+                INT_GE => primitive_int(stack, |l, r| { if l >= r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
+                DOUBLE_ADD => primitive_float(stack, |l, r| { Float(l + r) }),
+                DOUBLE_SUB => primitive_float(stack, |l, r| { Float(l - r) }),
+                DOUBLE_MULTIPLY => primitive_float(stack, |l, r| { Float(l * r) }),
+                DOUBLE_DIVIDE => primitive_float(stack, |l, r| { Float(l / r) }),
+                DOUBLE_REMAINDER => primitive_float(stack, |l, r| { Float(l % r) }),
+                DOUBLE_EQ => primitive_float(stack, |l, r| { if l == r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
+                DOUBLE_LT => primitive_float(stack, |l, r| { if l < r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
+                DOUBLE_LE => primitive_float(stack, |l, r| { if l <= r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
+                DOUBLE_GT => primitive_float(stack, |l, r| { if l > r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
+                DOUBLE_GE => primitive_float(stack, |l, r| { if l >= r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
+                INT_TO_DOUBLE => {
                     let top = stack.pop();
                     stack.push(match top.borrow() {
                         &Int(i) => Node::new(Float(i as f64)),
-                        _ => fail!("Excpected Int in Int -> Double cast")
+                        _ => self.runtime_error(code, i, ~"Excpected Int in Int -> Double cast")
                     });
                 }
-                &DoubleToInt => {
+                DOUBLE_TO_INT => {
                     let top = stack.pop();
                     stack.push(match top.borrow() {
                         &Float(f) => Node::new(Int(f as int)),
-                        _ => fail!("Excpected Double in Double -> Int cast")
+                        _ => self.runtime_error(code, i, ~"Excpected Double in Double -> Int cast")
                     });
                 }
-                &PushInt(value) => { stack.push(Node::new(Int(value))); }
-                &PushFloat(value) => { stack.push(Node::new(Float(value))); }
-                &PushChar(value) => { stack.push(Node::new(Char(value))); }
-                &Push(index) => {
-                    let x = stack[index].clone();
-                    debug!("Pushed {}", x.borrow());
-                    for j in range(0, stack.len()) {
-                        debug!(" {}  {}", j, stack[j].borrow());
+                CHAR_EQ => primitive_char(stack, |l, r| { if l == r { Constructor(0, ~[]) } else { Constructor(1, ~[]) } }),
+                PUSH_INT => { stack.push(Node::new(Int(code.int(value)))); }
+                PUSH_FLOAT => { stack.push(Node::new(Float(code.double(value)))); }
+                PUSH_CHAR => { stack.push(Node::new(Char(op.char()))); }
+                PUSH_STRING => { stack.push(string_node(code.string(value))); }
+                PUSH => {
+                    let x = stack[value].clone();
+                    if trace {
+                        ::logging::log(::logging::Executing, ::logging::Trace, format!("Pushed {}", x.borrow()));
+                        for j in range(0, stack.len()) {
+                            ::logging::log(::logging::Executing, ::logging::Trace, format!(" {}  {}", j, stack[j].borrow()));
+                        }
                     }
                     stack.push(x);
                 }
-                &PushGlobal(index) => {
-                    let (assembly_index, index) = self.globals[index];
+                PUSH_GLOBAL => {
+                    let (assembly_index, index) = self.globals[value];
                     let sc = &self.assembly[assembly_index].superCombinators[index];
                     stack.push(Node::new(Combinator(sc)));
                 }
-                &Mkap => {
+                MKAP => {
                     assert!(stack.len() >= 2);
                     let func = stack.pop();
                     let arg = stack.pop();
-                    debug!("Mkap {} {}", func.borrow(), arg.borrow());
+                    if trace {
+                        ::logging::log(::logging::Executing, ::logging::Trace, format!("Mkap {} {}", func.borrow(), arg.borrow()));
+                    }
                     stack.push(Node::new(Application(func, arg)));
                 }
-                &Eval => {
-                    static unwindCode : &'static [Instruction] = &[Unwind];
-                    let mut newStack = ~[stack.pop()];
-                    self.execute(&mut newStack, unwindCode, assembly_id);
+                EVAL => {
+                    let mut newStack = self.take_stack();
+                    newStack.push(stack.pop());
+                    self.execute(&mut newStack, unwind_code(), assembly_id);
                     stack.push(newStack.pop());
+                    self.return_stack(newStack);
                 }
-                &Pop(num) => {
-                    for _ in range(0, num) {
+                POP => {
+                    for _ in range(0, value) {
                         stack.pop();
                     }
                 }
-                &Update(index) => {
-                    stack[index] = Node::new(Indirection(stack[stack.len() - 1].clone()));
+                UPDATE => {
+                    stack[value] = Node::new(Indirection(stack[stack.len() - 1].clone()));
+                }
+                ALLOC => {
+                    for _ in range(0, value) {
+                        stack.push(Node::new(Hole(RefCell::new(None))));
+                    }
                 }
-                &Unwind => {
+                FILL => {
+                    let node = stack.pop();
+                    match stack[value].borrow() {
+                        &Hole(ref hole) => {
+                            let mut filled = hole.borrow_mut();
+                            *filled.get() = Some(node);
+                        }
+                        _ => self.runtime_error(code, i, ~"Expected a hole in Fill instruction")
+                    }
+                }
+                UNWIND => {
                     let x = (*stack[stack.len() - 1].borrow()).clone();
-                    debug!("Unwinding {}", x);
+                    if trace {
+                        ::logging::log(::logging::Executing, ::logging::Trace, format!("Unwinding {}", x));
+                    }
                     match x {
                         Application(func, _) => {
                             stack.push(func);
@@ -229,29 +377,35 @@ impl <'a> VM<'a> {
                                 for j in range(stack.len() - (comb.arity as uint) - 1, stack.len() - 1) {
                                     stack[j] = match stack[j].borrow() {
                                         &Application(_, ref arg) => arg.clone(),
-                                        _ => fail!("Expected Application")
+                                        _ => self.runtime_error(code, i, ~"Expected Application")
                                     };
                                 }
-                                let mut newStack = ~[];
+                                let mut newStack = self.take_stack();
                                 for i in range(0, comb.arity as uint) {
                                     let index = stack.len() - i - 2;
                                     newStack.push(stack[index].clone());
                                 }
                                 
-                                debug!("Called {}", comb.name);
-                                for j in range(0, newStack.len()) {
-                                    debug!(" {}  {}", j, newStack[j].borrow());
+                                self.reductions.set(self.reductions.get() + 1);
+                                if trace {
+                                    ::logging::log(::logging::Executing, ::logging::Trace, format!("Called {}", comb.name));
+                                    for j in range(0, newStack.len()) {
+                                        ::logging::log(::logging::Executing, ::logging::Trace, format!(" {}  {}", j, newStack[j].borrow()));
+                                    }
                                 }
-                                self.execute(&mut newStack, comb.instructions, comb.assembly_id);
-                                debug!("Returned {}", comb.name);
-                                for j in range(0, newStack.len()) {
-                                    debug!(" {}  {}", j, newStack[j].borrow());
+                                self.execute(&mut newStack, comb.code(), comb.assembly_id);
+                                if trace {
+                                    ::logging::log(::logging::Executing, ::logging::Trace, format!("Returned {}", comb.name));
+                                    for j in range(0, newStack.len()) {
+                                        ::logging::log(::logging::Executing, ::logging::Trace, format!(" {}  {}", j, newStack[j].borrow()));
+                                    }
                                 }
                                 assert_eq!(newStack.len(), 1);
                                 for _ in range(0, comb.arity + 1) {
                                     stack.pop();
                                 }
                                 stack.push(newStack.pop());
+                                self.return_stack(newStack);
                                 i -= 1;
                             }
                         }
@@ -259,17 +413,25 @@ impl <'a> VM<'a> {
                             stack[stack.len() - 1] = node;
                             i -= 1;
                         }
+                        Hole(hole) => {
+                            let node = hole.borrow().get().clone();
+                            match node {
+                                Some(node) => stack[stack.len() - 1] = node,
+                                None => self.runtime_error(code, i, ~"A let binding was evaluated before it was defined")
+                            }
+                            i -= 1;
+                        }
                         _ => ()
                     }
                 }
-                &Slide(size) => {
+                SLIDE => {
                     let top = stack.pop();
-                    for _ in range(0, size) {
+                    for _ in range(0, value) {
                         stack.pop();
                     }
                     stack.push(top);
                 }
-                &Split(_) => {
+                SPLIT => {
                     let x = stack.pop();
                     match x.borrow() {
                         &Constructor(_, ref fields) => {
@@ -277,25 +439,25 @@ impl <'a> VM<'a> {
                                 stack.push(field.clone());
                             }
                         }
-                        _ => fail!("Expected constructor in Split instruction")
+                        _ => self.runtime_error(code, i, ~"Expected constructor in Split instruction")
                     }
                 }
-                &Pack(tag, arity) => {
-                    let args = from_fn(arity as uint, |_| stack.pop());
-                    stack.push(Node::new(Constructor(tag, args)));
+                PACK => {
+                    let args = from_fn(op.low(), |_| stack.pop());
+                    stack.push(Node::new(Constructor(op.high() as u16, args)));
                 }
-                &JumpFalse(address) => {
+                JUMP_FALSE => {
                     match stack[stack.len() - 1].borrow() {
                         &Constructor(0, _) => (),
-                        &Constructor(1, _) => i = address - 1,
+                        &Constructor(1, _) => i = value - 1,
                         _ => ()
                     }
                     stack.pop();
                 }
-                &CaseJump(jump_tag) => {
+                CASE_JUMP => {
                     let jumped = match stack[stack.len() - 1].borrow() {
                         &Constructor(tag, _) => {
-                            if jump_tag == tag as uint {
+                            if value == tag as uint {
                                 i += 1;//Skip the jump instruction ie continue to the next test
                                 true
                             }
@@ -303,39 +465,109 @@ impl <'a> VM<'a> {
                                 false
                             }
                         }
-                        x => fail!("Expected constructor when executing CaseJump, got {}", x),
+                        x => self.runtime_error(code, i, format!("Expected constructor when executing CaseJump, got {}", x)),
                     };
                     if !jumped {
                         stack.pop();
                     }
                 }
-                &Jump(to) => {
-                    i = to - 1;
+                CASE_TABLE => {
+                    match stack[stack.len() - 1].borrow() {
+                        //The jump for the tag is executed next
+                        &Constructor(tag, _) if (tag as uint) < value => i += tag as uint,
+                        x => self.runtime_error(code, i, format!("Expected a constructor with a tag less than {} when executing CaseTable, got {}", value, x))
+                    }
                 }
-                &PushDictionary(index) => {
+                JUMP => {
+                    i = value - 1;
+                }
+                PUSH_DICTIONARY => {
                     let assembly = &self.assembly[assembly_id];
-                    let dict : &[uint] = assembly.instance_dictionaries[index];
+                    let dict : &[uint] = assembly.instance_dictionaries[value];
                     stack.push(Node::new(Dictionary(dict)));
                 }
-                &PushDictionaryMember(index) => {
+                PUSH_DICTIONARY_MEMBER => {
                     let sc = {
                         let dict = match stack[0].borrow() {
                             &Dictionary(ref x) => x,
-                            x => fail!("Attempted to retrieve {} as dictionary", x)
+                            x => self.runtime_error(code, i, format!("Attempted to retrieve {} as dictionary", x))
                         };
-                        let gi = dict[index];
+                        let gi = dict[value];
                         let (assembly_index, i) = self.globals[gi];
                         &self.assembly[assembly_index].superCombinators[i]
                     };
                     stack.push(Node::new(Combinator(sc)));
                 }
-                //undefined => fail!("Use of undefined instruction {:?}", undefined)
+                PUSH_DICTIONARY_RANGE => {
+                    let dict = match stack[0].borrow() {
+                        &Dictionary(ref x) => x.slice(op.high(), op.high() + op.low()),
+                        x => self.runtime_error(code, i, format!("Attempted to retrieve {} as dictionary", x))
+                    };
+                    stack.push(Node::new(Dictionary(dict)));
+                }
+                FAIL => self.runtime_error(code, i, ~"No alternative of the case expression matched"),
+                opcode => self.runtime_error(code, i, format!("Invalid opcode {}", opcode))
             }
             i += 1;
         }
-        debug!("End frame");
-        debug!("--------------------------");
+        if trace {
+            ::logging::log(::logging::Executing, ::logging::Trace, "End frame");
+            ::logging::log(::logging::Executing, ::logging::Trace, "--------------------------");
+        }
+    }
+}
+
+///Checks that the operands of the instructions in the linked 'assembly' are valid in a VM which has 'loaded_globals' globals
+fn validate_assembly(assembly: &Assembly, loaded_globals: uint, loaded_dictionary_size: uint) -> Result<(), ~str> {
+    let globals = loaded_globals + assembly.superCombinators.len();
+    //The dictionary which a function is given may come from any assembly so members are checked against the largest
+    let dictionary_size = assembly.instance_dictionaries.iter().fold(loaded_dictionary_size, |size, dict| max(size, dict.len()));
+    for (i, dict) in assembly.instance_dictionaries.iter().enumerate() {
+        if dict.iter().any(|index| *index >= globals) {
+            return Err(format!("The instance dictionary {} refers to a function which does not exist", i));
+        }
+    }
+    for sc in assembly.superCombinators.iter() {
+        let code = sc.code();
+        //The characters and constants are checked before any instruction is decoded
+        try!(code.check().map_err(|msg| format!("{} in {}", msg, sc.name)));
+        let instructions = code.instructions();
+        //Only Alloc and Split push more than one node and no instruction jumps backwards so this bounds the stack
+        let stack_size = instructions.iter().fold(sc.arity + 1, |size, instruction| size + match *instruction {
+            Alloc(n) | Split(n) => n,
+            _ => 1
+        });
+        for (i, instruction) in instructions.iter().enumerate() {
+            let valid = match *instruction {
+                Jump(address) | JumpFalse(address) => address <= code.len(),
+                //The instruction after a CaseJump is skipped when the tag matches
+                CaseJump(_) => i + 1 < code.len(),
+                CaseTable(size) => i + size < code.len()
+                    && instructions.slice(i + 1, i + 1 + size).iter().all(|jump| match *jump { Jump(_) => true, _ => false }),
+                PushGlobal(index) => index < globals,
+                PushDictionary(index) => index < assembly.instance_dictionaries.len(),
+                PushDictionaryMember(index) => index < dictionary_size,
+                PushDictionaryRange(offset, size) => offset + size <= dictionary_size,
+                Push(index) | Update(index) | Fill(index) => index < stack_size,
+                Pop(size) | Slide(size) => size <= stack_size,
+                Split(size) => size <= ::std::u16::max_value as uint,
+                _ => true
+            };
+            if !valid {
+                return Err(format!("Invalid instruction {:?} at {} in {}", *instruction, i, sc.name));
+            }
+        }
     }
+    Ok(())
+}
+
+///Creates the list of characters which a string literal stands for, 'a' : 'b' : [] for "ab"
+fn string_node<'a>(s: &str) -> Node<'a> {
+    let mut list = Node::new(Constructor(0, ~[]));
+    for c in s.chars_rev() {
+        list = Node::new(Constructor(1, ~[Node::new(Char(c)), list]));
+    }
+    list
 }
 
 fn primitive_int(stack: &mut ~[Node], f: |int, int| -> Node_) {
@@ -346,6 +578,14 @@ fn primitive_int(stack: &mut ~[Node], f: |int, int| -> Node_) {
         (lhs, rhs) => fail!("Expected fully evaluted numbers in primitive instruction\n LHS: {}\nRHS: {} ", lhs, rhs)
     }
 }
+fn primitive_char(stack: &mut ~[Node], f: |char, char| -> Node_) {
+    let l = stack.pop();
+    let r = stack.pop();
+    match (l.borrow(), r.borrow()) {
+        (&Char(lhs), &Char(rhs)) => stack.push(Node::new(f(lhs, rhs))),
+        (lhs, rhs) => fail!("Expected fully evaluted characters in primitive instruction\n LHS: {}\nRHS: {} ", lhs, rhs)
+    }
+}
 fn primitive_float(stack: &mut ~[Node], f: |f64, f64| -> Node_) {
     let l = stack.pop();
     let r = stack.pop();
@@ -359,31 +599,171 @@ fn primitive(stack: &mut ~[Node], f: |int, int| -> int) {
 }
 
 #[deriving(Eq)]
-enum VMResult {
+pub enum VMResult {
     IntResult(int),
     DoubleResult(f64),
+    CharResult(char),
     ConstructorResult(u16, ~[VMResult])
 }
 
-fn compile_iter<T : Iterator<char>>(iterator: T) -> Assembly {
-    let mut parser = Parser::new(iterator);
+///Renders a fully evaluated result using its type to decide how constructors are displayed.
+///Strings are rendered without quotes so that the output of 'show' can be printed directly.
+pub fn show_result(result: &VMResult, typ: &Type) -> ~str {
+    match result {
+        &IntResult(i) => i.to_str(),
+        &DoubleResult(d) => d.to_str(),
+        &CharResult(c) => format!("'{}'", c),
+        &ConstructorResult(tag, ref fields) => {
+            let name = match &typ.typ {
+                &TypeOperator(ref op) => op.name.as_slice(),
+                &TypeVariable(_) => ""
+            };
+            if "[]" == name {
+                let mut elements = ~[];
+                let mut list = result;
+                loop {
+                    match list {
+                        &ConstructorResult(1, ref cons) => {
+                            elements.push(&cons[0]);
+                            list = &cons[1];
+                        }
+                        _ => break
+                    }
+                }
+                if typ.types()[0] == Type::new_op(~"Char", ~[]) {
+                    let mut s = ~"";
+                    for element in elements.iter() {
+                        match *element {
+                            &CharResult(c) => s.push_char(c),
+                            _ => ()
+                        }
+                    }
+                    s
+                }
+                else {
+                    let strs : ~[~str] = elements.iter().map(|e| show_result(*e, &typ.types()[0])).collect();
+                    format!("[{}]", strs.connect(", "))
+                }
+            }
+            else if name.starts_with("(,") {
+                let strs : ~[~str] = fields.iter().zip(typ.types().iter()).map(|(f, t)| show_result(f, t)).collect();
+                format!("({})", strs.connect(", "))
+            }
+            else {
+                let mut s = format!("<{}>", tag);
+                for field in fields.iter() {
+                    s.push_str(" (" + show_result(field, &Type::new_var(-1)) + ")");
+                }
+                s
+            }
+        }
+    }
+}
+
+fn parse_expression(source: &str) -> Result<TypedExpr, ~str> {
+    let mut parser = Parser::new(source.chars());
+    match parser.expression() {
+        Ok(Some(expr)) => Ok(expr),
+        Ok(None) => Err(~"Expected an expression"),
+        Err(diagnostic) => Err(format!("{}", diagnostic))
+    }
+}
+
+///Returns true if the type of 'expr' has a Show instance in 'assembly'
+fn has_show_instance(assembly: &Assembly, expr: &mut TypedExpr) -> bool {
+    let mut type_env = TypeEnvironment::new();
+    type_env.add_types(assembly as &Types);
+    if type_env.typecheck(expr).is_err() {
+        return false;
+    }
+    match &expr.typ.typ {
+        &TypeOperator(_) => type_env.has_instance("Show", &expr.typ),
+        &TypeVariable(_) => false
+    }
+}
+
+///Parses, typechecks and compiles 'source' as an expression which can use everything in 'assembly'.
+///Instance dictionaries needed by the expression are added to 'assembly'. The lifted functions are optimized as 'options' allows.
+///If 'show' is true and the expression's type has a Show instance the expression is wrapped in a call to show.
+///Returns the instructions together with the type of the compiled expression.
+pub fn compile_expression(assembly: &mut Assembly, source: &str, show: bool, options: &CompilerOptions) -> Result<(~[Instruction], Type), ~str> {
+    let mut expr = try!(parse_expression(source));
+    if show && has_show_instance(assembly, &mut expr) {
+        let inner = try!(parse_expression(source));
+        expr = TypedExpr::new(Apply(~(TypedExpr::new(Identifier(SHOW)), inner)));
+    }
+    let (instructions, combinators, dictionaries) = {
+        let mut type_env = TypeEnvironment::new();
+        type_env.add_types(&*assembly as &Types);
+        match type_env.typecheck(&mut expr) {
+            Ok(()) => (),
+            Err(error) => return Err(error.render(source))
+        }
+        //The lambdas and local functions of the expression are compiled as globals placed after those of 'assembly'
+        let mut lifted = Module::new(~"");
+        lifted.bindings = lift_expression(&mut expr, &mut type_env);
+        lifted.build_index();
+
+        let mut compiler = Compiler::new(&type_env);
+        compiler.options = options.clone();
+        compiler.assemblies.push(&*assembly);
+        let (combinators, instructions) = compiler.compile_lifted_expression(&lifted, &expr);
+        let dictionaries : ~[~[uint]] = compiler.instance_dictionaries.iter().map(|&(_, ref dict)| dict.clone()).collect();
+        (instructions, combinators, dictionaries)
+    };
+    assembly.superCombinators.push_all_move(combinators);
+    for dict in dictionaries.move_iter() {
+        assembly.instance_dictionaries.push(dict);
+    }
+    Ok((instructions, expr.typ))
+}
+
+///Runs instructions returned by compile_expression in a VM containing 'assembly' and evaluates the result fully
+pub fn eval_expr(assembly: Assembly, instructions: &[Instruction]) -> Option<VMResult> {
+    let mut vm = VM::new();
+    vm.add_assembly(assembly);
+    let code = Code::encode(instructions);
+    let mut stack = ~[];
+    vm.execute(&mut stack, code.as_slice(), 0);
+    vm.deep_evaluate(stack.pop())
+}
+
+pub fn compile_iter<T : Iterator<char>>(iterator: T) -> Assembly {
+    let (_, assembly) = compile_module(iterator);
+    assembly
+}
+
+///Compiles a module, returning the typechecked module together with the assembly compiled from it
+pub fn compile_module<T : Iterator<char>>(iterator: T) -> (Module, Assembly) {
+    compile_module_with(iterator, &Default::default())
+}
+
+///Compiles a module with 'options', returning the typechecked module together with the assembly compiled from it
+pub fn compile_module_with<T : Iterator<char>>(iterator: T, options: &CompilerOptions) -> (Module, Assembly) {
+    let contents : ~str = iterator.collect();
+    let mut parser = Parser::new(contents.chars());
     let mut module = parser.module();
-    
-    let mut typer = TypeEnvironment::new();
-    typer.typecheck_module(&mut module);
-    
-    let mut compiler = Compiler::new(&typer);
-    compiler.compileModule(&module)
+    let mut warnings = ~[];
+    match loader::compile_module([], &mut warnings, &mut module, contents, options) {
+        Ok(assembly) => (module, assembly),
+        Err(msg) => fail!("{}", msg)
+    }
+}
+
+///Compiles the file at 'filename' which is read through 'fs'
+pub fn compile_file_with(fs: &FileSystem, filename: &str) -> Result<Assembly, ~str> {
+    fs.read_file(filename).map(|contents| compile_iter(contents.chars()))
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 pub fn compile_file(filename: &str) -> Assembly {
-    let path = &Path::new(filename);
-    let s  = File::open(path).read_to_end();
-    let contents : &str = from_utf8(s);
-    compile_iter(contents.chars())
+    match compile_file_with(&NativeFileSystem as &FileSystem, filename) {
+        Ok(assembly) => assembly,
+        Err(msg) => fail!("{}", msg)
+    }
 }
 
-fn extract_result(node: Node_) -> Option<VMResult> {
+pub fn extract_result(node: Node_) -> Option<VMResult> {
     match node {
         Constructor(tag, fields) => {
             let mut result = ~[];
@@ -397,21 +777,44 @@ fn extract_result(node: Node_) -> Option<VMResult> {
         }
         Int(i) => Some(IntResult(i)),
         Float(i) => Some(DoubleResult(i)),
+        Char(c) => Some(CharResult(c)),
         x => {
-            println!("Can't extract result {}", x);
+            log_phase!(Executing, Debug, "Can't extract result {}", x);
             None
         }
     }
 }
 
 pub fn execute_main<T : Iterator<char>>(iterator: T) -> Option<VMResult> {
+    run_main(compile_iter(iterator))
+}
+
+///Evaluates the 'main' function of 'assembly', returns None if there is no main function
+pub fn run_main(assembly: Assembly) -> Option<VMResult> {
+    run_main_with(~[assembly])
+}
+
+///Adds each assembly to a VM in order and evaluates the first 'main' function found in them
+pub fn run_main_with(assemblies: ~[Assembly]) -> Option<VMResult> {
+    run_main_with_options(assemblies, &Default::default())
+}
+
+///Runs 'main' like 'run_main_with', if 'options' enables optimizations the supercombinators
+///which 'main' can't reach are removed first
+pub fn run_main_with_options(assemblies: ~[Assembly], options: &CompilerOptions) -> Option<VMResult> {
+    let mut assemblies = assemblies;
+    if options.optimization_level > 0 {
+        remove_dead_code(&mut assemblies, "main");
+    }
     let mut vm = VM::new();
-    vm.add_assembly(compile_iter(iterator));
-    let x = vm.assembly.iter().flat_map(|a| a.superCombinators.iter()).find(|sc| sc.name == ~"main");
+    for assembly in assemblies.move_iter() {
+        vm.add_assembly(assembly);
+    }
+    let x = vm.assembly.iter().flat_map(|a| a.superCombinators.iter()).find(|sc| sc.name == MAIN);
     match x {
         Some(sc) => {
             assert!(sc.arity == 0);
-            let result = vm.evaluate(sc.instructions, sc.assembly_id);
+            let result = vm.evaluate(sc.code(), sc.assembly_id);
             extract_result(result)
         }
         None => None
@@ -427,7 +830,13 @@ use std::str::from_utf8;
 use typecheck::TypeEnvironment;
 use compiler::Compiler;
 use parser::Parser;
-use vm::{VM, execute_main, extract_result, IntResult, DoubleResult, ConstructorResult};
+use vm::{VM, execute_main, extract_result, run_main_with, IntResult, DoubleResult, ConstructorResult};
+use vm::{compile_file, compile_iter, compile_module_with, compile_expression, eval_expr, show_result, run_main};
+use interner::intern;
+use hbc::{encode_assembly, decode_assembly};
+use bytecode::Code;
+use compiler::{CompilerOptions, PushInt, PushGlobal, PushDictionary, PushDictionaryMember, PushDictionaryRange, Fill, Fail, Jump, Update, Unwind, Relocation};
+use extra::arc::Arc;
 
 #[test]
 fn test_primitive()
@@ -508,6 +917,289 @@ main = case test of
     assert_eq!(execute_main(module.chars()), Some(IntResult(0)));
 }
 
+#[test]
+fn test_if_then_else()
+{
+    let module = 
+r"data Bool = True | False
+
+max x y = if primIntLT x y then y else x
+
+main = case [max 3 7] of
+    : x xs -> if primIntLT x 5 then 0 else x
+    [] -> 10";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(7)));
+}
+
+#[test]
+fn test_binding_patterns()
+{
+    let module = 
+r"data Bool = True | False
+
+and True True = True
+and x y = False
+
+fib 0 = 0
+fib 1 = 1
+fib n = primIntAdd (fib (primIntSubtract n 1)) (fib (primIntSubtract n 2))
+
+first (a, b) = a
+
+main = case and True False of
+    True -> 100
+    False -> first (fib 10, 0)";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(55)));
+}
+
+#[test]
+fn test_operator_as_expression()
+{
+    let module = 
+r"x <+> y = primIntAdd x y
+
+twice f x = f x x
+
+main = twice (<+>) 3";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(6)));
+}
+
+#[test]
+fn test_let_pattern_binding()
+{
+    let module = 
+r"data Maybe a = Just a | Nothing
+
+main = let
+        (x, y) = (1, 2)
+        Just z = Just 3
+        (_, w) = (0, 4)
+    in primIntAdd x (primIntAdd y (primIntAdd z w))";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(10)));
+}
+
+#[test]
+fn test_recursive_let()
+{
+    let module = 
+r"main = let
+        ones = 1 : twos
+        twos = 2 : ones
+    in case twos of
+        : x rest -> case rest of
+            : y ys -> primIntAdd x y";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(3)));
+}
+
+#[test]
+fn test_superclass_dictionary()
+{
+    let module = 
+r"class Size a where
+    size :: a -> Int
+
+class Size a => Weight a where
+    weight :: a -> Int
+
+instance Size Int where
+    size x = 1
+
+instance Weight Int where
+    weight x = primIntAdd x 1
+
+sizeOf :: Size a => a -> Int
+sizeOf x = size x
+
+total :: Weight a => a -> Int
+total x = primIntAdd (size x) (weight x)
+
+heavy :: Weight a => a -> Int
+heavy x = primIntAdd (sizeOf x) (weight x)
+
+main = primIntAdd (total (primIntAdd 2 0)) (heavy (primIntAdd 3 0))";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(9)));
+}
+
+#[test]
+fn test_lambda_case()
+{
+    let module = 
+r"data Maybe a = Just a | Nothing
+
+fromMaybe d = \case
+    Just x -> x
+    Nothing -> d
+
+main = primIntAdd (fromMaybe 0 (Just 2)) (fromMaybe 3 Nothing)";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(5)));
+}
+
+#[test]
+fn test_string_pattern()
+{
+    let module = 
+r#"answer "yes" = 1
+answer "no" = 2
+answer x = 3
+
+main = primIntAdd (answer "yes") (primIntAdd (answer "no") (answer "ye"))"#;
+    assert_eq!(execute_main(module.chars()), Some(IntResult(6)));
+}
+
+#[test]
+fn test_overloaded_literals()
+{
+    let module = 
+r"data Ratio = Ratio Double
+
+class Num a where
+    fromInteger :: Int -> a
+
+class Fractional a where
+    fromRational :: Double -> a
+
+instance Num Ratio where
+    fromInteger x = Ratio (primIntToDouble x)
+
+instance Fractional Ratio where
+    fromRational x = Ratio x
+
+value (Ratio x) = x
+
+one :: Ratio
+one = 1
+
+half :: Ratio
+half = 0.5
+
+main = primDoubleAdd (value one) (value half)";
+    assert_eq!(execute_main(module.chars()), Some(DoubleResult(1.5)));
+}
+
+#[test]
+fn test_function_and_tuple_instances()
+{
+    let module = 
+r"class Describe a where
+    describe :: a -> Int
+
+instance Describe Int where
+    describe x = x
+
+instance Describe (a -> b) where
+    describe f = 100
+
+instance (Describe a, Describe b) => Describe (a, b) where
+    describe p = case p of
+        (x, y) -> primIntAdd (describe x) (describe y)
+
+one = primIntAdd 0 1
+
+main = primIntAdd (describe (\x -> primIntAdd x one)) (describe (one, primIntAdd one one))";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(103)));
+}
+
+#[test]
+fn call_method_of_constrained_instance_at_known_type()
+{
+    let module = 
+r"class Describe a where
+    describe :: a -> Int
+
+instance Describe Int where
+    describe x = x
+
+data Box a = Box a
+
+instance Describe a => Describe (Box a) where
+    describe b = case b of
+        Box x -> primIntAdd 1 (describe x)
+
+main = describe (Box (Box (primIntAdd 1 1)))";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(4)));
+}
+
+#[test]
+fn test_nested_constructor_patterns()
+{
+    let module = 
+r"data Maybe a = Just a | Nothing
+data Either a b = Left a | Right b
+
+get d (Just (Left x)) = x
+get d (Just (Right (a, b))) = b
+get d x = d
+
+main = primIntAdd (get 1 (Just (Right (2, 3)))) (get 10 Nothing)";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(13)));
+}
+
+#[test]
+fn nested_patterns_on_unevaluated_fields()
+{
+    let module = 
+r"data Maybe a = Just a | Nothing
+
+f (Just 2) = 10
+f (Just x) = x
+f Nothing = 0
+
+g (Just (Just x)) = x
+g y = 0
+
+main = primIntAdd (f (Just (primIntAdd 1 1))) (g (Just (Just (primIntAdd 2 3))))";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(15)));
+}
+
+#[test]
+fn match_string_pattern_on_result_of_show()
+{
+    let module = 
+r#"class Show a where
+    show :: a -> [Char]
+
+instance Show Bool where
+    show x = case x of
+        True -> append "Tr" "ue"
+        False -> append "Fal" "se"
+
+append xs ys = case xs of
+    : x rest -> x : append rest ys
+    [] -> ys
+
+isTrue x = case show x of
+    "True" -> 1
+    y -> 10
+
+main = primIntAdd (isTrue True) (isTrue False)"#;
+    assert_eq!(execute_main(module.chars()), Some(IntResult(11)));
+}
+
+#[test]
+fn test_infix_definition()
+{
+    let module = 
+r"x <+> y = primIntAdd x (primIntMultiply y 2)
+(a, b) `plus` c = primIntAdd a c
+
+main = (1 <+> 3, 0) `plus` 10";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(17)));
+}
+
+#[test]
+fn test_records()
+{
+    let module = 
+r"data Point = Point { x :: Int, y :: Int } | Point3 { x :: Int, y :: Int, z :: Int }
+
+move p = p { x = primIntAdd (x p) 10 }
+
+main = case move (Point3 { z = 3, y = 2, x = 1 }) of
+    Point a b -> 0
+    Point3 a b c -> primIntAdd (x (Point { x = a, y = b })) c";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(14)));
+}
+
 #[test]
 fn test_typeclasses_known_types()
 {
@@ -562,7 +1254,7 @@ fn test_run_prelude() {
         let contents : &str = from_utf8(s);
         let mut parser = Parser::new(contents.chars()); 
         let mut module = parser.module();
-        type_env.typecheck_module(&mut module);
+        type_env.typecheck_module(&mut module).unwrap();
         let mut compiler = Compiler::new(&type_env);
         compiler.compileModule(&mut module)
     };
@@ -573,7 +1265,7 @@ r"add x y = primIntAdd x y
 main = foldl add 0 [1,2,3,4]";
         let mut parser = Parser::new(file.chars());
         let mut module = parser.module();
-        type_env.typecheck_module(&mut module);
+        type_env.typecheck_module(&mut module).unwrap();
         let mut compiler = Compiler::new(&type_env);
         compiler.assemblies.push(&prelude);
         compiler.compileModule(&module)
@@ -582,11 +1274,11 @@ main = foldl add 0 [1,2,3,4]";
     let mut vm = VM::new();
     vm.add_assembly(prelude);
     vm.add_assembly(assembly);
-    let x = vm.assembly.iter().flat_map(|a| a.superCombinators.iter()).find(|sc| sc.name == ~"main");
+    let x = vm.assembly.iter().flat_map(|a| a.superCombinators.iter()).find(|sc| sc.name == intern("main"));
     let result = match x {
         Some(sc) => {
             assert!(sc.arity == 0);
-            let result = vm.evaluate(sc.instructions, sc.assembly_id);
+            let result = vm.evaluate(sc.code(), sc.assembly_id);
             extract_result(result)
         }
         None => None
@@ -603,7 +1295,7 @@ fn instance_super_class() {
         let mut parser = Parser::new(contents.chars()); 
         let mut module = parser.module();
         let mut type_env = TypeEnvironment::new();
-        type_env.typecheck_module(&mut module);
+        type_env.typecheck_module(&mut module).unwrap();
         let mut compiler = Compiler::new(&type_env);
         compiler.compileModule(&mut module)
     };
@@ -614,7 +1306,7 @@ fn instance_super_class() {
         let mut module = parser.module();
         let mut type_env = TypeEnvironment::new();
         type_env.add_types(&prelude);
-        type_env.typecheck_module(&mut module);
+        type_env.typecheck_module(&mut module).unwrap();
         let mut compiler = Compiler::new(&type_env);
         compiler.assemblies.push(&prelude);
         compiler.compileModule(&module)
@@ -623,11 +1315,11 @@ fn instance_super_class() {
     let mut vm = VM::new();
     vm.add_assembly(prelude);
     vm.add_assembly(assembly);
-    let x = vm.assembly.iter().flat_map(|a| a.superCombinators.iter()).find(|sc| sc.name == ~"main");
+    let x = vm.assembly.iter().flat_map(|a| a.superCombinators.iter()).find(|sc| sc.name == intern("main"));
     let result = match x {
         Some(sc) => {
             assert!(sc.arity == 0);
-            let result = vm.evaluate(sc.instructions, sc.assembly_id);
+            let result = vm.evaluate(sc.code(), sc.assembly_id);
             extract_result(result)
         }
         None => None
@@ -635,4 +1327,304 @@ fn instance_super_class() {
     assert_eq!(result, Some(ConstructorResult(1, ~[])));
 }
 
+#[test]
+fn derived_instances() {
+    let prelude = {
+        let path = &Path::new("Prelude.hs");
+        let s  = File::open(path).read_to_end();
+        let contents : &str = from_utf8(s);
+        let mut parser = Parser::new(contents.chars());
+        let mut module = parser.module();
+        let mut type_env = TypeEnvironment::new();
+        type_env.typecheck_module(&mut module).unwrap();
+        let mut compiler = Compiler::new(&type_env);
+        compiler.compileModule(&mut module)
+    };
+
+    let assembly = {
+        let file =
+r"data T = A Int Int | B Bool deriving (Eq, Ord, Show)
+main = case compare (A 1 3) (A 1 2) of
+    GT -> case A 1 2 == A 1 2 of
+        True -> length (show (B True))
+        False -> 0
+    EQ -> 0
+    LT -> 0";
+        let mut parser = Parser::new(file.chars());
+        let mut module = parser.module();
+        let mut type_env = TypeEnvironment::new();
+        type_env.add_types(&prelude);
+        type_env.typecheck_module(&mut module).unwrap();
+        let mut compiler = Compiler::new(&type_env);
+        compiler.assemblies.push(&prelude);
+        compiler.compileModule(&module)
+    };
+
+    let mut vm = VM::new();
+    vm.add_assembly(prelude);
+    vm.add_assembly(assembly);
+    let x = vm.assembly.iter().flat_map(|a| a.superCombinators.iter()).find(|sc| sc.name == intern("main"));
+    let result = match x {
+        Some(sc) => {
+            let result = vm.evaluate(sc.code(), sc.assembly_id);
+            extract_result(result)
+        }
+        None => None
+    };
+    //"B True"
+    assert_eq!(result, Some(IntResult(6)));
+}
+
+#[test]
+fn derived_show_puts_parentheses_around_fields() {
+    let prelude = compile_file("Prelude.hs");
+    let assembly = {
+        let file =
+r"data T = A | B Bool | C T T deriving (Show)
+main = length (show (C (B True) A))";
+        let mut parser = Parser::new(file.chars());
+        let mut module = parser.module();
+        let mut type_env = TypeEnvironment::new();
+        type_env.add_types(&prelude);
+        type_env.typecheck_module(&mut module).unwrap();
+        let mut compiler = Compiler::new(&type_env);
+        compiler.assemblies.push(&prelude);
+        compiler.compileModule(&module)
+    };
+    //"C (B True) A"
+    assert_eq!(run_main_with(~[prelude, assembly]), Some(IntResult(12)));
+}
+
+#[test]
+fn load_checked_assembly() {
+    let prelude = compile_file("Prelude.hs");
+    let mut vm = VM::new();
+    assert_eq!(vm.add_checked_assembly(decode_assembly(encode_assembly(&prelude)).unwrap()), Ok(0));
+
+    let mut invalid = compile_file("Prelude.hs");
+    invalid.offset = prelude.superCombinators.len();
+    invalid.superCombinators[0].instructions = Arc::new(Code::encode([PushInt(1), Jump(5), Update(0), Unwind]));
+    let err = vm.add_checked_assembly(invalid.clone());
+    assert!(err.is_err());
+    invalid.superCombinators[0].instructions = Arc::new(Code::encode([PushGlobal(100000), Update(0), Unwind]));
+    assert!(vm.add_checked_assembly(invalid.clone()).is_err());
+    invalid.superCombinators[0].instructions = Arc::new(Code::encode([PushDictionary(100000), Update(0), Unwind]));
+    assert!(vm.add_checked_assembly(invalid.clone()).is_err());
+    invalid.superCombinators[0].instructions = Arc::new(Code::encode([PushDictionaryMember(100000), Update(0), Unwind]));
+    assert!(vm.add_checked_assembly(invalid.clone()).is_err());
+    invalid.superCombinators[0].instructions = Arc::new(Code::encode([PushDictionaryRange(0, 100000), Update(0), Unwind]));
+    assert!(vm.add_checked_assembly(invalid.clone()).is_err());
+    invalid.superCombinators[0].instructions = Arc::new(Code::encode([PushInt(1), Fill(100000), Update(0), Unwind]));
+    assert!(vm.add_checked_assembly(invalid.clone()).is_err());
+    invalid.superCombinators[0].instructions = Arc::new(Code::encode([Push(100000), Update(0), Unwind]));
+    assert!(vm.add_checked_assembly(invalid.clone()).is_err());
+    invalid.superCombinators[0].instructions = Arc::new(Code::encode([PushInt(1), Update(100000), Unwind]));
+    assert!(vm.add_checked_assembly(invalid.clone()).is_err());
+    invalid.superCombinators[0].instructions = Arc::new(Code::encode([PushInt(1), Slide(100000), Update(0), Unwind]));
+    assert!(vm.add_checked_assembly(invalid.clone()).is_err());
+    invalid.superCombinators[0].instructions = Arc::new(Code::encode([PushGlobal(0), Update(0), Unwind]));
+    invalid.relocations = ~[Relocation { index: 0, module: ~"Missing", name: intern("missing") }];
+    assert!(vm.add_checked_assembly(invalid.clone()).is_err());
+    assert_eq!(vm.assembly.len(), 1);
+    //Assemblies are linked so the same assembly can be loaded at another position
+    assert_eq!(vm.add_checked_assembly(compile_file("Prelude.hs")), Ok(1));
+}
+
+#[test]
+fn partially_applied_builtin_constructors() {
+    let module =
+r"zipWith f xs ys = case xs of
+    x:xs2 -> case ys of
+        y:ys2 -> f x y : zipWith f xs2 ys2
+        [] -> []
+    [] -> []
+
+sum xs = case xs of
+    y:ys -> primIntAdd y (sum ys)
+    [] -> 0
+
+first p = case p of
+    (x, y) -> x
+
+sumFirst ps = case ps of
+    p:rest -> primIntAdd (first p) (sumFirst rest)
+    [] -> 0
+
+sumAll xss = case xss of
+    xs:rest -> primIntAdd (sum xs) (sumAll rest)
+    [] -> 0
+
+main = primIntAdd (sumFirst (zipWith (,) [1, 2] [3, 4])) (sumAll (zipWith (:) [10, 20] [[], [5]]))";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(38)));
+}
+
+#[test]
+#[should_fail]
+fn pattern_match_failure() {
+    let module =
+r"data Maybe a = Just a | Nothing
+
+test m = case m of
+    Just x -> primIntAdd x 1
+
+main = test Nothing";
+    execute_main(module.chars());
+}
+
+#[test]
+fn runtime_error_location() {
+    let mut vm = VM::new();
+    vm.add_assembly(compile_iter(
+r"data Maybe a = Just a | Nothing
+
+test m = case m of
+    Just x -> primIntAdd x 1
+
+main = test Nothing".chars()));
+    let (global, fail) = vm.assembly[0].superCombinators.iter().enumerate()
+        .filter_map(|(global, sc)| sc.code().instructions().iter().position(|instruction| *instruction == Fail).map(|fail| (global, fail)))
+        .next().unwrap();
+    assert_eq!(vm.find_location(global, fail).map(|location| location.row), Some(3));
+}
+
+#[test]
+fn instances_for_nested_types() {
+    let module =
+r"data Maybe a = Just a | Nothing
+
+class Test a where
+    test :: a -> Int
+
+instance Test (Maybe Int) where
+    test x = 1
+
+instance Test (Maybe [a]) where
+    test x = 2
+
+main = primIntAdd (test (Just [primIntAdd 1 1])) (primIntMultiply 10 (test (Just (primIntAdd 1 1))))";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(12)));
+}
+
+#[test]
+fn evaluate_expression_with_show() {
+    let mut prelude = compile_file("Prelude.hs");
+    let (instructions, typ) = compile_expression(&mut prelude, "Just True", true, &Default::default()).unwrap();
+    let result = eval_expr(prelude, instructions).unwrap();
+    assert_eq!(show_result(&result, &typ), ~"Just (True)");
+}
+
+#[test]
+fn evaluate_expression_without_show_instance() {
+    let mut prelude = compile_file("Prelude.hs");
+    let (instructions, typ) = compile_expression(&mut prelude, "(primIntAdd 1 2, 'a')", true, &Default::default()).unwrap();
+    let result = eval_expr(prelude, instructions).unwrap();
+    assert_eq!(show_result(&result, &typ), ~"(3, 'a')");
+}
+
+#[test]
+fn evaluate_string_literal() {
+    let mut prelude = compile_file("Prelude.hs");
+    let (instructions, typ) = compile_expression(&mut prelude, "\"abc\"", false, &Default::default()).unwrap();
+    let result = eval_expr(prelude, instructions).unwrap();
+    assert_eq!(show_result(&result, &typ), ~"abc");
+}
+
+#[test]
+fn evaluate_expression_with_lambda() {
+    let mut prelude = compile_file("Prelude.hs");
+    let (instructions, typ) = compile_expression(&mut prelude, r"let add x = primIntAdd x 1 in (\y -> add y) 2", false, &Default::default()).unwrap();
+    let result = eval_expr(prelude, instructions).unwrap();
+    assert_eq!(show_result(&result, &typ), ~"3");
+}
+
+#[test]
+fn reuse_stacks() {
+    let module =
+r"sum xs = case xs of
+    y:ys -> primIntAdd y (sum ys)
+    [] -> 0
+main = sum [1,2,3,4,5,6,7,8,9,10]";
+    let assembly = compile_iter(module.chars());
+    let mut vm = VM::new();
+    vm.add_assembly(assembly);
+    let sc = vm.assembly[0].superCombinators.iter().find(|sc| sc.name == intern("main")).unwrap();
+    let result = vm.evaluate(sc.code(), sc.assembly_id);
+    assert_eq!(extract_result(result), Some(IntResult(55)));
+    //At most one stack per nested call is kept, far fewer than the number of reductions
+    let pooled = vm.stack_pool.borrow().get().len();
+    assert!(pooled > 0);
+    assert!(pooled < vm.metrics().reductions);
+}
+
+#[test]
+fn case_of_known_constructor_with_shadowed_names() {
+    let module =
+r"f a b = case (b, a) of
+    (a, b) -> primIntSubtract a b
+main = f 10 3";
+    let options = CompilerOptions { optimization_level: 1, .. Default::default() };
+    let (_, assembly) = compile_module_with(module.chars(), &options);
+    assert_eq!(run_main(assembly), Some(IntResult(-7)));
+}
+
+#[test]
+fn case_tables_and_default_alternatives() {
+    let module =
+r"data Op = Plus | Minus | Times | Negate Int
+
+partial op = case op of
+    Plus -> 1
+    Minus -> 10
+    Negate n -> n
+    other -> 100
+
+complete op = case op of
+    Plus -> 1
+    Minus -> 2
+    Times -> 3
+    Negate n -> n
+
+sumPartial ops = case ops of
+    o:rest -> primIntAdd (partial o) (sumPartial rest)
+    [] -> 0
+
+sumComplete ops = case ops of
+    o:rest -> primIntAdd (complete o) (sumComplete rest)
+    [] -> 0
+
+firstOr d xs = case xs of
+    y:ys -> y
+    other -> d
+
+main = primIntAdd (sumPartial [Plus, Minus, Times, Negate 4]) (primIntAdd (sumComplete [Plus, Minus, Times, Negate 4]) (firstOr 1000 []))";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(1125)));
+}
+
+#[test]
+fn partially_applied_constructors() {
+    let module =
+r"data Pair a b = Pair a b
+data Box a = Box a
+
+map f xs = case xs of
+    y:ys -> f y : map f ys
+    [] -> []
+
+sum xs = case xs of
+    y:ys -> primIntAdd y (sum ys)
+    [] -> 0
+
+first p = case p of
+    Pair x y -> x
+
+second p = case p of
+    Pair x y -> y
+
+unbox b = case b of
+    Box x -> x
+
+main = primIntAdd (sum (map first (map (Pair 1) [2, 3]))) (primIntAdd (sum (map second (map (Pair 10) [2, 3]))) (sum (map unbox (map Box [4]))))";
+    assert_eq!(execute_main(module.chars()), Some(IntResult(11)));
+}
+
 }